@@ -0,0 +1,65 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+
+/// What I/O buffers are aligned to.  This covers the memory alignment
+/// O_DIRECT wants on every host block device.
+pub const BUFFER_ALIGNMENT: usize = 4096;
+
+/// A zeroed, page aligned piece of memory that disk images read into
+/// and write from.  Unlike a `Vec`, it can be handed straight to the
+/// host with O_DIRECT.
+pub struct Buffer {
+    pointer: *mut u8,
+    length: usize,
+}
+
+// The buffer owns its memory, just like a `Vec<u8>` would.
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    pub fn new(length: usize) -> Buffer {
+        let pointer = unsafe { alloc::alloc_zeroed(Buffer::layout(length)) };
+        if pointer.is_null() {
+            alloc::handle_alloc_error(Buffer::layout(length));
+        }
+
+        Buffer { pointer, length }
+    }
+
+    fn layout(length: usize) -> Layout {
+        // Allocating nothing isn't allowed, even when nothing is what's
+        // needed.
+        Layout::from_size_align(length.max(1), BUFFER_ALIGNMENT).unwrap()
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.pointer, Buffer::layout(self.length)) };
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.pointer, self.length) }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.pointer, self.length) }
+    }
+}
+
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Buffer")
+            .field("length", &self.length)
+            .finish()
+    }
+}
@@ -0,0 +1,106 @@
+use super::{Buffer, Image};
+use error::*;
+use std::cmp;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+mod pool;
+mod stats;
+mod throttle;
+mod uring;
+
+pub use self::pool::Pool;
+pub use self::stats::{Counted, Counters, Statistics, Stats};
+pub use self::throttle::{Limit, Limits, Throttle};
+pub use self::uring::Uring;
+
+/// How many requests a disk has in flight at once, unless told
+/// otherwise.
+pub const DEFAULT_QUEUE_DEPTH: u32 = 64;
+
+/// The most threads a pool gets, however deep its queue is.
+const MAX_THREADS: u32 = 8;
+
+/// Something to do to a disk image.  Buffers travel with the operation,
+/// so they stay put for as long as the host might be using them.
+#[derive(Debug)]
+pub enum Operation {
+    Read {
+        offset: u64,
+        buffer: Buffer,
+    },
+    Write {
+        offset: u64,
+        buffer: Buffer,
+    },
+    Flush,
+    Discard {
+        offset: u64,
+        length: u64,
+    },
+    WriteZeroes {
+        offset: u64,
+        length: u64,
+        unmap: bool,
+    },
+}
+
+impl Operation {
+    /// Carries the operation out right here, on the calling thread.
+    pub fn perform(&mut self, image: &Image) -> Result<()> {
+        match *self {
+            Operation::Read {
+                offset,
+                ref mut buffer,
+            } => image.read_at(offset, buffer),
+            Operation::Write { offset, ref buffer } => image.write_at(offset, buffer),
+            Operation::Flush => image.flush(),
+            Operation::Discard { offset, length } => image.discard(offset, length),
+            Operation::WriteZeroes {
+                offset,
+                length,
+                unmap,
+            } => image.write_zeroes(offset, length, unmap),
+        }
+    }
+}
+
+/// Called once an operation is finished, with the operation handed
+/// back.
+pub type Done = Box<FnOnce(Operation, Result<()>) + Send>;
+
+/// Runs operations on a disk image in the background, so whoever asked
+/// for them can go on with something else.
+pub trait Engine: Debug + Send + Sync {
+    /// Starts an operation.  `done` is called from some other thread
+    /// once it's over, whether it worked or not.
+    fn submit(&self, operation: Operation, done: Done);
+}
+
+/// Picks the best engine for an image, with room for `depth`
+/// operations at once: io_uring if the image lives in a single file the
+/// host kernel can get at directly, and a thread pool otherwise.  What
+/// goes through it is held to `limits`, and counted in `stats`.
+pub fn open(
+    image: Arc<Image>,
+    depth: u32,
+    limits: Limits,
+    stats: Arc<Stats>,
+) -> Result<Arc<Engine>> {
+    let mut engine = None;
+    if image.direct_fd().is_some() {
+        match Uring::new(image.clone(), depth) {
+            Ok(uring) => engine = Some(Arc::new(uring) as Arc<Engine>),
+            Err(e) => info!("io_uring isn't available, using threads: {}", e),
+        }
+    }
+
+    let mut engine = match engine {
+        Some(engine) => engine,
+        None => Arc::new(Pool::new(image, cmp::min(depth, MAX_THREADS))?),
+    };
+    if !limits.is_empty() {
+        engine = Arc::new(Throttle::new(engine, limits)?);
+    }
+    Ok(Arc::new(Counted::new(engine, stats)))
+}
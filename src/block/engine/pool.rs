@@ -0,0 +1,147 @@
+use super::{Done, Engine, Operation};
+use block::Image;
+use error::*;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = (Operation, Done);
+
+/// Runs operations with plain blocking calls, spread over a few
+/// threads.  This works for every image, however it's laid out.
+#[derive(Debug)]
+pub struct Pool {
+    sender: Mutex<Option<Sender<Job>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    pub fn new(image: Arc<Image>, threads: u32) -> Result<Pool> {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..threads.max(1))
+            .map(|i| {
+                let image = image.clone();
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("block-pool-{}", i))
+                    .spawn(move || work(image.as_ref(), &receiver))
+                    .map_err(Error::from)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Pool {
+            sender: Mutex::new(Some(sender)),
+            threads,
+        })
+    }
+}
+
+/// Takes jobs off the queue until the pool goes away.
+fn work(image: &Image, receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        let (mut operation, done) = match job {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let result = operation.perform(image);
+        done(operation, result);
+    }
+}
+
+impl Engine for Pool {
+    fn submit(&self, operation: Operation, done: Done) {
+        let sender = self.sender.lock().unwrap();
+        // The threads only stop once the pool is dropped, so the
+        // sender is always there.
+        if let Some(ref sender) = *sender {
+            sender.send((operation, done)).unwrap();
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::Buffer;
+    use std::sync::mpsc::channel;
+
+    /// An image that's sixteen bytes of its own position.
+    #[derive(Debug)]
+    struct Counting;
+
+    impl Image for Counting {
+        fn size(&self) -> u64 {
+            16
+        }
+
+        fn read_only(&self) -> bool {
+            true
+        }
+
+        fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+            ::block::check_range(self, offset, data.len() as u64)?;
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = offset as u8 + i as u8;
+            }
+            Ok(())
+        }
+
+        fn write_at(&self, _: u64, _: &[u8]) -> Result<()> {
+            Err(ErrorKind::ImageError("read only").into())
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_completes_operations_on_its_threads() {
+        let pool = Pool::new(Arc::new(Counting), 2).unwrap();
+        let (sender, receiver) = channel();
+        for offset in 0..4 {
+            let sender = sender.clone();
+            pool.submit(
+                Operation::Read {
+                    offset: offset * 4,
+                    buffer: Buffer::new(4),
+                },
+                Box::new(move |operation, result| sender.send((operation, result)).unwrap()),
+            );
+        }
+        let sender = sender.clone();
+        pool.submit(
+            Operation::Write {
+                offset: 0,
+                buffer: Buffer::new(4),
+            },
+            Box::new(move |operation, result| sender.send((operation, result)).unwrap()),
+        );
+
+        let mut reads = vec![];
+        let mut failures = 0;
+        for _ in 0..5 {
+            match receiver.recv().unwrap() {
+                (Operation::Read { buffer, .. }, Ok(())) => reads.push(buffer[0]),
+                (_, Err(_)) => failures += 1,
+                (operation, result) => panic!("unexpected {:?}: {:?}", operation, result),
+            }
+        }
+
+        reads.sort();
+        assert_eq!(reads, vec![0, 4, 8, 12]);
+        assert_eq!(failures, 1);
+    }
+}
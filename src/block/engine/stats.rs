@@ -0,0 +1,204 @@
+use super::{Done, Engine, Operation};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many buckets latencies are counted in.  Bucket `i` counts what
+/// took less than `2^i` microseconds, and the last one everything that
+/// took longer than that, which is some eight seconds.
+pub const LATENCY_BUCKETS: usize = 24;
+
+/// The counters for one kind of operation.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Counters {
+    pub operations: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    /// How long operations took, from the moment they were submitted,
+    /// so time spent being throttled counts too.
+    pub latency: [u64; LATENCY_BUCKETS],
+}
+
+/// What a disk did since it was set up.  Zeroing counts as writing,
+/// without any bytes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Statistics {
+    pub read: Counters,
+    pub write: Counters,
+    pub flush: Counters,
+    pub discard: Counters,
+}
+
+#[derive(Debug, Default)]
+struct AtomicCounters {
+    operations: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl AtomicCounters {
+    fn record(&self, bytes: u64, latency: Duration, ok: bool) {
+        self.operations.fetch_add(1, Ordering::Relaxed);
+        if ok {
+            self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        } else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let micros = latency.as_secs() * 1_000_000 + latency.subsec_micros() as u64;
+        let bucket = (64 - micros.leading_zeros()) as usize;
+        self.latency[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> Counters {
+        let mut counters = Counters {
+            operations: self.operations.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: [0; LATENCY_BUCKETS],
+        };
+        for (count, bucket) in counters.latency.iter_mut().zip(self.latency.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        counters
+    }
+}
+
+/// A disk's counters, which can be read while it's busy.
+#[derive(Debug, Default)]
+pub struct Stats {
+    read: AtomicCounters,
+    write: AtomicCounters,
+    flush: AtomicCounters,
+    discard: AtomicCounters,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    pub fn load(&self) -> Statistics {
+        Statistics {
+            read: self.read.load(),
+            write: self.write.load(),
+            flush: self.flush.load(),
+            discard: self.discard.load(),
+        }
+    }
+
+    /// The counters an operation goes to, and how many bytes it moves.
+    fn counters(&self, operation: &Operation) -> (&AtomicCounters, u64) {
+        match *operation {
+            Operation::Read { ref buffer, .. } => (&self.read, buffer.len() as u64),
+            Operation::Write { ref buffer, .. } => (&self.write, buffer.len() as u64),
+            Operation::WriteZeroes { .. } => (&self.write, 0),
+            Operation::Flush => (&self.flush, 0),
+            Operation::Discard { .. } => (&self.discard, 0),
+        }
+    }
+}
+
+/// Counts everything that goes through to another engine.
+#[derive(Debug)]
+pub struct Counted {
+    inner: Arc<Engine>,
+    stats: Arc<Stats>,
+}
+
+impl Counted {
+    pub fn new(inner: Arc<Engine>, stats: Arc<Stats>) -> Counted {
+        Counted { inner, stats }
+    }
+}
+
+impl Engine for Counted {
+    fn submit(&self, operation: Operation, done: Done) {
+        let started = Instant::now();
+        let stats = self.stats.clone();
+        self.inner.submit(
+            operation,
+            Box::new(move |operation, result| {
+                {
+                    let (counters, bytes) = stats.counters(&operation);
+                    counters.record(bytes, started.elapsed(), result.is_ok());
+                }
+                done(operation, result)
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::Buffer;
+    use error::*;
+
+    /// Fails everything past the first kilobyte, right away.
+    #[derive(Debug)]
+    struct Small;
+
+    impl Engine for Small {
+        fn submit(&self, operation: Operation, done: Done) {
+            let result = match operation {
+                Operation::Read { offset, .. } | Operation::Write { offset, .. }
+                    if offset >= 1024 =>
+                {
+                    Err(ErrorKind::ImageError("too far").into())
+                }
+                _ => Ok(()),
+            };
+            done(operation, result);
+        }
+    }
+
+    #[test]
+    fn it_counts_operations() {
+        let stats = Arc::new(Stats::new());
+        let engine = Counted::new(Arc::new(Small), stats.clone());
+        let operations = vec![
+            Operation::Read {
+                offset: 0,
+                buffer: Buffer::new(512),
+            },
+            Operation::Read {
+                offset: 2048,
+                buffer: Buffer::new(512),
+            },
+            Operation::Write {
+                offset: 512,
+                buffer: Buffer::new(512),
+            },
+            Operation::WriteZeroes {
+                offset: 0,
+                length: 1024,
+                unmap: false,
+            },
+            Operation::Flush,
+        ];
+        for operation in operations {
+            engine.submit(operation, Box::new(|_, _| ()));
+        }
+
+        let statistics = stats.load();
+        assert_eq!(
+            (
+                statistics.read.operations,
+                statistics.read.bytes,
+                statistics.read.errors
+            ),
+            (2, 512, 1)
+        );
+        assert_eq!(
+            (statistics.write.operations, statistics.write.bytes),
+            (2, 512)
+        );
+        assert_eq!(statistics.flush.operations, 1);
+        assert_eq!(statistics.discard, Counters::default());
+        // Every operation lands in some bucket, failed or not.
+        assert_eq!(statistics.read.latency.iter().sum::<u64>(), 2);
+        assert_eq!(statistics.write.latency.iter().sum::<u64>(), 2);
+    }
+}
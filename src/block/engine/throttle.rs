@@ -0,0 +1,402 @@
+use super::{Done, Engine, Operation};
+use error::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How fast something may happen on average, and how much of it may
+/// happen at once after a quiet spell.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Limit {
+    /// Per second.
+    pub rate: u64,
+    /// What builds up while nothing happens; a second's worth if it's
+    /// less than that.
+    pub burst: u64,
+}
+
+/// The limits on a disk.  Reads and writes are limited separately, both
+/// in operations and in bytes; flushes aren't limited at all.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Limits {
+    pub read_iops: Option<Limit>,
+    pub write_iops: Option<Limit>,
+    pub read_bandwidth: Option<Limit>,
+    pub write_bandwidth: Option<Limit>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// A token bucket, which fills up at the limit's rate and is emptied by
+/// what's done.  It can go into debt, so something bigger than the
+/// whole bucket still gets through once the bucket is full.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    level: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit) -> Bucket {
+        let rate = limit.rate.max(1) as f64;
+        let capacity = (limit.burst as f64).max(rate);
+        Bucket {
+            rate,
+            capacity,
+            level: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.level = (self.level + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long until something costing `cost` can go ahead.
+    fn wait(&self, cost: f64) -> Duration {
+        let missing = cost.min(self.capacity) - self.level;
+        if missing <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            let wait = missing / self.rate;
+            Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+        }
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.level -= cost;
+    }
+}
+
+/// The buckets operations in one direction go through.
+struct Direction {
+    iops: Option<Bucket>,
+    bandwidth: Option<Bucket>,
+    /// What's waiting for the buckets to fill, in order.
+    queue: VecDeque<(Operation, Done, u64)>,
+}
+
+impl Direction {
+    fn new(iops: Option<Limit>, bandwidth: Option<Limit>) -> Direction {
+        Direction {
+            iops: iops.map(Bucket::new),
+            bandwidth: bandwidth.map(Bucket::new),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// How long until an operation moving `bytes` can go ahead.
+    fn wait(&mut self, now: Instant, bytes: u64) -> Duration {
+        let mut wait = Duration::from_secs(0);
+        if let Some(ref mut bucket) = self.iops {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait(1.0));
+        }
+        if let Some(ref mut bucket) = self.bandwidth {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait(bytes as f64));
+        }
+        wait
+    }
+
+    fn take(&mut self, bytes: u64) {
+        if let Some(ref mut bucket) = self.iops {
+            bucket.take(1.0);
+        }
+        if let Some(ref mut bucket) = self.bandwidth {
+            bucket.take(bytes as f64);
+        }
+    }
+
+    /// Takes off the queue whatever can go ahead now, returning how
+    /// long until the next one can, if there's one.
+    fn release(&mut self, now: Instant, ready: &mut Vec<(Operation, Done)>) -> Option<Duration> {
+        while let Some(bytes) = self.queue.front().map(|queued| queued.2) {
+            let wait = self.wait(now, bytes);
+            if wait > Duration::from_secs(0) {
+                return Some(wait);
+            }
+
+            self.take(bytes);
+            let (operation, done, _) = self.queue.pop_front().unwrap();
+            ready.push((operation, done));
+        }
+
+        None
+    }
+}
+
+impl ::std::fmt::Debug for Direction {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Direction")
+            .field("iops", &self.iops)
+            .field("bandwidth", &self.bandwidth)
+            .field("queued", &self.queue.len())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    read: Direction,
+    write: Direction,
+    stopping: bool,
+}
+
+impl State {
+    /// The direction an operation goes in, and how many bytes it moves,
+    /// unless it isn't limited at all.
+    fn direction(&mut self, operation: &Operation) -> Option<(&mut Direction, u64)> {
+        match *operation {
+            Operation::Read { ref buffer, .. } => Some((&mut self.read, buffer.len() as u64)),
+            Operation::Write { ref buffer, .. } => Some((&mut self.write, buffer.len() as u64)),
+            Operation::Discard { .. } | Operation::WriteZeroes { .. } => Some((&mut self.write, 0)),
+            Operation::Flush => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    wakeup: Condvar,
+}
+
+/// Holds operations back so they stay within a disk's limits, then
+/// hands them on.  Operations wait in order, reads and writes apart, on
+/// a thread that releases them as their buckets fill up.
+#[derive(Debug)]
+pub struct Throttle {
+    inner: Arc<Engine>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Throttle {
+    pub fn new(inner: Arc<Engine>, limits: Limits) -> Result<Throttle> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                read: Direction::new(limits.read_iops, limits.read_bandwidth),
+                write: Direction::new(limits.write_iops, limits.write_bandwidth),
+                stopping: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+
+        let thread = {
+            let inner = inner.clone();
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("block-throttle".to_owned())
+                .spawn(move || release(&shared, inner.as_ref()))?
+        };
+
+        Ok(Throttle {
+            inner,
+            shared,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Hands queued operations on as they're allowed to go ahead, until
+/// the throttle goes away; then whatever is left goes right away.
+fn release(shared: &Shared, inner: &Engine) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let mut ready = vec![];
+        let read = state.read.release(now, &mut ready);
+        let write = state.write.release(now, &mut ready);
+        if state.stopping {
+            let state = &mut *state;
+            let queued = state
+                .read
+                .queue
+                .drain(..)
+                .chain(state.write.queue.drain(..));
+            ready.extend(queued.map(|(operation, done, _)| (operation, done)));
+        }
+
+        let stopping = state.stopping;
+        if !ready.is_empty() {
+            drop(state);
+            for (operation, done) in ready {
+                inner.submit(operation, done);
+            }
+            state = shared.state.lock().unwrap();
+            continue;
+        }
+        if stopping {
+            return;
+        }
+
+        state = match (read, write) {
+            (Some(a), Some(b)) => shared.wakeup.wait_timeout(state, a.min(b)).unwrap().0,
+            (Some(wait), None) | (None, Some(wait)) => {
+                shared.wakeup.wait_timeout(state, wait).unwrap().0
+            }
+            (None, None) => shared.wakeup.wait(state).unwrap(),
+        };
+    }
+}
+
+impl Engine for Throttle {
+    fn submit(&self, operation: Operation, done: Done) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let now = Instant::now();
+            let queued = match state.direction(&operation) {
+                Some((direction, bytes)) => {
+                    if direction.queue.is_empty()
+                        && direction.wait(now, bytes) == Duration::from_secs(0)
+                    {
+                        direction.take(bytes);
+                        None
+                    } else {
+                        Some((direction, bytes))
+                    }
+                }
+                None => None,
+            };
+
+            if let Some((direction, bytes)) = queued {
+                direction.queue.push_back((operation, done, bytes));
+                self.shared.wakeup.notify_one();
+                return;
+            }
+        }
+
+        self.inner.submit(operation, done);
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopping = true;
+        self.shared.wakeup.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::Buffer;
+    use std::sync::mpsc::{channel, Sender};
+
+    /// Does nothing, right away.
+    #[derive(Debug)]
+    struct Immediate;
+
+    impl Engine for Immediate {
+        fn submit(&self, operation: Operation, done: Done) {
+            done(operation, Ok(()));
+        }
+    }
+
+    fn submit(throttle: &Throttle, read: bool, sender: &Sender<(bool, Instant)>) {
+        let sender = sender.clone();
+        let operation = if read {
+            Operation::Read {
+                offset: 0,
+                buffer: Buffer::new(512),
+            }
+        } else {
+            write(512)
+        };
+        throttle.submit(
+            operation,
+            Box::new(move |_, _| sender.send((read, Instant::now())).unwrap()),
+        );
+    }
+
+    fn write(length: usize) -> Operation {
+        Operation::Write {
+            offset: 0,
+            buffer: Buffer::new(length),
+        }
+    }
+
+    #[test]
+    fn it_spaces_operations_out() {
+        let limits = Limits {
+            write_iops: Some(Limit { rate: 20, burst: 0 }),
+            ..Limits::default()
+        };
+        // Only a bucket's worth go right away.
+        let throttle = Throttle::new(Arc::new(Immediate), limits).unwrap();
+        let (sender, receiver) = channel();
+        let started = Instant::now();
+        for _ in 0..22 {
+            submit(&throttle, false, &sender);
+        }
+        submit(&throttle, true, &sender);
+
+        let done = (0..23)
+            .map(|_| receiver.recv().unwrap())
+            .collect::<Vec<_>>();
+        let read = done.iter().position(|&(read, _)| read).unwrap();
+        assert!(read < 22);
+        let last = done.last().unwrap().1.duration_since(started);
+        assert!(last >= Duration::from_millis(80), "{:?}", last);
+        assert!(last < Duration::from_secs(2), "{:?}", last);
+    }
+
+    #[test]
+    fn it_limits_bandwidth_with_a_burst() {
+        let limits = Limits {
+            write_bandwidth: Some(Limit {
+                rate: 1 << 20,
+                burst: 2 << 20,
+            }),
+            ..Limits::default()
+        };
+        let throttle = Throttle::new(Arc::new(Immediate), limits).unwrap();
+        let (sender, receiver) = channel();
+        let started = Instant::now();
+        // The burst covers the first two; the third waits for a
+        // quarter of a second's worth.
+        for length in &[1 << 20, 1 << 20, 1 << 18] {
+            let sender = sender.clone();
+            throttle.submit(
+                write(*length),
+                Box::new(move |_, _| sender.send((false, Instant::now())).unwrap()),
+            );
+        }
+
+        let done = (0..3)
+            .map(|_| receiver.recv().unwrap().1)
+            .collect::<Vec<_>>();
+        assert!(done[1].duration_since(started) < Duration::from_millis(100));
+        let last = done[2].duration_since(started);
+        assert!(last >= Duration::from_millis(200), "{:?}", last);
+    }
+
+    #[test]
+    fn it_lets_everything_go_when_dropped() {
+        let limits = Limits {
+            read_iops: Some(Limit { rate: 1, burst: 1 }),
+            ..Limits::default()
+        };
+        let throttle = Throttle::new(Arc::new(Immediate), limits).unwrap();
+        let (sender, receiver) = channel();
+        for _ in 0..3 {
+            submit(&throttle, true, &sender);
+        }
+        drop(throttle);
+        assert_eq!(receiver.try_iter().count(), 3);
+    }
+}
@@ -0,0 +1,610 @@
+use super::{Done, Engine, Operation, Pool};
+use block::{check_range, Image};
+use error::*;
+use libc;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_REGISTER_PROBE: u32 = 8;
+const IO_URING_OP_SUPPORTED: u16 = 1 << 0;
+
+const IORING_OP_NOP: u8 = 0;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+const IORING_FSYNC_DATASYNC: u32 = 1 << 0;
+
+/// The most entries we ask the kernel for.
+const MAX_ENTRIES: u32 = 4096;
+/// The operations a probe can report on.
+const PROBE_OPS: usize = 256;
+/// The user data of the no-op that tells the reaper to stop.
+const STOP: u64 = u64::MAX;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct SubmissionOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct CompletionOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SubmissionOffsets,
+    cq_off: CompletionOffsets,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct SubmissionEntry {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct CompletionEntry {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Probe {
+    last_op: u8,
+    ops_len: u8,
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [ProbeOp; PROBE_OPS],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct ProbeOp {
+    op: u8,
+    resv: u8,
+    flags: u16,
+    resv2: u32,
+}
+
+/// Memory shared with the kernel.
+#[derive(Debug)]
+struct Mapping {
+    address: *mut u8,
+    length: usize,
+}
+
+// The kernel is the only other one touching the memory, and every
+// access goes through atomics or the submission lock.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(fd: RawFd, length: usize, offset: libc::off_t) -> Result<Mapping> {
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Mapping {
+            address: address as *mut u8,
+            length,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.address.offset(offset as isize) as *mut T }
+    }
+
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.at::<AtomicU32>(offset) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.address as *mut libc::c_void, self.length) };
+    }
+}
+
+/// The operations the kernel has, by their token.
+#[derive(Default)]
+struct Flight {
+    next: u64,
+    operations: HashMap<u64, (Operation, Done)>,
+}
+
+/// An io_uring instance, and the operations in it.
+struct Ring {
+    file: File,
+    params: Params,
+    submissions: Mapping,
+    entries: Mapping,
+    completions: Mapping,
+    flight: Mutex<Flight>,
+}
+
+impl Ring {
+    fn new(entries: u32) -> Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let file = unsafe { File::from_raw_fd(fd as RawFd) };
+        let fd = file.as_raw_fd();
+        let submissions = Mapping::new(
+            fd,
+            params.sq_off.array as usize + params.sq_entries as usize * 4,
+            IORING_OFF_SQ_RING,
+        )?;
+        let entries = Mapping::new(
+            fd,
+            params.sq_entries as usize * mem::size_of::<SubmissionEntry>(),
+            IORING_OFF_SQES,
+        )?;
+        let completions = Mapping::new(
+            fd,
+            params.cq_off.cqes as usize
+                + params.cq_entries as usize * mem::size_of::<CompletionEntry>(),
+            IORING_OFF_CQ_RING,
+        )?;
+
+        let ring = Ring {
+            file,
+            params,
+            submissions,
+            entries,
+            completions,
+            flight: Mutex::new(Flight::default()),
+        };
+        ring.probe()?;
+        Ok(ring)
+    }
+
+    /// Makes sure the kernel knows every operation we hand to it.
+    /// Older kernels have io_uring, but not plain reads and writes.
+    fn probe(&self) -> Result<()> {
+        let mut probe: Probe = unsafe { mem::zeroed() };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.file.as_raw_fd(),
+                IORING_REGISTER_PROBE,
+                &mut probe as *mut Probe,
+                PROBE_OPS as u32,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let supported = |op: u8| {
+            op <= probe.last_op && (probe.ops[op as usize].flags & IO_URING_OP_SUPPORTED) != 0
+        };
+        if [
+            IORING_OP_NOP,
+            IORING_OP_FSYNC,
+            IORING_OP_READ,
+            IORING_OP_WRITE,
+        ]
+        .iter()
+        .all(|&op| supported(op))
+        {
+            Ok(())
+        } else {
+            Err(ErrorKind::ImageError("io_uring can't read and write").into())
+        }
+    }
+
+    fn enter(&self, submit: u32, wait: u32, flags: u32) -> io::Result<()> {
+        loop {
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.file.as_raw_fd(),
+                    submit,
+                    wait,
+                    flags,
+                    ptr::null::<libc::sigset_t>(),
+                    0,
+                )
+            };
+            if result >= 0 {
+                return Ok(());
+            }
+
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+
+    /// Queues an entry, returning whether there was room for it.  Has
+    /// to be called with the flight locked.
+    fn push(&self, entry: SubmissionEntry) -> bool {
+        let offsets = &self.params.sq_off;
+        let head = self
+            .submissions
+            .atomic(offsets.head)
+            .load(Ordering::Acquire);
+        let tail = self
+            .submissions
+            .atomic(offsets.tail)
+            .load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) >= self.params.sq_entries {
+            return false;
+        }
+
+        let mask = unsafe { *self.submissions.at::<u32>(offsets.ring_mask) };
+        let index = tail & mask;
+        unsafe {
+            ptr::write(
+                self.entries
+                    .at::<SubmissionEntry>(index * mem::size_of::<SubmissionEntry>() as u32),
+                entry,
+            );
+            ptr::write(self.submissions.at::<u32>(offsets.array + index * 4), index);
+        }
+        self.submissions
+            .atomic(offsets.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Tells the kernel about every entry it hasn't taken yet,
+    /// including any an earlier call couldn't get in.
+    fn submit(&self) -> io::Result<()> {
+        let offsets = &self.params.sq_off;
+        let head = self
+            .submissions
+            .atomic(offsets.head)
+            .load(Ordering::Acquire);
+        let tail = self
+            .submissions
+            .atomic(offsets.tail)
+            .load(Ordering::Relaxed);
+        self.enter(tail.wrapping_sub(head), 0, 0)
+    }
+
+    /// Takes every completion the kernel posted so far.
+    fn completions(&self) -> Vec<CompletionEntry> {
+        let offsets = &self.params.cq_off;
+        let head = self.completions.atomic(offsets.head);
+        let tail = self
+            .completions
+            .atomic(offsets.tail)
+            .load(Ordering::Acquire);
+        let mask = unsafe { *self.completions.at::<u32>(offsets.ring_mask) };
+        let mut current = head.load(Ordering::Relaxed);
+        let mut entries = vec![];
+        while current != tail {
+            let offset = offsets.cqes + (current & mask) * mem::size_of::<CompletionEntry>() as u32;
+            entries.push(unsafe { ptr::read(self.completions.at::<CompletionEntry>(offset)) });
+            current = current.wrapping_add(1);
+        }
+
+        head.store(current, Ordering::Release);
+        entries
+    }
+}
+
+/// Runs reads, writes and flushes through io_uring, straight on the
+/// image's file, with a thread collecting the results.  Everything
+/// else goes to a small thread pool.
+pub struct Uring {
+    image: Arc<Image>,
+    ring: Arc<Ring>,
+    pool: Pool,
+    reaper: Option<JoinHandle<()>>,
+}
+
+impl Uring {
+    pub fn new(image: Arc<Image>, depth: u32) -> Result<Uring> {
+        if image.direct_fd().is_none() {
+            return Err(ErrorKind::ImageError("image has no file for io_uring").into());
+        }
+
+        let entries = depth.max(1).next_power_of_two().min(MAX_ENTRIES);
+        let ring = Arc::new(Ring::new(entries)?);
+        let pool = Pool::new(image.clone(), 1)?;
+        let reaper = {
+            let ring = ring.clone();
+            let image = image.clone();
+            thread::Builder::new()
+                .name("block-uring".to_owned())
+                .spawn(move || reap(&ring, image.as_ref()))?
+        };
+
+        Ok(Uring {
+            image,
+            ring,
+            pool,
+            reaper: Some(reaper),
+        })
+    }
+}
+
+/// Hands completions to whoever is waiting for them, until the ring is
+/// told to stop.
+fn reap(ring: &Ring, image: &Image) {
+    loop {
+        if let Err(e) = ring.enter(0, 1, IORING_ENTER_GETEVENTS) {
+            error!("could not wait for io_uring: {}", e);
+            return;
+        }
+
+        let mut stop = false;
+        for completion in ring.completions() {
+            if completion.user_data == STOP {
+                stop = true;
+                continue;
+            }
+
+            let removed = ring
+                .flight
+                .lock()
+                .unwrap()
+                .operations
+                .remove(&completion.user_data);
+            let (mut operation, done) = match removed {
+                Some(flight) => flight,
+                None => continue,
+            };
+
+            let result = if completion.res < 0 {
+                Err(io::Error::from_raw_os_error(-completion.res).into())
+            } else {
+                finish(&mut operation, completion.res as usize, image)
+            };
+            done(operation, result);
+        }
+
+        if stop {
+            return;
+        }
+    }
+}
+
+/// Does whatever's left of a read or write the kernel only did part
+/// of.
+fn finish(operation: &mut Operation, done: usize, image: &Image) -> Result<()> {
+    match *operation {
+        Operation::Read {
+            offset,
+            ref mut buffer,
+        } if done < buffer.len() => image.read_at(offset + done as u64, &mut buffer[done..]),
+        Operation::Write { offset, ref buffer } if done < buffer.len() => {
+            image.write_at(offset + done as u64, &buffer[done..])
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Engine for Uring {
+    fn submit(&self, mut operation: Operation, done: Done) {
+        let fd = self.image.direct_fd().unwrap();
+        let mut entry = SubmissionEntry {
+            fd,
+            ..SubmissionEntry::default()
+        };
+        let range = match operation {
+            Operation::Read {
+                offset,
+                ref mut buffer,
+            } => {
+                entry.opcode = IORING_OP_READ;
+                entry.off = offset;
+                entry.addr = buffer.as_mut_ptr() as u64;
+                entry.len = buffer.len() as u32;
+                check_range(self.image.as_ref(), offset, buffer.len() as u64)
+            }
+            Operation::Write { offset, ref buffer } => {
+                entry.opcode = IORING_OP_WRITE;
+                entry.off = offset;
+                entry.addr = buffer.as_ptr() as u64;
+                entry.len = buffer.len() as u32;
+                check_range(self.image.as_ref(), offset, buffer.len() as u64)
+            }
+            Operation::Flush => {
+                entry.opcode = IORING_OP_FSYNC;
+                entry.op_flags = IORING_FSYNC_DATASYNC;
+                Ok(())
+            }
+            _ => return self.pool.submit(operation, done),
+        };
+        if let Err(e) = range {
+            return done(operation, Err(e));
+        }
+
+        // The buffer's memory stays where it is while the operation
+        // waits in the flight, however much the operation moves.
+        let mut flight = self.ring.flight.lock().unwrap();
+        let token = flight.next;
+        flight.next += 1;
+        entry.user_data = token;
+        flight.operations.insert(token, (operation, done));
+        if !self.ring.push(entry) {
+            let (operation, done) = flight.operations.remove(&token).unwrap();
+            drop(flight);
+            return self.pool.submit(operation, done);
+        }
+
+        // An entry in the ring is the kernel's now, and goes along with
+        // the next one if it can't go in this time.
+        if let Err(e) = self.ring.submit() {
+            warn!("could not submit to io_uring: {}", e);
+        }
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        let stop = SubmissionEntry {
+            opcode: IORING_OP_NOP,
+            user_data: STOP,
+            ..SubmissionEntry::default()
+        };
+        let pushed = {
+            let _flight = self.ring.flight.lock().unwrap();
+            self.ring.push(stop) && self.ring.submit().is_ok()
+        };
+
+        if !pushed {
+            warn!("could not stop io_uring");
+            return;
+        }
+
+        if let Some(reaper) = self.reaper.take() {
+            let _ = reaper.join();
+        }
+    }
+}
+
+impl ::std::fmt::Debug for Uring {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Uring")
+            .field("image", &self.image)
+            .field("entries", &self.ring.params.sq_entries)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::{Buffer, Raw};
+    use scratch;
+    use std::fs;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn it_reads_and_writes_through_the_ring() {
+        let path = scratch::path("uring");
+        File::create(&path).unwrap().set_len(1 << 16).unwrap();
+        let image = Arc::new(Raw::open(&path, false, false).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // Plenty of hosts don't let us have io_uring; the pool takes
+        // over there.
+        let uring = match Uring::new(image.clone(), 4) {
+            Ok(uring) => uring,
+            Err(_) => return,
+        };
+
+        let mut written = Buffer::new(21);
+        written.copy_from_slice(b"written by the kernel");
+        let (sender, receiver) = channel();
+        let done = |sender: &::std::sync::mpsc::Sender<_>| -> Done {
+            let sender = sender.clone();
+            Box::new(move |operation, result| sender.send((operation, result)).unwrap())
+        };
+
+        uring.submit(
+            Operation::Write {
+                offset: 4096,
+                buffer: written,
+            },
+            done(&sender),
+        );
+        assert!(receiver.recv().unwrap().1.is_ok());
+        uring.submit(Operation::Flush, done(&sender));
+        assert!(receiver.recv().unwrap().1.is_ok());
+
+        uring.submit(
+            Operation::Read {
+                offset: 4096,
+                buffer: Buffer::new(21),
+            },
+            done(&sender),
+        );
+        match receiver.recv().unwrap() {
+            (Operation::Read { buffer, .. }, Ok(())) => {
+                assert_eq!(&buffer[..], b"written by the kernel")
+            }
+            (operation, result) => panic!("unexpected {:?}: {:?}", operation, result),
+        }
+
+        uring.submit(
+            Operation::Read {
+                offset: (1 << 16) - 4,
+                buffer: Buffer::new(8),
+            },
+            done(&sender),
+        );
+        assert!(receiver.recv().unwrap().1.is_err());
+    }
+}
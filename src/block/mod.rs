@@ -0,0 +1,307 @@
+use error::*;
+use std::cmp;
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+mod buffer;
+pub mod engine;
+mod qcow2;
+mod raw;
+
+pub use self::buffer::Buffer;
+pub use self::qcow2::{Qcow2, DEFAULT_CLUSTER_BITS};
+pub use self::raw::Raw;
+
+/// The size of a sector, which is what block devices address the disk
+/// in.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// How much is written at once when zeroes have to be written out by
+/// hand.
+const ZERO_CHUNK: usize = 1 << 16;
+
+/// How many backing files deep an image can go.
+const MAX_CHAIN: u32 = 16;
+
+/// Tells apart the ephemeral overlays this process makes.
+static EPHEMERAL: AtomicUsize = AtomicUsize::new(0);
+
+/// How an image is laid out in its file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// The disk's contents, byte for byte.
+    Raw,
+    Qcow2,
+}
+
+impl Format {
+    /// Works out an image's format from what's in it.  Anything that
+    /// isn't recognisably something else is raw.
+    pub fn probe<P: AsRef<Path>>(path: P) -> Result<Format> {
+        if Qcow2::probe(path)? {
+            Ok(Format::Qcow2)
+        } else {
+            Ok(Format::Raw)
+        }
+    }
+}
+
+/// A disk image: something that holds the contents of a guest's disk,
+/// however it's laid out on the host.
+pub trait Image: Debug + Send + Sync {
+    /// The size of the disk the guest sees, in bytes.
+    fn size(&self) -> u64;
+    /// Whether the guest is kept from changing the disk.
+    fn read_only(&self) -> bool;
+    /// The smallest write the host can do without reading anything
+    /// back first.
+    fn block_size(&self) -> u32 {
+        SECTOR_SIZE as u32
+    }
+
+    /// What the offset and length of every access have to be a
+    /// multiple of.
+    fn alignment(&self) -> u32 {
+        SECTOR_SIZE as u32
+    }
+
+    /// The file the disk's contents sit in as they are, if there's
+    /// one, so it can be read and written without going through the
+    /// image.
+    fn direct_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Fills `data` from the disk, starting at `offset`.
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()>;
+    /// Writes `data` to the disk, starting at `offset`.
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()>;
+    /// Makes sure everything written so far survives a host crash.
+    fn flush(&self) -> Result<()>;
+
+    /// Whether discarding actually gives space back to the host, rather
+    /// than doing nothing.
+    fn can_discard(&self) -> bool {
+        false
+    }
+
+    /// Tells the image the guest doesn't care about the given range
+    /// anymore.  What's read back from it afterwards is unspecified.
+    fn discard(&self, _offset: u64, _length: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Zeroes the given range.  If `unmap` is set, the space may be
+    /// given back to the host too, as long as the range reads back as
+    /// zeroes.
+    fn write_zeroes(&self, offset: u64, length: u64, _unmap: bool) -> Result<()> {
+        fill_zeroes(self, offset, length)
+    }
+}
+
+/// Zeroes the given range the slow way, by writing zeroes over it.
+pub fn fill_zeroes<I: Image + ?Sized>(image: &I, offset: u64, length: u64) -> Result<()> {
+    let zeroes = Buffer::new(cmp::min(length, ZERO_CHUNK as u64) as usize);
+    let mut done = 0;
+    while done < length {
+        let count = cmp::min(length - done, zeroes.len() as u64) as usize;
+        image.write_at(offset + done, &zeroes[..count])?;
+        done += count as u64;
+    }
+
+    Ok(())
+}
+
+/// Makes sure the given range fits in the image.
+pub fn check_range(image: &Image, offset: u64, length: u64) -> Result<()> {
+    match offset.checked_add(length) {
+        Some(end) if end <= image.size() => Ok(()),
+        _ => Err(ErrorKind::ImageError("access past the end of the disk").into()),
+    }
+}
+
+/// Opens the image at `path`.  If `format` isn't given, it's worked
+/// out from the image; a guest that can write to a raw image could make
+/// it look like something else, so it's best given.  `direct` only
+/// applies to raw images.
+pub fn open<P: AsRef<Path>>(
+    path: P,
+    format: Option<Format>,
+    read_only: bool,
+    direct: bool,
+) -> Result<Arc<Image>> {
+    open_chained(path.as_ref(), format, read_only, direct, 0)
+}
+
+/// Opens an image `depth` backing files down a chain.
+fn open_chained(
+    path: &Path,
+    format: Option<Format>,
+    read_only: bool,
+    direct: bool,
+    depth: u32,
+) -> Result<Arc<Image>> {
+    if depth > MAX_CHAIN {
+        return Err(ErrorKind::ImageError("backing file chain is too long").into());
+    }
+
+    let format = match format {
+        Some(format) => format,
+        None => Format::probe(path)?,
+    };
+    Ok(match format {
+        Format::Raw => Arc::new(Raw::open(path, read_only, direct)?),
+        Format::Qcow2 => Arc::new(Qcow2::open_chained(path, read_only, depth)?),
+    })
+}
+
+/// Creates a qcow2 overlay at `path` on top of the image at `base`.
+/// What's written to the overlay stays there, and `base` stays as it
+/// is; it's named by its full path, so the overlay can go anywhere.
+pub fn create_overlay<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    base: Q,
+    format: Option<Format>,
+) -> Result<()> {
+    let base = base.as_ref().canonicalize()?;
+    let format = match format {
+        Some(format) => format,
+        None => Format::probe(&base)?,
+    };
+    let size = open(&base, Some(format), true, false)?.size();
+    create_qcow2_overlay(path.as_ref(), &base, format, size)
+}
+
+fn create_qcow2_overlay(path: &Path, base: &Path, format: Format, size: u64) -> Result<()> {
+    let name = match base.to_str() {
+        Some(name) => name,
+        None => return Err(ErrorKind::ImageError("backing file name isn't UTF-8").into()),
+    };
+    Qcow2::create(path, size, DEFAULT_CLUSTER_BITS, Some((name, format)))
+}
+
+/// Opens a throwaway overlay on top of the image at `base`, which
+/// itself is only read.  The overlay's file is gone as soon as it's
+/// open, so what's written to it goes away with the image, however the
+/// process ends.
+pub fn open_ephemeral<P: AsRef<Path>>(
+    base: P,
+    format: Option<Format>,
+    read_only: bool,
+) -> Result<Arc<Image>> {
+    let path = env::temp_dir().join(format!(
+        "vent-{}-overlay-{}.qcow2",
+        process::id(),
+        EPHEMERAL.fetch_add(1, Ordering::Relaxed)
+    ));
+    create_overlay(&path, base, format)?;
+    let image = open(&path, Some(Format::Qcow2), read_only, false);
+    fs::remove_file(&path)?;
+    image
+}
+
+/// Takes an external snapshot of `image`, the live image at `top`: a
+/// new overlay is created at `overlay` on top of it, and opened on top
+/// of `image` itself.  Once nothing writes to `image` anymore, `top`
+/// holds the disk as it was at that point.
+pub fn snapshot<P: AsRef<Path>, Q: AsRef<Path>>(
+    image: Arc<Image>,
+    top: P,
+    format: Format,
+    overlay: Q,
+) -> Result<Arc<Image>> {
+    let top = top.as_ref().canonicalize()?;
+    create_qcow2_overlay(overlay.as_ref(), &top, format, image.size())?;
+    let read_only = image.read_only();
+    Ok(Arc::new(Qcow2::open_over(overlay, read_only, image)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch::Scratch;
+
+    #[test]
+    fn it_keeps_the_base_of_an_overlay_as_it_is() {
+        let mut scratch = Scratch::new();
+        let base = scratch.path("overlay-base.raw");
+        let overlay = scratch.path("overlay-top.qcow2");
+        fs::write(&base, vec![0x11; 8192]).unwrap();
+        create_overlay(&overlay, &base, None).unwrap();
+        assert_eq!(Format::probe(&overlay).unwrap(), Format::Qcow2);
+
+        let image = open(&overlay, None, false, false).unwrap();
+        image.write_at(512, &[0x22; 512]).unwrap();
+        let mut data = vec![0u8; 1536];
+        image.read_at(0, &mut data).unwrap();
+        assert_eq!(
+            data,
+            [vec![0x11; 512], vec![0x22; 512], vec![0x11; 512]].concat()
+        );
+        assert_eq!(fs::read(&base).unwrap(), vec![0x11; 8192]);
+    }
+
+    #[test]
+    fn it_leaves_nothing_of_an_ephemeral_overlay_behind() {
+        let mut scratch = Scratch::new();
+        let base = scratch.path("ephemeral-base.raw");
+        fs::write(&base, vec![0x11; 8192]).unwrap();
+
+        let image = open_ephemeral(&base, Some(Format::Raw), false).unwrap();
+        image.write_at(0, &[0x22; 512]).unwrap();
+        let mut data = vec![0u8; 1024];
+        image.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [vec![0x22; 512], vec![0x11; 512]].concat());
+        assert_eq!(fs::read(&base).unwrap(), vec![0x11; 8192]);
+
+        let prefix = format!("vent-{}-overlay-", process::id());
+        let left = fs::read_dir(env::temp_dir())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().starts_with(&prefix)
+            })
+            .count();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn it_snapshots_a_live_image() {
+        let mut scratch = Scratch::new();
+        let base = scratch.path("snapshot-base.raw");
+        let overlay = scratch.path("snapshot-top.qcow2");
+        fs::write(&base, vec![0x11; 8192]).unwrap();
+        let image = open(&base, Some(Format::Raw), false, false).unwrap();
+        image.write_at(0, &[0x22; 512]).unwrap();
+
+        let top = snapshot(image, &base, Format::Raw, &overlay).unwrap();
+        top.write_at(512, &[0x33; 512]).unwrap();
+        let mut data = vec![0u8; 1536];
+        top.read_at(0, &mut data).unwrap();
+        assert_eq!(
+            data,
+            [vec![0x22; 512], vec![0x33; 512], vec![0x11; 512]].concat()
+        );
+        drop(top);
+
+        // The base holds the disk as it was when the snapshot was
+        // taken, and the overlay can be opened again by itself.
+        let base = fs::read(&base).unwrap();
+        assert_eq!(
+            base[..1024],
+            [vec![0x22; 512], vec![0x11; 512]].concat()[..]
+        );
+        let top = open(&overlay, None, true, false).unwrap();
+        top.read_at(0, &mut data).unwrap();
+        assert_eq!(
+            data,
+            [vec![0x22; 512], vec![0x33; 512], vec![0x11; 512]].concat()
+        );
+    }
+}
@@ -0,0 +1,1321 @@
+use super::{Buffer, Format, Image, SECTOR_SIZE};
+use byteorder::{BigEndian, ByteOrder};
+use error::*;
+use miniz_oxide::inflate;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// "QFI\xfb".
+const MAGIC: u32 = 0x5146_49fb;
+
+const V2_HEADER_LENGTH: u32 = 72;
+const V3_HEADER_LENGTH: u32 = 104;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// The cluster size images are created with, 64KiB.
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+/// Refcounts are 16 bits wide; it's the only width we write.
+const REFCOUNT_ORDER: u32 = 4;
+const REFCOUNT_BYTES: u64 = 2;
+
+/// The image wasn't closed cleanly, so its refcounts can't be trusted.
+const INCOMPATIBLE_DIRTY: u64 = 1 << 0;
+/// Something found the image's metadata to be inconsistent.
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+
+/// Where a table or cluster is, in L1 and L2 entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster is only referenced once, so it can be written in place.
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeroes (version 3 only).
+const ZERO: u64 = 1 << 0;
+
+const EXTENSION_END: u32 = 0;
+const EXTENSION_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// The longest backing file name there can be.
+const MAX_BACKING_NAME: u32 = 1023;
+/// The fixed part of an internal snapshot's entry in the snapshot
+/// table.
+const SNAPSHOT_HEADER_LENGTH: u64 = 40;
+
+/// How many L2 tables are kept in memory.
+const L2_CACHE_SIZE: usize = 64;
+
+/// The biggest L1 and refcount tables that are read in, in bytes, as
+/// QEMU has them; images that claim more are taken as corrupt.
+const MAX_L1_SIZE: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+
+/// The fields of the header we care about.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    snapshots_offset: u64,
+    incompatible_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+}
+
+impl Header {
+    fn read(file: &File) -> Result<Header> {
+        let mut data = [0u8; V3_HEADER_LENGTH as usize];
+        file.read_exact_at(&mut data[..V2_HEADER_LENGTH as usize], 0)?;
+        if BigEndian::read_u32(&data[0..4]) != MAGIC {
+            return Err(ErrorKind::ImageError("not a qcow2 image").into());
+        }
+
+        let version = BigEndian::read_u32(&data[4..8]);
+        let (incompatible_features, refcount_order, header_length) = match version {
+            2 => (0, REFCOUNT_ORDER, V2_HEADER_LENGTH),
+            3 => {
+                file.read_exact_at(&mut data[V2_HEADER_LENGTH as usize..], 72)?;
+                (
+                    BigEndian::read_u64(&data[72..80]),
+                    BigEndian::read_u32(&data[96..100]),
+                    BigEndian::read_u32(&data[100..104]),
+                )
+            }
+            _ => return Err(ErrorKind::ImageError("unsupported qcow2 version").into()),
+        };
+
+        let header = Header {
+            version,
+            backing_file_offset: BigEndian::read_u64(&data[8..16]),
+            backing_file_size: BigEndian::read_u32(&data[16..20]),
+            cluster_bits: BigEndian::read_u32(&data[20..24]),
+            size: BigEndian::read_u64(&data[24..32]),
+            l1_size: BigEndian::read_u32(&data[36..40]),
+            l1_table_offset: BigEndian::read_u64(&data[40..48]),
+            refcount_table_offset: BigEndian::read_u64(&data[48..56]),
+            refcount_table_clusters: BigEndian::read_u32(&data[56..60]),
+            nb_snapshots: BigEndian::read_u32(&data[60..64]),
+            snapshots_offset: BigEndian::read_u64(&data[64..72]),
+            incompatible_features,
+            refcount_order,
+            header_length,
+        };
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&header.cluster_bits) {
+            return Err(ErrorKind::ImageError("unsupported qcow2 cluster size").into());
+        }
+        if BigEndian::read_u32(&data[32..36]) != 0 {
+            return Err(ErrorKind::ImageError("encrypted qcow2 images aren't supported").into());
+        }
+        if header.header_length < V2_HEADER_LENGTH || header.backing_file_size > MAX_BACKING_NAME {
+            return Err(ErrorKind::ImageError("invalid qcow2 header").into());
+        }
+        if (header.incompatible_features & !(INCOMPATIBLE_DIRTY | INCOMPATIBLE_CORRUPT)) != 0 {
+            return Err(ErrorKind::ImageError("unsupported qcow2 features").into());
+        }
+        if (header.l1_size as u64) < header.l1_entries_needed() {
+            return Err(ErrorKind::ImageError("qcow2 L1 table is too small").into());
+        }
+
+        // The tables are read in whole, so they have to be of a sane
+        // size, and in the file.
+        let length = file.metadata()?.len();
+        let l1_size = header.l1_size as u64 * 8;
+        if l1_size > MAX_L1_SIZE || !fits(header.l1_table_offset, l1_size, length) {
+            return Err(ErrorKind::ImageError("invalid qcow2 L1 table").into());
+        }
+        let refcount_table_size = header.refcount_table_clusters as u64 * header.cluster_size();
+        if header.refcount_table_clusters == 0
+            || refcount_table_size > MAX_REFCOUNT_TABLE_SIZE
+            || !fits(header.refcount_table_offset, refcount_table_size, length)
+        {
+            return Err(ErrorKind::ImageError("invalid qcow2 refcount table").into());
+        }
+
+        Ok(header)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// How much of the disk each L2 table covers.
+    fn l2_coverage(&self) -> u64 {
+        self.cluster_size() * (self.cluster_size() / 8)
+    }
+
+    fn l1_entries_needed(&self) -> u64 {
+        let coverage = self.l2_coverage();
+        match self.size {
+            0 => 0,
+            size => (size - 1) / coverage + 1,
+        }
+    }
+
+    /// Reads the backing file's name and format, if there's one.
+    fn backing(&self, file: &File) -> Result<Option<(String, Option<Format>)>> {
+        if self.backing_file_offset == 0 {
+            return Ok(None);
+        }
+
+        let mut name = vec![0u8; self.backing_file_size as usize];
+        file.read_exact_at(&mut name, self.backing_file_offset)?;
+        let name = String::from_utf8(name)
+            .map_err(|_| ErrorKind::ImageError("qcow2 backing file name isn't UTF-8"))?;
+
+        // The backing format is one of the header extensions, which
+        // run from the end of the header to the end of the first
+        // cluster.
+        let mut format = None;
+        let mut offset = self.header_length as u64;
+        while offset + 8 <= self.cluster_size() {
+            let mut extension = [0u8; 8];
+            file.read_exact_at(&mut extension, offset)?;
+            let kind = BigEndian::read_u32(&extension[0..4]);
+            let length = BigEndian::read_u32(&extension[4..8]) as u64;
+            if kind == EXTENSION_END {
+                break;
+            }
+
+            if kind == EXTENSION_BACKING_FORMAT && length <= 16 {
+                let mut data = vec![0u8; length as usize];
+                file.read_exact_at(&mut data, offset + 8)?;
+                format = match &data[..] {
+                    b"raw" => Some(Format::Raw),
+                    b"qcow2" => Some(Format::Qcow2),
+                    _ => None,
+                };
+            }
+            offset += 8 + ((length + 7) & !7);
+        }
+
+        Ok(Some((name, format)))
+    }
+}
+
+/// Where an image's backing file is, and what format it's in if the
+/// image says so.
+pub type BackingFile = (PathBuf, Option<Format>);
+
+/// Where a guest cluster's contents are.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Mapping {
+    /// Nowhere; they're in the backing file, or zero without one.
+    Unallocated,
+    Zero,
+    Data(u64),
+    /// Compressed, as described by the L2 entry.
+    Compressed(u64),
+}
+
+/// What checking an image's refcounts found.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Check {
+    /// Clusters used more often than their refcount says, which could
+    /// be freed and overwritten while still in use.
+    pub errors: u64,
+    /// Clusters with a bigger refcount than there are uses of them,
+    /// which only waste space.
+    pub leaks: u64,
+}
+
+/// The metadata that changes as the guest writes.
+#[derive(Debug)]
+struct State {
+    l1: Vec<u64>,
+    refcount_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    /// Where the next cluster is allocated.  Clusters only ever come
+    /// off the end of the file.
+    end: u64,
+    l2_cache: HashMap<u64, Vec<u64>>,
+    /// The last compressed cluster read, by its L2 entry.
+    compressed: Option<(u64, Vec<u8>)>,
+}
+
+/// A qcow2 image: clusters are only allocated in the file once they're
+/// written, and what was never written comes from the backing file.
+#[derive(Debug)]
+pub struct Qcow2 {
+    file: File,
+    read_only: bool,
+    header: Header,
+    backing_file: Option<BackingFile>,
+    backing: Option<Arc<Image>>,
+    state: Mutex<State>,
+}
+
+impl Qcow2 {
+    /// Whether the file at `path` is a qcow2 image.
+    pub fn probe<P: AsRef<Path>>(path: P) -> Result<bool> {
+        let file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        Ok(file.read_exact_at(&mut magic, 0).is_ok() && BigEndian::read_u32(&magic) == MAGIC)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Qcow2> {
+        Qcow2::open_chained(path.as_ref(), read_only, 0)
+    }
+
+    /// Opens an image `depth` backing files down a chain.
+    pub(super) fn open_chained(path: &Path, read_only: bool, depth: u32) -> Result<Qcow2> {
+        let (file, header, backing_file) = Qcow2::load(path, read_only)?;
+        let backing = match backing_file {
+            Some((ref name, format)) => {
+                Some(super::open_chained(name, format, true, false, depth + 1)?)
+            }
+            None => None,
+        };
+
+        Qcow2::assemble(file, header, read_only, backing_file, backing)
+    }
+
+    /// Opens an image on top of a backing image that's open already,
+    /// rather than the one the image names; that has to be the same
+    /// image.
+    pub fn open_over<P: AsRef<Path>>(
+        path: P,
+        read_only: bool,
+        backing: Arc<Image>,
+    ) -> Result<Qcow2> {
+        let (file, header, backing_file) = Qcow2::load(path.as_ref(), read_only)?;
+        if backing_file.is_none() {
+            return Err(ErrorKind::ImageError("qcow2 image has no backing file").into());
+        }
+
+        Qcow2::assemble(file, header, read_only, backing_file, Some(backing))
+    }
+
+    /// Opens an image's file and reads its header, along with where its
+    /// backing file is; a relative name is relative to the image.
+    fn load(path: &Path, read_only: bool) -> Result<(File, Header, Option<BackingFile>)> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let header = Header::read(&file)?;
+        if !read_only {
+            if (header.incompatible_features & INCOMPATIBLE_CORRUPT) != 0 {
+                return Err(ErrorKind::ImageError("qcow2 image is marked corrupt").into());
+            }
+            if (header.incompatible_features & INCOMPATIBLE_DIRTY) != 0 {
+                return Err(ErrorKind::ImageError("qcow2 image wasn't closed cleanly").into());
+            }
+            if header.refcount_order != REFCOUNT_ORDER {
+                return Err(ErrorKind::ImageError("unsupported qcow2 refcount width").into());
+            }
+        }
+
+        let backing_file = header.backing(&file)?.map(|(name, format)| {
+            let name = PathBuf::from(name);
+            match path.parent() {
+                Some(parent) if name.is_relative() => (parent.join(name), format),
+                _ => (name, format),
+            }
+        });
+        Ok((file, header, backing_file))
+    }
+
+    fn assemble(
+        file: File,
+        header: Header,
+        read_only: bool,
+        backing_file: Option<BackingFile>,
+        backing: Option<Arc<Image>>,
+    ) -> Result<Qcow2> {
+        let cluster_size = header.cluster_size();
+        let l1 = read_table(&file, header.l1_table_offset, header.l1_size as u64)?;
+        let refcount_table = read_table(
+            &file,
+            header.refcount_table_offset,
+            header.refcount_table_clusters as u64 * cluster_size / 8,
+        )?;
+        let length = file.metadata()?.len();
+        let state = State {
+            l1,
+            refcount_table,
+            refcount_table_offset: header.refcount_table_offset,
+            refcount_table_clusters: header.refcount_table_clusters,
+            end: (length + cluster_size - 1) & !(cluster_size - 1),
+            l2_cache: HashMap::new(),
+            compressed: None,
+        };
+
+        Ok(Qcow2 {
+            file,
+            read_only,
+            header,
+            backing_file,
+            backing,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Creates an empty image, `size` bytes big, with clusters of
+    /// `1 << cluster_bits` bytes.  What isn't written comes from
+    /// `backing`, if there is one, which is looked for relative to the
+    /// new image.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        size: u64,
+        cluster_bits: u32,
+        backing: Option<(&str, Format)>,
+    ) -> Result<()> {
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(ErrorKind::ImageError("unsupported qcow2 cluster size").into());
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_coverage = cluster_size * (cluster_size / 8);
+        let l1_size = (size + l2_coverage - 1) / l2_coverage;
+        let l1_clusters = cmp::max((l1_size * 8 + cluster_size - 1) / cluster_size, 1);
+        // The header, then the refcount table and its first block, then
+        // the L1 table.
+        let clusters = 3 + l1_clusters;
+        if clusters > cluster_size / REFCOUNT_BYTES {
+            return Err(ErrorKind::ImageError("qcow2 image is too big for its clusters").into());
+        }
+
+        let mut first = vec![0u8; cluster_size as usize];
+        BigEndian::write_u32(&mut first[0..4], MAGIC);
+        BigEndian::write_u32(&mut first[4..8], 3);
+        BigEndian::write_u32(&mut first[20..24], cluster_bits);
+        BigEndian::write_u64(&mut first[24..32], size);
+        BigEndian::write_u32(&mut first[36..40], l1_size as u32);
+        BigEndian::write_u64(&mut first[40..48], 3 * cluster_size);
+        BigEndian::write_u64(&mut first[48..56], cluster_size);
+        BigEndian::write_u32(&mut first[56..60], 1);
+        BigEndian::write_u32(&mut first[96..100], REFCOUNT_ORDER);
+        BigEndian::write_u32(&mut first[100..104], V3_HEADER_LENGTH);
+
+        let mut offset = V3_HEADER_LENGTH as usize;
+        if let Some((name, format)) = backing {
+            let format: &[u8] = match format {
+                Format::Raw => b"raw",
+                Format::Qcow2 => b"qcow2",
+            };
+            BigEndian::write_u32(&mut first[offset..offset + 4], EXTENSION_BACKING_FORMAT);
+            BigEndian::write_u32(&mut first[offset + 4..offset + 8], format.len() as u32);
+            first[offset + 8..offset + 8 + format.len()].copy_from_slice(format);
+            offset += 8 + ((format.len() + 7) & !7);
+            // The end of the extensions.
+            offset += 8;
+
+            if name.len() > MAX_BACKING_NAME as usize || offset + name.len() > first.len() {
+                return Err(ErrorKind::ImageError("qcow2 backing file name is too long").into());
+            }
+            first[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+            BigEndian::write_u64(&mut first[8..16], offset as u64);
+            BigEndian::write_u32(&mut first[16..20], name.len() as u32);
+        }
+
+        let mut refcounts = vec![0u8; 2 * cluster_size as usize];
+        BigEndian::write_u64(&mut refcounts[0..8], 2 * cluster_size);
+        for cluster in 0..clusters as usize {
+            let entry = cluster_size as usize + cluster * REFCOUNT_BYTES as usize;
+            BigEndian::write_u16(&mut refcounts[entry..entry + 2], 1);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all_at(&first, 0)?;
+        file.write_all_at(&refcounts, cluster_size)?;
+        file.set_len(clusters * cluster_size)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.header.cluster_size()
+    }
+
+    pub fn backing_file(&self) -> Option<&BackingFile> {
+        self.backing_file.as_ref()
+    }
+
+    /// Grows the disk to `size` bytes; what's new reads as zeroes, or
+    /// as the backing file if that's bigger.  The L1 table moves to the
+    /// end of the file if it doesn't cover the new size.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if self.read_only {
+            return Err(ErrorKind::ImageError("image is read only").into());
+        }
+        if size < self.header.size {
+            return Err(ErrorKind::ImageError("qcow2 images can't shrink").into());
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut header = self.header.clone();
+        header.size = size;
+        let needed = header.l1_entries_needed();
+        let room = (header.l1_size as u64 * 8 + cluster_size - 1) / cluster_size * cluster_size / 8;
+        {
+            let mut state = self.state.lock().unwrap();
+            if needed > state.l1.len() as u64 && needed <= room {
+                // There's room in the table's clusters already.
+                state.l1.resize(needed as usize, 0);
+                let mut data = [0u8; 4];
+                BigEndian::write_u32(&mut data, needed as u32);
+                self.file.write_all_at(&data, 36)?;
+                header.l1_size = needed as u32;
+            } else if needed > state.l1.len() as u64 {
+                let clusters = (needed * 8 + cluster_size - 1) / cluster_size;
+                let offset = state.end;
+                state.end += clusters * cluster_size;
+
+                let mut l1 = state.l1.clone();
+                l1.resize((clusters * cluster_size / 8) as usize, 0);
+                write_table(&self.file, offset, &l1)?;
+                for cluster in 0..clusters {
+                    self.set_refcount(&mut state, offset + cluster * cluster_size, 1)?;
+                }
+
+                let mut data = [0u8; 12];
+                BigEndian::write_u32(&mut data[0..4], l1.len() as u32);
+                BigEndian::write_u64(&mut data[4..12], offset);
+                self.file.write_all_at(&data, 36)?;
+
+                let old = header.l1_table_offset;
+                for cluster in 0..room * 8 / cluster_size {
+                    self.set_refcount(&mut state, old + cluster * cluster_size, 0)?;
+                }
+                header.l1_size = l1.len() as u32;
+                header.l1_table_offset = offset;
+                state.l1 = l1;
+            }
+        }
+
+        let mut data = [0u8; 8];
+        BigEndian::write_u64(&mut data, size);
+        self.file.write_all_at(&data, 24)?;
+        self.file.sync_data()?;
+        self.header = header;
+        Ok(())
+    }
+
+    /// Works out every cluster's refcount from the tables that point to
+    /// it, and compares that to the refcounts the image has.
+    pub fn check(&self) -> Result<Check> {
+        if self.header.refcount_order != REFCOUNT_ORDER {
+            return Err(ErrorKind::ImageError("unsupported qcow2 refcount width").into());
+        }
+
+        let state = self.state.lock().unwrap();
+        let cluster_size = self.cluster_size();
+        let length = self.file.metadata()?.len();
+        let mut check = Check::default();
+        let mut expected = vec![0u64; ((length + cluster_size - 1) / cluster_size) as usize];
+        {
+            let mut reference = |offset: u64, length: u64| {
+                let first = offset / cluster_size;
+                let last = (offset + cmp::max(length, 1) - 1) / cluster_size;
+                for cluster in first..last + 1 {
+                    match expected.get_mut(cluster as usize) {
+                        Some(count) => *count += 1,
+                        None => check.errors += 1,
+                    }
+                }
+            };
+
+            reference(0, cluster_size);
+            let mut l1_tables = vec![(self.header.l1_table_offset, self.header.l1_size as u64)];
+            let mut offset = self.header.snapshots_offset;
+            for _ in 0..self.header.nb_snapshots {
+                let mut entry = [0u8; SNAPSHOT_HEADER_LENGTH as usize];
+                self.file.read_exact_at(&mut entry, offset)?;
+                l1_tables.push((
+                    BigEndian::read_u64(&entry[0..8]),
+                    BigEndian::read_u32(&entry[8..12]) as u64,
+                ));
+                let names = BigEndian::read_u16(&entry[12..14]) as u64
+                    + BigEndian::read_u16(&entry[14..16]) as u64;
+                let extra = BigEndian::read_u32(&entry[36..40]) as u64;
+                let next = offset + SNAPSHOT_HEADER_LENGTH + extra + names;
+                offset = (next + 7) & !7;
+            }
+            if self.header.nb_snapshots > 0 {
+                reference(
+                    self.header.snapshots_offset,
+                    offset - self.header.snapshots_offset,
+                );
+            }
+
+            for (l1_offset, l1_size) in l1_tables {
+                reference(l1_offset, l1_size * 8);
+                for l1_entry in read_table(&self.file, l1_offset, l1_size)? {
+                    let l2 = l1_entry & OFFSET_MASK;
+                    if l2 == 0 {
+                        continue;
+                    }
+                    reference(l2, cluster_size);
+                    for entry in read_table(&self.file, l2, cluster_size / 8)? {
+                        if (entry & COMPRESSED) != 0 {
+                            let (offset, length) = self.compressed_extent(entry);
+                            reference(offset, length);
+                        } else if (entry & OFFSET_MASK) != 0 {
+                            reference(entry & OFFSET_MASK, cluster_size);
+                        }
+                    }
+                }
+            }
+
+            reference(
+                state.refcount_table_offset,
+                state.refcount_table_clusters as u64 * cluster_size,
+            );
+            for block in &state.refcount_table {
+                if (block & OFFSET_MASK) != 0 {
+                    reference(block & OFFSET_MASK, cluster_size);
+                }
+            }
+        }
+
+        for (cluster, &count) in expected.iter().enumerate() {
+            let actual = self.refcount(&state, cluster as u64 * cluster_size)?;
+            if actual < count {
+                check.errors += 1;
+            } else if actual > count {
+                check.leaks += 1;
+            }
+        }
+
+        Ok(check)
+    }
+
+    /// Where an offset's L2 entry is: the index into the L1 table, and
+    /// into the L2 table.
+    fn indices(&self, offset: u64) -> (usize, usize) {
+        let l2_entries = self.cluster_size() / 8;
+        let cluster = offset >> self.header.cluster_bits;
+        (
+            (cluster / l2_entries) as usize,
+            (cluster % l2_entries) as usize,
+        )
+    }
+
+    /// Loads an L2 table, if it isn't in the cache already.
+    fn l2<'a>(&self, state: &'a mut State, offset: u64) -> Result<&'a mut Vec<u64>> {
+        if !state.l2_cache.contains_key(&offset) {
+            if state.l2_cache.len() >= L2_CACHE_SIZE {
+                state.l2_cache.clear();
+            }
+            let table = read_table(&self.file, offset, self.cluster_size() / 8)?;
+            state.l2_cache.insert(offset, table);
+        }
+
+        Ok(state.l2_cache.get_mut(&offset).unwrap())
+    }
+
+    fn lookup(&self, state: &mut State, offset: u64) -> Result<Mapping> {
+        let (l1_index, l2_index) = self.indices(offset);
+        let l2_offset = match state.l1.get(l1_index) {
+            Some(&entry) if (entry & OFFSET_MASK) != 0 => entry & OFFSET_MASK,
+            _ => return Ok(Mapping::Unallocated),
+        };
+
+        let entry = self.l2(state, l2_offset)?[l2_index];
+        Ok(if (entry & COMPRESSED) != 0 {
+            Mapping::Compressed(entry & !(COPIED | COMPRESSED))
+        } else if (entry & ZERO) != 0 {
+            Mapping::Zero
+        } else if (entry & OFFSET_MASK) == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data(entry & OFFSET_MASK)
+        })
+    }
+
+    /// Where a compressed cluster is, and how many bytes it can take up
+    /// at most.
+    fn compressed_extent(&self, descriptor: u64) -> (u64, u64) {
+        let shift = 62 - (self.header.cluster_bits - 8);
+        let offset = descriptor & ((1 << shift) - 1);
+        let sectors = (descriptor & !(COPIED | COMPRESSED)) >> shift;
+        (offset, (sectors + 1) * SECTOR_SIZE - (offset % SECTOR_SIZE))
+    }
+
+    fn decompress<'a>(&self, state: &'a mut State, descriptor: u64) -> Result<&'a [u8]> {
+        let cached = match state.compressed {
+            Some((cached, _)) => cached == descriptor,
+            None => false,
+        };
+        if !cached {
+            let (offset, length) = self.compressed_extent(descriptor);
+            // The last compressed cluster can run right up to the end
+            // of the file.
+            let available = self.file.metadata()?.len().saturating_sub(offset);
+            let mut data = vec![0u8; cmp::min(length, available) as usize];
+            self.file.read_exact_at(&mut data, offset)?;
+            let cluster_size = self.cluster_size() as usize;
+            let cluster = inflate::decompress_to_vec_with_limit(&data, cluster_size)
+                .map_err(|_| ErrorKind::ImageError("invalid compressed qcow2 cluster"))?;
+            if cluster.len() != cluster_size {
+                return Err(ErrorKind::ImageError("short compressed qcow2 cluster").into());
+            }
+            state.compressed = Some((descriptor, cluster));
+        }
+
+        Ok(&state.compressed.as_ref().unwrap().1)
+    }
+
+    /// Reads from a single cluster.
+    fn read_piece(&self, state: &mut State, offset: u64, data: &mut [u8]) -> Result<()> {
+        let within = (offset & (self.cluster_size() - 1)) as usize;
+        match self.lookup(state, offset)? {
+            Mapping::Data(host) => self.file.read_exact_at(data, host + within as u64)?,
+            Mapping::Zero => zero(data),
+            Mapping::Compressed(descriptor) => {
+                let cluster = self.decompress(state, descriptor)?;
+                data.copy_from_slice(&cluster[within..within + data.len()]);
+            }
+            Mapping::Unallocated => match self.backing {
+                Some(ref backing) if offset < backing.size() => {
+                    // The backing file can be smaller than the image.
+                    let count = cmp::min(data.len() as u64, backing.size() - offset) as usize;
+                    backing.read_at(offset, &mut data[..count])?;
+                    zero(&mut data[count..]);
+                }
+                _ => zero(data),
+            },
+        }
+
+        Ok(())
+    }
+
+    /// The refcount of the cluster at `offset`.
+    fn refcount(&self, state: &State, offset: u64) -> Result<u64> {
+        let (table_index, block_index) = self.refcount_indices(offset);
+        let block = match state.refcount_table.get(table_index) {
+            Some(&block) if (block & OFFSET_MASK) != 0 => block & OFFSET_MASK,
+            _ => return Ok(0),
+        };
+
+        let mut data = [0u8; REFCOUNT_BYTES as usize];
+        self.file
+            .read_exact_at(&mut data, block + block_index * REFCOUNT_BYTES)?;
+        Ok(BigEndian::read_u16(&data) as u64)
+    }
+
+    fn refcount_indices(&self, offset: u64) -> (usize, u64) {
+        let per_block = self.cluster_size() / REFCOUNT_BYTES;
+        let cluster = offset >> self.header.cluster_bits;
+        ((cluster / per_block) as usize, cluster % per_block)
+    }
+
+    fn set_refcount(&self, state: &mut State, offset: u64, value: u64) -> Result<()> {
+        let (table_index, block_index) = self.refcount_indices(offset);
+        if table_index >= state.refcount_table.len() {
+            self.grow_refcount_table(state, table_index + 1)?;
+        }
+
+        let mut block = state.refcount_table[table_index] & OFFSET_MASK;
+        if block == 0 {
+            // The new refcount block needs a refcount itself, which can
+            // well be in the block.
+            block = state.end;
+            state.end += self.cluster_size();
+            self.file
+                .write_all_at(&Buffer::new(self.cluster_size() as usize), block)?;
+            state.refcount_table[table_index] = block;
+            self.write_entry(state.refcount_table_offset, table_index, block)?;
+            self.set_refcount(state, block, 1)?;
+        }
+
+        let mut data = [0u8; REFCOUNT_BYTES as usize];
+        BigEndian::write_u16(&mut data, cmp::min(value, u16::MAX as u64) as u16);
+        self.file
+            .write_all_at(&data, block + block_index * REFCOUNT_BYTES)?;
+        Ok(())
+    }
+
+    /// Moves the refcount table somewhere it has room for at least
+    /// `entries` blocks.
+    fn grow_refcount_table(&self, state: &mut State, entries: usize) -> Result<()> {
+        let per_cluster = self.cluster_size() / 8;
+        let clusters = cmp::max(
+            (entries as u64 + per_cluster - 1) / per_cluster,
+            state.refcount_table_clusters as u64 * 2,
+        );
+        let offset = state.end;
+        state.end += clusters * self.cluster_size();
+
+        let mut table = state.refcount_table.clone();
+        table.resize((clusters * per_cluster) as usize, 0);
+        write_table(&self.file, offset, &table)?;
+        let mut header = [0u8; 12];
+        BigEndian::write_u64(&mut header[0..8], offset);
+        BigEndian::write_u32(&mut header[8..12], clusters as u32);
+        self.file.write_all_at(&header, 48)?;
+
+        let old = (state.refcount_table_offset, state.refcount_table_clusters);
+        state.refcount_table = table;
+        state.refcount_table_offset = offset;
+        state.refcount_table_clusters = clusters as u32;
+        for cluster in 0..clusters {
+            self.set_refcount(state, offset + cluster * self.cluster_size(), 1)?;
+        }
+        for cluster in 0..old.1 as u64 {
+            self.set_refcount(state, old.0 + cluster * self.cluster_size(), 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes a new cluster off the end of the file.
+    fn allocate(&self, state: &mut State) -> Result<u64> {
+        let offset = state.end;
+        state.end += self.cluster_size();
+        self.set_refcount(state, offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Drops a reference to whatever an L2 entry pointed to.
+    fn release(&self, state: &mut State, entry: u64) -> Result<()> {
+        let (offset, length) = if (entry & COMPRESSED) != 0 {
+            self.compressed_extent(entry)
+        } else {
+            (entry & OFFSET_MASK, 1)
+        };
+        if offset == 0 {
+            return Ok(());
+        }
+
+        let first = offset & !(self.cluster_size() - 1);
+        let mut cluster = first;
+        while cluster < offset + length {
+            let count = self.refcount(state, cluster)?;
+            self.set_refcount(state, cluster, count.saturating_sub(1))?;
+            cluster += self.cluster_size();
+        }
+
+        Ok(())
+    }
+
+    fn write_entry(&self, table: u64, index: usize, entry: u64) -> Result<()> {
+        let mut data = [0u8; 8];
+        BigEndian::write_u64(&mut data, entry);
+        self.file.write_all_at(&data, table + index as u64 * 8)?;
+        Ok(())
+    }
+
+    fn set_l2_entry(&self, state: &mut State, l2: u64, index: usize, entry: u64) -> Result<()> {
+        self.l2(state, l2)?[index] = entry;
+        self.write_entry(l2, index, entry)
+    }
+
+    /// The L2 table for an L1 entry, copying or creating it if it isn't
+    /// only ours.
+    fn l2_for_write(&self, state: &mut State, l1_index: usize) -> Result<u64> {
+        let entry = match state.l1.get(l1_index) {
+            Some(&entry) => entry,
+            None => return Err(ErrorKind::ImageError("write past the end of the L1 table").into()),
+        };
+        let old = entry & OFFSET_MASK;
+        if (entry & COPIED) != 0 && old != 0 {
+            return Ok(old);
+        }
+
+        let table = if old != 0 {
+            self.l2(state, old)?.clone()
+        } else {
+            vec![0; (self.cluster_size() / 8) as usize]
+        };
+        let new = self.allocate(state)?;
+        write_table(&self.file, new, &table)?;
+        state.l2_cache.insert(new, table);
+        state.l1[l1_index] = new | COPIED;
+        self.write_entry(self.header.l1_table_offset, l1_index, new | COPIED)?;
+        if old != 0 {
+            self.release(state, old)?;
+        }
+
+        Ok(new)
+    }
+
+    /// The cluster in the file a guest cluster can be written to in
+    /// place, allocating it if need be.  Unless the guest is about to
+    /// write the whole cluster, the new one starts out with whatever
+    /// the guest would have read from it.
+    fn cluster_for_write(&self, state: &mut State, offset: u64, whole: bool) -> Result<u64> {
+        let (l1_index, l2_index) = self.indices(offset);
+        let l2 = self.l2_for_write(state, l1_index)?;
+        let entry = self.l2(state, l2)?[l2_index];
+        if (entry & (COPIED | COMPRESSED | ZERO)) == COPIED && (entry & OFFSET_MASK) != 0 {
+            return Ok(entry & OFFSET_MASK);
+        }
+
+        let host = self.allocate(state)?;
+        if !whole {
+            let start = offset & !(self.cluster_size() - 1);
+            let mut contents = Buffer::new(self.cluster_size() as usize);
+            self.read_piece(state, start, &mut contents)?;
+            self.file.write_all_at(&contents, host)?;
+        }
+
+        self.set_l2_entry(state, l2, l2_index, host | COPIED)?;
+        self.release(state, entry)?;
+        Ok(host)
+    }
+
+    /// Points a whole guest cluster at nothing, or at zeroes, giving
+    /// back what it used to point to.
+    fn unmap(&self, state: &mut State, offset: u64, entry: u64) -> Result<()> {
+        let (l1_index, l2_index) = self.indices(offset);
+        if let Mapping::Unallocated = self.lookup(state, offset)? {
+            if entry == 0 {
+                return Ok(());
+            }
+        }
+
+        let l2 = self.l2_for_write(state, l1_index)?;
+        let current = self.l2(state, l2)?[l2_index];
+        self.set_l2_entry(state, l2, l2_index, entry)?;
+
+        // A cluster shared with a snapshot has to stay where it is, and
+        // only loses a reference.
+        let host = current & OFFSET_MASK;
+        if (current & (COPIED | COMPRESSED)) == COPIED
+            && host != 0
+            && self.refcount(state, host)? == 1
+        {
+            // Not every filesystem can do this; the cluster is free
+            // either way.
+            let _ = super::raw::punch_hole(&self.file, host, self.cluster_size());
+            self.set_refcount(state, host, 0)?;
+        } else {
+            self.release(state, current)?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with each cluster-sized piece of a range: the offset
+    /// it starts at, and where it is in the range.
+    fn pieces<F>(&self, offset: u64, length: u64, mut f: F) -> Result<()>
+    where
+        F: FnMut(u64, usize, usize) -> Result<()>,
+    {
+        let mut done = 0;
+        while done < length {
+            let current = offset + done;
+            let within = current & (self.cluster_size() - 1);
+            let count = cmp::min(length - done, self.cluster_size() - within);
+            f(current, done as usize, count as usize)?;
+            done += count;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn l2_entry(&self, offset: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let (l1_index, l2_index) = self.indices(offset);
+        let l2 = state.l1[l1_index] & OFFSET_MASK;
+        self.l2(&mut state, l2).unwrap()[l2_index]
+    }
+}
+
+impl Image for Qcow2 {
+    fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        super::check_range(self, offset, data.len() as u64)?;
+        let mut state = self.state.lock().unwrap();
+        self.pieces(offset, data.len() as u64, |current, start, count| {
+            self.read_piece(&mut state, current, &mut data[start..start + count])
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+        super::check_range(self, offset, data.len() as u64)?;
+        if self.read_only {
+            return Err(ErrorKind::ImageError("image is read only").into());
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut state = self.state.lock().unwrap();
+        self.pieces(offset, data.len() as u64, |current, start, count| {
+            let host = self.cluster_for_write(&mut state, current, count as u64 == cluster_size)?;
+            let within = current & (cluster_size - 1);
+            self.file
+                .write_all_at(&data[start..start + count], host + within)?;
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Metadata goes straight to the file, so there's nothing in
+        // memory to write out first.
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn can_discard(&self) -> bool {
+        !self.read_only
+    }
+
+    /// Gives back every whole cluster in the range.  With a backing
+    /// file, what was discarded reads as the backing file again.
+    fn discard(&self, offset: u64, length: u64) -> Result<()> {
+        super::check_range(self, offset, length)?;
+        if self.read_only {
+            return Err(ErrorKind::ImageError("image is read only").into());
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut state = self.state.lock().unwrap();
+        self.pieces(offset, length, |current, _, count| {
+            if count as u64 == cluster_size {
+                self.unmap(&mut state, current, 0)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn write_zeroes(&self, offset: u64, length: u64, _unmap: bool) -> Result<()> {
+        super::check_range(self, offset, length)?;
+        if self.read_only {
+            return Err(ErrorKind::ImageError("image is read only").into());
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut state = self.state.lock().unwrap();
+        self.pieces(offset, length, |current, _, count| {
+            if count as u64 == cluster_size && self.header.version >= 3 {
+                return self.unmap(&mut state, current, ZERO);
+            }
+
+            // Without zero clusters, or for part of a cluster, zeroes
+            // have to be written out.
+            let host = self.cluster_for_write(&mut state, current, false)?;
+            let within = current & (cluster_size - 1);
+            self.file.write_all_at(&Buffer::new(count), host + within)?;
+            Ok(())
+        })
+    }
+}
+
+fn zero(data: &mut [u8]) {
+    for byte in data.iter_mut() {
+        *byte = 0;
+    }
+}
+
+/// Whether `size` bytes at `offset` are within a file `length` bytes
+/// long.
+fn fits(offset: u64, size: u64, length: u64) -> bool {
+    match offset.checked_add(size) {
+        Some(end) => end <= length,
+        None => false,
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: u64) -> Result<Vec<u64>> {
+    let mut data = vec![0u8; entries as usize * 8];
+    file.read_exact_at(&mut data, offset)?;
+    Ok(data.chunks(8).map(BigEndian::read_u64).collect())
+}
+
+fn write_table(file: &File, offset: u64, table: &[u64]) -> Result<()> {
+    let mut data = vec![0u8; table.len() * 8];
+    for (entry, chunk) in table.iter().zip(data.chunks_mut(8)) {
+        BigEndian::write_u64(chunk, *entry);
+    }
+    file.write_all_at(&data, offset)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate;
+    use scratch::Scratch;
+    use std::fs;
+
+    fn pattern(seed: u8, length: usize) -> Vec<u8> {
+        (0..length).map(|i| seed.wrapping_add(i as u8)).collect()
+    }
+
+    #[test]
+    fn it_reads_back_what_was_written() {
+        let mut scratch = Scratch::new();
+        let path = scratch.path("qcow2-write.qcow2");
+        Qcow2::create(&path, 1 << 20, 12, None).unwrap();
+
+        {
+            let image = Qcow2::open(&path, false).unwrap();
+            assert_eq!(image.size(), 1 << 20);
+            let mut data = vec![0xffu8; 8192];
+            image.read_at(0, &mut data).unwrap();
+            assert!(data.iter().all(|&b| b == 0));
+
+            // Across a cluster boundary, and a whole cluster.
+            image.write_at(4000, &pattern(1, 200)).unwrap();
+            image.write_at(8192, &pattern(2, 4096)).unwrap();
+            image.flush().unwrap();
+        }
+
+        let image = Qcow2::open(&path, true).unwrap();
+        let mut data = vec![0u8; 200];
+        image.read_at(4000, &mut data).unwrap();
+        assert_eq!(data, pattern(1, 200));
+        let mut data = vec![0u8; 4096];
+        image.read_at(8192, &mut data).unwrap();
+        assert_eq!(data, pattern(2, 4096));
+        assert!(image.write_at(0, &[1]).is_err());
+    }
+
+    #[test]
+    fn it_keeps_refcounts_up_to_date() {
+        let mut scratch = Scratch::new();
+        let path = scratch.path("qcow2-refcounts.qcow2");
+        // With 512 byte clusters, a refcount block covers 256 clusters,
+        // and the refcount table 64 blocks; this needs more of both.
+        Qcow2::create(&path, 16 << 20, 9, None).unwrap();
+        let image = Qcow2::open(&path, false).unwrap();
+        for cluster in 0..20000u64 {
+            image
+                .write_at(cluster * 512, &pattern(cluster as u8, 16))
+                .unwrap();
+        }
+
+        let image = Qcow2::open(&path, false).unwrap();
+        let mut data = vec![0u8; 16];
+        image.read_at(19999 * 512, &mut data).unwrap();
+        assert_eq!(data, pattern(19999u64 as u8, 16));
+
+        // Every cluster in the file is used exactly once, except the
+        // first refcount table, which moved.
+        let state = image.state.lock().unwrap();
+        assert_ne!(state.refcount_table_offset, 512);
+        let length = image.file.metadata().unwrap().len();
+        let mut free = 0;
+        for cluster in 0..length / 512 {
+            match image.refcount(&state, cluster * 512).unwrap() {
+                0 => free += 1,
+                1 => (),
+                count => panic!("cluster {} has refcount {}", cluster, count),
+            }
+        }
+        assert_eq!(free, 1);
+    }
+
+    #[test]
+    fn it_reads_compressed_clusters() {
+        let mut scratch = Scratch::new();
+        let path = scratch.path("qcow2-compressed.qcow2");
+        Qcow2::create(&path, 1 << 20, 12, None).unwrap();
+        let image = Qcow2::open(&path, false).unwrap();
+        // Gets the L2 table allocated.
+        image.write_at(0, &[1]).unwrap();
+
+        let contents = pattern(7, 4096);
+        let compressed = deflate::compress_to_vec(&contents, 6);
+        let offset = image.file.metadata().unwrap().len() + 100;
+        image.file.write_all_at(&compressed, offset).unwrap();
+        let sectors = (offset + compressed.len() as u64 - 1) / 512 - offset / 512;
+        let descriptor = COMPRESSED | offset | (sectors << (62 - (12 - 8)));
+        {
+            let mut state = image.state.lock().unwrap();
+            let l2 = state.l1[0] & OFFSET_MASK;
+            image.set_l2_entry(&mut state, l2, 1, descriptor).unwrap();
+            state.end += 2 * 4096;
+        }
+
+        let mut data = vec![0u8; 100];
+        image.read_at(4096 + 50, &mut data).unwrap();
+        assert_eq!(&data[..], &contents[50..150]);
+
+        // Writing to it decompresses it into a cluster of its own.
+        image.write_at(4096, &[0xee]).unwrap();
+        let mut data = vec![0u8; 4096];
+        image.read_at(4096, &mut data).unwrap();
+        assert_eq!(data[0], 0xee);
+        assert_eq!(&data[1..], &contents[1..]);
+        assert_eq!(image.l2_entry(4096) & (COPIED | COMPRESSED), COPIED);
+    }
+
+    #[test]
+    fn it_reads_through_to_the_backing_file() {
+        let mut scratch = Scratch::new();
+        let base = scratch.path("qcow2-base.raw");
+        let middle = scratch.path("qcow2-middle.qcow2");
+        let top = scratch.path("qcow2-top.qcow2");
+        fs::write(&base, pattern(3, 16384)).unwrap();
+        let name = |path: &PathBuf| path.file_name().unwrap().to_str().unwrap().to_owned();
+        Qcow2::create(&middle, 1 << 16, 12, Some((&name(&base), Format::Raw))).unwrap();
+        Qcow2::create(&top, 1 << 16, 12, Some((&name(&middle), Format::Qcow2))).unwrap();
+
+        Qcow2::open(&middle, false)
+            .unwrap()
+            .write_at(4096, &[0xaa; 4096])
+            .unwrap();
+        let image = Qcow2::open(&top, false).unwrap();
+        image.write_at(100, &[0xbb; 10]).unwrap();
+
+        let mut data = vec![0u8; 16384 + 4096];
+        image.read_at(0, &mut data).unwrap();
+        let mut expected = pattern(3, 16384);
+        expected[4096..8192].copy_from_slice(&[0xaa; 4096]);
+        expected[100..110].copy_from_slice(&[0xbb; 10]);
+        expected.extend(vec![0; 4096]);
+        assert_eq!(data, expected);
+
+        // The backing files stay as they were.
+        assert_eq!(fs::read(&base).unwrap(), pattern(3, 16384));
+        let mut data = vec![0u8; 10];
+        Qcow2::open(&middle, true)
+            .unwrap()
+            .read_at(100, &mut data)
+            .unwrap();
+        assert_eq!(data, pattern(3, 16384)[100..110].to_vec());
+    }
+
+    #[test]
+    fn it_grows() {
+        let mut scratch = Scratch::new();
+        let path = scratch.path("qcow2-resize.qcow2");
+        // With 512 byte clusters, the first L1 table cluster covers
+        // 2MiB.
+        Qcow2::create(&path, 1 << 20, 9, None).unwrap();
+        {
+            let mut image = Qcow2::open(&path, false).unwrap();
+            image.write_at(4096, &pattern(1, 512)).unwrap();
+            image.resize(3 << 19).unwrap();
+            image.resize(4 << 20).unwrap();
+            assert!(image.resize(1 << 20).is_err());
+            image.write_at((4 << 20) - 512, &pattern(2, 512)).unwrap();
+        }
+
+        let image = Qcow2::open(&path, true).unwrap();
+        assert_eq!(image.size(), 4 << 20);
+        let mut data = vec![0u8; 512];
+        image.read_at(4096, &mut data).unwrap();
+        assert_eq!(data, pattern(1, 512));
+        image.read_at((4 << 20) - 512, &mut data).unwrap();
+        assert_eq!(data, pattern(2, 512));
+        image.read_at(3 << 20, &mut data).unwrap();
+        assert_eq!(data, vec![0; 512]);
+        assert_eq!(image.check().unwrap(), Check::default());
+    }
+
+    #[test]
+    fn it_finds_inconsistent_refcounts() {
+        let mut scratch = Scratch::new();
+        let path = scratch.path("qcow2-check.qcow2");
+        Qcow2::create(&path, 1 << 20, 12, None).unwrap();
+        let image = Qcow2::open(&path, false).unwrap();
+        image.write_at(0, &pattern(1, 8192)).unwrap();
+        assert_eq!(image.check().unwrap(), Check::default());
+
+        let data = image.l2_entry(0) & OFFSET_MASK;
+        {
+            let mut state = image.state.lock().unwrap();
+            image.set_refcount(&mut state, data, 0).unwrap();
+            let leaked = image.allocate(&mut state).unwrap();
+            image.file.write_all_at(&[0; 4096], leaked).unwrap();
+        }
+        assert_eq!(
+            image.check().unwrap(),
+            Check {
+                errors: 1,
+                leaks: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn it_zeroes_and_discards_clusters() {
+        let mut scratch = Scratch::new();
+        let base = scratch.path("qcow2-zero-base.raw");
+        let path = scratch.path("qcow2-zero.qcow2");
+        fs::write(&base, vec![0x11; 16384]).unwrap();
+        let name = base.file_name().unwrap().to_str().unwrap();
+        Qcow2::create(&path, 16384, 12, Some((name, Format::Raw))).unwrap();
+        let image = Qcow2::open(&path, false).unwrap();
+        image.write_at(0, &[0x22; 16384]).unwrap();
+
+        image.write_zeroes(4096, 4096, true).unwrap();
+        image.write_zeroes(100, 10, false).unwrap();
+        image.discard(8192, 4096).unwrap();
+        assert_eq!(image.l2_entry(4096), ZERO);
+        assert_eq!(image.l2_entry(8192), 0);
+
+        let mut data = vec![0u8; 16384];
+        image.read_at(0, &mut data).unwrap();
+        assert!(data[100..110].iter().all(|&b| b == 0));
+        assert!(data[4096..8192].iter().all(|&b| b == 0));
+        assert!(data[8192..12288].iter().all(|&b| b == 0x11));
+        assert!(data[12288..].iter().all(|&b| b == 0x22));
+    }
+
+    #[test]
+    fn it_keeps_shared_clusters_when_discarding() {
+        let mut scratch = Scratch::new();
+        let path = scratch.path("qcow2-shared.qcow2");
+        Qcow2::create(&path, 16384, 12, None).unwrap();
+        let image = Qcow2::open(&path, false).unwrap();
+        image.write_at(0, &[0x33; 8192]).unwrap();
+
+        // Have the first cluster look like a snapshot holds on to it.
+        let shared = image.l2_entry(0) & OFFSET_MASK;
+        {
+            let mut state = image.state.lock().unwrap();
+            image.set_refcount(&mut state, shared, 2).unwrap();
+            let l2 = state.l1[0] & OFFSET_MASK;
+            image.set_l2_entry(&mut state, l2, 0, shared).unwrap();
+        }
+
+        let own = image.l2_entry(4096) & OFFSET_MASK;
+        image.discard(0, 8192).unwrap();
+        assert_eq!((image.l2_entry(0), image.l2_entry(4096)), (0, 0));
+        let state = image.state.lock().unwrap();
+        assert_eq!(image.refcount(&state, shared).unwrap(), 1);
+        assert_eq!(image.refcount(&state, own).unwrap(), 0);
+        let mut data = [0u8; 4096];
+        image.file.read_exact_at(&mut data, shared).unwrap();
+        assert!(data.iter().all(|&b| b == 0x33));
+    }
+
+    #[test]
+    fn it_refuses_tables_that_are_too_big() {
+        let mut scratch = Scratch::new();
+        let path = scratch.path("qcow2-tables.qcow2");
+        Qcow2::create(&path, 16384, 12, None).unwrap();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let mut field = [0u8; 4];
+
+        // An L1 table past the end of the file.
+        BigEndian::write_u32(&mut field, 1 << 20);
+        file.write_all_at(&field, 36).unwrap();
+        assert!(Qcow2::open(&path, false).is_err());
+        BigEndian::write_u32(&mut field, 1);
+        file.write_all_at(&field, 36).unwrap();
+        assert!(Qcow2::open(&path, false).is_ok());
+
+        // A refcount table bigger than anything QEMU would make.
+        BigEndian::write_u32(&mut field, 1 << 12);
+        file.write_all_at(&field, 56).unwrap();
+        assert!(Qcow2::open(&path, false).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use super::{fill_zeroes, Image};
+use error::*;
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The largest block size we believe the host reports.
+const MAX_BLOCK_SIZE: u32 = 1 << 16;
+
+/// A raw image: the disk's contents, byte for byte, in a file or a
+/// block device.
+#[derive(Debug)]
+pub struct Raw {
+    file: File,
+    read_only: bool,
+    /// Whether the host's page cache is bypassed.
+    direct: bool,
+    size: u64,
+    block_size: u32,
+    /// Whether holes can be punched in the file.  This starts out set
+    /// for regular files, and is cleared the first time the filesystem
+    /// says it can't.
+    holes: AtomicBool,
+}
+
+impl Raw {
+    /// Opens an image.  With `direct`, the host's page cache is left
+    /// out, and everything has to be aligned to the block size.
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool, direct: bool) -> Result<Raw> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .custom_flags(if direct { libc::O_DIRECT } else { 0 })
+            .open(path)?;
+        let metadata = file.metadata()?;
+        // The length of a block device isn't in its metadata, but it
+        // still knows where its end is.
+        let size = file.seek(SeekFrom::End(0))?;
+        let block_size = metadata.blksize() as u32;
+        let block_size = if block_size.is_power_of_two() && block_size <= MAX_BLOCK_SIZE {
+            block_size.max(super::SECTOR_SIZE as u32)
+        } else {
+            super::SECTOR_SIZE as u32
+        };
+
+        Ok(Raw {
+            file,
+            read_only,
+            direct,
+            size,
+            block_size,
+            holes: AtomicBool::new(metadata.file_type().is_file() && !read_only),
+        })
+    }
+
+    /// Creates an image `size` bytes big, with nothing allocated in it
+    /// yet.
+    pub fn create<P: AsRef<Path>>(path: P, size: u64) -> Result<()> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.set_len(size)?;
+        Ok(())
+    }
+
+    /// Grows the disk to `size` bytes.  This only works on a file; a
+    /// block device is as big as it is.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if self.read_only {
+            return Err(ErrorKind::ImageError("image is read only").into());
+        }
+        if size < self.size {
+            return Err(ErrorKind::ImageError("images can't shrink").into());
+        }
+        if !self.file.metadata()?.file_type().is_file() {
+            return Err(ErrorKind::ImageError("only files can be resized").into());
+        }
+
+        self.file.set_len(size)?;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Deallocates the given range, which then reads back as zeroes.
+    /// Returns whether the filesystem went along with it.
+    fn punch_hole(&self, offset: u64, length: u64) -> Result<bool> {
+        if !self.holes.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+
+        let error = match punch_hole(&self.file, offset, length) {
+            Ok(()) => return Ok(true),
+            Err(error) => error,
+        };
+        match error.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => {
+                info!("filesystem can't punch holes, not discarding");
+                self.holes.store(false, Ordering::Relaxed);
+                Ok(false)
+            }
+            _ => Err(error.into()),
+        }
+    }
+}
+
+/// Deallocates the given range of a file, keeping its size.
+pub fn punch_hole(file: &File, offset: u64, length: u64) -> io::Result<()> {
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            length as libc::off_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+impl Image for Raw {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn alignment(&self) -> u32 {
+        if self.direct {
+            self.block_size
+        } else {
+            super::SECTOR_SIZE as u32
+        }
+    }
+
+    fn direct_fd(&self) -> Option<RawFd> {
+        Some(self.file.as_raw_fd())
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        super::check_range(self, offset, data.len() as u64)?;
+        self.file.read_exact_at(data, offset)?;
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+        super::check_range(self, offset, data.len() as u64)?;
+        self.file.write_all_at(data, offset)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn can_discard(&self) -> bool {
+        self.holes.load(Ordering::Relaxed)
+    }
+
+    fn discard(&self, offset: u64, length: u64) -> Result<()> {
+        super::check_range(self, offset, length)?;
+        self.punch_hole(offset, length).map(|_| ())
+    }
+
+    fn write_zeroes(&self, offset: u64, length: u64, unmap: bool) -> Result<()> {
+        super::check_range(self, offset, length)?;
+        if unmap && self.punch_hole(offset, length)? {
+            return Ok(());
+        }
+
+        fill_zeroes(self, offset, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch::Scratch;
+    use std::path::PathBuf;
+
+    /// A file of `size` bytes in the scratch.
+    fn file(scratch: &mut Scratch, name: &str, size: u64) -> PathBuf {
+        let path = scratch.path(name);
+        File::create(&path).unwrap().set_len(size).unwrap();
+        path
+    }
+
+    #[test]
+    fn it_reads_back_what_was_written() {
+        let mut scratch = Scratch::new();
+        let path = file(&mut scratch, "raw-write", 4096);
+        let raw = Raw::open(&path, false, false).unwrap();
+        assert_eq!(raw.size(), 4096);
+        raw.write_at(1000, b"hello").unwrap();
+        raw.flush().unwrap();
+
+        let mut data = [0u8; 7];
+        raw.read_at(999, &mut data).unwrap();
+        assert_eq!(&data, b"\0hello\0");
+        assert!(raw.read_at(4095, &mut data).is_err());
+    }
+
+    #[test]
+    fn it_zeroes_ranges() {
+        let mut scratch = Scratch::new();
+        let path = file(&mut scratch, "raw-zeroes", 1 << 16);
+        let raw = Raw::open(&path, false, false).unwrap();
+        raw.write_at(0, &[0xff; 1 << 16]).unwrap();
+        raw.write_zeroes(4096, 8192, true).unwrap();
+        raw.write_zeroes(20000, 100, false).unwrap();
+
+        let mut data = vec![0u8; 1 << 16];
+        raw.read_at(0, &mut data).unwrap();
+        assert!(data[..4096].iter().all(|&b| b == 0xff));
+        assert!(data[4096..12288].iter().all(|&b| b == 0));
+        assert!(data[12288..20000].iter().all(|&b| b == 0xff));
+        assert!(data[20000..20100].iter().all(|&b| b == 0));
+        assert!(data[20100..].iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn it_refuses_writes_when_read_only() {
+        let mut scratch = Scratch::new();
+        let path = file(&mut scratch, "raw-read-only", 4096);
+        let raw = Raw::open(&path, true, false).unwrap();
+        assert!(raw.read_only());
+        assert!(!raw.can_discard());
+        assert!(raw.write_at(0, b"nope").is_err());
+    }
+}
@@ -6,4 +6,193 @@ pub struct MachineConfiguration {
     pub uuid: Option<Uuid>,
     pub cores: i32,
     pub memory: u64,
+    /// The number of empty PCI Express slots devices can be hot-plugged
+    /// into while the machine runs.
+    pub hotplug_slots: u16,
+    /// How virtio devices are exposed to the guest.
+    pub transport: Transport,
+    /// Where to listen for a guest agent, which the guest sees as a
+    /// named console port.
+    pub agent_socket: Option<String>,
+    /// Where to listen for commands that change the machine while it
+    /// runs, like hot-plugging a disk.
+    pub control_socket: Option<String>,
+    /// The disks the guest gets, in order.
+    pub disks: Vec<DiskConfiguration>,
+    /// The network cards the guest gets, in order.
+    pub networks: Vec<NetworkConfiguration>,
+    /// Devices whose queues other processes work, over vhost-user.
+    pub vhost_user: Vec<VhostUserConfiguration>,
+    /// A socket device, for connections between the guest and the
+    /// host that don't go over the network.
+    pub vsock: Option<VsockConfiguration>,
+}
+
+/// A disk, backed by an image on the host.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DiskConfiguration {
+    /// Where the image lives.
+    pub path: String,
+    /// How the image is laid out; worked out from its contents if this
+    /// isn't set.
+    pub format: Option<DiskFormat>,
+    /// Whether the guest is kept from writing to the disk.
+    pub read_only: bool,
+    /// A qcow2 image the guest's writes go to instead, with the image
+    /// at `path` under it, only ever read.  It's created when the
+    /// machine starts, unless it's there already.
+    pub overlay: Option<String>,
+    /// Whether the guest's writes go to a throwaway overlay, so the
+    /// disk is back to how it was once the machine is gone.
+    pub ephemeral: bool,
+    /// Whether to go around the host's page cache.  The guest then
+    /// has to use the host's block size.
+    pub direct: bool,
+    /// How many requests can be in flight at once; there's a sensible
+    /// default if this isn't set.
+    pub queue_depth: Option<u32>,
+    /// The serial number the guest sees; made up from the disk's
+    /// position if there's none.
+    pub serial: Option<String>,
+    /// How fast the guest can use the disk, if that's limited.
+    pub throttle: Option<ThrottleConfiguration>,
+}
+
+/// Limits on a disk; whatever isn't set isn't limited.  Reads and
+/// writes are limited apart, both in operations and in bytes per second.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ThrottleConfiguration {
+    pub read_iops: Option<RateConfiguration>,
+    pub write_iops: Option<RateConfiguration>,
+    pub read_bandwidth: Option<RateConfiguration>,
+    pub write_bandwidth: Option<RateConfiguration>,
+}
+
+/// A rate, per second, and how much of it can build up while the disk
+/// is idle; a second's worth if that isn't set.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RateConfiguration {
+    pub rate: u64,
+    pub burst: Option<u64>,
+}
+
+/// A network card.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NetworkConfiguration {
+    /// The card's MAC address, as six colon-separated hexadecimal
+    /// bytes; it's made up from the machine's UUID and the card's
+    /// position if there's none.
+    pub mac: Option<String>,
+    /// Where the guest's frames go on the host.
+    pub backend: NetworkBackend,
+    /// A pcapng file the card's frames are recorded to, from the
+    /// start.
+    pub capture: Option<String>,
+    /// Whether the host kernel's vhost-net moves the card's frames,
+    /// which only works for TAP interfaces, and not while they're
+    /// captured.  They go through us as usual if it can't.
+    pub vhost: bool,
+}
+
+/// What's on the other end of a network card.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NetworkBackend {
+    /// A TAP interface, by name.  It's created if it isn't there,
+    /// which takes `CAP_NET_ADMIN`.
+    Tap(String),
+    /// A TAP interface opened for us by whoever started us, by its
+    /// file descriptor.
+    TapFd(i32),
+    /// A network of the guest's own behind NAT, which takes no
+    /// privileges.
+    User(UserNetworkConfiguration),
+    /// A Unix socket, framed the way QEMU's `stream` and `dgram`
+    /// network backends frame theirs.
+    Socket(SocketConfiguration),
+}
+
+/// Which Unix socket frames go over.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SocketConfiguration {
+    /// Connect to a stream socket at the path, like a switch's.
+    Connect(String),
+    /// Listen on a stream socket at the path, for a peer at a time.
+    Listen(String),
+    /// Take datagrams on a socket at `local`, and send them to the one
+    /// at `remote`.
+    Datagram { local: String, remote: String },
+}
+
+/// How a user-mode network is set up.  The guest is `10.0.2.15`, and
+/// the host is the gateway, `10.0.2.2`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct UserNetworkConfiguration {
+    /// The IP address of the nameserver the guest's DNS queries go
+    /// to; the host's own if there's none.
+    pub nameserver: Option<String>,
+    /// Ports on the host that lead to the guest.
+    pub forwards: Vec<ForwardConfiguration>,
+}
+
+/// A port on the host that leads to one on the guest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ForwardConfiguration {
+    pub protocol: ForwardProtocol,
+    /// The address to listen on, like `127.0.0.1:2222`.
+    pub host: String,
+    pub guest_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// A virtio device whose queues another process works, like
+/// virtiofsd, over vhost-user.  The guest's memory is shared with it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VhostUserConfiguration {
+    /// The Unix socket the backend listens on.  We connect to it again
+    /// whenever the backend goes away.
+    pub socket: String,
+    /// The virtio device ID the guest sees, like 26 for a file system.
+    pub device_type: u16,
+    /// How many queues the device has.
+    pub queues: u16,
+    /// How big each of the queues can be.
+    pub queue_size: u16,
+    /// The size of the device configuration, which the backend keeps;
+    /// the device has none if this is zero.
+    pub config_size: usize,
+}
+
+/// A virtio socket device, bridged to Unix sockets on the host the way
+/// Firecracker bridges its own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VsockConfiguration {
+    /// The guest's context ID, which has to be 3 or more.
+    pub cid: u64,
+    /// The socket the host connects to, then writes `CONNECT <port>`
+    /// to reach a port on the guest.  The guest's connections to port
+    /// `p` on the host go to the socket at `<uds_path>_<p>`.
+    pub uds_path: String,
+}
+
+/// How a disk image is laid out in its file.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+/// The bus virtio devices sit on.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Behind a PCI Express host bridge, where the guest can find them
+    /// by itself.
+    Pci,
+    /// As bare MMIO windows, with no PCI at all; the guest is told
+    /// about them in the DSDT.
+    Mmio,
 }
@@ -1,3 +1,7 @@
 mod machine;
 
-pub use self::machine::MachineConfiguration;
+pub use self::machine::{
+    DiskConfiguration, DiskFormat, ForwardProtocol, MachineConfiguration, NetworkBackend,
+    NetworkConfiguration, RateConfiguration, SocketConfiguration, Transport,
+    UserNetworkConfiguration, VhostUserConfiguration,
+};
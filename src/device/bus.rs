@@ -0,0 +1,89 @@
+use super::super::error::*;
+use super::Device;
+use kvm::core::{IoAction, IoAddress};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone)]
+struct Entry {
+    length: u64,
+    device: Arc<Device>,
+}
+
+/// Dispatches accesses to ranges of I/O ports or guest physical
+/// memory.  Unlike the fixed addresses a device returns from
+/// [`Device::request`], ranges on the bus can be added and removed
+/// while the machine is running, e.g. when the guest reprograms a PCI
+/// BAR.
+///
+/// Devices on the bus are handed the absolute address that was
+/// accessed, and are expected to work out their own offsets.
+#[derive(Debug, Default)]
+pub struct Bus {
+    ports: RwLock<BTreeMap<u64, Entry>>,
+    memory: RwLock<BTreeMap<u64, Entry>>,
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    fn space(&self, address: IoAddress) -> (&RwLock<BTreeMap<u64, Entry>>, u64) {
+        match address {
+            IoAddress::Port(port) => (&self.ports, port),
+            IoAddress::Memory(address) => (&self.memory, address),
+        }
+    }
+
+    /// Adds a range to the bus.  Ranges may not overlap.
+    pub fn insert(&self, start: IoAddress, length: u64, device: Arc<Device>) -> Result<()> {
+        let (space, start) = self.space(start);
+        let mut map = space.write().unwrap();
+
+        if length == 0 {
+            return Err(ErrorKind::BusError("cannot map an empty range").into());
+        }
+
+        let before = map
+            .range(..(start + length))
+            .next_back()
+            .map(|(base, entry)| base + entry.length > start)
+            .unwrap_or(false);
+
+        if before {
+            return Err(ErrorKind::BusError("range overlaps an existing mapping").into());
+        }
+
+        map.insert(start, Entry { length, device });
+        Ok(())
+    }
+
+    /// Removes the range starting at the given address, returning the
+    /// device that was mapped there.
+    pub fn remove(&self, start: IoAddress) -> Option<Arc<Device>> {
+        let (space, start) = self.space(start);
+        let mut map = space.write().unwrap();
+        map.remove(&start).map(|entry| entry.device)
+    }
+
+    /// Finds the device mapped at the given address.
+    pub fn lookup(&self, address: IoAddress) -> Option<Arc<Device>> {
+        let (space, address) = self.space(address);
+        let map = space.read().unwrap();
+        map.range(..=address).next_back().and_then(|(base, entry)| {
+            if base + entry.length > address {
+                Some(entry.device.clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn dispatch(&self, io: IoAction, memory: &mut [u8]) -> Option<()> {
+        // The lock isn't held while the device handles the access, so
+        // that the device is free to remap itself.
+        self.lookup(io.0)
+            .and_then(|device| device.handle(io, memory))
+    }
+}
@@ -0,0 +1,206 @@
+use error::*;
+use libc;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often the terminal is checked for size changes.
+const RESIZE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where a character device frontend sends what comes in from its
+/// backend.
+pub trait Sink: Debug + Send + Sync {
+    /// Bytes arrived from the backend.
+    fn receive(&self, data: &[u8]);
+    /// The backend's terminal changed size.
+    fn resize(&self, _columns: u16, _rows: u16) {}
+}
+
+/// The host side of a character device, like a terminal or a socket.
+/// Frontends (serial ports, virtio consoles) write what the guest sends
+/// into it, and get what the host sends through a `Sink`.
+pub trait Chardev: Debug + Send + Sync {
+    /// Sends bytes from the guest out of the backend.
+    fn write(&self, data: &[u8]) -> Result<()>;
+    /// Starts delivering input to the given sink.  Backends only have
+    /// one frontend, so this is only called once.
+    fn attach(&self, sink: Arc<Sink>) -> Result<()>;
+    /// The size of the backend's terminal, as columns and rows, if it
+    /// is one.
+    fn size(&self) -> Option<(u16, u16)> {
+        None
+    }
+}
+
+/// Our own standard input and output.
+#[derive(Debug, Default)]
+pub struct Stdio;
+
+impl Stdio {
+    pub fn new() -> Stdio {
+        Stdio
+    }
+}
+
+/// The size of the terminal on our standard output, if there is one.
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_col != 0 {
+        Some((size.ws_col, size.ws_row))
+    } else {
+        None
+    }
+}
+
+impl Chardev for Stdio {
+    fn write(&self, data: &[u8]) -> Result<()> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(data)?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+        let input = sink.clone();
+        thread::Builder::new()
+            .name("stdin".to_owned())
+            .spawn(move || pump(io::stdin(), input.as_ref()))?;
+
+        // There's no way to wait for SIGWINCH without taking over
+        // signal handling for the whole process, so just look every
+        // now and then.
+        if let Some(mut last) = terminal_size() {
+            thread::Builder::new()
+                .name("stdin-resize".to_owned())
+                .spawn(move || loop {
+                    thread::sleep(RESIZE_INTERVAL);
+                    match terminal_size() {
+                        Some(size) if size != last => {
+                            last = size;
+                            sink.resize(size.0, size.1);
+                        }
+                        _ => (),
+                    }
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn size(&self) -> Option<(u16, u16)> {
+        terminal_size()
+    }
+}
+
+/// Reads from `input` until it closes, handing everything to `sink`.
+fn pump<R: Read>(mut input: R, sink: &Sink) {
+    let mut buffer = [0u8; 4096];
+    loop {
+        match input.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(count) => sink.receive(&buffer[..count]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => {
+                warn!("could not read chardev input: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// A listening Unix socket.  One client is connected at a time; while
+/// there's none, whatever the guest sends is dropped.
+#[derive(Debug)]
+pub struct Socket {
+    path: PathBuf,
+    listener: Mutex<Option<UnixListener>>,
+    client: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl Socket {
+    /// Starts listening at `path`, replacing whatever socket was left
+    /// there.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Socket> {
+        let path = path.as_ref().to_owned();
+        if fs::symlink_metadata(&path).is_ok() {
+            fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        Ok(Socket {
+            path,
+            listener: Mutex::new(Some(listener)),
+            client: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+impl Chardev for Socket {
+    fn write(&self, data: &[u8]) -> Result<()> {
+        let mut client = self.client.lock().unwrap();
+        let failed = match *client {
+            Some(ref mut stream) => stream.write_all(data).is_err(),
+            None => false,
+        };
+
+        // The client went away; the next one gets a fresh start.
+        if failed {
+            *client = None;
+        }
+
+        Ok(())
+    }
+
+    fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+        let listener = match self.listener.lock().unwrap().take() {
+            Some(listener) => listener,
+            None => return Err(ErrorKind::DeviceError("chardev already attached").into()),
+        };
+        let client = self.client.clone();
+
+        thread::Builder::new()
+            .name("chardev-socket".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("could not accept chardev client: {}", e);
+                            continue;
+                        }
+                    };
+
+                    match stream.try_clone() {
+                        Ok(writer) => *client.lock().unwrap() = Some(writer),
+                        Err(e) => {
+                            warn!("could not set up chardev client: {}", e);
+                            continue;
+                        }
+                    }
+
+                    pump(stream, sink.as_ref());
+                    *client.lock().unwrap() = None;
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,108 @@
+use super::virtio::Block;
+use block::engine::{Limit, Limits, Statistics, DEFAULT_QUEUE_DEPTH};
+use block::{self, Format};
+use configuration::{DiskConfiguration, DiskFormat, RateConfiguration};
+use error::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A disk the guest sees, along with where the guest's writes go on the
+/// host, so it can be snapshotted while the machine runs.
+#[derive(Debug)]
+pub struct Disk {
+    block: Arc<Block>,
+    /// The image at the top of the disk's chain, and its format; there
+    /// isn't one for an ephemeral disk, whose top has no name.
+    top: Mutex<Option<(PathBuf, Format)>>,
+}
+
+impl Disk {
+    /// Opens the disk at `index` in the machine's configuration.
+    pub fn open(config: &DiskConfiguration, index: usize) -> Result<Disk> {
+        let format = config.format.map(|format| match format {
+            DiskFormat::Raw => Format::Raw,
+            DiskFormat::Qcow2 => Format::Qcow2,
+        });
+
+        let (image, top) = match config.overlay {
+            _ if config.ephemeral && config.overlay.is_some() => {
+                return Err(ErrorKind::DeviceError("ephemeral disks can't have an overlay").into())
+            }
+            _ if config.ephemeral => (
+                block::open_ephemeral(&config.path, format, config.read_only)?,
+                None,
+            ),
+            // An overlay that's there already carries on from where it
+            // was left.
+            Some(ref overlay) => {
+                if !Path::new(overlay).exists() {
+                    block::create_overlay(overlay, &config.path, format)?;
+                }
+                let image = block::open(overlay, Some(Format::Qcow2), config.read_only, false)?;
+                (image, Some((PathBuf::from(overlay), Format::Qcow2)))
+            }
+            None => {
+                let format = match format {
+                    Some(format) => format,
+                    None => Format::probe(&config.path)?,
+                };
+                let image =
+                    block::open(&config.path, Some(format), config.read_only, config.direct)?;
+                (image, Some((PathBuf::from(&config.path), format)))
+            }
+        };
+
+        let serial = match config.serial {
+            Some(ref serial) => serial.clone(),
+            None => format!("vent-disk-{}", index),
+        };
+        let depth = config.queue_depth.unwrap_or(DEFAULT_QUEUE_DEPTH);
+        let limit = |rate: Option<RateConfiguration>| {
+            rate.map(|rate| Limit {
+                rate: rate.rate,
+                burst: rate.burst.unwrap_or(rate.rate),
+            })
+        };
+        let limits = match config.throttle {
+            Some(throttle) => Limits {
+                read_iops: limit(throttle.read_iops),
+                write_iops: limit(throttle.write_iops),
+                read_bandwidth: limit(throttle.read_bandwidth),
+                write_bandwidth: limit(throttle.write_bandwidth),
+            },
+            None => Limits::default(),
+        };
+        Ok(Disk {
+            block: Arc::new(Block::new(image, &serial, depth, limits)?),
+            top: Mutex::new(top),
+        })
+    }
+
+    /// The virtio device the guest sees the disk through.
+    pub fn block(&self) -> Arc<Block> {
+        self.block.clone()
+    }
+
+    /// What the guest did with the disk so far.
+    pub fn statistics(&self) -> Statistics {
+        self.block.statistics()
+    }
+
+    /// Takes an external snapshot of the disk: a new qcow2 overlay is
+    /// created at `overlay`, and the guest's writes go there from now
+    /// on.  What was the top of the disk stays as it was at this point.
+    pub fn snapshot<P: AsRef<Path>>(&self, overlay: P) -> Result<()> {
+        let mut top = self.top.lock().unwrap();
+        let (path, format) = match *top {
+            Some(ref top) => top.clone(),
+            None => {
+                return Err(ErrorKind::DeviceError("ephemeral disks can't be snapshotted").into())
+            }
+        };
+
+        let image = block::snapshot(self.block.image(), &path, format, overlay.as_ref())?;
+        self.block.switch(image)?;
+        *top = Some((overlay.as_ref().to_owned(), Format::Qcow2));
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use super::super::error::*;
+use std::fmt::Debug;
+use std::os::unix::io::RawFd;
+
+/// A message signaled interrupt, as written by the guest into an MSI
+/// or MSI-X capability.  On x86, the address selects the destination
+/// local APIC, and the data selects the vector and delivery mode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Message {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl Message {
+    pub fn new(address: u64, data: u32) -> Message {
+        Message { address, data }
+    }
+
+    /// Whether or not the guest has actually configured this message.
+    /// An address of zero can never reach a local APIC, so we treat it
+    /// as unprogrammed.
+    pub fn is_valid(&self) -> bool {
+        self.address != 0
+    }
+}
+
+/// The interface devices use to deliver interrupts into the guest.
+/// This is implemented by the machine, which forwards everything to
+/// the in-kernel irqchip.
+pub trait Interrupts: Debug + Send + Sync {
+    /// Sets the level of a legacy interrupt line (GSI) on the
+    /// irqchip.
+    fn line(&self, irq: u32, level: bool) -> Result<()>;
+    /// Injects a message signaled interrupt directly.
+    fn signal(&self, message: Message) -> Result<()>;
+    /// Allocates a GSI that can later be routed to an MSI message.
+    fn allocate(&self) -> Result<u32>;
+    /// Routes the given GSI to the given message, or removes the
+    /// routing entry if the message is `None`.
+    fn route(&self, gsi: u32, message: Option<Message>) -> Result<()>;
+
+    /// Has every write to the eventfd `fd` raise the given GSI, without
+    /// going through us; or stops that, if `attach` is false.
+    fn irqfd(&self, _gsi: u32, _fd: RawFd, _attach: bool) -> Result<()> {
+        Err(ErrorKind::InterruptError("irqfds are not supported").into())
+    }
+
+    /// Has the guest's writes of `length` bytes to the MMIO `address`
+    /// write to the eventfd `fd` instead of exiting to us, if they're
+    /// of `data` (or of anything, without it); or stops that, if
+    /// `attach` is false.  These are interrupts going the other way,
+    /// and they're set up on the same irqchip.
+    fn ioeventfd(
+        &self,
+        _address: u64,
+        _length: u32,
+        _data: Option<u64>,
+        _fd: RawFd,
+        _attach: bool,
+    ) -> Result<()> {
+        Err(ErrorKind::InterruptError("ioeventfds are not supported").into())
+    }
+}
@@ -0,0 +1,69 @@
+use super::super::error::*;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt::Debug;
+use std::os::unix::io::RawFd;
+
+/// A stretch of the guest's physical memory, and where it's mapped in
+/// our own address space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MemoryRegion {
+    pub guest: u64,
+    pub size: u64,
+    pub host: u64,
+    /// The file the region is mapped from, from its start, if other
+    /// processes can map it too.
+    pub fd: Option<RawFd>,
+}
+
+/// The interface devices use to get at the guest's physical memory,
+/// e.g. to follow the buffers a driver hands them.  This is
+/// implemented by the machine; accesses can't span memory regions, and
+/// accesses outside of memory fail with a `GuestMemoryError`.
+pub trait GuestMemory: Debug + Send + Sync {
+    fn read(&self, address: u64, data: &mut [u8]) -> Result<()>;
+    fn write(&self, address: u64, data: &[u8]) -> Result<()>;
+
+    /// Where the guest's memory is mapped, for handing it over to
+    /// something that accesses it directly, like the kernel's vhost
+    /// drivers, or a vhost-user backend.  Memory that isn't mapped in
+    /// our address space has no regions.
+    fn regions(&self) -> Vec<MemoryRegion> {
+        vec![]
+    }
+
+    fn read_u16(&self, address: u64) -> Result<u16> {
+        let mut data = [0u8; 2];
+        self.read(address, &mut data)?;
+        Ok(LittleEndian::read_u16(&data))
+    }
+
+    fn read_u32(&self, address: u64) -> Result<u32> {
+        let mut data = [0u8; 4];
+        self.read(address, &mut data)?;
+        Ok(LittleEndian::read_u32(&data))
+    }
+
+    fn read_u64(&self, address: u64) -> Result<u64> {
+        let mut data = [0u8; 8];
+        self.read(address, &mut data)?;
+        Ok(LittleEndian::read_u64(&data))
+    }
+
+    fn write_u16(&self, address: u64, value: u16) -> Result<()> {
+        let mut data = [0u8; 2];
+        LittleEndian::write_u16(&mut data, value);
+        self.write(address, &data)
+    }
+
+    fn write_u32(&self, address: u64, value: u32) -> Result<()> {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        self.write(address, &data)
+    }
+
+    fn write_u64(&self, address: u64, value: u64) -> Result<()> {
+        let mut data = [0u8; 8];
+        LittleEndian::write_u64(&mut data, value);
+        self.write(address, &data)
+    }
+}
@@ -1,14 +1,34 @@
+use super::configuration::{MachineConfiguration, Transport};
 use super::error::*;
-use super::machine::Machine;
+use super::machine::{Machine, PCI_ECAM_BUSES, PCI_ECAM_START, VIRTIO_MMIO_START};
+use super::virtio::{MmioTransport, PciTransport, Virtio};
+use super::vsock::Muxer;
 use kvm;
+use kvm::core::IoAddress;
 use std::fmt::Debug;
 use std::sync::Arc;
 
+pub mod bus;
+pub mod chardev;
 pub mod cmos;
 pub mod debug;
+mod disk;
+pub mod interrupt;
+pub mod memory;
+mod network;
 pub mod pci;
 pub mod virtio;
 
+pub use self::disk::Disk;
+pub use self::network::Network;
+
+/// The legacy interrupt lines virtio devices are wired to, in turn.
+/// These are the ISA lines nothing else on the machine uses.
+pub const LEGACY_IRQS: [u8; 4] = [5, 9, 10, 11];
+
+/// The name of the console port a guest agent talks through.
+const AGENT_PORT: &str = "org.vent.agent.0";
+
 pub trait Device: Debug + Send + Sync {
     fn request(&self) -> Vec<kvm::core::IoAddress>;
     fn handle(&self, io: kvm::core::IoAction, memory: &mut [u8]) -> Option<()>;
@@ -24,17 +44,106 @@ impl<T: Device> Device for Box<T> {
     }
 }
 
-pub(crate) fn prepare(machine: &mut Machine) -> Result<()> {
+pub(crate) fn prepare(machine: &mut Machine, config: &MachineConfiguration) -> Result<()> {
     for device in debug::default().into_iter() {
         machine.push(device)?;
     }
 
     machine.push(Arc::new(cmos::Cmos::new()))?;
 
-    machine.push(Arc::new(pci::Host::new(
-        None,
-        Some(Arc::new(virtio::Console::new()) as Arc<pci::Pci>),
-    )))?;
+    let mut ports = vec![virtio::Port::console(Arc::new(chardev::Stdio::new()))];
+    if let Some(ref path) = config.agent_socket {
+        let socket = chardev::Socket::bind(path)?;
+        ports.push(virtio::Port::named(AGENT_PORT, Arc::new(socket)));
+    }
+
+    let mut devices = vec![Arc::new(virtio::Console::new(ports)?) as Arc<Virtio>];
+    for (i, disk) in config.disks.iter().enumerate() {
+        let disk = Arc::new(Disk::open(disk, i)?);
+        devices.push(disk.block());
+        machine.push_disk(disk);
+    }
+    for (i, network) in config.networks.iter().enumerate() {
+        let network = Arc::new(Network::open(network, config.uuid, i)?);
+        devices.push(network.net());
+        machine.push_network(network);
+    }
+    for vhost_user in &config.vhost_user {
+        devices.push(Arc::new(virtio::VhostUser::connect(vhost_user)?));
+    }
+    if let Some(ref vsock) = config.vsock {
+        // The host is 2, and the ones below it are reserved, as is
+        // the one that stands for any.
+        if vsock.cid < 3 || vsock.cid >= u64::from(u32::max_value()) {
+            return Err(ErrorKind::UsageError("invalid guest context ID").into());
+        }
+        let muxer = Arc::new(Muxer::bind(vsock.cid, &vsock.uds_path)?);
+        devices.push(Arc::new(virtio::Vsock::new(vsock.cid, muxer)?));
+    }
+
+    match config.transport {
+        Transport::Pci => prepare_pci(machine, config, devices),
+        Transport::Mmio => prepare_mmio(machine, devices),
+    }
+}
+
+/// Puts the virtio devices behind a PCI Express host bridge, along with
+/// the hot-plug slots.
+fn prepare_pci(
+    machine: &mut Machine,
+    config: &MachineConfiguration,
+    devices: Vec<Arc<Virtio>>,
+) -> Result<()> {
+    let mut pcis = vec![];
+    for (i, device) in devices.into_iter().enumerate() {
+        let irq = LEGACY_IRQS[i % LEGACY_IRQS.len()];
+        pcis.push(Arc::new(PciTransport::new(
+            device,
+            machine.memory(),
+            machine.interrupts(),
+            Some(irq),
+        )?) as Arc<pci::Pci>);
+    }
+
+    for number in 0..config.hotplug_slots {
+        pcis.push(Arc::new(pci::Bridge::hotplug(
+            machine.interrupts(),
+            number,
+        )?));
+    }
+
+    let allocator = machine.pci_allocator(config);
+    let host = Arc::new(pci::Host::new(None, machine.bus(), allocator, pcis));
+    let ecam = pci::Ecam::new(host.clone(), PCI_ECAM_START, 0, PCI_ECAM_BUSES);
+    machine
+        .bus()
+        .insert(IoAddress::Memory(ecam.base()), ecam.size(), Arc::new(ecam))?;
+    machine.set_pci(host.clone());
+    machine.push(host)?;
+
+    Ok(())
+}
+
+/// Lays the virtio devices out one after the other as virtio-mmio
+/// windows, and tells the guest where they are.
+fn prepare_mmio(machine: &mut Machine, devices: Vec<Arc<Virtio>>) -> Result<()> {
+    let mut base = VIRTIO_MMIO_START;
+    for (i, device) in devices.into_iter().enumerate() {
+        let irq = LEGACY_IRQS[i % LEGACY_IRQS.len()];
+        let transport = MmioTransport::new(
+            device,
+            machine.memory(),
+            machine.interrupts(),
+            base,
+            irq as u32,
+        );
+        let size = transport.size();
+        machine.push_mmio(transport.base(), size, transport.irq());
+        machine
+            .bus()
+            .insert(IoAddress::Memory(base), size, Arc::new(transport))?;
+        base += size;
+    }
 
     Ok(())
 }
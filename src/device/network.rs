@@ -0,0 +1,172 @@
+use super::virtio::{Net, VhostNet};
+use configuration::{
+    ForwardProtocol, NetworkBackend, NetworkConfiguration, SocketConfiguration,
+    UserNetworkConfiguration,
+};
+use error::*;
+use net::{host_nameserver, Backend, Capture, Forward, MacAddress, Protocol, Socket, Tap, User};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use uuid::Uuid;
+use virtio::Virtio;
+
+/// A network card the guest sees, along with a capture of the frames
+/// going through it that can be started and stopped while the machine
+/// runs.
+#[derive(Debug)]
+pub struct Network {
+    net: Arc<Virtio>,
+    capture: Capture,
+    /// Whether vhost-net moves the card's frames, so they never go
+    /// through us to be captured.
+    accelerated: bool,
+}
+
+impl Network {
+    /// Sets up the network card at `index` in the configuration of the
+    /// machine with the given UUID.  Machines without one all make up
+    /// the same addresses, so their cards had better have theirs set.
+    pub fn open(
+        config: &NetworkConfiguration,
+        machine: Option<Uuid>,
+        index: usize,
+    ) -> Result<Network> {
+        let mac = match config.mac {
+            Some(ref mac) => MacAddress::parse(mac)?,
+            None => MacAddress::derive(&machine.unwrap_or_else(Uuid::nil), index),
+        };
+
+        let (backend, description): (Arc<Backend>, String) = match config.backend {
+            NetworkBackend::Tap(ref name) => {
+                return Network::tap(config, mac, index, Tap::open(name)?)
+            }
+            NetworkBackend::TapFd(fd) => {
+                return Network::tap(config, mac, index, Tap::from_fd(fd)?)
+            }
+            NetworkBackend::User(ref user) => {
+                (Arc::new(self::user(user)?), "user network".to_owned())
+            }
+            NetworkBackend::Socket(ref socket) => {
+                let socket = match *socket {
+                    SocketConfiguration::Connect(ref path) => Socket::connect(path)?,
+                    SocketConfiguration::Listen(ref path) => Socket::listen(path)?,
+                    SocketConfiguration::Datagram {
+                        ref local,
+                        ref remote,
+                    } => Socket::datagram(local, remote)?,
+                };
+                let description = format!("socket {}", socket.path().display());
+                (Arc::new(socket), description)
+            }
+        };
+        Network::userspace(config, mac, index, backend, &description)
+    }
+
+    /// A card on a TAP interface, whose frames go through vhost-net if
+    /// they can, and were asked to.
+    fn tap(
+        config: &NetworkConfiguration,
+        mac: MacAddress,
+        index: usize,
+        tap: Tap,
+    ) -> Result<Network> {
+        let description = format!("TAP interface {}", tap.name());
+        if config.vhost && config.capture.is_some() {
+            warn!(
+                "network card {} is captured, so it can't use vhost-net",
+                index
+            );
+        } else if config.vhost {
+            match VhostNet::open(&tap) {
+                Ok(vhost) => {
+                    debug!(
+                        "network card {} is {}, on {} through vhost-net",
+                        index, mac, description
+                    );
+                    return Ok(Network {
+                        net: Arc::new(VhostNet::new(mac, tap, vhost)),
+                        capture: Capture::new(&format!("net{}", index), mac, &description),
+                        accelerated: true,
+                    });
+                }
+                Err(e) => warn!("network card {} can't use vhost-net: {}", index, e),
+            }
+        }
+
+        Network::userspace(config, mac, index, Arc::new(tap), &description)
+    }
+
+    /// A card whose frames go through us, on their way to `backend`.
+    fn userspace(
+        config: &NetworkConfiguration,
+        mac: MacAddress,
+        index: usize,
+        backend: Arc<Backend>,
+        description: &str,
+    ) -> Result<Network> {
+        debug!("network card {} is {}, on {}", index, mac, description);
+        let capture = Capture::new(&format!("net{}", index), mac, description);
+        if let Some(ref path) = config.capture {
+            capture.start(path)?;
+        }
+        let net = Net::new(mac, Arc::new(capture.wrap(backend)))?;
+        Ok(Network {
+            net: Arc::new(net),
+            capture,
+            accelerated: false,
+        })
+    }
+
+    /// The virtio device the guest sees the card as.
+    pub fn net(&self) -> Arc<Virtio> {
+        self.net.clone()
+    }
+
+    /// Starts recording the frames going through the card to a pcapng
+    /// file at `path`, instead of wherever they went before.
+    pub fn start_capture<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if self.accelerated {
+            return Err(ErrorKind::DeviceError("frames go through vhost-net").into());
+        }
+        self.capture.start(path)
+    }
+
+    pub fn stop_capture(&self) {
+        self.capture.stop()
+    }
+
+    /// Whether the card's frames are being recorded.
+    pub fn capturing(&self) -> bool {
+        self.capture.running()
+    }
+}
+
+fn user(config: &UserNetworkConfiguration) -> Result<User> {
+    let nameserver = match config.nameserver {
+        Some(ref address) => {
+            let address = address
+                .parse::<IpAddr>()
+                .map_err(|_| ErrorKind::DeviceError("invalid nameserver address"))?;
+            Some(SocketAddr::new(address, 53))
+        }
+        None => host_nameserver(),
+    };
+
+    let mut forwards = vec![];
+    for forward in &config.forwards {
+        forwards.push(Forward {
+            protocol: match forward.protocol {
+                ForwardProtocol::Tcp => Protocol::Tcp,
+                ForwardProtocol::Udp => Protocol::Udp,
+            },
+            host: forward
+                .host
+                .parse()
+                .map_err(|_| ErrorKind::DeviceError("invalid forward address"))?,
+            guest: forward.guest_port,
+        });
+    }
+
+    User::new(&forwards, nameserver)
+}
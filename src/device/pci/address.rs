@@ -1,26 +1,83 @@
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct Address(pub u8, pub u8, pub u8, pub u8);
+/// A configuration space address: bus, device, function, and register
+/// (byte offset into the function's configuration space).  The
+/// register can address the full 4 KiB extended configuration space,
+/// though only the first 256 bytes are reachable through the legacy
+/// I/O ports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Address(pub u8, pub u8, pub u8, pub u16);
+
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// The size of the memory mapped configuration space of a single bus:
+/// 32 devices of 8 functions of 4 KiB each.
+pub const ECAM_BUS_SIZE: u64 = 1 << 20;
 
 impl Address {
+    /// Decodes the value written to CONFIG_ADDRESS (0xcf8) using
+    /// configuration mechanism #1:
+    ///
+    /// - bit 31: enable bit
+    /// - bits 30-24: reserved
+    /// - bits 23-16: bus number
+    /// - bits 15-11: device number
+    /// - bits 10-8: function number
+    /// - bits 7-2: register (dword) number
+    /// - bits 1-0: always zero
+    ///
+    /// The low two bits of the register come from which of the four
+    /// CONFIG_DATA ports is accessed instead; see [`Address::offset`].
     pub fn from(value: u32) -> Option<Address> {
-        let enabled = value >> 31;
-        let bus = (value >> 16) & 0b1111111;
-        let device = (value >> 11) & 0b1111;
-        let function = (value >> 8) & 0b11;
-        let register = value & 0b111111;
-        if enabled > 0 {
+        let bus = (value >> 16) & 0xff;
+        let device = (value >> 11) & 0b11111;
+        let function = (value >> 8) & 0b111;
+        let register = value & 0b11111100;
+        if (value & CONFIG_ENABLE) != 0 {
             Some(Address(
                 bus as u8,
                 device as u8,
                 function as u8,
-                register as u8,
+                register as u16,
             ))
         } else {
             None
         }
     }
 
-    pub fn bridge(&self) -> u8 {
+    /// Decodes an offset into an ECAM (PCI Express enhanced
+    /// configuration access mechanism) window:
+    ///
+    /// - bits 27-20: bus number
+    /// - bits 19-15: device number
+    /// - bits 14-12: function number
+    /// - bits 11-0: register
+    ///
+    /// `start` is the bus number the window starts at.
+    pub fn from_ecam(start: u8, offset: u64) -> Address {
+        Address(
+            start.wrapping_add((offset >> 20) as u8),
+            ((offset >> 15) & 0b11111) as u8,
+            ((offset >> 12) & 0b111) as u8,
+            (offset & 0xfff) as u16,
+        )
+    }
+
+    /// Encodes the address as it would be written to CONFIG_ADDRESS.
+    /// The low two bits of the register are dropped.
+    #[cfg(test)]
+    pub fn into_config_address(self) -> u32 {
+        CONFIG_ENABLE
+            | ((self.0 as u32) << 16)
+            | ((self.1 as u32 & 0b11111) << 11)
+            | ((self.2 as u32 & 0b111) << 8)
+            | (self.3 as u32 & 0b11111100)
+    }
+
+    /// The same address, `by` bytes further into configuration space.
+    pub fn offset(&self, by: u16) -> Address {
+        Address(self.0, self.1, self.2, self.3.wrapping_add(by))
+    }
+
+    pub fn bus(&self) -> u8 {
         self.0
     }
 
@@ -28,11 +85,91 @@ impl Address {
         self.1
     }
 
-    // pub fn function(&self) -> u8 {
-    //     self.2
-    // }
+    pub fn function(&self) -> u8 {
+        self.2
+    }
 
-    pub fn register(&self) -> u8 {
+    pub fn register(&self) -> u16 {
         self.3
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Address;
+
+    #[test]
+    fn it_ignores_disabled_addresses() {
+        assert_eq!(Address::from(0), None);
+        assert_eq!(Address::from(0x7fff_ffff), None);
+        assert_eq!(Address::from(0x0000_0800), None);
+    }
+
+    #[test]
+    fn it_decodes_every_address() {
+        for bus in 0..256u32 {
+            for device in 0..32u32 {
+                for function in 0..8u32 {
+                    for register in 0..64u32 {
+                        let value = (1 << 31)
+                            | (bus << 16)
+                            | (device << 11)
+                            | (function << 8)
+                            | (register << 2);
+                        let address = Address::from(value).unwrap();
+                        assert_eq!(address.bus() as u32, bus);
+                        assert_eq!(address.device() as u32, device);
+                        assert_eq!(address.function() as u32, function);
+                        assert_eq!(address.register() as u32, register << 2);
+                        assert_eq!(address.into_config_address(), value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_decodes_every_ecam_address() {
+        for bus in 0..256u64 {
+            for device in 0..32u64 {
+                for function in 0..8u64 {
+                    for &register in &[0x000u64, 0x03c, 0x0fc, 0x100, 0xffc] {
+                        let offset = (bus << 20) | (device << 15) | (function << 12) | register;
+                        let address = Address::from_ecam(0, offset);
+                        assert_eq!(address.bus() as u64, bus);
+                        assert_eq!(address.device() as u64, device);
+                        assert_eq!(address.function() as u64, function);
+                        assert_eq!(address.register() as u64, register);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_offsets_ecam_buses() {
+        let address = Address::from_ecam(0x10, (2 << 20) | 0x100);
+        assert_eq!(address, Address(0x12, 0, 0, 0x100));
+    }
+
+    #[test]
+    fn it_ignores_reserved_bits() {
+        let address = Address::from(0xff01_0810).unwrap();
+        assert_eq!(address, Address(1, 1, 0, 0x10));
+    }
+
+    #[test]
+    fn it_ignores_the_low_register_bits() {
+        for low in 0..4 {
+            let address = Address::from(0x8000_0010 | low).unwrap();
+            assert_eq!(address.register(), 0x10);
+        }
+    }
+
+    #[test]
+    fn it_offsets_into_the_register() {
+        let address = Address::from(0x8000_00fc).unwrap();
+        assert_eq!(address.offset(0), Address(0, 0, 0, 0xfc));
+        assert_eq!(address.offset(3), Address(0, 0, 0, 0xff));
+    }
+}
@@ -0,0 +1,96 @@
+use super::bar::Kind;
+
+/// A range of addresses that BARs can be placed in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Window {
+    end: u64,
+    next: u64,
+}
+
+impl Window {
+    /// Creates a window covering `start` up to, but not including,
+    /// `end`.
+    pub fn new(start: u64, end: u64) -> Window {
+        Window { end, next: start }
+    }
+
+    /// The next address that would be handed out, ignoring alignment.
+    pub fn position(&self) -> u64 {
+        self.next
+    }
+
+    /// Skips ahead to the next multiple of `boundary`, which must be a
+    /// power of two.
+    pub fn align(&mut self, boundary: u64) {
+        let aligned = (self.next + boundary - 1) & !(boundary - 1);
+        self.next = ::std::cmp::min(aligned, self.end);
+    }
+
+    /// Allocates a naturally aligned region of the given size, which
+    /// must be a power of two.
+    pub fn allocate(&mut self, size: u64) -> Option<u64> {
+        let address = (self.next + size - 1) & !(size - 1);
+        if address < self.next || address.checked_add(size)? > self.end {
+            return None;
+        }
+
+        self.next = address + size;
+        Some(address)
+    }
+}
+
+/// Hands out addresses for BARs the way firmware would, from
+/// separate I/O, 32-bit memory, and 64-bit memory windows.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Allocator {
+    io: Window,
+    memory32: Window,
+    memory64: Window,
+}
+
+impl Allocator {
+    pub fn new(io: Window, memory32: Window, memory64: Window) -> Allocator {
+        Allocator {
+            io,
+            memory32,
+            memory64,
+        }
+    }
+
+    /// Aligns every window to the given boundaries: `io` for the I/O
+    /// window, and `memory` for both memory windows.
+    pub fn align(&mut self, io: u64, memory: u64) {
+        self.io.align(io);
+        self.memory32.align(memory);
+        self.memory64.align(memory);
+    }
+
+    /// The current position in the I/O, 32-bit memory, and 64-bit
+    /// memory windows, in that order.
+    pub fn positions(&self) -> (u64, u64, u64) {
+        (
+            self.io.position(),
+            self.memory32.position(),
+            self.memory64.position(),
+        )
+    }
+
+    /// Allocates space for a BAR of the given kind and size.
+    /// Prefetchable 64-bit BARs are placed above 4 GiB, falling back to
+    /// the 32-bit window if that is exhausted.  The rest stay in the
+    /// 32-bit window: behind a bridge, the 64-bit window is the
+    /// prefetchable one, which non-prefetchable BARs can't go in.
+    pub fn allocate(&mut self, kind: Kind, size: u64) -> Option<u64> {
+        match kind {
+            Kind::Io => self.io.allocate(size),
+            Kind::Memory32 { .. }
+            | Kind::Memory64 {
+                prefetchable: false,
+            } => self.memory32.allocate(size),
+            Kind::Memory64 { prefetchable: true } => self
+                .memory64
+                .allocate(size)
+                .or_else(|| self.memory32.allocate(size)),
+        }
+    }
+}
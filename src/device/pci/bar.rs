@@ -0,0 +1,192 @@
+use kvm::core::IoAddress;
+
+pub const BAR_COUNT: usize = 6;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// The kind of address space a BAR decodes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Io,
+    Memory32 { prefetchable: bool },
+    Memory64 { prefetchable: bool },
+}
+
+/// A single base address register.  The size is fixed by the device;
+/// the guest (or the host, on its behalf) only gets to pick the
+/// address, which must be naturally aligned to the size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Bar {
+    kind: Kind,
+    size: u64,
+    address: u64,
+}
+
+impl Bar {
+    /// Creates a 32-bit memory BAR.  The size is rounded up to a power
+    /// of two, and is at least 16 bytes.
+    #[cfg(test)]
+    pub fn memory32(size: u64, prefetchable: bool) -> Bar {
+        Bar::new(Kind::Memory32 { prefetchable }, size.max(16))
+    }
+
+    /// Creates a 64-bit memory BAR, which takes up two BAR slots.  The
+    /// size is rounded up to a power of two, and is at least 16 bytes.
+    pub fn memory64(size: u64, prefetchable: bool) -> Bar {
+        Bar::new(Kind::Memory64 { prefetchable }, size.max(16))
+    }
+
+    fn new(kind: Kind, size: u64) -> Bar {
+        Bar {
+            kind,
+            size: size.next_power_of_two(),
+            address: 0,
+        }
+    }
+
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        match self.kind {
+            Kind::Memory64 { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the BAR decodes the given address, and if so, the offset
+    /// into the BAR.
+    pub fn offset(&self, address: IoAddress) -> Option<u64> {
+        let (io, address) = match address {
+            IoAddress::Port(port) => (true, port),
+            IoAddress::Memory(address) => (false, address),
+        };
+
+        if io == (self.kind == Kind::Io)
+            && address >= self.address
+            && address - self.address < self.size
+        {
+            Some(address - self.address)
+        } else {
+            None
+        }
+    }
+
+    fn flags(&self) -> u32 {
+        match self.kind {
+            Kind::Io => BAR_IO,
+            Kind::Memory32 { prefetchable } => {
+                if prefetchable {
+                    BAR_PREFETCHABLE
+                } else {
+                    0
+                }
+            }
+            Kind::Memory64 { prefetchable } => {
+                BAR_MEMORY_64 | if prefetchable { BAR_PREFETCHABLE } else { 0 }
+            }
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        let mask = !(self.size - 1);
+        match self.kind {
+            Kind::Io => mask & 0xffff_fffc,
+            Kind::Memory32 { .. } => mask & 0xffff_fff0,
+            Kind::Memory64 { .. } => mask & !0xf,
+        }
+    }
+
+    /// Reads the lower (or, for a 64-bit BAR, upper) half of the
+    /// register.  After the guest writes all ones, this reads back the
+    /// size mask, which is how BARs are sized.
+    pub fn read(&self, upper: bool) -> u32 {
+        let value = self.address & self.mask();
+        if upper {
+            (value >> 32) as u32
+        } else {
+            value as u32 | self.flags()
+        }
+    }
+
+    pub fn write(&mut self, upper: bool, value: u32) {
+        let address = if upper {
+            (self.address & 0xffff_ffff) | ((value as u64) << 32)
+        } else {
+            (self.address & !0xffff_ffff) | value as u64
+        };
+
+        self.address = address & self.mask();
+    }
+}
+
+/// The six BARs of a type 0 header.  A 64-bit BAR takes up its own
+/// slot and the one after it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Bars([Option<Bar>; BAR_COUNT]);
+
+impl Bars {
+    pub fn new() -> Bars {
+        Bars::default()
+    }
+
+    /// Places a BAR in the given slot.  Panics if the slot is out of
+    /// range, or if a 64-bit BAR is placed in the last slot.
+    pub fn set(&mut self, index: usize, bar: Bar) {
+        assert!(index < BAR_COUNT && !(bar.is_64bit() && index == BAR_COUNT - 1));
+        self.0[index] = Some(bar);
+        if bar.is_64bit() {
+            self.0[index + 1] = None;
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Bar> {
+        self.0.get(index).and_then(|bar| bar.as_ref())
+    }
+
+    /// Finds the slot of the BAR that backs the given slot, and
+    /// whether the given slot is the upper half of a 64-bit BAR.
+    fn resolve(&self, index: usize) -> Option<(usize, bool)> {
+        if index >= BAR_COUNT {
+            None
+        } else if self.0[index].is_some() {
+            Some((index, false))
+        } else if index > 0 && self.0[index - 1].map(|bar| bar.is_64bit()).unwrap_or(false) {
+            Some((index - 1, true))
+        } else {
+            None
+        }
+    }
+
+    /// Reads the register for the given BAR slot.  Empty slots read as
+    /// zero, which tells the guest there's nothing there.
+    pub fn read(&self, index: usize) -> u32 {
+        self.resolve(index)
+            .and_then(|(i, upper)| self.0[i].map(|bar| bar.read(upper)))
+            .unwrap_or(0)
+    }
+
+    pub fn write(&mut self, index: usize, value: u32) {
+        if let Some((i, upper)) = self.resolve(index) {
+            if let Some(ref mut bar) = self.0[i] {
+                bar.write(upper, value);
+            }
+        }
+    }
+
+    /// Finds the BAR decoding the given address, returning its index
+    /// and the offset into it.
+    pub fn locate(&self, address: IoAddress) -> Option<(usize, u64)> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, bar)| {
+                bar.and_then(|b| b.offset(address))
+                    .map(|offset| (i, offset))
+            })
+            .next()
+    }
+}
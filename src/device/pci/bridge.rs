@@ -0,0 +1,291 @@
+use super::super::super::error::*;
+use super::super::interrupt::Interrupts;
+use super::super::Device;
+use super::config::{
+    CONFIG_SPACE_SIZE, PCI_COMMAND_IO, PCI_COMMAND_MEMORY, PCI_IO_BASE, PCI_IO_LIMIT,
+    PCI_MEMORY_BASE, PCI_MEMORY_LIMIT, PCI_PREFETCHABLE_BASE, PCI_PREFETCHABLE_BASE_UPPER,
+    PCI_PREFETCHABLE_LIMIT, PCI_PREFETCHABLE_LIMIT_UPPER, PCI_PRIMARY_BUS, PCI_SECONDARY_BUS,
+    PCI_SUBORDINATE_BUS,
+};
+use super::{
+    Address, Allocator, Bars, ConfigSpace, Delivery, Express, Header, Msi, Pci, PortType, Window,
+    EXTENDED_CONFIG_SPACE_SIZE,
+};
+use kvm::core::{IoAction, IoAddress};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The granularity of a bridge's I/O window.
+pub const BRIDGE_IO_ALIGN: u64 = 1 << 12;
+/// The granularity of a bridge's memory windows.
+pub const BRIDGE_MEMORY_ALIGN: u64 = 1 << 20;
+
+/// How much of each window a hot-plug slot keeps for whatever gets
+/// plugged into it later.
+pub const HOTPLUG_IO_RESERVE: u64 = 1 << 12;
+pub const HOTPLUG_MEMORY_RESERVE: u64 = 1 << 21;
+pub const HOTPLUG_PREFETCHABLE_RESERVE: u64 = 1 << 28;
+
+static PCI_BRIDGE_HEADER: Header = Header {
+    vendor: 0x1b36,
+    device: 0x0001,
+    revision: 0x00,
+    class: 0x060400,
+    subsystem_vendor: 0x1b36,
+    subsystem: 0x0001,
+    interrupt_pin: 0x00,
+};
+
+static ROOT_PORT_HEADER: Header = Header {
+    vendor: 0x1b36,
+    device: 0x000c,
+    revision: 0x00,
+    class: 0x060400,
+    subsystem_vendor: 0x1b36,
+    subsystem: 0x000c,
+    interrupt_pin: 0x00,
+};
+
+/// A PCI-to-PCI bridge.  The functions behind it sit on its secondary
+/// bus, one per slot, in the order they were given.  The bridge only
+/// forwards configuration cycles for buses between its secondary and
+/// subordinate bus numbers, and only forwards I/O and memory accesses
+/// that fall in its windows; both are left to whoever enumerates the
+/// bus to program.
+///
+/// A root port can also have a hot-plug slot, which holds at most one
+/// device and tells the guest about insertion and removal through the
+/// slot registers of its PCI Express capability.
+#[derive(Debug)]
+pub struct Bridge {
+    space: Mutex<ConfigSpace>,
+    devices: Mutex<Vec<Arc<Pci>>>,
+    slot: Option<Slot>,
+}
+
+#[derive(Debug)]
+struct Slot {
+    express: Arc<Express>,
+    msi: Arc<Msi>,
+    /// Whether we're waiting for the guest to power the slot down so
+    /// that its card can be removed.
+    removing: AtomicBool,
+}
+
+impl Bridge {
+    /// Creates a conventional PCI-to-PCI bridge.
+    pub fn new<V: IntoIterator<Item = Arc<Pci>>>(devices: V) -> Bridge {
+        Bridge {
+            space: Mutex::new(ConfigSpace::bridge(
+                PCI_BRIDGE_HEADER,
+                Bars::new(),
+                CONFIG_SPACE_SIZE,
+            )),
+            devices: Mutex::new(devices.into_iter().collect()),
+            slot: None,
+        }
+    }
+
+    /// Creates a PCI Express root port.
+    #[cfg(test)]
+    pub fn root_port<V: IntoIterator<Item = Arc<Pci>>>(devices: V) -> Bridge {
+        let mut space =
+            ConfigSpace::bridge(ROOT_PORT_HEADER, Bars::new(), EXTENDED_CONFIG_SPACE_SIZE);
+        space.push(Arc::new(Express::new(PortType::RootPort)));
+
+        Bridge {
+            space: Mutex::new(space),
+            devices: Mutex::new(devices.into_iter().collect()),
+            slot: None,
+        }
+    }
+
+    /// Creates a PCI Express root port with an empty hot-plug slot.
+    /// Hot-plug events are signalled through MSI.
+    pub fn hotplug(interrupts: Arc<Interrupts>, number: u16) -> Result<Bridge> {
+        let express = Arc::new(Express::with_slot(PortType::RootPort, number));
+        let msi = Arc::new(Msi::new(interrupts, 1, Delivery::Direct)?);
+        let mut space =
+            ConfigSpace::bridge(ROOT_PORT_HEADER, Bars::new(), EXTENDED_CONFIG_SPACE_SIZE);
+        space.push(express.clone());
+        space.push(msi.clone());
+
+        Ok(Bridge {
+            space: Mutex::new(space),
+            devices: Mutex::new(vec![]),
+            slot: Some(Slot {
+                express,
+                msi,
+                removing: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    pub fn devices(&self) -> Vec<Arc<Pci>> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// The physical number of the bridge's hot-plug slot, if it has
+    /// one.
+    pub fn slot(&self) -> Option<u16> {
+        self.slot.as_ref().and_then(|slot| slot.express.slot())
+    }
+
+    /// Inserts a card into the hot-plug slot and tells the guest.
+    pub fn plug(&self, pci: Arc<Pci>) -> Result<()> {
+        let slot = self
+            .slot
+            .as_ref()
+            .ok_or(ErrorKind::BusError("bridge has no hot-plug slot"))?;
+        let mut devices = self.devices.lock().unwrap();
+        if !devices.is_empty() || slot.express.present() {
+            return Err(ErrorKind::BusError("hot-plug slot is occupied").into());
+        }
+
+        devices.push(pci);
+        slot.removing.store(false, Ordering::SeqCst);
+        if slot.express.set_present(true) {
+            slot.msi.notify(0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Asks the guest to give up the card in the hot-plug slot by
+    /// pushing the attention button.  The card stays put until the
+    /// guest powers the slot down; see `ejected`.
+    pub fn request_removal(&self) -> Result<()> {
+        let slot = self
+            .slot
+            .as_ref()
+            .ok_or(ErrorKind::BusError("bridge has no hot-plug slot"))?;
+        if self.devices.lock().unwrap().is_empty() {
+            return Err(ErrorKind::BusError("hot-plug slot is empty").into());
+        }
+
+        slot.removing.store(true, Ordering::SeqCst);
+        if slot.express.press_attention() {
+            slot.msi.notify(0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the card in the hot-plug slot was asked to be removed,
+    /// and the guest has since powered the slot down.
+    pub fn ejected(&self) -> bool {
+        match self.slot {
+            Some(ref slot) => slot.removing.load(Ordering::SeqCst) && !slot.express.powered(),
+            None => false,
+        }
+    }
+
+    /// Pulls the card out of the hot-plug slot, returning it.
+    pub fn unplug(&self) -> Option<Arc<Pci>> {
+        let slot = self.slot.as_ref()?;
+        let pci = self.devices.lock().unwrap().pop();
+        slot.removing.store(false, Ordering::SeqCst);
+        if slot.express.set_present(false) {
+            if let Err(e) = slot.msi.notify(0) {
+                warn!("could not signal hot-plug event: {}", e);
+            }
+        }
+
+        pci
+    }
+
+    /// The primary, secondary, and subordinate bus numbers.
+    pub fn buses(&self) -> (u8, u8, u8) {
+        let space = self.space.lock().unwrap();
+        (
+            space.read_u8(PCI_PRIMARY_BUS),
+            space.read_u8(PCI_SECONDARY_BUS),
+            space.read_u8(PCI_SUBORDINATE_BUS),
+        )
+    }
+
+    /// Whether configuration cycles for the given bus go through this
+    /// bridge.
+    pub fn routes(&self, bus: u8) -> bool {
+        let (_, secondary, subordinate) = self.buses();
+        secondary != 0 && secondary <= bus && bus <= subordinate
+    }
+
+    /// An allocator over the bridge's current windows, for placing the
+    /// BARs of functions behind it.
+    pub fn allocator(&self) -> Allocator {
+        let (io, memory, prefetchable) = self.windows();
+        let window = |(base, limit): (u64, u64)| Window::new(base, limit.wrapping_add(1));
+        Allocator::new(window(io), window(memory), window(prefetchable))
+    }
+
+    /// The I/O, memory, and prefetchable memory windows, as inclusive
+    /// base and limit pairs.  A window whose base is above its limit is
+    /// closed.
+    fn windows(&self) -> ((u64, u64), (u64, u64), (u64, u64)) {
+        let space = self.space.lock().unwrap();
+        let io = (
+            ((space.read_u8(PCI_IO_BASE) & 0xf0) as u64) << 8,
+            (((space.read_u8(PCI_IO_LIMIT) & 0xf0) as u64) << 8) | 0xfff,
+        );
+        let memory = (
+            ((space.read_u16(PCI_MEMORY_BASE) & 0xfff0) as u64) << 16,
+            (((space.read_u16(PCI_MEMORY_LIMIT) & 0xfff0) as u64) << 16) | 0xfffff,
+        );
+        let prefetchable = (
+            ((space.read_u32(PCI_PREFETCHABLE_BASE_UPPER) as u64) << 32)
+                | (((space.read_u16(PCI_PREFETCHABLE_BASE) & 0xfff0) as u64) << 16),
+            ((space.read_u32(PCI_PREFETCHABLE_LIMIT_UPPER) as u64) << 32)
+                | (((space.read_u16(PCI_PREFETCHABLE_LIMIT) & 0xfff0) as u64) << 16)
+                | 0xfffff,
+        );
+
+        (io, memory, prefetchable)
+    }
+
+    /// Whether the bridge forwards accesses to the given range
+    /// downstream.
+    pub fn forwards(&self, start: IoAddress, size: u64) -> bool {
+        let command = self.space.lock().unwrap().command();
+        let (io, memory, prefetchable) = self.windows();
+        let contains = |(base, limit): (u64, u64), address: u64| {
+            base <= limit && address >= base && address + size - 1 <= limit
+        };
+
+        match start {
+            IoAddress::Port(port) => (command & PCI_COMMAND_IO) != 0 && contains(io, port),
+            IoAddress::Memory(address) => {
+                (command & PCI_COMMAND_MEMORY) != 0
+                    && (contains(memory, address) || contains(prefetchable, address))
+            }
+        }
+    }
+}
+
+impl Device for Bridge {
+    fn request(&self) -> Vec<IoAddress> {
+        vec![]
+    }
+
+    fn handle(&self, _io: IoAction, _memory: &mut [u8]) -> Option<()> {
+        None
+    }
+}
+
+impl Pci for Bridge {
+    fn config_read(&self, address: Address, data: &mut [u8]) -> Option<()> {
+        let space = self.space.lock().unwrap();
+        space.read(address.register() as usize, data);
+        Some(())
+    }
+
+    fn config_write(&self, address: Address, data: &[u8]) -> Option<()> {
+        let mut space = self.space.lock().unwrap();
+        space.write(address.register() as usize, data);
+        Some(())
+    }
+
+    fn bridge(&self) -> Option<&Bridge> {
+        Some(self)
+    }
+}
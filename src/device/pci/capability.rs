@@ -0,0 +1,39 @@
+use std::fmt::Debug;
+
+/// A PCI capability that lives in a device's configuration space.
+/// Offsets given to the capability are relative to the start of the
+/// capability structure, so offset 0 is the capability ID and offset
+/// 1 is the next pointer (which the capability itself leaves as 0;
+/// whoever lays out the configuration space fills it in).
+pub trait Capability: Debug + Send + Sync {
+    /// The capability ID, as assigned by the PCI SIG.
+    fn id(&self) -> u8;
+    /// The length of the capability structure, in bytes, including
+    /// the two byte header.
+    fn len(&self) -> usize;
+    fn read(&self, offset: usize, data: &mut [u8]);
+    fn write(&self, offset: usize, data: &[u8]);
+}
+
+/// Copies `data` into `registers` at `offset`, only modifying the bits
+/// that are set in `writable`.  Bytes that fall outside of
+/// `registers` are dropped.
+pub fn write_masked(registers: &mut [u8], writable: &[u8], offset: usize, data: &[u8]) {
+    for (i, byte) in data.iter().enumerate() {
+        let position = offset + i;
+        if position >= registers.len() {
+            break;
+        }
+
+        let mask = writable.get(position).cloned().unwrap_or(0);
+        registers[position] = (registers[position] & !mask) | (byte & mask);
+    }
+}
+
+/// Copies `registers` at `offset` into `data`; anything past the end
+/// of `registers` reads as zero.
+pub fn read_into(registers: &[u8], offset: usize, data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = registers.get(offset + i).cloned().unwrap_or(0);
+    }
+}
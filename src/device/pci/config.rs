@@ -0,0 +1,564 @@
+use super::bar::{Bars, BAR_COUNT};
+use super::capability::{read_into, Capability};
+use byteorder::{ByteOrder, LittleEndian};
+use std::sync::Arc;
+
+/// The size of the configuration space of a conventional PCI
+/// function.
+pub const CONFIG_SPACE_SIZE: usize = 0x100;
+/// The size of the configuration space of a PCI Express function.
+pub const EXTENDED_CONFIG_SPACE_SIZE: usize = 0x1000;
+
+pub const PCI_VENDOR_ID: usize = 0x00;
+pub const PCI_DEVICE_ID: usize = 0x02;
+pub const PCI_COMMAND: usize = 0x04;
+pub const PCI_STATUS: usize = 0x06;
+pub const PCI_REVISION_ID: usize = 0x08;
+pub const PCI_CLASS_CODE: usize = 0x09;
+pub const PCI_CACHE_LINE_SIZE: usize = 0x0c;
+pub const PCI_LATENCY_TIMER: usize = 0x0d;
+pub const PCI_HEADER_TYPE: usize = 0x0e;
+pub const PCI_BAR0: usize = 0x10;
+pub const PCI_PRIMARY_BUS: usize = 0x18;
+pub const PCI_SECONDARY_BUS: usize = 0x19;
+pub const PCI_SUBORDINATE_BUS: usize = 0x1a;
+pub const PCI_SECONDARY_LATENCY_TIMER: usize = 0x1b;
+pub const PCI_IO_BASE: usize = 0x1c;
+pub const PCI_IO_LIMIT: usize = 0x1d;
+pub const PCI_SECONDARY_STATUS: usize = 0x1e;
+pub const PCI_MEMORY_BASE: usize = 0x20;
+pub const PCI_MEMORY_LIMIT: usize = 0x22;
+pub const PCI_PREFETCHABLE_BASE: usize = 0x24;
+pub const PCI_PREFETCHABLE_LIMIT: usize = 0x26;
+pub const PCI_PREFETCHABLE_BASE_UPPER: usize = 0x28;
+pub const PCI_PREFETCHABLE_LIMIT_UPPER: usize = 0x2c;
+pub const PCI_BRIDGE_CONTROL: usize = 0x3e;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+pub const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+pub const PCI_COMMAND_IO: u16 = 1 << 0;
+pub const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+pub const PCI_COMMAND_MASTER: u16 = 1 << 2;
+pub const PCI_COMMAND_PARITY: u16 = 1 << 6;
+pub const PCI_COMMAND_SERR: u16 = 1 << 8;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+pub const PCI_HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+pub const PCI_STATUS_INTERRUPT: u16 = 1 << 3;
+pub const PCI_STATUS_CAPABILITY_LIST: u16 = 1 << 4;
+/// The error bits of the status register, which the guest clears by
+/// writing ones to them.
+pub const PCI_STATUS_ERRORS: u16 = 0xf900;
+
+/// The first byte past the standard header, and so where capabilities
+/// start.
+const CAPABILITY_START: usize = 0x40;
+
+/// The number of BARs in a type 1 (bridge) header.
+pub const BRIDGE_BAR_COUNT: usize = 2;
+
+/// The Bridge Subsystem Vendor ID capability, which is where bridges
+/// keep the subsystem IDs, since their header has no room for them.
+const SUBSYSTEM_CAPABILITY_ID: u8 = 0x0d;
+const SUBSYSTEM_LENGTH: usize = 8;
+const SUBSYSTEM_VENDOR: usize = 4;
+const SUBSYSTEM: usize = 6;
+
+/// The fixed, read-only fields of a configuration header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Header {
+    pub vendor: u16,
+    pub device: u16,
+    pub revision: u8,
+    /// The 24-bit class code: base class, sub-class, and programming
+    /// interface, from most to least significant byte.
+    pub class: u32,
+    pub subsystem_vendor: u16,
+    pub subsystem: u16,
+    /// The INTx pin the function uses; 0 for none, 1 through 4 for
+    /// INTA# through INTD#.
+    pub interrupt_pin: u8,
+}
+
+/// Which part of the configuration space a byte belongs to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Target {
+    Register,
+    Bar(usize),
+    Capability(usize),
+}
+
+/// The configuration space of a PCI function.  The header is laid out
+/// from a [`Header`], the BARs are backed by a [`Bars`], and
+/// capabilities are pushed onto a list that is linked up
+/// automatically.  Everything else is plain registers, where every
+/// bit is either read-only, read-write, or write-1-to-clear.
+///
+/// Reads and writes may be of any width, and at any offset.
+#[derive(Debug)]
+pub struct ConfigSpace {
+    registers: Vec<u8>,
+    writable: Vec<u8>,
+    clearable: Vec<u8>,
+    bars: Bars,
+    bar_count: usize,
+    capabilities: Vec<(usize, Arc<Capability>)>,
+}
+
+impl ConfigSpace {
+    pub fn with_size(header: Header, bars: Bars, size: usize) -> ConfigSpace {
+        let mut space = ConfigSpace::common(header, bars, size);
+        space.set_u16(PCI_SUBSYSTEM_VENDOR_ID, header.subsystem_vendor);
+        space.set_u16(PCI_SUBSYSTEM_ID, header.subsystem);
+        space
+    }
+
+    /// Lays out the part of the header both header types share.
+    fn common(header: Header, bars: Bars, size: usize) -> ConfigSpace {
+        let mut space = ConfigSpace {
+            registers: vec![0u8; size],
+            writable: vec![0u8; size],
+            clearable: vec![0u8; size],
+            bars,
+            bar_count: BAR_COUNT,
+            capabilities: vec![],
+        };
+
+        space.set_u16(PCI_VENDOR_ID, header.vendor);
+        space.set_u16(PCI_DEVICE_ID, header.device);
+        space.registers[PCI_REVISION_ID] = header.revision;
+        space.set_u16(PCI_CLASS_CODE, header.class as u16);
+        space.registers[PCI_CLASS_CODE + 2] = (header.class >> 16) as u8;
+        space.registers[PCI_INTERRUPT_PIN] = header.interrupt_pin;
+
+        let command = PCI_COMMAND_IO
+            | PCI_COMMAND_MEMORY
+            | PCI_COMMAND_MASTER
+            | PCI_COMMAND_PARITY
+            | PCI_COMMAND_SERR
+            | PCI_COMMAND_INTX_DISABLE;
+        space.set_mask_u16(PCI_COMMAND, command, 0);
+        space.set_mask_u16(PCI_STATUS, 0, PCI_STATUS_ERRORS);
+        space.set_mask(PCI_CACHE_LINE_SIZE, 0xff, 0);
+        space.set_mask(PCI_LATENCY_TIMER, 0xff, 0);
+        space.set_mask(PCI_INTERRUPT_LINE, 0xff, 0);
+
+        space
+    }
+
+    /// Creates the configuration space of a PCI-to-PCI bridge, with a
+    /// type 1 header.  Only the first two BARs are available; the rest
+    /// of that space is taken up by the bus numbers and forwarding
+    /// windows, which are all writable.  The prefetchable window is
+    /// 64-bit, and the I/O window is 16-bit.  The subsystem IDs go in
+    /// a capability, which comes first in the list.
+    pub fn bridge(header: Header, bars: Bars, size: usize) -> ConfigSpace {
+        let mut space = ConfigSpace::common(header, bars, size);
+        space.bar_count = BRIDGE_BAR_COUNT;
+        space.registers[PCI_HEADER_TYPE] = PCI_HEADER_TYPE_BRIDGE;
+
+        space.set_mask(PCI_PRIMARY_BUS, 0xff, 0);
+        space.set_mask(PCI_SECONDARY_BUS, 0xff, 0);
+        space.set_mask(PCI_SUBORDINATE_BUS, 0xff, 0);
+        space.set_mask(PCI_SECONDARY_LATENCY_TIMER, 0xff, 0);
+        space.set_mask(PCI_IO_BASE, 0xf0, 0);
+        space.set_mask(PCI_IO_LIMIT, 0xf0, 0);
+        space.set_mask_u16(PCI_SECONDARY_STATUS, 0, PCI_STATUS_ERRORS);
+        space.set_mask_u16(PCI_MEMORY_BASE, 0xfff0, 0);
+        space.set_mask_u16(PCI_MEMORY_LIMIT, 0xfff0, 0);
+        space.set_u16(PCI_PREFETCHABLE_BASE, 0x0001);
+        space.set_u16(PCI_PREFETCHABLE_LIMIT, 0x0001);
+        space.set_mask_u16(PCI_PREFETCHABLE_BASE, 0xfff0, 0);
+        space.set_mask_u16(PCI_PREFETCHABLE_LIMIT, 0xfff0, 0);
+        space.set_mask_u32(PCI_PREFETCHABLE_BASE_UPPER, 0xffff_ffff, 0);
+        space.set_mask_u32(PCI_PREFETCHABLE_LIMIT_UPPER, 0xffff_ffff, 0);
+        space.set_mask_u16(PCI_BRIDGE_CONTROL, 0x0fff, 0);
+
+        space.push(Arc::new(Subsystem(
+            header.subsystem_vendor,
+            header.subsystem,
+        )));
+        space
+    }
+
+    /// Sets a read-only 16-bit register.
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        LittleEndian::write_u16(&mut self.registers[offset..offset + 2], value);
+    }
+
+    /// Marks the given bits of a byte as writable, or write-1-to-clear.
+    pub fn set_mask(&mut self, offset: usize, writable: u8, clearable: u8) {
+        self.writable[offset] = writable;
+        self.clearable[offset] = clearable;
+    }
+
+    pub fn set_mask_u16(&mut self, offset: usize, writable: u16, clearable: u16) {
+        LittleEndian::write_u16(&mut self.writable[offset..offset + 2], writable);
+        LittleEndian::write_u16(&mut self.clearable[offset..offset + 2], clearable);
+    }
+
+    pub fn set_mask_u32(&mut self, offset: usize, writable: u32, clearable: u32) {
+        LittleEndian::write_u32(&mut self.writable[offset..offset + 4], writable);
+        LittleEndian::write_u32(&mut self.clearable[offset..offset + 4], clearable);
+    }
+
+    pub fn command(&self) -> u16 {
+        LittleEndian::read_u16(&self.registers[PCI_COMMAND..PCI_COMMAND + 2])
+    }
+
+    pub fn status(&self) -> u16 {
+        LittleEndian::read_u16(&self.registers[PCI_STATUS..PCI_STATUS + 2])
+    }
+
+    /// Sets or clears the interrupt status bit, which reflects whether
+    /// the function is asserting INTx.
+    pub fn set_interrupt_status(&mut self, asserted: bool) {
+        let status = if asserted {
+            self.status() | PCI_STATUS_INTERRUPT
+        } else {
+            self.status() & !PCI_STATUS_INTERRUPT
+        };
+        self.set_u16(PCI_STATUS, status);
+    }
+
+    pub fn bars(&self) -> &Bars {
+        &self.bars
+    }
+
+    /// Appends a capability to the capability list, returning the
+    /// offset it was placed at.  Panics if the capability doesn't fit.
+    pub fn push(&mut self, capability: Arc<Capability>) -> usize {
+        let offset = match self.capabilities.last() {
+            Some(&(offset, ref last)) => (offset + last.len() + 3) & !3,
+            None => CAPABILITY_START,
+        };
+
+        assert!(
+            offset + capability.len() <= CONFIG_SPACE_SIZE,
+            "capability does not fit in configuration space"
+        );
+
+        match self.capabilities.last() {
+            Some(&(last, _)) => self.registers[last + 1] = offset as u8,
+            None => {
+                let status = self.status() | PCI_STATUS_CAPABILITY_LIST;
+                self.set_u16(PCI_STATUS, status);
+                self.registers[PCI_CAPABILITY_LIST] = offset as u8;
+            }
+        }
+
+        self.capabilities.push((offset, capability));
+        offset
+    }
+
+    fn target(&self, offset: usize) -> Target {
+        if offset >= PCI_BAR0 && offset < PCI_BAR0 + 4 * self.bar_count {
+            return Target::Bar((offset - PCI_BAR0) / 4);
+        }
+
+        self.capabilities
+            .iter()
+            .position(|&(start, ref cap)| offset >= start && offset < start + cap.len())
+            .map(Target::Capability)
+            .unwrap_or(Target::Register)
+    }
+
+    /// Splits an access into runs of bytes that all go to the same
+    /// place.
+    fn runs(&self, offset: usize, length: usize) -> Vec<(Target, usize, usize)> {
+        let mut runs: Vec<(Target, usize, usize)> = vec![];
+
+        for position in offset..(offset + length) {
+            let target = self.target(position);
+            match runs.last_mut() {
+                Some(&mut (last, _, ref mut end)) if last == target && *end == position => {
+                    *end += 1;
+                }
+                _ => runs.push((target, position, position + 1)),
+            }
+        }
+
+        runs
+    }
+
+    pub fn read(&self, offset: usize, data: &mut [u8]) {
+        for (target, start, end) in self.runs(offset, data.len()) {
+            let out = &mut data[(start - offset)..(end - offset)];
+            match target {
+                Target::Register => {
+                    for (i, byte) in out.iter_mut().enumerate() {
+                        *byte = self.registers.get(start + i).cloned().unwrap_or(0);
+                    }
+                }
+                Target::Bar(index) => {
+                    let mut value = [0u8; 4];
+                    LittleEndian::write_u32(&mut value, self.bars.read(index));
+                    let shift = start - (PCI_BAR0 + index * 4);
+                    out.copy_from_slice(&value[shift..shift + out.len()]);
+                }
+                Target::Capability(index) => {
+                    let (base, ref capability) = self.capabilities[index];
+                    capability.read(start - base, out);
+                    // The header belongs to us, not the capability.
+                    for (i, byte) in out.iter_mut().enumerate() {
+                        match start - base + i {
+                            0 => *byte = capability.id(),
+                            1 => *byte = self.registers[base + 1],
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        for (target, start, end) in self.runs(offset, data.len()) {
+            let input = &data[(start - offset)..(end - offset)];
+            match target {
+                Target::Register => {
+                    for (i, byte) in input.iter().enumerate() {
+                        let position = start + i;
+                        if position >= self.registers.len() {
+                            break;
+                        }
+
+                        let writable = self.writable[position];
+                        let clearable = self.clearable[position];
+                        let value = (self.registers[position] & !writable) | (byte & writable);
+                        self.registers[position] = value & !(byte & clearable);
+                    }
+                }
+                Target::Bar(index) => {
+                    let mut value = [0u8; 4];
+                    LittleEndian::write_u32(&mut value, self.bars.read(index));
+                    let shift = start - (PCI_BAR0 + index * 4);
+                    value[shift..shift + input.len()].copy_from_slice(input);
+                    self.bars.write(index, LittleEndian::read_u32(&value));
+                }
+                Target::Capability(index) => {
+                    let (base, ref capability) = self.capabilities[index];
+                    let skip = 2usize.saturating_sub(start - base);
+                    if skip < input.len() {
+                        capability.write(start - base + skip, &input[skip..]);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        let mut value = [0u8; 1];
+        self.read(offset, &mut value);
+        value[0]
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        let mut value = [0u8; 2];
+        self.read(offset, &mut value);
+        LittleEndian::read_u16(&value)
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        let mut value = [0u8; 4];
+        self.read(offset, &mut value);
+        LittleEndian::read_u32(&value)
+    }
+}
+
+/// A bridge's subsystem vendor and subsystem IDs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Subsystem(u16, u16);
+
+impl Capability for Subsystem {
+    fn id(&self) -> u8 {
+        SUBSYSTEM_CAPABILITY_ID
+    }
+
+    fn len(&self) -> usize {
+        SUBSYSTEM_LENGTH
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        let mut registers = [0u8; SUBSYSTEM_LENGTH];
+        registers[0] = SUBSYSTEM_CAPABILITY_ID;
+        LittleEndian::write_u16(&mut registers[SUBSYSTEM_VENDOR..], self.0);
+        LittleEndian::write_u16(&mut registers[SUBSYSTEM..], self.1);
+        read_into(&registers, offset, data);
+    }
+
+    fn write(&self, _offset: usize, _data: &[u8]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bar::{Bar, Bars};
+    use super::super::capability::{read_into, Capability};
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A capability that remembers what was written to it, and reads
+    /// back as a counting pattern everywhere else.
+    #[derive(Debug)]
+    struct Scratchpad(Mutex<Vec<u8>>);
+
+    impl Scratchpad {
+        fn new(len: usize) -> Arc<Scratchpad> {
+            Arc::new(Scratchpad(Mutex::new((0..len as u8).collect())))
+        }
+
+        fn registers(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Capability for Scratchpad {
+        fn id(&self) -> u8 {
+            0x09
+        }
+
+        fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+
+        fn read(&self, offset: usize, data: &mut [u8]) {
+            read_into(&self.0.lock().unwrap(), offset, data);
+        }
+
+        fn write(&self, offset: usize, data: &[u8]) {
+            let mut registers = self.0.lock().unwrap();
+            registers[offset..offset + data.len()].copy_from_slice(data);
+        }
+    }
+
+    fn space(bars: Bars) -> ConfigSpace {
+        let header = Header {
+            vendor: 0x1af4,
+            device: 0x1042,
+            revision: 1,
+            class: 0x01_8000,
+            subsystem_vendor: 0x1af4,
+            subsystem: 0x0002,
+            interrupt_pin: 1,
+        };
+        ConfigSpace::with_size(header, bars, CONFIG_SPACE_SIZE)
+    }
+
+    fn read(space: &ConfigSpace, offset: usize, length: usize) -> Vec<u8> {
+        let mut data = vec![0u8; length];
+        space.read(offset, &mut data);
+        data
+    }
+
+    fn write_u32(space: &mut ConfigSpace, offset: usize, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        space.write(offset, &data);
+    }
+
+    #[test]
+    fn it_keeps_read_only_registers() {
+        let mut space = space(Bars::new());
+        for &value in &[0xffff_ffff, 0] {
+            for offset in (0..0x40).step_by(4) {
+                write_u32(&mut space, offset, value);
+            }
+        }
+
+        assert_eq!(space.read_u32(PCI_VENDOR_ID), 0x1042_1af4);
+        assert_eq!(space.read_u32(PCI_REVISION_ID), 0x0180_0001);
+        assert_eq!(space.read_u32(PCI_SUBSYSTEM_VENDOR_ID), 0x0002_1af4);
+        assert_eq!(read(&space, PCI_CAPABILITY_LIST, 1), [0]);
+        assert_eq!(read(&space, PCI_INTERRUPT_PIN, 1), [1]);
+    }
+
+    #[test]
+    fn it_only_writes_writable_bits() {
+        let mut space = space(Bars::new());
+        write_u32(&mut space, PCI_COMMAND, 0x0000_ffff);
+        let command = PCI_COMMAND_IO
+            | PCI_COMMAND_MEMORY
+            | PCI_COMMAND_MASTER
+            | PCI_COMMAND_PARITY
+            | PCI_COMMAND_SERR
+            | PCI_COMMAND_INTX_DISABLE;
+        assert_eq!(space.command(), command);
+
+        write_u32(&mut space, PCI_INTERRUPT_LINE, 0xffff_ffff);
+        assert_eq!(read(&space, PCI_INTERRUPT_LINE, 2), [0xff, 1]);
+
+        space.set_mask(0x48, 0x0f, 0);
+        space.write(0x48, &[0xff]);
+        assert_eq!(read(&space, 0x48, 1), [0x0f]);
+    }
+
+    #[test]
+    fn it_clears_status_errors_written_as_ones() {
+        let mut space = space(Bars::new());
+        space.set_u16(PCI_STATUS, PCI_STATUS_ERRORS);
+        space.set_interrupt_status(true);
+
+        // Writing zeroes leaves everything alone, ...
+        write_u32(&mut space, PCI_COMMAND, 0);
+        assert_eq!(space.status(), PCI_STATUS_ERRORS | PCI_STATUS_INTERRUPT);
+
+        // ... ones clear only the error bits they're written to, ...
+        let status = 0x8100 | PCI_STATUS_INTERRUPT;
+        write_u32(&mut space, PCI_COMMAND, (status as u32) << 16);
+        assert_eq!(space.status(), 0x7800 | PCI_STATUS_INTERRUPT);
+
+        // ... and the bits don't come back by writing ones again.
+        space.write(PCI_STATUS + 1, &[0xff]);
+        assert_eq!(space.status(), PCI_STATUS_INTERRUPT);
+    }
+
+    #[test]
+    fn it_chains_capabilities() {
+        let mut space = space(Bars::new());
+        let second = Scratchpad::new(8);
+        assert_eq!(space.push(Scratchpad::new(6)), 0x40);
+        assert_eq!(space.push(second.clone()), 0x48);
+
+        assert_ne!(space.status() & PCI_STATUS_CAPABILITY_LIST, 0);
+        assert_eq!(read(&space, PCI_CAPABILITY_LIST, 1), [0x40]);
+        assert_eq!(space.read_u32(0x40), 0x0302_4809);
+        assert_eq!(space.read_u32(0x44), 0x0000_0504);
+        assert_eq!(space.read_u32(0x48), 0x0302_0009);
+        assert_eq!(space.read_u32(0x4c), 0x0706_0504);
+
+        // The header isn't the capability's to change.
+        write_u32(&mut space, 0x48, 0xdead_beef);
+        assert_eq!(space.read_u32(0x48), 0xdead_0009);
+        assert_eq!(second.registers()[..4], [0x00, 0x01, 0xad, 0xde][..]);
+        assert_eq!(read(&space, 0x41, 1), [0x48]);
+    }
+
+    #[test]
+    fn it_splits_partial_accesses() {
+        let mut bars = Bars::new();
+        bars.set(0, Bar::memory64(0x1000, false));
+        let mut space = space(bars);
+        let capability = Scratchpad::new(8);
+        space.push(capability.clone());
+
+        // Bytes and words at odd offsets.
+        assert_eq!(read(&space, PCI_VENDOR_ID + 1, 1), [0x1a]);
+        assert_eq!(read(&space, PCI_REVISION_ID + 1, 2), [0x00, 0x80]);
+        assert_eq!(read(&space, 0x43, 2), [0x03, 0x04]);
+
+        // Half of a BAR keeps the other half.
+        write_u32(&mut space, PCI_BAR0, 0x1234_5678);
+        space.write(PCI_BAR0 + 2, &[0xcd, 0xab]);
+        assert_eq!(space.read_u32(PCI_BAR0), 0xabcd_5004);
+
+        // An access spanning registers and a capability goes to both.
+        space.write(0x3c, &[0x0b; 8]);
+        assert_eq!(read(&space, PCI_INTERRUPT_LINE, 2), [0x0b, 1]);
+        assert_eq!(capability.registers()[..4], [0x00, 0x01, 0x0b, 0x0b][..]);
+        assert_eq!(read(&space, 0x3d, 6), [0x01, 0x00, 0x00, 0x09, 0x00, 0x0b]);
+    }
+}
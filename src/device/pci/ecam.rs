@@ -0,0 +1,75 @@
+use super::super::Device;
+use super::address::ECAM_BUS_SIZE;
+use super::{Address, Host};
+use kvm::core::{IoAction, IoAddress, IoDirection};
+use std::sync::Arc;
+
+/// The memory mapped configuration space of a host bridge, as
+/// described to the guest by the ACPI MCFG table.  Every function gets
+/// a 4 KiB window, so the extended configuration space (offsets 0x100
+/// and up) is reachable, unlike through the legacy I/O ports.
+#[derive(Debug)]
+pub struct Ecam {
+    host: Arc<Host>,
+    base: u64,
+    start: u8,
+    buses: u16,
+}
+
+impl Ecam {
+    /// Creates an ECAM window at `base` covering `buses` buses, the
+    /// first of which is `start`.
+    pub fn new(host: Arc<Host>, base: u64, start: u8, buses: u16) -> Ecam {
+        Ecam {
+            host,
+            base,
+            start,
+            buses,
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        self.buses as u64 * ECAM_BUS_SIZE
+    }
+}
+
+impl Device for Ecam {
+    fn request(&self) -> Vec<IoAddress> {
+        vec![]
+    }
+
+    fn handle(&self, io: IoAction, memory: &mut [u8]) -> Option<()> {
+        let IoAction(address, direction, size) = io;
+        let offset = match address {
+            IoAddress::Memory(address) if address >= self.base => address - self.base,
+            _ => return None,
+        };
+
+        if offset >= self.size() {
+            return None;
+        }
+
+        // Accesses wider than a dword (or unaligned ones that cross
+        // one) are split up, since functions only ever deal in pieces
+        // of a single dword.
+        let mut done = 0;
+        while done < size {
+            let address = Address::from_ecam(self.start, offset + done as u64);
+            let length = ::std::cmp::min(size - done, 4 - (address.register() as usize & 0x3));
+            let data = &mut memory[done..done + length];
+
+            match direction {
+                IoDirection::In => self.host.read(address, data),
+                IoDirection::Out => self.host.write(address, data),
+            }
+
+            done += length;
+        }
+
+        Some(())
+    }
+}
@@ -0,0 +1,221 @@
+use super::capability::{read_into, Capability};
+use byteorder::{ByteOrder, LittleEndian};
+use std::sync::Mutex;
+
+pub const EXPRESS_CAPABILITY_ID: u8 = 0x10;
+
+const EXPRESS_LENGTH: usize = 0x3c;
+const EXPRESS_VERSION: u16 = 2;
+
+const EXPRESS_CAPABILITIES: usize = 0x02;
+const EXPRESS_DEVICE_STATUS: usize = 0x0a;
+const EXPRESS_SLOT_CAPABILITIES: usize = 0x14;
+const EXPRESS_SLOT_CONTROL: usize = 0x18;
+const EXPRESS_SLOT_STATUS: usize = 0x1a;
+
+const EXPRESS_CAPABILITIES_SLOT: u16 = 1 << 8;
+
+const SLOT_CAPABILITIES_ATTENTION_BUTTON: u32 = 1 << 0;
+const SLOT_CAPABILITIES_POWER_CONTROLLER: u32 = 1 << 1;
+const SLOT_CAPABILITIES_ATTENTION_INDICATOR: u32 = 1 << 3;
+const SLOT_CAPABILITIES_POWER_INDICATOR: u32 = 1 << 4;
+const SLOT_CAPABILITIES_HOTPLUG_CAPABLE: u32 = 1 << 6;
+const SLOT_CAPABILITIES_NO_COMMAND_COMPLETED: u32 = 1 << 18;
+const SLOT_CAPABILITIES_NUMBER_SHIFT: u32 = 19;
+
+const SLOT_CONTROL_ATTENTION_BUTTON_ENABLE: u16 = 1 << 0;
+const SLOT_CONTROL_PRESENCE_DETECT_ENABLE: u16 = 1 << 3;
+const SLOT_CONTROL_HOTPLUG_INTERRUPT_ENABLE: u16 = 1 << 5;
+const SLOT_CONTROL_POWER_OFF: u16 = 1 << 10;
+
+const SLOT_STATUS_ATTENTION_BUTTON: u16 = 1 << 0;
+const SLOT_STATUS_PRESENCE_DETECT_CHANGED: u16 = 1 << 3;
+const SLOT_STATUS_PRESENCE_DETECT: u16 = 1 << 6;
+
+/// The device/port type field of the PCI Express capabilities
+/// register.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PortType {
+    RootPort = 0x4,
+    IntegratedEndpoint = 0x9,
+}
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static EXPRESS_WRITABLE: [u8; EXPRESS_LENGTH] = [
+    0x00, 0x00, 0x00, 0x00, // 0x00, (id, next, capabilities)
+    0x00, 0x00, 0x00, 0x00, // 0x04, (device capabilities)
+    0xff, 0x7f, 0x00, 0x00, // 0x08, (device control, device status)
+    0x00, 0x00, 0x00, 0x00, // 0x0c, (link capabilities)
+    0x00, 0x00, 0x00, 0x00, // 0x10, (link control, link status)
+    0x00, 0x00, 0x00, 0x00, // 0x14, (slot capabilities)
+    0xff, 0x1f, 0x00, 0x00, // 0x18, (slot control, slot status)
+    0x00, 0x00, 0x00, 0x00, // 0x1c, (root control, root capabilities)
+    0x00, 0x00, 0x00, 0x00, // 0x20, (root status)
+    0x00, 0x00, 0x00, 0x00, // 0x24, (device capabilities 2)
+    0x00, 0x00, 0x00, 0x00, // 0x28, (device control 2, device status 2)
+    0x00, 0x00, 0x00, 0x00, // 0x2c, (link capabilities 2)
+    0x00, 0x00, 0x00, 0x00, // 0x30, (link control 2, link status 2)
+    0x00, 0x00, 0x00, 0x00, // 0x34, (slot capabilities 2)
+    0x00, 0x00, 0x00, 0x00, // 0x38, (slot control 2, slot status 2)
+];
+
+/// The PCI Express capability (capability ID 0x10).  Its presence is
+/// what tells the guest a function is a PCI Express function, and so
+/// that it has a 4 KiB configuration space.
+#[derive(Debug)]
+pub struct Express(Mutex<[u8; EXPRESS_LENGTH]>);
+
+impl Express {
+    pub fn new(kind: PortType) -> Express {
+        let mut registers = [0u8; EXPRESS_LENGTH];
+        registers[0] = EXPRESS_CAPABILITY_ID;
+        LittleEndian::write_u16(
+            &mut registers[EXPRESS_CAPABILITIES..EXPRESS_CAPABILITIES + 2],
+            EXPRESS_VERSION | ((kind as u16) << 4),
+        );
+        Express(Mutex::new(registers))
+    }
+
+    /// Creates the capability of a port with a hot-plug capable slot,
+    /// with an attention button, a power controller, and indicators.
+    /// The slot starts out empty.  Commands complete immediately, so
+    /// the guest never waits for a command completed event.
+    pub fn with_slot(kind: PortType, number: u16) -> Express {
+        let express = Express::new(kind);
+        {
+            let mut registers = express.0.lock().unwrap();
+            let capabilities = get_u16(&*registers, EXPRESS_CAPABILITIES);
+            set_u16(
+                &mut *registers,
+                EXPRESS_CAPABILITIES,
+                capabilities | EXPRESS_CAPABILITIES_SLOT,
+            );
+            LittleEndian::write_u32(
+                &mut registers[EXPRESS_SLOT_CAPABILITIES..EXPRESS_SLOT_CAPABILITIES + 4],
+                SLOT_CAPABILITIES_ATTENTION_BUTTON
+                    | SLOT_CAPABILITIES_POWER_CONTROLLER
+                    | SLOT_CAPABILITIES_ATTENTION_INDICATOR
+                    | SLOT_CAPABILITIES_POWER_INDICATOR
+                    | SLOT_CAPABILITIES_HOTPLUG_CAPABLE
+                    | SLOT_CAPABILITIES_NO_COMMAND_COMPLETED
+                    | ((number as u32 & 0x1fff) << SLOT_CAPABILITIES_NUMBER_SHIFT),
+            );
+        }
+        express
+    }
+
+    /// The physical slot number, if the port has a hot-plug capable
+    /// slot.
+    pub fn slot(&self) -> Option<u16> {
+        let registers = self.0.lock().unwrap();
+        let capabilities = LittleEndian::read_u32(
+            &registers[EXPRESS_SLOT_CAPABILITIES..EXPRESS_SLOT_CAPABILITIES + 4],
+        );
+        if (capabilities & SLOT_CAPABILITIES_HOTPLUG_CAPABLE) == 0 {
+            return None;
+        }
+
+        Some((capabilities >> SLOT_CAPABILITIES_NUMBER_SHIFT) as u16)
+    }
+
+    /// Whether the guest has the slot powered on.
+    pub fn powered(&self) -> bool {
+        let registers = self.0.lock().unwrap();
+        (get_u16(&*registers, EXPRESS_SLOT_CONTROL) & SLOT_CONTROL_POWER_OFF) == 0
+    }
+
+    /// Whether a card is in the slot.
+    pub fn present(&self) -> bool {
+        let registers = self.0.lock().unwrap();
+        (get_u16(&*registers, EXPRESS_SLOT_STATUS) & SLOT_STATUS_PRESENCE_DETECT) != 0
+    }
+
+    /// Inserts a card into, or pulls it from, the slot.  Returns
+    /// whether the guest asked to be interrupted for the change.
+    pub fn set_present(&self, present: bool) -> bool {
+        let mut registers = self.0.lock().unwrap();
+        let status = get_u16(&*registers, EXPRESS_SLOT_STATUS);
+        if ((status & SLOT_STATUS_PRESENCE_DETECT) != 0) == present {
+            return false;
+        }
+
+        let status = if present {
+            status | SLOT_STATUS_PRESENCE_DETECT
+        } else {
+            status & !SLOT_STATUS_PRESENCE_DETECT
+        };
+        set_u16(
+            &mut *registers,
+            EXPRESS_SLOT_STATUS,
+            status | SLOT_STATUS_PRESENCE_DETECT_CHANGED,
+        );
+
+        event(&*registers, SLOT_CONTROL_PRESENCE_DETECT_ENABLE)
+    }
+
+    /// Pushes the slot's attention button, which asks the guest to
+    /// power the slot down so that the card can be removed.  Returns
+    /// whether the guest asked to be interrupted for it.
+    pub fn press_attention(&self) -> bool {
+        let mut registers = self.0.lock().unwrap();
+        let status = get_u16(&*registers, EXPRESS_SLOT_STATUS);
+        set_u16(
+            &mut *registers,
+            EXPRESS_SLOT_STATUS,
+            status | SLOT_STATUS_ATTENTION_BUTTON,
+        );
+
+        event(&*registers, SLOT_CONTROL_ATTENTION_BUTTON_ENABLE)
+    }
+}
+
+fn get_u16(registers: &[u8], offset: usize) -> u16 {
+    LittleEndian::read_u16(&registers[offset..offset + 2])
+}
+
+fn set_u16(registers: &mut [u8], offset: usize, value: u16) {
+    LittleEndian::write_u16(&mut registers[offset..offset + 2], value);
+}
+
+/// Whether the guest has enabled both hot-plug interrupts in general
+/// and the given event in particular.
+fn event(registers: &[u8], enable: u16) -> bool {
+    let control = get_u16(registers, EXPRESS_SLOT_CONTROL);
+    let wanted = SLOT_CONTROL_HOTPLUG_INTERRUPT_ENABLE | enable;
+    (control & wanted) == wanted
+}
+
+impl Capability for Express {
+    fn id(&self) -> u8 {
+        EXPRESS_CAPABILITY_ID
+    }
+
+    fn len(&self) -> usize {
+        EXPRESS_LENGTH
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        read_into(&*self.0.lock().unwrap(), offset, data);
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) {
+        let mut registers = self.0.lock().unwrap();
+        for (i, byte) in data.iter().enumerate() {
+            let position = offset + i;
+            if position >= EXPRESS_LENGTH {
+                break;
+            }
+
+            let mask = EXPRESS_WRITABLE[position];
+            registers[position] = (registers[position] & !mask) | (byte & mask);
+
+            // The error bits of the device status, and the event bits
+            // of the slot status, are write-1-to-clear.
+            match position {
+                EXPRESS_DEVICE_STATUS => registers[position] &= !(byte & 0x0f),
+                EXPRESS_SLOT_STATUS => registers[position] &= !(byte & 0x1f),
+                _ => (),
+            }
+        }
+    }
+}
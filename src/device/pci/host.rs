@@ -1,16 +1,36 @@
+use super::super::super::error::*;
+use super::super::bus::Bus;
 use super::super::Device;
-use super::{Address, Pci};
+use super::allocator::Allocator;
+use super::bar::{Kind, BAR_COUNT};
+use super::bridge::{
+    BRIDGE_IO_ALIGN, BRIDGE_MEMORY_ALIGN, HOTPLUG_IO_RESERVE, HOTPLUG_MEMORY_RESERVE,
+    HOTPLUG_PREFETCHABLE_RESERVE,
+};
+use super::config::{
+    BRIDGE_BAR_COUNT, PCI_IO_BASE, PCI_MEMORY_BASE, PCI_PREFETCHABLE_BASE,
+    PCI_PREFETCHABLE_BASE_UPPER, PCI_PREFETCHABLE_LIMIT_UPPER, PCI_PRIMARY_BUS,
+};
+use super::{Address, Bridge, Pci};
 use byteorder::{ByteOrder, LittleEndian};
-use kvm::core::{IoAction, IoAddress};
-use std::collections::HashMap;
+use kvm::core::{IoAction, IoAddress, IoDirection};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 
-#[derive(Debug)]
-pub struct Host(u8, AtomicUsize, HashMap<usize, Arc<Pci>>);
+pub static CONFIG_ADDRESS: IoAddress = IoAddress::Port(CONFIG_ADDRESS_PORT);
+pub static CONFIG_DATA: IoAddress = IoAddress::Port(CONFIG_DATA_PORT);
+
+const CONFIG_ADDRESS_PORT: u64 = 0xcf8;
+const CONFIG_DATA_PORT: u64 = 0xcfc;
+const CONFIG_DATA_END: u64 = 0xcff;
 
-pub static CONFIG_ADDRESS: IoAddress = IoAddress::Port(0xcf8);
-pub static CONFIG_DATA: IoAddress = IoAddress::Port(0xcfc);
+const PCI_COMMAND: u16 = 0x04;
+const PCI_COMMAND_IO: u32 = 1 << 0;
+const PCI_COMMAND_MEMORY: u32 = 1 << 1;
+const PCI_COMMAND_MASTER: u32 = 1 << 2;
+const PCI_BAR0: u16 = 0x10;
+/// The end of a bridge's bus number and window registers.
+const BRIDGE_WINDOWS_END: u16 = 0x30;
 
 // #[cfg_attr(rustfmt, rustfmt_skip)]
 // pub static DEFAULT_HOST_CONFIG: &[u8] = &[
@@ -36,76 +56,1168 @@ pub static CONFIG_DATA: IoAddress = IoAddress::Port(0xcfc);
 
 // ];
 
+/// A BAR as discovered by sizing it through configuration space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Region {
+    index: usize,
+    kind: Kind,
+    size: u64,
+}
+
+/// Forwards BAR accesses from the bus to the PCI device that owns
+/// them.
+#[derive(Debug)]
+struct Mapping(Arc<Pci>);
+
+impl Device for Mapping {
+    fn request(&self) -> Vec<IoAddress> {
+        vec![]
+    }
+
+    fn handle(&self, io: IoAction, memory: &mut [u8]) -> Option<()> {
+        self.0.handle(io, memory)
+    }
+}
+
+/// The slot of a function on the bus: its device and function number.
+type Slot = (u8, u8);
+
+/// A function somewhere in the hierarchy below the host bridge.
+#[derive(Debug)]
+struct Function {
+    pci: Arc<Pci>,
+    /// The index of the bridge this function sits behind, or `None`
+    /// for functions on the host bridge's own bus.
+    parent: Option<usize>,
+    slot: Slot,
+    regions: Vec<Region>,
+    mapped: Mutex<Vec<IoAddress>>,
+}
+
+#[derive(Debug)]
+pub struct Host {
+    bridge: u8,
+    address: AtomicUsize,
+    /// Every function in the hierarchy, indexed by position.  Removed
+    /// functions leave a hole behind, so that indices stay stable.
+    functions: RwLock<Vec<Option<Function>>>,
+    bus: Arc<Bus>,
+}
+
+/// The number of devices on a single bus.
+pub const DEVICES_PER_BUS: usize = 32;
+
+fn read(pci: &Pci, address: Address) -> u32 {
+    let mut data = [0u8; 4];
+    match pci.config_read(address, &mut data) {
+        Some(()) => LittleEndian::read_u32(&data),
+        None => 0,
+    }
+}
+
+fn write(pci: &Pci, address: Address, value: u32) {
+    let mut data = [0u8; 4];
+    LittleEndian::write_u32(&mut data, value);
+    pci.config_write(address, &data);
+}
+
+/// The number of BARs in the function's header.
+fn bar_count(pci: &Pci) -> usize {
+    match pci.bridge() {
+        Some(_) => BRIDGE_BAR_COUNT,
+        None => BAR_COUNT,
+    }
+}
+
+/// Sizes every BAR of the function, the same way the guest would: by
+/// writing all ones to each register and seeing which bits stick.
+fn probe(pci: &Pci, address: Address) -> Vec<Region> {
+    let mut regions = vec![];
+    let mut index = 0;
+    let count = bar_count(pci);
+
+    while index < count {
+        let register = Address(
+            address.0,
+            address.1,
+            address.2,
+            PCI_BAR0 + (index as u16) * 4,
+        );
+        let original = read(pci, register);
+        write(pci, register, 0xffff_ffff);
+        let lower = read(pci, register);
+        write(pci, register, original);
+
+        if lower == 0 {
+            index += 1;
+            continue;
+        }
+
+        let region = if (lower & 1) != 0 {
+            let mask = (lower & !0x3) as u64 | 0xffff_ffff_0000_0000;
+            Region {
+                index,
+                kind: Kind::Io,
+                size: !mask + 1,
+            }
+        } else if ((lower >> 1) & 0b11) == 0b10 && index + 1 < count {
+            let high = register.offset(4);
+            let original = read(pci, high);
+            write(pci, high, 0xffff_ffff);
+            let upper = read(pci, high);
+            write(pci, high, original);
+            let mask = ((upper as u64) << 32) | (lower & !0xf) as u64;
+            Region {
+                index,
+                kind: Kind::Memory64 {
+                    prefetchable: (lower & (1 << 3)) != 0,
+                },
+                size: !mask + 1,
+            }
+        } else {
+            let mask = (lower & !0xf) as u64 | 0xffff_ffff_0000_0000;
+            Region {
+                index,
+                kind: Kind::Memory32 {
+                    prefetchable: (lower & (1 << 3)) != 0,
+                },
+                size: !mask + 1,
+            }
+        };
+
+        index += match region.kind {
+            Kind::Memory64 { .. } => 2,
+            _ => 1,
+        };
+        regions.push(region);
+    }
+
+    regions
+}
+
+/// Looks up a live function by index.
+fn get(functions: &[Option<Function>], index: usize) -> Option<&Function> {
+    functions.get(index).and_then(|f| f.as_ref())
+}
+
+fn bridge_of(functions: &[Option<Function>], index: usize) -> Option<&Bridge> {
+    get(functions, index).and_then(|f| f.pci.bridge())
+}
+
 impl Host {
-    pub fn new<V: IntoIterator<Item = Arc<Pci>>>(bridge: Option<u8>, pcis: V) -> Host {
-        let mut devices = HashMap::new();
+    /// Creates a host bridge with the given devices, each placed in
+    /// its own slot as function 0.  Every BAR of every device is
+    /// assigned an address from the allocator, and decoding is enabled,
+    /// so devices work even without firmware that enumerates the bus;
+    /// the guest is free to move them afterwards.
+    ///
+    /// Bridges among the devices are numbered depth-first and given
+    /// windows covering everything behind them.  If there are more
+    /// devices than fit on a bus, the last slot is given to a bridge
+    /// holding the rest.
+    pub fn new<V: IntoIterator<Item = Arc<Pci>>>(
+        bridge: Option<u8>,
+        bus: Arc<Bus>,
+        mut allocator: Allocator,
+        pcis: V,
+    ) -> Host {
         let bridge = bridge.unwrap_or(0);
+        let mut functions = vec![];
+        let mut next = bridge;
+
+        Host::attach(
+            None,
+            bridge,
+            pcis.into_iter().collect(),
+            &mut allocator,
+            &mut next,
+            &mut functions,
+        );
+
+        let host = Host {
+            bridge,
+            address: 0usize.into(),
+            functions: RwLock::new(functions),
+            bus,
+        };
+
+        host.remap_all(&host.functions.read().unwrap());
+        host
+    }
+
+    /// Places the functions on the given bus, behind the given parent,
+    /// recursing into any bridges among them.  `next` is the highest
+    /// bus number handed out so far.
+    fn attach(
+        parent: Option<usize>,
+        bus: u8,
+        mut pcis: Vec<Arc<Pci>>,
+        allocator: &mut Allocator,
+        next: &mut u8,
+        functions: &mut Vec<Option<Function>>,
+    ) {
+        if pcis.len() > DEVICES_PER_BUS {
+            let rest = pcis.split_off(DEVICES_PER_BUS - 1);
+            pcis.push(Arc::new(Bridge::new(rest)));
+        }
 
         for (i, pci) in pcis.into_iter().enumerate() {
-            devices.insert(i, pci);
+            let slot = (i as u8, 0);
+            let address = Address(bus, slot.0, slot.1, 0);
+            let regions = Host::assign(pci.as_ref(), address, allocator);
+            let index = functions.len();
+            functions.push(Some(Function {
+                pci: pci.clone(),
+                parent,
+                slot,
+                regions,
+                mapped: Mutex::new(vec![]),
+            }));
+
+            if let Some(bridge) = pci.bridge() {
+                Host::enumerate(bridge, index, address, allocator, next, functions);
+            }
+        }
+    }
+
+    /// Numbers the buses behind a bridge, assigns everything behind it,
+    /// and opens its windows around what was assigned.  Hot-plug slots
+    /// get some room in each window even when empty.
+    fn enumerate(
+        bridge: &Bridge,
+        index: usize,
+        address: Address,
+        allocator: &mut Allocator,
+        next: &mut u8,
+        functions: &mut Vec<Option<Function>>,
+    ) {
+        let register = |offset: usize| Address(address.0, address.1, address.2, offset as u16);
+
+        if *next == 0xff {
+            warn!("out of bus numbers, ignoring devices behind {:x?}", address);
+            return;
         }
 
-        Host(bridge, 0usize.into(), devices)
+        *next += 1;
+        let secondary = *next;
+        let buses = |subordinate: u8| {
+            address.0 as u32 | (secondary as u32) << 8 | (subordinate as u32) << 16
+        };
+        write(bridge, register(PCI_PRIMARY_BUS), buses(0xff));
+
+        allocator.align(BRIDGE_IO_ALIGN, BRIDGE_MEMORY_ALIGN);
+        let (io, memory, prefetchable) = allocator.positions();
+        Host::attach(
+            Some(index),
+            secondary,
+            bridge.devices(),
+            allocator,
+            next,
+            functions,
+        );
+
+        if bridge.slot().is_some() {
+            let reserved = [
+                (Kind::Io, HOTPLUG_IO_RESERVE),
+                (
+                    Kind::Memory32 {
+                        prefetchable: false,
+                    },
+                    HOTPLUG_MEMORY_RESERVE,
+                ),
+                (
+                    Kind::Memory64 { prefetchable: true },
+                    HOTPLUG_PREFETCHABLE_RESERVE,
+                ),
+            ];
+            for &(kind, size) in reserved.iter() {
+                if allocator.allocate(kind, size).is_none() {
+                    warn!(
+                        "could not reserve {:x?} for hot-plug slot at {:x?}",
+                        kind, address
+                    );
+                }
+            }
+        }
+
+        allocator.align(BRIDGE_IO_ALIGN, BRIDGE_MEMORY_ALIGN);
+        let (io_end, memory_end, prefetchable_end) = allocator.positions();
+
+        write(bridge, register(PCI_PRIMARY_BUS), buses(*next));
+
+        // An empty window is closed by putting its base above its
+        // limit.
+        let (io_base, io_limit) = if io_end > io {
+            ((io >> 8) as u32 & 0xf0, ((io_end - 1) >> 8) as u32 & 0xf0)
+        } else {
+            (0xf0, 0x00)
+        };
+        write(bridge, register(PCI_IO_BASE), io_base | io_limit << 8);
+
+        let (memory_base, memory_limit) = if memory_end > memory {
+            (
+                (memory >> 16) as u32 & 0xfff0,
+                ((memory_end - 1) >> 16) as u32 & 0xfff0,
+            )
+        } else {
+            (0xfff0, 0x0000)
+        };
+        write(
+            bridge,
+            register(PCI_MEMORY_BASE),
+            memory_base | memory_limit << 16,
+        );
+
+        let (prefetchable_base, prefetchable_limit) = if prefetchable_end > prefetchable {
+            (prefetchable, prefetchable_end - 1)
+        } else {
+            (0xfff0_0000, 0)
+        };
+        write(
+            bridge,
+            register(PCI_PREFETCHABLE_BASE),
+            (prefetchable_base >> 16) as u32 & 0xfff0
+                | ((prefetchable_limit >> 16) as u32 & 0xfff0) << 16,
+        );
+        write(
+            bridge,
+            register(PCI_PREFETCHABLE_BASE_UPPER),
+            (prefetchable_base >> 32) as u32,
+        );
+        write(
+            bridge,
+            register(PCI_PREFETCHABLE_LIMIT_UPPER),
+            (prefetchable_limit >> 32) as u32,
+        );
+
+        let command = read(bridge, register(PCI_COMMAND as usize)) & 0xffff;
+        write(
+            bridge,
+            register(PCI_COMMAND as usize),
+            command | PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER,
+        );
     }
 
-    fn lookup(&self, device: u8) -> Option<&Pci> {
-        self.2.get(&(device as usize)).map(|v| v.as_ref() as &Pci)
+    /// Sizes the function's BARs, assigns them addresses, and turns on
+    /// decoding and bus mastering.
+    fn assign(pci: &Pci, address: Address, allocator: &mut Allocator) -> Vec<Region> {
+        let mut found = probe(pci, address);
+        let mut command = 0;
+
+        // Allocating the biggest BARs first keeps the windows from
+        // fragmenting.
+        found.sort_by(|a, b| b.size.cmp(&a.size));
+        for region in &found {
+            let register = Address(
+                address.0,
+                address.1,
+                address.2,
+                PCI_BAR0 + (region.index as u16) * 4,
+            );
+            match allocator.allocate(region.kind, region.size) {
+                Some(base) => {
+                    write(pci, register, base as u32);
+                    if let Kind::Memory64 { .. } = region.kind {
+                        write(pci, register.offset(4), (base >> 32) as u32);
+                    }
+
+                    command |= match region.kind {
+                        Kind::Io => PCI_COMMAND_IO,
+                        _ => PCI_COMMAND_MEMORY,
+                    };
+                }
+
+                None => warn!(
+                    "could not allocate {:x?} of size {:#x} for {:x?}",
+                    region.kind, region.size, address
+                ),
+            }
+        }
+
+        let register = Address(address.0, address.1, address.2, PCI_COMMAND);
+        let current = read(pci, register) & 0xffff;
+        write(pci, register, current | command | PCI_COMMAND_MASTER);
+        found
+    }
+
+    /// The bus number the function currently sits on, as far as its
+    /// parent bridge is concerned.
+    fn bus_of(&self, functions: &[Option<Function>], index: usize) -> u8 {
+        match get(functions, index).and_then(|f| f.parent) {
+            Some(parent) => bridge_of(functions, parent)
+                .map(|b| b.buses().1)
+                .unwrap_or(0),
+            None => self.bridge,
+        }
+    }
+
+    /// The address of one of the function's registers.
+    fn register(&self, functions: &[Option<Function>], index: usize, register: u16) -> Address {
+        let (device, function) = get(functions, index).map(|f| f.slot).unwrap_or((0, 0));
+        Address(self.bus_of(functions, index), device, function, register)
+    }
+
+    /// Finds the function a configuration cycle is for, following it
+    /// down through whichever bridges claim its bus number.
+    fn resolve(&self, functions: &[Option<Function>], address: Address) -> Option<usize> {
+        let mut parent = None;
+        let mut bus = self.bridge;
+
+        loop {
+            let children = functions
+                .iter()
+                .enumerate()
+                .filter_map(|(i, f)| f.as_ref().map(|f| (i, f)))
+                .filter(|&(_, f)| f.parent == parent);
+
+            if address.bus() == bus {
+                let slot = (address.device(), address.function());
+                return children
+                    .filter(|&(_, f)| f.slot == slot)
+                    .map(|(i, _)| i)
+                    .next();
+            }
+
+            let (index, bridge) = children
+                .filter_map(|(i, f)| f.pci.bridge().map(|b| (i, b)))
+                .find(|&(_, b)| b.routes(address.bus()))?;
+            parent = Some(index);
+            bus = bridge.buses().1;
+        }
+    }
+
+    /// Finds the bridge with the given hot-plug slot number.
+    fn find_slot(&self, functions: &[Option<Function>], number: u16) -> Result<usize> {
+        (0..functions.len())
+            .find(|&i| bridge_of(functions, i).and_then(|b| b.slot()) == Some(number))
+            .ok_or_else(|| ErrorKind::BusError("no such hot-plug slot").into())
+    }
+
+    fn remap_all(&self, functions: &[Option<Function>]) {
+        for index in 0..functions.len() {
+            self.remap(functions, index);
+        }
+    }
+
+    /// Brings the bus in line with the function's BARs and command
+    /// register, mapping the BARs the function currently decodes, and
+    /// that every bridge above it forwards, and unmapping everything
+    /// else.
+    fn remap(&self, functions: &[Option<Function>], index: usize) {
+        let function = match get(functions, index) {
+            Some(function) => function,
+            None => return,
+        };
+        let pci = function.pci.as_ref();
+        let command = read(pci, self.register(functions, index, PCI_COMMAND));
+        let mut wanted = vec![];
+
+        for region in &function.regions {
+            let register = self.register(functions, index, PCI_BAR0 + (region.index as u16) * 4);
+            let lower = read(pci, register);
+            let sizing = !(region.size - 1) as u32;
+            let (enabled, probing, start) = match region.kind {
+                Kind::Io => (
+                    (command & PCI_COMMAND_IO) != 0,
+                    (lower & !0x3) == (sizing & !0x3),
+                    IoAddress::Port((lower & !0x3) as u64),
+                ),
+                Kind::Memory32 { .. } => (
+                    (command & PCI_COMMAND_MEMORY) != 0,
+                    (lower & !0xf) == (sizing & !0xf),
+                    IoAddress::Memory((lower & !0xf) as u64),
+                ),
+                Kind::Memory64 { .. } => {
+                    let upper = read(pci, register.offset(4));
+                    let sizing = !(region.size - 1);
+                    (
+                        (command & PCI_COMMAND_MEMORY) != 0,
+                        (lower & !0xf) == (sizing as u32 & !0xf) && upper == (sizing >> 32) as u32,
+                        IoAddress::Memory(((upper as u64) << 32) | (lower & !0xf) as u64),
+                    )
+                }
+            };
+
+            // A BAR at zero hasn't been programmed yet, and one that
+            // reads back as its own size mask is in the middle of being
+            // sized; neither should be decoded.
+            let programmed = match start {
+                IoAddress::Port(address) | IoAddress::Memory(address) => address != 0,
+            };
+            if enabled
+                && programmed
+                && !probing
+                && self.forwarded(functions, index, start, region.size)
+            {
+                wanted.push((start, region.size));
+            }
+        }
+
+        self.unmap(function);
+        let mut current = function.mapped.lock().unwrap();
+        for (start, size) in wanted {
+            match self
+                .bus
+                .insert(start, size, Arc::new(Mapping(function.pci.clone())))
+            {
+                Ok(()) => current.push(start),
+                Err(e) => warn!("could not map {:x?} for {:x?}: {}", start, function.slot, e),
+            }
+        }
+    }
+
+    fn unmap(&self, function: &Function) {
+        for start in function.mapped.lock().unwrap().drain(..) {
+            self.bus.remove(start);
+        }
+    }
+
+    /// Whether every bridge between the function and the host bridge
+    /// forwards the range.
+    fn forwarded(
+        &self,
+        functions: &[Option<Function>],
+        index: usize,
+        start: IoAddress,
+        size: u64,
+    ) -> bool {
+        let mut parent = get(functions, index).and_then(|f| f.parent);
+        while let Some(index) = parent {
+            match bridge_of(functions, index) {
+                Some(bridge) if bridge.forwards(start, size) => (),
+                _ => return false,
+            }
+            parent = get(functions, index).and_then(|f| f.parent);
+        }
+
+        true
+    }
+
+    /// Plugs a device into the hot-plug slot with the given number.
+    /// Its BARs are assigned from the slot's windows, and the guest is
+    /// told about it.
+    pub fn plug(&self, number: u16, pci: Arc<Pci>) -> Result<()> {
+        if pci.bridge().is_some() {
+            return Err(ErrorKind::BusError("cannot hot-plug a bridge").into());
+        }
+
+        let mut functions = self.functions.write().unwrap();
+        let parent = self.find_slot(&functions, number)?;
+        let bridge = bridge_of(&functions, parent).expect("slot without a bridge");
+        if !bridge.devices().is_empty() {
+            return Err(ErrorKind::BusError("hot-plug slot is occupied").into());
+        }
+
+        let slot = (0, 0);
+        let address = Address(bridge.buses().1, slot.0, slot.1, 0);
+        let regions = Host::assign(pci.as_ref(), address, &mut bridge.allocator());
+        bridge.plug(pci.clone())?;
+
+        let index = functions.len();
+        functions.push(Some(Function {
+            pci,
+            parent: Some(parent),
+            slot,
+            regions,
+            mapped: Mutex::new(vec![]),
+        }));
+        self.remap(&functions, index);
+
+        Ok(())
+    }
+
+    /// Asks the guest to give up the device in the hot-plug slot with
+    /// the given number.  The device is only removed once the guest
+    /// has powered the slot down.
+    pub fn unplug(&self, number: u16) -> Result<()> {
+        let functions = self.functions.read().unwrap();
+        let index = self.find_slot(&functions, number)?;
+        bridge_of(&functions, index)
+            .expect("slot without a bridge")
+            .request_removal()
+    }
+
+    /// Whether the hot-plug slot with the given number holds a device.
+    pub fn plugged(&self, number: u16) -> Result<bool> {
+        let functions = self.functions.read().unwrap();
+        let index = self.find_slot(&functions, number)?;
+        Ok(!bridge_of(&functions, index)
+            .expect("slot without a bridge")
+            .devices()
+            .is_empty())
+    }
+
+    /// Tears down the devices behind a hot-plug slot the guest has
+    /// ejected.
+    fn eject(&self, index: usize) {
+        let mut functions = self.functions.write().unwrap();
+        match bridge_of(&functions, index) {
+            Some(bridge) if bridge.ejected() => {
+                if let Some(pci) = bridge.unplug() {
+                    info!("ejected {:?}", pci);
+                }
+            }
+            _ => return,
+        }
+
+        for function in functions.iter_mut() {
+            let child = match *function {
+                Some(ref f) => f.parent == Some(index),
+                None => false,
+            };
+            if child {
+                if let Some(f) = function.take() {
+                    self.unmap(&f);
+                }
+            }
+        }
+    }
+
+    /// Reads from the configuration space of a function.  Functions
+    /// that don't exist read as all ones.
+    pub fn read(&self, address: Address, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = 0xff;
+        }
+
+        let functions = self.functions.read().unwrap();
+        if let Some(index) = self.resolve(&functions, address) {
+            if get(&functions, index)
+                .map_or(Some(()), |f| f.pci.config_read(address, data))
+                .is_none()
+            {
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+    }
+
+    /// Writes to the configuration space of a function, remapping its
+    /// BARs if the write could have changed them.  Changes to a
+    /// bridge's bus numbers, windows, or command register can affect
+    /// anything behind it, so those remap everything.  Writes to a
+    /// hot-plug slot may be the guest ejecting its device.
+    pub fn write(&self, address: Address, data: &[u8]) {
+        let ejecting = {
+            let functions = self.functions.read().unwrap();
+            let index = match self.resolve(&functions, address) {
+                Some(index) => index,
+                None => return,
+            };
+            let function = match get(&functions, index) {
+                Some(function) => function,
+                None => return,
+            };
+            let pci = function.pci.as_ref();
+
+            debug!("config write to {:x?}: {:x?}", address, data);
+            pci.config_write(address, data);
+
+            // A 64-bit BAR is written a dword at a time, the lower one
+            // first; its address isn't settled until the upper one is.
+            let register = address.register() & !0x3;
+            let bars = PCI_BAR0 + 4 * bar_count(pci) as u16;
+            let settling = function.regions.iter().any(|region| match region.kind {
+                Kind::Memory64 { .. } => register == PCI_BAR0 + (region.index as u16) * 4,
+                _ => false,
+            });
+            if pci.bridge().is_some()
+                && (register == PCI_COMMAND || (register >= bars && register < BRIDGE_WINDOWS_END))
+            {
+                self.remap_all(&functions);
+            } else if register == PCI_COMMAND
+                || (register >= PCI_BAR0 && register < bars && !settling)
+            {
+                self.remap(&functions, index);
+            }
+
+            match pci.bridge() {
+                Some(bridge) if bridge.ejected() => Some(index),
+                _ => None,
+            }
+        };
+
+        if let Some(index) = ejecting {
+            self.eject(index);
+        }
+    }
+
+    /// The address selected by CONFIG_ADDRESS, adjusted for which of
+    /// the CONFIG_DATA ports was accessed.  Accesses that would cross
+    /// the end of the dword are dropped.
+    fn selected(&self, port: u64, size: usize) -> Option<Address> {
+        let offset = (port - CONFIG_DATA_PORT) as u16;
+        if size == 0 || offset as usize + size > 4 {
+            return None;
+        }
+
+        Address::from(self.address.load(Ordering::SeqCst) as u32)
+            .map(|address| address.offset(offset))
     }
 }
 
 impl Device for Host {
     fn request(&self) -> Vec<IoAddress> {
-        vec![CONFIG_ADDRESS, CONFIG_DATA]
+        let mut ports = vec![CONFIG_ADDRESS];
+        ports.extend((0..4).map(|i| CONFIG_DATA + i));
+        ports
     }
 
     fn handle(&self, io: IoAction, memory: &mut [u8]) -> Option<()> {
         // info!("{:x?}/{:x?}", io, memory);
-        if io == CONFIG_ADDRESS.outw() {
-            self.1
-                .store(LittleEndian::read_u32(memory) as usize, Ordering::SeqCst);
-            Some(())
-        } else if io == CONFIG_DATA.inw() {
-            let addr = Address::from(self.1.load(Ordering::SeqCst) as u32);
-
-            match addr {
-                Some(address) if address.bridge() == self.0 => {
-                    match self.lookup(address.device()) {
-                        Some(device) => {
-                            let value = device.config_read(address).unwrap_or(0);
-                            LittleEndian::write_u32(memory, value);
-                        }
+        let IoAction(address, direction, size) = io;
+        let port = match address {
+            IoAddress::Port(port) => port,
+            _ => return None,
+        };
+
+        match (port, direction) {
+            // Only dword accesses hit CONFIG_ADDRESS; the byte-sized
+            // port at 0xcf9 is something else entirely.
+            (CONFIG_ADDRESS_PORT, IoDirection::Out) if size == 4 => {
+                self.address
+                    .store(LittleEndian::read_u32(memory) as usize, Ordering::SeqCst);
+                Some(())
+            }
 
-                        None => {
-                            for i in 0..4 {
-                                memory[i] = 0xff;
-                            }
+            (CONFIG_ADDRESS_PORT, IoDirection::In) if size == 4 => {
+                LittleEndian::write_u32(memory, self.address.load(Ordering::SeqCst) as u32);
+                Some(())
+            }
+
+            (CONFIG_DATA_PORT..=CONFIG_DATA_END, IoDirection::In) => {
+                let data = &mut memory[..size];
+                match self.selected(port, size) {
+                    Some(address) => self.read(address, data),
+                    None => {
+                        for byte in data.iter_mut() {
+                            *byte = 0xff;
                         }
                     }
                 }
-                _ => {}
+
+                Some(())
             }
 
-            Some(())
-        } else if io == CONFIG_DATA.outw() {
-            let addr = Address::from(self.1.load(Ordering::SeqCst) as u32);
-
-            match addr {
-                Some(address) if address.bridge() == self.0 => {
-                    match self.lookup(address.device()) {
-                        Some(device) => {
-                            let value = LittleEndian::read_u32(memory);
-                            warn!("config request for {:x?} successful: {:x?}", address, value);
-                            device.config_write(address, value);
-                        }
-                        None => {}
-                    }
+            (CONFIG_DATA_PORT..=CONFIG_DATA_END, IoDirection::Out) => {
+                if let Some(address) = self.selected(port, size) {
+                    self.write(address, &memory[..size]);
                 }
 
-                _ => {}
+                Some(())
             }
+
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::super::error::*;
+    use super::super::super::bus::Bus;
+    use super::super::super::interrupt::{Interrupts, Message};
+    use super::super::super::Device;
+    use super::super::allocator::{Allocator, Window};
+    use super::super::bar::{Bar, Bars};
+    use super::super::config::{ConfigSpace, Header, CONFIG_SPACE_SIZE};
+    use super::super::{Address, Bridge, Pci};
+    use super::Host;
+    use byteorder::{ByteOrder, LittleEndian};
+    use kvm::core::{IoAction, IoAddress, IoDirection};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug)]
+    struct Function(Mutex<ConfigSpace>);
+
+    impl Function {
+        fn new(device: u16) -> Arc<Pci> {
+            let header = Header {
+                vendor: 0x1af4,
+                device,
+                ..Header::default()
+            };
+            let mut bars = Bars::new();
+            bars.set(0, Bar::memory32(0x1000, false));
+            Arc::new(Function(Mutex::new(ConfigSpace::with_size(
+                header,
+                bars,
+                CONFIG_SPACE_SIZE,
+            ))))
+        }
+
+        /// A function with a 64-bit BAR instead.
+        fn wide(device: u16) -> Arc<Pci> {
+            let header = Header {
+                vendor: 0x1af4,
+                device,
+                ..Header::default()
+            };
+            let mut bars = Bars::new();
+            bars.set(0, Bar::memory64(0x4000, false));
+            Arc::new(Function(Mutex::new(ConfigSpace::with_size(
+                header,
+                bars,
+                CONFIG_SPACE_SIZE,
+            ))))
+        }
+    }
+
+    impl Device for Function {
+        fn request(&self) -> Vec<IoAddress> {
+            vec![]
+        }
+
+        fn handle(&self, _io: IoAction, _memory: &mut [u8]) -> Option<()> {
             Some(())
-        } else {
-            None
         }
     }
+
+    impl Pci for Function {
+        fn config_read(&self, address: Address, data: &mut [u8]) -> Option<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .read(address.register() as usize, data);
+            Some(())
+        }
+
+        fn config_write(&self, address: Address, data: &[u8]) -> Option<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .write(address.register() as usize, data);
+            Some(())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Signals(Mutex<Vec<Message>>);
+
+    impl Interrupts for Signals {
+        fn line(&self, _irq: u32, _level: bool) -> Result<()> {
+            Ok(())
+        }
+
+        fn signal(&self, message: Message) -> Result<()> {
+            self.0.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn allocate(&self) -> Result<u32> {
+            Ok(0)
+        }
+
+        fn route(&self, _gsi: u32, _message: Option<Message>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn host(count: u16) -> (Host, Arc<Bus>) {
+        with_devices((0..count).map(Function::new).collect())
+    }
+
+    fn with_devices(devices: Vec<Arc<Pci>>) -> (Host, Arc<Bus>) {
+        let bus = Arc::new(Bus::new());
+        let allocator = Allocator::new(
+            Window::new(0xc000, 0x10000),
+            Window::new(0xc000_0000, 0xfec0_0000),
+            Window::new(1 << 32, 1 << 36),
+        );
+        (Host::new(None, bus.clone(), allocator, devices), bus)
+    }
+
+    fn read_u32(host: &Host, address: Address) -> u32 {
+        select(host, address);
+        LittleEndian::read_u32(&read(host, 0xcfc, 4))
+    }
+
+    fn select(host: &Host, address: Address) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, address.into_config_address());
+        let io = IoAction(IoAddress::Port(0xcf8), IoDirection::Out, 4);
+        assert_eq!(host.handle(io, &mut data), Some(()));
+    }
+
+    fn read(host: &Host, port: u64, size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        let io = IoAction(IoAddress::Port(port), IoDirection::In, size);
+        assert_eq!(host.handle(io, &mut data), Some(()));
+        data
+    }
+
+    fn write(host: &Host, port: u64, data: &[u8]) {
+        let mut data = data.to_owned();
+        let io = IoAction(IoAddress::Port(port), IoDirection::Out, data.len());
+        assert_eq!(host.handle(io, &mut data), Some(()));
+    }
+
+    #[test]
+    fn it_reads_back_config_address() {
+        let (host, _) = host(1);
+        select(&host, Address(0, 3, 2, 0x10));
+        let data = read(&host, 0xcf8, 4);
+        assert_eq!(LittleEndian::read_u32(&data), 0x8000_1a10);
+    }
+
+    #[test]
+    fn it_reads_partial_widths() {
+        let (host, _) = host(1);
+        select(&host, Address(0, 0, 0, 0));
+        assert_eq!(read(&host, 0xcfc, 4), vec![0xf4, 0x1a, 0x00, 0x00]);
+        assert_eq!(read(&host, 0xcfc, 2), vec![0xf4, 0x1a]);
+        assert_eq!(read(&host, 0xcfd, 1), vec![0x1a]);
+        assert_eq!(read(&host, 0xcfe, 2), vec![0x00, 0x00]);
+        assert_eq!(read(&host, 0xcff, 1), vec![0x00]);
+    }
+
+    #[test]
+    fn it_writes_partial_widths() {
+        let (host, _) = host(1);
+        select(&host, Address(0, 0, 0, 0x3c));
+        write(&host, 0xcfc, &[0x0b]);
+        write(&host, 0xcfd, &[0x04]);
+        assert_eq!(read(&host, 0xcfc, 4), vec![0x0b, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn it_addresses_every_device_and_function() {
+        let (host, _) = host(32);
+        for device in 0..32u8 {
+            for function in 0..8u8 {
+                select(&host, Address(0, device, function, 0));
+                let data = read(&host, 0xcfc, 4);
+                if function == 0 {
+                    assert_eq!(LittleEndian::read_u16(&data[2..]), device as u16);
+                } else {
+                    assert_eq!(data, vec![0xff; 4]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_ignores_other_buses() {
+        let (host, _) = host(1);
+        select(&host, Address(1, 0, 0, 0));
+        assert_eq!(read(&host, 0xcfc, 4), vec![0xff; 4]);
+    }
+
+    #[test]
+    fn it_maps_and_unmaps_bars() {
+        let (host, bus) = host(1);
+        select(&host, Address(0, 0, 0, 0x10));
+        let base = LittleEndian::read_u32(&read(&host, 0xcfc, 4)) as u64;
+        assert_eq!(base, 0xc000_0000);
+        assert!(bus.lookup(IoAddress::Memory(base + 0xfff)).is_some());
+
+        select(&host, Address(0, 0, 0, 0x04));
+        write(&host, 0xcfc, &[0x00, 0x00]);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_none());
+
+        select(&host, Address(0, 0, 0, 0x10));
+        write(&host, 0xcfc, &[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(read(&host, 0xcfc, 4), vec![0x00, 0xf0, 0xff, 0xff]);
+        write(&host, 0xcfc, &[0x00, 0x00, 0x00, 0xd0]);
+
+        select(&host, Address(0, 0, 0, 0x04));
+        write(&host, 0xcfc, &[0x02]);
+        assert!(bus.lookup(IoAddress::Memory(0xd000_0000)).is_some());
+        assert!(bus.lookup(IoAddress::Memory(base)).is_none());
+    }
+
+    #[test]
+    fn it_maps_64_bit_bars_once_both_halves_are_written() {
+        let (host, bus) = with_devices(vec![Function::wide(0)]);
+        let base = read_u32(&host, Address(0, 0, 0, 0x10)) as u64 & !0xf;
+        let base = base | (read_u32(&host, Address(0, 0, 0, 0x14)) as u64) << 32;
+        assert!(bus.lookup(IoAddress::Memory(base)).is_some());
+
+        // Sizing the lower half leaves the BAR where it was, and sizing
+        // both halves takes it off the bus.
+        select(&host, Address(0, 0, 0, 0x10));
+        write(&host, 0xcfc, &[0xff, 0xff, 0xff, 0xff]);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_some());
+        select(&host, Address(0, 0, 0, 0x14));
+        write(&host, 0xcfc, &[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0x10)), 0xffff_c004);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_none());
+        assert!(bus
+            .lookup(IoAddress::Memory(0xffff_ffff_ffff_c000))
+            .is_none());
+
+        // Moving it maps nothing until the upper half is in.
+        select(&host, Address(0, 0, 0, 0x10));
+        write(&host, 0xcfc, &[0x00, 0x00, 0x00, 0xd0]);
+        assert!(bus
+            .lookup(IoAddress::Memory(0xffff_ffff_d000_0000))
+            .is_none());
+        assert!(bus.lookup(IoAddress::Memory(0xd000_0000)).is_none());
+        select(&host, Address(0, 0, 0, 0x14));
+        write(&host, 0xcfc, &[0x00, 0x00, 0x00, 0x00]);
+        assert!(bus.lookup(IoAddress::Memory(0xd000_0000)).is_some());
+        assert!(bus.lookup(IoAddress::Memory(0xd000_3fff)).is_some());
+    }
+
+    #[test]
+    fn it_numbers_buses_depth_first() {
+        let (host, _) = with_devices(vec![
+            Arc::new(Bridge::new(vec![
+                Arc::new(Bridge::new(vec![Function::new(0)])) as Arc<Pci>,
+            ])),
+            Arc::new(Bridge::root_port(vec![Function::new(1)])),
+        ]);
+
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0x18)) & 0xffffff, 0x020100);
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0x18)) & 0xffffff, 0x020201);
+        assert_eq!(read_u32(&host, Address(0, 1, 0, 0x18)) & 0xffffff, 0x030300);
+        assert_eq!(read_u32(&host, Address(0, 1, 0, 0x0c)) >> 16, 0x01);
+    }
+
+    #[test]
+    fn it_routes_config_cycles_through_bridges() {
+        let (host, _) = with_devices(vec![
+            Function::new(0x10),
+            Arc::new(Bridge::new(vec![Function::new(0x11), Function::new(0x12)])),
+        ]);
+
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0)) >> 16, 0x10);
+        assert_eq!(read_u32(&host, Address(0, 1, 0, 0)), 0x0001_1b36);
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0)) >> 16, 0x11);
+        assert_eq!(read_u32(&host, Address(1, 1, 0, 0)) >> 16, 0x12);
+        assert_eq!(read_u32(&host, Address(1, 2, 0, 0)), 0xffff_ffff);
+        assert_eq!(read_u32(&host, Address(2, 0, 0, 0)), 0xffff_ffff);
+
+        // Renumbering the bridge moves everything behind it.
+        select(&host, Address(0, 1, 0, 0x18));
+        write(&host, 0xcfc, &[0x00, 0x05, 0x05, 0x00]);
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0)), 0xffff_ffff);
+        assert_eq!(read_u32(&host, Address(5, 1, 0, 0)) >> 16, 0x12);
+    }
+
+    #[test]
+    fn it_spills_extra_devices_behind_a_bridge() {
+        let (host, _) = host(40);
+        assert_eq!(read_u32(&host, Address(0, 30, 0, 0)) >> 16, 30);
+        assert_eq!(read_u32(&host, Address(0, 31, 0, 0)), 0x0001_1b36);
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0)) >> 16, 31);
+        assert_eq!(read_u32(&host, Address(1, 8, 0, 0)) >> 16, 39);
+    }
+
+    #[test]
+    fn it_keeps_bridge_subsystem_ids_in_a_capability() {
+        let (host, _) = with_devices(vec![Arc::new(Bridge::new(vec![Function::new(0)]))]);
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0x34)) & 0xff, 0x40);
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0x40)) & 0xff, 0x0d);
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0x44)), 0x0001_1b36);
+
+        // Which leaves the prefetchable limit's upper half writable.
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0x2c)), 0);
+        select(&host, Address(0, 0, 0, 0x2c));
+        write(&host, 0xcfc, &[0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(read_u32(&host, Address(0, 0, 0, 0x2c)), 1);
+    }
+
+    #[test]
+    fn it_maps_bars_only_inside_bridge_windows() {
+        let (host, bus) = with_devices(vec![Arc::new(Bridge::new(vec![Function::new(0)]))]);
+        let base = read_u32(&host, Address(1, 0, 0, 0x10)) as u64;
+        let window = read_u32(&host, Address(0, 0, 0, 0x20));
+        assert_eq!(base, 0xc000_0000);
+        assert_eq!(window, 0xc000_c000);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_some());
+
+        // Turning off memory decoding on the bridge hides the BAR.
+        select(&host, Address(0, 0, 0, 0x04));
+        write(&host, 0xcfc, &[0x05, 0x00]);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_none());
+        write(&host, 0xcfc, &[0x07, 0x00]);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_some());
+
+        // So does moving the BAR outside the bridge's window.
+        select(&host, Address(1, 0, 0, 0x10));
+        write(&host, 0xcfc, &[0x00, 0x00, 0x00, 0xd0]);
+        assert!(bus.lookup(IoAddress::Memory(0xd000_0000)).is_none());
+
+        // Until the window follows it.
+        select(&host, Address(0, 0, 0, 0x20));
+        write(&host, 0xcfc, &[0x00, 0xd0, 0x00, 0xd0]);
+        assert!(bus.lookup(IoAddress::Memory(0xd000_0000)).is_some());
+    }
+
+    #[test]
+    fn it_hot_plugs_and_ejects_devices() {
+        let signals = Arc::new(Signals::default());
+        let (host, bus) = with_devices(vec![
+            Function::new(0x10),
+            Arc::new(Bridge::hotplug(signals.clone(), 7).unwrap()),
+        ]);
+        let count = || signals.0.lock().unwrap().len();
+
+        // Enable MSI, unmask its vector, and ask for attention button
+        // and presence detect events, like the guest's driver would.
+        select(&host, Address(0, 1, 0, 0x88));
+        write(&host, 0xcfc, &[0x00, 0x00, 0xe0, 0xfe]);
+        select(&host, Address(0, 1, 0, 0x90));
+        write(&host, 0xcfc, &[0x41, 0x00]);
+        select(&host, Address(0, 1, 0, 0x94));
+        write(&host, 0xcfc, &[0x00, 0x00, 0x00, 0x00]);
+        select(&host, Address(0, 1, 0, 0x84));
+        write(&host, 0xcfe, &[0x01]);
+        select(&host, Address(0, 1, 0, 0x60));
+        write(&host, 0xcfc, &[0x29, 0x00]);
+
+        assert!(host.plug(3, Function::new(0x20)).is_err());
+        assert!(host.unplug(7).is_err());
+        assert!(!host.plugged(7).unwrap());
+
+        host.plug(7, Function::new(0x20)).unwrap();
+        assert_eq!(count(), 1);
+        assert!(host.plugged(7).unwrap());
+        assert!(host.plug(7, Function::new(0x21)).is_err());
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0)) >> 16, 0x20);
+        assert_eq!(read_u32(&host, Address(0, 1, 0, 0x60)) >> 16, 0x48);
+
+        let base = read_u32(&host, Address(1, 0, 0, 0x10)) as u64;
+        let window = read_u32(&host, Address(0, 1, 0, 0x20));
+        assert_eq!(base, ((window & 0xfff0) as u64) << 16);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_some());
+
+        // Asking for removal only pushes the attention button; the
+        // device stays until the guest powers the slot down.
+        host.unplug(7).unwrap();
+        assert_eq!(count(), 2);
+        assert_eq!(read_u32(&host, Address(0, 1, 0, 0x60)) >> 16, 0x49);
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0)) >> 16, 0x20);
+
+        select(&host, Address(0, 1, 0, 0x60));
+        write(&host, 0xcfc, &[0x29, 0x04, 0x09, 0x00]);
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0)), 0xffff_ffff);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_none());
+        assert!(!host.plugged(7).unwrap());
+        assert_eq!(count(), 3);
+
+        // The slot can be reused once it has been emptied.
+        host.plug(7, Function::new(0x21)).unwrap();
+        assert_eq!(read_u32(&host, Address(1, 0, 0, 0)) >> 16, 0x21);
+    }
+
+    #[test]
+    fn it_keeps_non_prefetchable_bars_out_of_the_prefetchable_window() {
+        let signals = Arc::new(Signals::default());
+        let (host, bus) = with_devices(vec![Arc::new(Bridge::hotplug(signals, 7).unwrap())]);
+        host.plug(7, Function::wide(0x20)).unwrap();
+
+        let base = read_u32(&host, Address(1, 0, 0, 0x10)) as u64 & !0xf;
+        let base = base | (read_u32(&host, Address(1, 0, 0, 0x14)) as u64) << 32;
+        let window = read_u32(&host, Address(0, 0, 0, 0x20));
+        let memory = (
+            ((window & 0xfff0) as u64) << 16,
+            ((window >> 16) as u64) << 16 | 0xf_ffff,
+        );
+        assert!(memory.0 <= base && base + 0x4000 - 1 <= memory.1);
+        assert!(base < 1 << 32);
+        assert!(bus.lookup(IoAddress::Memory(base)).is_some());
+    }
 }
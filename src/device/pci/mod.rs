@@ -1,13 +1,40 @@
 use super::Device;
 
 mod address;
+mod allocator;
+mod bar;
+mod bridge;
+mod capability;
+mod config;
+mod ecam;
+mod express;
 mod host;
+mod msi;
+mod msix;
 
-pub use self::address::Address;
-pub use self::host::Host;
+pub use self::address::{Address, ECAM_BUS_SIZE};
+pub use self::allocator::{Allocator, Window};
+pub use self::bar::{Bar, Bars};
+pub use self::bridge::Bridge;
+pub use self::capability::{read_into, write_masked, Capability};
+pub use self::config::{ConfigSpace, Header, EXTENDED_CONFIG_SPACE_SIZE, PCI_INTERRUPT_LINE};
+pub use self::ecam::Ecam;
+pub use self::express::{Express, PortType};
+pub use self::host::{Host, DEVICES_PER_BUS};
+pub use self::msi::{Delivery, Msi};
+pub use self::msix::Msix;
 
+/// A PCI function.  Configuration space accesses may be one, two, or
+/// four bytes wide, starting at `address.register()`, and never cross
+/// a dword boundary.
 pub trait Pci: Device {
-    fn config_read(&self, address: Address) -> Option<u32>;
+    fn config_read(&self, address: Address, data: &mut [u8]) -> Option<()>;
     // fn config_space(&self) -> &[u8];
-    fn config_write(&self, address: Address, value: u32) -> Option<()>;
+    fn config_write(&self, address: Address, data: &[u8]) -> Option<()>;
+
+    /// If this function is a PCI-to-PCI bridge, the bridge, so that
+    /// configuration cycles can be routed through it.
+    fn bridge(&self) -> Option<&Bridge> {
+        None
+    }
 }
@@ -0,0 +1,359 @@
+use super::super::super::error::*;
+use super::super::interrupt::{Interrupts, Message};
+use super::capability::{read_into, write_masked, Capability};
+use byteorder::{ByteOrder, LittleEndian};
+use std::sync::{Arc, Mutex};
+
+pub const MSI_CAPABILITY_ID: u8 = 0x05;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+/// We always expose the 64-bit, per-vector masking layout of the
+/// capability; it's a superset of the others, and every guest we care
+/// about understands it.
+const MSI_LENGTH: usize = 0x18;
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static MSI_WRITABLE: [u8; MSI_LENGTH] = [
+    0x00, 0x00, 0x71, 0x00, // 0x00, (id, next, control)
+    0xfc, 0xff, 0xff, 0xff, // 0x04, (address)
+    0xff, 0xff, 0xff, 0xff, // 0x08, (upper address)
+    0xff, 0xff, 0x00, 0x00, // 0x0c, (data, reserved)
+    0xff, 0xff, 0xff, 0xff, // 0x10, (mask bits)
+    0x00, 0x00, 0x00, 0x00, // 0x14, (pending bits)
+];
+
+/// How a vector reaches the guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Delivery {
+    /// Every interrupt is injected with its message directly.
+    Direct,
+    /// Each vector is given its own GSI, and the guest's writes to the
+    /// vector are mirrored into KVM's routing table.  This lets the
+    /// vector be triggered without going through us (e.g. by an
+    /// irqfd).
+    Routed,
+}
+
+/// A single interrupt vector, shared between the MSI and MSI-X
+/// implementations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub(super) struct Vector {
+    pub message: Message,
+    pub masked: bool,
+    pub pending: bool,
+    pub gsi: Option<u32>,
+}
+
+impl Vector {
+    pub fn new(interrupts: &Interrupts, delivery: Delivery) -> Result<Vector> {
+        let gsi = match delivery {
+            Delivery::Direct => None,
+            Delivery::Routed => Some(interrupts.allocate()?),
+        };
+
+        Ok(Vector {
+            message: Message::default(),
+            masked: true,
+            pending: false,
+            gsi,
+        })
+    }
+
+    fn deliverable(&self, enabled: bool) -> bool {
+        enabled && !self.masked && self.message.is_valid()
+    }
+
+    /// Brings the routing entry (if any) in line with the vector, and
+    /// delivers any interrupt that was held back while the vector was
+    /// masked.
+    pub fn update(&mut self, interrupts: &Interrupts, enabled: bool) -> Result<()> {
+        let deliverable = self.deliverable(enabled);
+
+        if let Some(gsi) = self.gsi {
+            interrupts.route(
+                gsi,
+                if deliverable {
+                    Some(self.message)
+                } else {
+                    None
+                },
+            )?;
+        }
+
+        if deliverable && self.pending {
+            self.pending = false;
+            interrupts.signal(self.message)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn fire(&mut self, interrupts: &Interrupts, enabled: bool) -> Result<()> {
+        if !enabled {
+            Ok(())
+        } else if self.deliverable(enabled) {
+            interrupts.signal(self.message)
+        } else {
+            self.pending = true;
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    control: u16,
+    address: u64,
+    data: u16,
+    /// The mask bits as the guest wrote them; vectors past the ones
+    /// it enabled are masked too, but that doesn't show here.
+    mask: u32,
+    vectors: Vec<Vector>,
+}
+
+impl State {
+    fn enabled(&self) -> bool {
+        (self.control & MSI_CONTROL_ENABLE) != 0
+    }
+
+    /// The number of vectors the guest has enabled; always a power of
+    /// two, and never more than we offered.
+    fn count(&self) -> usize {
+        let enabled = 1 << ((self.control >> 4) & 0b111);
+        ::std::cmp::min(enabled, self.vectors.len())
+    }
+
+    fn serialize(&self) -> [u8; MSI_LENGTH] {
+        let mut registers = [0u8; MSI_LENGTH];
+        let pending = self
+            .vectors
+            .iter()
+            .enumerate()
+            .fold(0u32, |m, (i, v)| m | ((v.pending as u32) << i));
+        registers[0] = MSI_CAPABILITY_ID;
+        LittleEndian::write_u16(&mut registers[0x02..0x04], self.control);
+        LittleEndian::write_u64(&mut registers[0x04..0x0c], self.address);
+        LittleEndian::write_u16(&mut registers[0x0c..0x0e], self.data);
+        LittleEndian::write_u32(&mut registers[0x10..0x14], self.mask);
+        LittleEndian::write_u32(&mut registers[0x14..0x18], pending);
+        registers
+    }
+
+    fn deserialize(&mut self, registers: &[u8; MSI_LENGTH]) {
+        self.control = LittleEndian::read_u16(&registers[0x02..0x04]);
+        self.address = LittleEndian::read_u64(&registers[0x04..0x0c]);
+        self.data = LittleEndian::read_u16(&registers[0x0c..0x0e]);
+        self.mask = LittleEndian::read_u32(&registers[0x10..0x14]);
+        let count = self.count();
+        let mask = self.mask;
+
+        for (i, vector) in self.vectors.iter_mut().enumerate() {
+            // With multiple messages enabled, the device modifies the
+            // low bits of the data to select the vector.
+            let data = (self.data as u32 & !(count as u32 - 1)) | i as u32;
+            vector.message = Message::new(self.address, data);
+            vector.masked = (mask & (1 << i)) != 0 || i >= count;
+        }
+    }
+}
+
+/// The MSI capability (capability ID 0x05).
+#[derive(Debug)]
+pub struct Msi(Arc<Interrupts>, Mutex<State>);
+
+impl Msi {
+    /// Creates a new MSI capability offering the given number of
+    /// vectors, which is rounded up to a power of two (up to 32).
+    pub fn new(interrupts: Arc<Interrupts>, vectors: usize, delivery: Delivery) -> Result<Msi> {
+        let vectors = ::std::cmp::min(vectors.max(1).next_power_of_two(), 32);
+        let mask = ((1u64 << vectors) - 1) as u32;
+        let capable = vectors.trailing_zeros() as u16;
+        let control = MSI_CONTROL_64BIT | MSI_CONTROL_PER_VECTOR_MASK | (capable << 1);
+        let vectors = (0..vectors)
+            .map(|_| Vector::new(interrupts.as_ref(), delivery))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Msi(
+            interrupts,
+            Mutex::new(State {
+                control,
+                address: 0,
+                data: 0,
+                mask,
+                vectors,
+            }),
+        ))
+    }
+
+    /// Raises the given vector.  If MSI is disabled, nothing happens
+    /// and the caller should fall back to INTx; if the vector is
+    /// masked, its pending bit is set instead.
+    pub fn notify(&self, vector: usize) -> Result<()> {
+        let mut state = self.1.lock().unwrap();
+        let enabled = state.enabled();
+        if vector >= state.count() {
+            return Ok(());
+        }
+
+        state.vectors[vector].fire(self.0.as_ref(), enabled)
+    }
+}
+
+impl Capability for Msi {
+    fn id(&self) -> u8 {
+        MSI_CAPABILITY_ID
+    }
+
+    fn len(&self) -> usize {
+        MSI_LENGTH
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        let state = self.1.lock().unwrap();
+        read_into(&state.serialize(), offset, data);
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) {
+        let mut state = self.1.lock().unwrap();
+        let mut registers = state.serialize();
+        write_masked(&mut registers, &MSI_WRITABLE, offset, data);
+        state.deserialize(&registers);
+
+        let enabled = state.enabled();
+        for vector in state.vectors.iter_mut() {
+            if let Err(e) = vector.update(self.0.as_ref(), enabled) {
+                warn!("could not update msi vector: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::super::interrupt::{Interrupts, Message};
+    use super::super::capability::Capability;
+    use super::*;
+    use std::collections::HashMap;
+
+    pub const ADDRESS: u64 = 0xfee0_0000;
+
+    /// Keeps track of the messages delivered, and of KVM's routing
+    /// table.  GSIs are handed out from 24 up.
+    #[derive(Debug, Default)]
+    pub struct Recorder {
+        signals: Mutex<Vec<Message>>,
+        routes: Mutex<HashMap<u32, Message>>,
+        allocated: Mutex<u32>,
+    }
+
+    impl Recorder {
+        /// The messages delivered so far, which are then forgotten.
+        pub fn signals(&self) -> Vec<Message> {
+            self.signals.lock().unwrap().drain(..).collect()
+        }
+
+        /// Where the GSI is routed to, if anywhere.
+        pub fn route(&self, gsi: u32) -> Option<Message> {
+            self.routes.lock().unwrap().get(&gsi).cloned()
+        }
+    }
+
+    impl Interrupts for Recorder {
+        fn line(&self, _irq: u32, _level: bool) -> Result<()> {
+            Ok(())
+        }
+
+        fn signal(&self, message: Message) -> Result<()> {
+            self.signals.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn allocate(&self) -> Result<u32> {
+            let mut allocated = self.allocated.lock().unwrap();
+            *allocated += 1;
+            Ok(23 + *allocated)
+        }
+
+        fn route(&self, gsi: u32, message: Option<Message>) -> Result<()> {
+            let mut routes = self.routes.lock().unwrap();
+            match message {
+                Some(message) => routes.insert(gsi, message),
+                None => routes.remove(&gsi),
+            };
+            Ok(())
+        }
+    }
+
+    fn read_u32(msi: &Msi, offset: usize) -> u32 {
+        let mut data = [0u8; 4];
+        msi.read(offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write_u32(msi: &Msi, offset: usize, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        msi.write(offset, &data);
+    }
+
+    #[test]
+    fn it_holds_masked_vectors_pending() {
+        let recorder = Arc::new(Recorder::default());
+        let msi = Msi::new(recorder.clone(), 1, Delivery::Direct).unwrap();
+        write_u32(&msi, 0x04, ADDRESS as u32 | 0x3);
+        write_u32(&msi, 0x0c, 0x41);
+        assert_eq!(read_u32(&msi, 0x04), ADDRESS as u32);
+
+        // Nothing happens while MSI is off, not even a pending bit.
+        msi.notify(0).unwrap();
+        assert_eq!(read_u32(&msi, 0x14), 0);
+        msi.write(0x02, &[0x01]);
+
+        // Vectors start out masked.
+        msi.notify(0).unwrap();
+        assert_eq!(read_u32(&msi, 0x10), 1);
+        assert_eq!(read_u32(&msi, 0x14), 1);
+        assert!(recorder.signals().is_empty());
+
+        // The pending bits are read-only, and unmasking delivers.
+        write_u32(&msi, 0x14, 0);
+        assert_eq!(read_u32(&msi, 0x14), 1);
+        write_u32(&msi, 0x10, 0);
+        assert_eq!(recorder.signals(), vec![Message::new(ADDRESS, 0x41)]);
+        assert_eq!(read_u32(&msi, 0x14), 0);
+
+        msi.notify(0).unwrap();
+        assert_eq!(recorder.signals(), vec![Message::new(ADDRESS, 0x41)]);
+    }
+
+    #[test]
+    fn it_mirrors_vectors_into_kvm_routes() {
+        let recorder = Arc::new(Recorder::default());
+        let msi = Msi::new(recorder.clone(), 2, Delivery::Routed).unwrap();
+
+        write_u32(&msi, 0x04, ADDRESS as u32);
+        write_u32(&msi, 0x0c, 0x40);
+        write_u32(&msi, 0x10, 0);
+        assert_eq!(recorder.route(24), None);
+
+        // Two messages, which differ in the low bit of their data.
+        msi.write(0x02, &[0x11]);
+        assert_eq!(recorder.route(24), Some(Message::new(ADDRESS, 0x40)));
+        assert_eq!(recorder.route(25), Some(Message::new(ADDRESS, 0x41)));
+
+        write_u32(&msi, 0x10, 0b10);
+        assert_eq!(recorder.route(24), Some(Message::new(ADDRESS, 0x40)));
+        assert_eq!(recorder.route(25), None);
+
+        // With only the one message enabled, the other is masked.
+        write_u32(&msi, 0x10, 0);
+        msi.write(0x02, &[0x01]);
+        assert_eq!(recorder.route(25), None);
+
+        msi.write(0x02, &[0x00]);
+        assert_eq!(recorder.route(24), None);
+    }
+}
@@ -0,0 +1,357 @@
+use super::super::super::error::*;
+use super::super::interrupt::{Interrupts, Message};
+use super::capability::{read_into, write_masked, Capability};
+use super::msi::{Delivery, Vector};
+use byteorder::{ByteOrder, LittleEndian};
+use std::sync::{Arc, Mutex};
+
+pub const MSIX_CAPABILITY_ID: u8 = 0x11;
+
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_LENGTH: usize = 0x0c;
+const MSIX_ENTRY_SIZE: usize = 0x10;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static MSIX_WRITABLE: [u8; MSIX_LENGTH] = [
+    0x00, 0x00, 0x00, 0xc0, // 0x00, (id, next, control)
+    0x00, 0x00, 0x00, 0x00, // 0x04, (table offset, table bir)
+    0x00, 0x00, 0x00, 0x00, // 0x08, (pba offset, pba bir)
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static MSIX_ENTRY_WRITABLE: [u8; MSIX_ENTRY_SIZE] = [
+    0xfc, 0xff, 0xff, 0xff, // 0x00, (message address)
+    0xff, 0xff, 0xff, 0xff, // 0x04, (message upper address)
+    0xff, 0xff, 0xff, 0xff, // 0x08, (message data)
+    0x01, 0x00, 0x00, 0x00, // 0x0c, (vector control)
+];
+
+#[derive(Debug)]
+struct State {
+    control: u16,
+    vectors: Vec<Vector>,
+}
+
+impl State {
+    fn enabled(&self) -> bool {
+        (self.control & MSIX_CONTROL_ENABLE) != 0
+    }
+
+    /// Vectors are only delivered if MSI-X is enabled, and the
+    /// function as a whole isn't masked.
+    fn delivering(&self) -> bool {
+        self.enabled() && (self.control & MSIX_CONTROL_FUNCTION_MASK) == 0
+    }
+
+    fn update(&mut self, interrupts: &Interrupts) {
+        let delivering = self.delivering();
+        for vector in self.vectors.iter_mut() {
+            if let Err(e) = vector.update(interrupts, delivering) {
+                warn!("could not update msi-x vector: {}", e);
+            }
+        }
+    }
+}
+
+/// The MSI-X capability (capability ID 0x11), along with the vector
+/// table and pending bit array it describes.  The table and PBA live
+/// in one of the device's BARs; the device forwards accesses to those
+/// regions to `table_read`/`table_write` and `pba_read`.
+#[derive(Debug)]
+pub struct Msix {
+    interrupts: Arc<Interrupts>,
+    table: (u8, u32),
+    pba: (u8, u32),
+    state: Mutex<State>,
+}
+
+impl Msix {
+    /// Creates a new MSI-X capability with the given number of vectors
+    /// (at most 2048).  `table` and `pba` are each a BAR index and the
+    /// offset of the structure into that BAR; the offsets must be
+    /// 8-byte aligned.
+    pub fn new(
+        interrupts: Arc<Interrupts>,
+        vectors: usize,
+        table: (u8, u32),
+        pba: (u8, u32),
+        delivery: Delivery,
+    ) -> Result<Msix> {
+        let vectors = ::std::cmp::min(vectors.max(1), 2048);
+        let control = (vectors - 1) as u16;
+        let vectors = (0..vectors)
+            .map(|_| Vector::new(interrupts.as_ref(), delivery))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Msix {
+            interrupts,
+            table,
+            pba,
+            state: Mutex::new(State { control, vectors }),
+        })
+    }
+
+    pub fn vectors(&self) -> usize {
+        self.state.lock().unwrap().vectors.len()
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.state.lock().unwrap().enabled()
+    }
+
+    /// The GSI the given vector is routed through, if the capability
+    /// was created with `Delivery::Routed`.
+    pub fn gsi(&self, vector: usize) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        state.vectors.get(vector).and_then(|v| v.gsi)
+    }
+
+    /// Raises the given vector.  If MSI-X is disabled, nothing happens
+    /// and the caller should fall back to INTx; if the vector (or the
+    /// function) is masked, its pending bit is set instead.
+    pub fn notify(&self, vector: usize) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let delivering = state.delivering();
+        let enabled = state.enabled();
+
+        match state.vectors.get_mut(vector) {
+            Some(ref mut vector) if enabled && !delivering => {
+                vector.pending = true;
+                Ok(())
+            }
+            Some(vector) => vector.fire(self.interrupts.as_ref(), enabled),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads from the vector table; `offset` is relative to the start
+    /// of the table.
+    pub fn table_read(&self, offset: usize, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        for (i, byte) in data.iter_mut().enumerate() {
+            let position = offset + i;
+            *byte = match state.vectors.get(position / MSIX_ENTRY_SIZE) {
+                Some(vector) => serialize(vector)[position % MSIX_ENTRY_SIZE],
+                None => 0,
+            };
+        }
+    }
+
+    /// Writes to the vector table; `offset` is relative to the start
+    /// of the table.
+    pub fn table_write(&self, offset: usize, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let delivering = state.delivering();
+        let mut position = offset;
+        let end = offset + data.len();
+
+        while position < end {
+            let index = position / MSIX_ENTRY_SIZE;
+            let start = index * MSIX_ENTRY_SIZE;
+            let stop = ::std::cmp::min(end, start + MSIX_ENTRY_SIZE);
+            let interrupts = self.interrupts.as_ref();
+
+            if let Some(vector) = state.vectors.get_mut(index) {
+                let mut registers = serialize(vector);
+                write_masked(
+                    &mut registers,
+                    &MSIX_ENTRY_WRITABLE,
+                    position - start,
+                    &data[(position - offset)..(stop - offset)],
+                );
+                deserialize(vector, &registers);
+
+                if let Err(e) = vector.update(interrupts, delivering) {
+                    warn!("could not update msi-x vector {}: {}", index, e);
+                }
+            }
+
+            position = stop;
+        }
+    }
+
+    /// Reads from the pending bit array; `offset` is relative to the
+    /// start of the array.  The array is read-only, so there's no
+    /// corresponding write.
+    pub fn pba_read(&self, offset: usize, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        for (i, byte) in data.iter_mut().enumerate() {
+            let base = (offset + i) * 8;
+            *byte = (0..8).fold(0u8, |m, bit| match state.vectors.get(base + bit) {
+                Some(vector) if vector.pending => m | (1 << bit),
+                _ => m,
+            });
+        }
+    }
+}
+
+fn serialize(vector: &Vector) -> [u8; MSIX_ENTRY_SIZE] {
+    let mut registers = [0u8; MSIX_ENTRY_SIZE];
+    let control = if vector.masked { MSIX_ENTRY_MASKED } else { 0 };
+    LittleEndian::write_u64(&mut registers[0x00..0x08], vector.message.address);
+    LittleEndian::write_u32(&mut registers[0x08..0x0c], vector.message.data);
+    LittleEndian::write_u32(&mut registers[0x0c..0x10], control);
+    registers
+}
+
+fn deserialize(vector: &mut Vector, registers: &[u8; MSIX_ENTRY_SIZE]) {
+    vector.message = Message::new(
+        LittleEndian::read_u64(&registers[0x00..0x08]),
+        LittleEndian::read_u32(&registers[0x08..0x0c]),
+    );
+    vector.masked = (LittleEndian::read_u32(&registers[0x0c..0x10]) & MSIX_ENTRY_MASKED) != 0;
+}
+
+impl Capability for Msix {
+    fn id(&self) -> u8 {
+        MSIX_CAPABILITY_ID
+    }
+
+    fn len(&self) -> usize {
+        MSIX_LENGTH
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        let mut registers = [0u8; MSIX_LENGTH];
+        registers[0] = MSIX_CAPABILITY_ID;
+        LittleEndian::write_u16(&mut registers[0x02..0x04], state.control);
+        LittleEndian::write_u32(
+            &mut registers[0x04..0x08],
+            self.table.1 | self.table.0 as u32,
+        );
+        LittleEndian::write_u32(&mut registers[0x08..0x0c], self.pba.1 | self.pba.0 as u32);
+        read_into(&registers, offset, data);
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let mut registers = [0u8; MSIX_LENGTH];
+        LittleEndian::write_u16(&mut registers[0x02..0x04], state.control);
+        write_masked(&mut registers, &MSIX_WRITABLE, offset, data);
+        state.control = LittleEndian::read_u16(&registers[0x02..0x04]);
+        state.update(self.interrupts.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::msi::tests::{Recorder, ADDRESS};
+    use super::*;
+
+    fn msix(recorder: &Arc<Recorder>, delivery: Delivery) -> Msix {
+        Msix::new(recorder.clone(), 3, (2, 0x1000), (2, 0x1800), delivery).unwrap()
+    }
+
+    /// Writes a vector's whole table entry.
+    fn program(msix: &Msix, vector: usize, data: u32, masked: bool) {
+        let mut entry = [0u8; MSIX_ENTRY_SIZE];
+        LittleEndian::write_u64(&mut entry[0x00..0x08], ADDRESS);
+        LittleEndian::write_u32(&mut entry[0x08..0x0c], data);
+        entry[0x0c] = masked as u8;
+        msix.table_write(vector * MSIX_ENTRY_SIZE, &entry);
+    }
+
+    fn pending(msix: &Msix) -> u8 {
+        let mut data = [0u8; 1];
+        msix.pba_read(0, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn it_lays_out_the_table_and_pba() {
+        let recorder = Arc::new(Recorder::default());
+        let msix = msix(&recorder, Delivery::Direct);
+        let mut registers = [0u8; MSIX_LENGTH];
+        msix.read(0, &mut registers);
+        assert_eq!(
+            registers,
+            [0x11, 0, 0x02, 0x00, 0x02, 0x10, 0, 0, 0x02, 0x18, 0, 0]
+        );
+
+        // Entries start out masked, and can be written a piece at a
+        // time; the low bits of the address are reserved.
+        let mut entry = [0u8; MSIX_ENTRY_SIZE];
+        msix.table_read(MSIX_ENTRY_SIZE, &mut entry);
+        assert_eq!(entry, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        msix.table_write(MSIX_ENTRY_SIZE, &[0x03, 0x00, 0xe0, 0xfe]);
+        msix.table_write(MSIX_ENTRY_SIZE + 0x08, &[0x42, 0x00]);
+        msix.table_write(MSIX_ENTRY_SIZE + 0x0c, &[0xfe, 0xff, 0xff, 0xff]);
+        msix.table_read(MSIX_ENTRY_SIZE, &mut entry);
+        assert_eq!(
+            entry,
+            [0, 0, 0xe0, 0xfe, 0, 0, 0, 0, 0x42, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        // Past the end of the table, there's nothing.
+        let mut past = [0xffu8; 4];
+        msix.table_read(3 * MSIX_ENTRY_SIZE, &mut past);
+        assert_eq!(past, [0; 4]);
+    }
+
+    #[test]
+    fn it_holds_masked_vectors_pending() {
+        let recorder = Arc::new(Recorder::default());
+        let msix = msix(&recorder, Delivery::Direct);
+        program(&msix, 0, 0x41, true);
+        program(&msix, 2, 0x43, false);
+
+        // Nothing happens while MSI-X is off.
+        msix.notify(0).unwrap();
+        assert_eq!(pending(&msix), 0);
+        msix.write(0x03, &[0x80]);
+        assert!(msix.enabled());
+
+        msix.notify(0).unwrap();
+        msix.notify(2).unwrap();
+        assert_eq!(pending(&msix), 0b001);
+        assert_eq!(recorder.signals(), vec![Message::new(ADDRESS, 0x43)]);
+
+        // Unmasking the vector delivers what was held back.
+        msix.table_write(0x0c, &[0, 0, 0, 0]);
+        assert_eq!(pending(&msix), 0);
+        assert_eq!(recorder.signals(), vec![Message::new(ADDRESS, 0x41)]);
+
+        // So does unmasking the whole function.
+        msix.write(0x03, &[0xc0]);
+        msix.notify(2).unwrap();
+        assert_eq!(pending(&msix), 0b100);
+        assert!(recorder.signals().is_empty());
+        msix.write(0x03, &[0x80]);
+        assert_eq!(pending(&msix), 0);
+        assert_eq!(recorder.signals(), vec![Message::new(ADDRESS, 0x43)]);
+    }
+
+    #[test]
+    fn it_mirrors_vectors_into_kvm_routes() {
+        let recorder = Arc::new(Recorder::default());
+        let msix = msix(&recorder, Delivery::Routed);
+        assert_eq!(
+            (msix.gsi(0), msix.gsi(2), msix.gsi(3)),
+            (Some(24), Some(26), None)
+        );
+
+        program(&msix, 0, 0x41, false);
+        program(&msix, 1, 0x42, true);
+        assert_eq!(recorder.route(24), None);
+
+        msix.write(0x03, &[0x80]);
+        assert_eq!(recorder.route(24), Some(Message::new(ADDRESS, 0x41)));
+        assert_eq!(recorder.route(25), None);
+
+        // Reprogramming a vector moves its route along.
+        program(&msix, 0, 0x51, false);
+        assert_eq!(recorder.route(24), Some(Message::new(ADDRESS, 0x51)));
+        msix.table_write(0x0c, &[1]);
+        assert_eq!(recorder.route(24), None);
+        msix.table_write(0x0c, &[0]);
+        assert_eq!(recorder.route(24), Some(Message::new(ADDRESS, 0x51)));
+
+        msix.write(0x03, &[0xc0]);
+        assert_eq!(recorder.route(24), None);
+        msix.write(0x03, &[0x00]);
+        assert_eq!(recorder.route(24), None);
+    }
+}
@@ -0,0 +1,1056 @@
+use block::engine::{self, Engine, Limits, Operation, Statistics, Stats};
+use block::{Buffer, Image, SECTOR_SIZE};
+use byteorder::{ByteOrder, LittleEndian};
+use device::pci::read_into;
+use error::*;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use virtio::queue::{Chain, Queue, Reader, VIRTIO_F_RING_PACKED};
+use virtio::{Activation, Virtio};
+
+const VIRTIO_ID_BLOCK: u16 = 2;
+
+/// The configuration holds the biggest a buffer of a request can be.
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+/// The configuration holds the most buffers a request can have.
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+/// The disk can't be written to.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The configuration holds the block size.
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+/// Writes can be cached, and flush requests are needed.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+/// The configuration holds the physical block size and alignment.
+const VIRTIO_BLK_F_TOPOLOGY: u64 = 1 << 10;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+/// The type, a reserved field, and the sector a request starts at.
+const HEADER_SIZE: usize = 16;
+/// The sector, number of sectors, and flags of a discard or write
+/// zeroes range.
+const SEGMENT_SIZE: usize = 16;
+/// How long the serial number reported through GET_ID is.
+const ID_SIZE: usize = 20;
+/// Everything up to and including the write zeroes fields.
+const CONFIG_SIZE: usize = 60;
+
+const QUEUE_SIZE: u16 = 256;
+/// Every request has a header and a status besides its data.
+const SEG_MAX: u32 = QUEUE_SIZE as u32 - 2;
+/// The biggest a buffer of a request can be, which is as big as Linux
+/// makes them anyway.
+const SIZE_MAX: u32 = 1 << 16;
+/// The most data a request can carry, going by the limits above.
+/// Chains with more are refused before anything's allocated for them.
+const MAX_REQUEST_SIZE: u64 = SEG_MAX as u64 * SIZE_MAX as u64;
+/// How many ranges a discard or write zeroes request can have.
+const MAX_SEGMENTS: u32 = 32;
+/// How many sectors a single discard or write zeroes range can cover.
+const MAX_SEGMENT_SECTORS: u32 = 1 << 22;
+
+/// A range of the disk to discard or zero.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Segment {
+    offset: u64,
+    length: u64,
+    unmap: bool,
+}
+
+#[derive(Debug)]
+enum Request {
+    Read { offset: u64, length: u64 },
+    Write { offset: u64, data: Buffer },
+    Flush,
+    GetId,
+    Discard(Vec<Segment>),
+    WriteZeroes(Vec<Segment>),
+    Unsupported(u32),
+}
+
+impl Request {
+    /// Reads a request out of the readable part of a chain.  `room` is
+    /// how much the driver left for the device to write back, besides
+    /// the status.  Reads and writes have to fit in the image, which is
+    /// checked before their buffers are allocated.
+    fn parse(reader: &mut Reader, room: u64, image: &Image) -> Result<Request> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let kind = LittleEndian::read_u32(&header[0..4]);
+        let offset = LittleEndian::read_u64(&header[8..16])
+            .checked_mul(SECTOR_SIZE)
+            .ok_or(ErrorKind::QueueError("block request sector out of range"))?;
+
+        let request = match kind {
+            VIRTIO_BLK_T_IN => {
+                ::block::check_range(image, offset, room)?;
+                Request::Read {
+                    offset,
+                    length: room,
+                }
+            }
+            VIRTIO_BLK_T_OUT => {
+                let length = reader.remaining();
+                ::block::check_range(image, offset, length)?;
+                let mut data = Buffer::new(length as usize);
+                reader.read_exact(&mut data)?;
+                Request::Write { offset, data }
+            }
+            VIRTIO_BLK_T_FLUSH => Request::Flush,
+            VIRTIO_BLK_T_GET_ID => Request::GetId,
+            VIRTIO_BLK_T_DISCARD => Request::Discard(Request::segments(reader)?),
+            VIRTIO_BLK_T_WRITE_ZEROES => Request::WriteZeroes(Request::segments(reader)?),
+            kind => Request::Unsupported(kind),
+        };
+
+        Ok(request)
+    }
+
+    fn segments(reader: &mut Reader) -> Result<Vec<Segment>> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        if data.len() % SEGMENT_SIZE != 0 || data.len() / SEGMENT_SIZE > MAX_SEGMENTS as usize {
+            return Err(ErrorKind::QueueError("invalid block request ranges").into());
+        }
+
+        data.chunks(SEGMENT_SIZE)
+            .map(|segment| {
+                let sectors = LittleEndian::read_u32(&segment[8..12]);
+                let flags = LittleEndian::read_u32(&segment[12..16]);
+                if sectors > MAX_SEGMENT_SECTORS
+                    || (flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP) != 0
+                {
+                    return Err(ErrorKind::QueueError("invalid block request range").into());
+                }
+
+                let offset = LittleEndian::read_u64(&segment[0..8])
+                    .checked_mul(SECTOR_SIZE)
+                    .ok_or(ErrorKind::QueueError("block request sector out of range"))?;
+                Ok(Segment {
+                    offset,
+                    length: sectors as u64 * SECTOR_SIZE,
+                    unmap: (flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP) != 0,
+                })
+            })
+            .collect()
+    }
+}
+
+/// What to do about a request.
+#[derive(Debug)]
+enum Plan {
+    /// It's over already, with this status and what goes back to the
+    /// driver.
+    Done(u8, Vec<u8>),
+    /// It needs these operations on the image.
+    Start(Vec<Operation>),
+}
+
+/// The part of the device the I/O thread needs too.
+#[derive(Debug)]
+struct Disk {
+    image: Arc<Image>,
+    engine: Arc<Engine>,
+    id: [u8; ID_SIZE],
+}
+
+impl Disk {
+    /// Whether an access fits the image's alignment.
+    fn aligned(&self, offset: u64, length: u64) -> bool {
+        let alignment = self.image.alignment() as u64;
+        offset % alignment == 0 && length % alignment == 0
+    }
+
+    /// Works out what a request takes.
+    fn plan(&self, features: u64, request: Request) -> Result<Plan> {
+        let plan = match request {
+            Request::Write { .. } | Request::Discard(_) | Request::WriteZeroes(_)
+                if self.image.read_only() =>
+            {
+                Plan::Done(VIRTIO_BLK_S_IOERR, vec![])
+            }
+            Request::Read { offset, length } => {
+                if !self.aligned(offset, length) {
+                    return Err(ErrorKind::QueueError("misaligned block read").into());
+                }
+
+                let buffer = Buffer::new(length as usize);
+                Plan::Start(vec![Operation::Read { offset, buffer }])
+            }
+            Request::Write { offset, data } => {
+                if !self.aligned(offset, data.len() as u64) {
+                    return Err(ErrorKind::QueueError("misaligned block write").into());
+                }
+
+                Plan::Start(vec![Operation::Write {
+                    offset,
+                    buffer: data,
+                }])
+            }
+            Request::Flush => Plan::Start(vec![Operation::Flush]),
+            Request::GetId => Plan::Done(VIRTIO_BLK_S_OK, self.id.to_vec()),
+            Request::Discard(ref segments) if (features & VIRTIO_BLK_F_DISCARD) != 0 => {
+                if segments.iter().any(|segment| segment.unmap) {
+                    return Ok(Plan::Done(VIRTIO_BLK_S_UNSUPP, vec![]));
+                }
+
+                Plan::Start(
+                    segments
+                        .iter()
+                        .map(|segment| Operation::Discard {
+                            offset: segment.offset,
+                            length: segment.length,
+                        })
+                        .collect(),
+                )
+            }
+            Request::WriteZeroes(ref segments) if (features & VIRTIO_BLK_F_WRITE_ZEROES) != 0 => {
+                Plan::Start(
+                    segments
+                        .iter()
+                        .map(|segment| Operation::WriteZeroes {
+                            offset: segment.offset,
+                            length: segment.length,
+                            unmap: segment.unmap,
+                        })
+                        .collect(),
+                )
+            }
+            Request::Discard(_) | Request::WriteZeroes(_) => {
+                Plan::Done(VIRTIO_BLK_S_UNSUPP, vec![])
+            }
+            Request::Unsupported(kind) => {
+                debug!("unsupported block request {}", kind);
+                Plan::Done(VIRTIO_BLK_S_UNSUPP, vec![])
+            }
+        };
+
+        match plan {
+            Plan::Start(ref operations) if operations.is_empty() => {
+                Ok(Plan::Done(VIRTIO_BLK_S_OK, vec![]))
+            }
+            plan => Ok(plan),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Event {
+    /// The driver made requests available.
+    Notify,
+    /// An operation for the given request is over.
+    Done(u64, Operation, Result<()>),
+    /// The device is being reset; stop once everything in flight is
+    /// over.
+    Stop,
+    /// Carry on with another disk once everything in flight is over,
+    /// saying how that went.
+    Switch(Arc<Disk>, Sender<Result<()>>),
+}
+
+/// A request the engine is working on.
+#[derive(Debug)]
+struct Pending {
+    chain: Chain,
+    room: u64,
+    /// How many of its operations aren't over yet.
+    operations: usize,
+    status: u8,
+    data: Option<Buffer>,
+}
+
+/// Takes requests off the queue and hands them to the engine, on a
+/// thread of its own, so the guest never waits on the disk.  The
+/// completions come back here too.
+#[derive(Debug)]
+struct Worker {
+    disk: Arc<Disk>,
+    activation: Activation,
+    events: Receiver<Event>,
+    sender: Sender<Event>,
+    depth: usize,
+    pending: HashMap<u64, Pending>,
+    next: u64,
+    /// The disk to switch to, once nothing's in flight anymore; no
+    /// new requests are started until then.
+    switching: Option<(Arc<Disk>, Sender<Result<()>>)>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut stopping = false;
+        let mut broken = false;
+        while !stopping || !self.pending.is_empty() {
+            let first = match self.events.recv() {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+            let mut events = vec![];
+            for event in Some(first).into_iter().chain(self.events.try_iter()) {
+                match event {
+                    Event::Stop => stopping = true,
+                    Event::Switch(disk, reply) => self.switching = Some((disk, reply)),
+                    event => events.push(event),
+                }
+            }
+
+            // Once something went wrong with the queue, all there is
+            // left to do is wait for the reset.
+            if broken {
+                for event in events {
+                    if let Event::Done(token, _, _) = event {
+                        self.pending.remove(&token);
+                    }
+                }
+                self.switch();
+                continue;
+            }
+
+            if let Err(e) = self.handle(events, stopping) {
+                warn!("could not process block queue: {}", e);
+                self.activation.interrupt.needs_reset();
+                broken = true;
+            }
+        }
+    }
+
+    fn handle(&mut self, events: Vec<Event>, stopping: bool) -> Result<()> {
+        let mut used = false;
+        for event in events {
+            if let Event::Done(token, operation, result) = event {
+                used |= self.complete(token, operation, result)?;
+            }
+        }
+
+        self.switch();
+        if !stopping && self.switching.is_none() {
+            used |= self.fill()?;
+        }
+
+        let memory = self.activation.memory.clone();
+        let needed = match self.queue() {
+            Some(queue) if used => queue.needs_notification(memory.as_ref())?,
+            _ => false,
+        };
+        if needed {
+            self.activation.interrupt.queue(0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Switches disks, if that's been asked for and nothing's in flight
+    /// on the old one.  Whatever was written to it is flushed first, so
+    /// it's all there if the new disk reads through to it.
+    fn switch(&mut self) {
+        if !self.pending.is_empty() {
+            return;
+        }
+
+        if let Some((disk, reply)) = self.switching.take() {
+            let result = self.disk.image.flush();
+            if result.is_ok() {
+                self.disk = disk;
+            }
+            let _ = reply.send(result);
+        }
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        match self.activation.queues.get_mut(0) {
+            Some(&mut Some(ref mut queue)) => Some(queue),
+            _ => None,
+        }
+    }
+
+    /// Starts as many requests as the queue depth allows, returning
+    /// whether any were over right away.  The driver needn't kick us
+    /// while this runs: once the queue's empty `pop` asks for kicks
+    /// again, and when it stops at the depth a completion comes back
+    /// here.
+    fn fill(&mut self) -> Result<bool> {
+        let memory = self.activation.memory.clone();
+        if let Some(queue) = self.queue() {
+            queue.disable_notification(memory.as_ref())?;
+        }
+
+        let mut used = false;
+        while self.pending.len() < self.depth {
+            let chain = match self.queue() {
+                Some(queue) => queue.pop(memory.as_ref())?,
+                None => None,
+            };
+            match chain {
+                Some(chain) => used |= self.start(chain)?,
+                None => break,
+            }
+        }
+
+        Ok(used)
+    }
+
+    /// Starts the request in a chain, returning whether it's over
+    /// already.
+    fn start(&mut self, chain: Chain) -> Result<bool> {
+        let room = match chain.writable_len().checked_sub(1) {
+            Some(room) => room,
+            None => return Err(ErrorKind::QueueError("block request without a status").into()),
+        };
+
+        if room > MAX_REQUEST_SIZE || chain.readable_len() > HEADER_SIZE as u64 + MAX_REQUEST_SIZE {
+            return Err(ErrorKind::QueueError("block request is too large").into());
+        }
+
+        let memory = self.activation.memory.clone();
+        let features = self.activation.features;
+        let image = self.disk.image.clone();
+        let plan = Request::parse(&mut chain.reader(memory.as_ref()), room, image.as_ref())
+            .and_then(|request| self.disk.plan(features, request));
+        let operations = match plan {
+            Ok(Plan::Start(operations)) => operations,
+            Ok(Plan::Done(status, data)) => {
+                self.finish(&chain, room, status, &data)?;
+                return Ok(true);
+            }
+            Err(e) => {
+                warn!("block request failed: {}", e);
+                self.finish(&chain, room, VIRTIO_BLK_S_IOERR, &[])?;
+                return Ok(true);
+            }
+        };
+
+        let token = self.next;
+        self.next += 1;
+        self.pending.insert(
+            token,
+            Pending {
+                chain,
+                room,
+                operations: operations.len(),
+                status: VIRTIO_BLK_S_OK,
+                data: None,
+            },
+        );
+
+        for operation in operations {
+            let sender = self.sender.clone();
+            self.disk.engine.submit(
+                operation,
+                Box::new(move |operation, result| {
+                    let _ = sender.send(Event::Done(token, operation, result));
+                }),
+            );
+        }
+
+        Ok(false)
+    }
+
+    /// Takes note of a finished operation, returning whether that was
+    /// the last one its request was waiting for.
+    fn complete(&mut self, token: u64, operation: Operation, result: Result<()>) -> Result<bool> {
+        {
+            let pending = match self.pending.get_mut(&token) {
+                Some(pending) => pending,
+                None => return Ok(false),
+            };
+
+            pending.operations -= 1;
+            match (result, operation) {
+                (Err(e), _) => {
+                    warn!("block request failed: {}", e);
+                    pending.status = VIRTIO_BLK_S_IOERR;
+                }
+                (Ok(()), Operation::Read { buffer, .. }) => pending.data = Some(buffer),
+                _ => (),
+            }
+
+            if pending.operations > 0 {
+                return Ok(false);
+            }
+        }
+
+        let pending = self.pending.remove(&token).unwrap();
+        let data = match pending.data {
+            Some(ref data) if pending.status == VIRTIO_BLK_S_OK => &data[..],
+            _ => &[][..],
+        };
+        self.finish(&pending.chain, pending.room, pending.status, data)?;
+        Ok(true)
+    }
+
+    /// Hands a chain back to the driver.  The status goes in the very
+    /// last writable byte, with `data` before it.
+    fn finish(&mut self, chain: &Chain, room: u64, status: u8, data: &[u8]) -> Result<()> {
+        let memory = self.activation.memory.clone();
+        let written = {
+            let mut writer = chain.writer(memory.as_ref());
+            let count = cmp::min(room, data.len() as u64) as usize;
+            writer.write_all(&data[..count])?;
+            writer.write_all(&vec![0u8; room as usize - count])?;
+            writer.write_all(&[status])?;
+            writer.written()
+        };
+
+        match self.queue() {
+            Some(queue) => queue.push(memory.as_ref(), chain.head(), written),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The I/O thread of a running device.
+#[derive(Debug)]
+struct Io {
+    sender: Sender<Event>,
+    thread: JoinHandle<()>,
+}
+
+/// A virtio block device, serving a disk image.  Requests are carried
+/// out in the background, so the guest can have a few of them in
+/// flight at once.
+#[derive(Debug)]
+pub struct Block {
+    disk: Mutex<Arc<Disk>>,
+    limits: Limits,
+    stats: Arc<Stats>,
+    depth: u32,
+    io: Mutex<Option<Io>>,
+}
+
+impl Block {
+    /// Creates a block device for the given image, working on up to
+    /// `depth` requests at once, within `limits`.  The guest can read
+    /// `id` (cut down to 20 bytes) as the disk's serial number.
+    pub fn new(image: Arc<Image>, id: &str, depth: u32, limits: Limits) -> Result<Block> {
+        let depth = cmp::min(cmp::max(depth, 1), QUEUE_SIZE as u32);
+        let mut serial = [0u8; ID_SIZE];
+        let length = cmp::min(id.len(), ID_SIZE);
+        serial[..length].copy_from_slice(&id.as_bytes()[..length]);
+        let stats = Arc::new(Stats::new());
+        Ok(Block {
+            disk: Mutex::new(Arc::new(Disk {
+                engine: engine::open(image.clone(), depth, limits, stats.clone())?,
+                image,
+                id: serial,
+            })),
+            limits,
+            stats,
+            depth,
+            io: Mutex::new(None),
+        })
+    }
+
+    /// What the disk did so far, whichever images it went to.
+    pub fn statistics(&self) -> Statistics {
+        self.stats.load()
+    }
+
+    /// The image the guest's requests go to.
+    pub fn image(&self) -> Arc<Image> {
+        self.disk.lock().unwrap().image.clone()
+    }
+
+    /// Sends the guest's requests to another image from now on, once
+    /// the ones in flight are over.  The guest doesn't notice, so the
+    /// image has to look just like the old one.
+    pub fn switch(&self, image: Arc<Image>) -> Result<()> {
+        let disk = {
+            let current = self.disk.lock().unwrap();
+            if image.size() != current.image.size()
+                || image.read_only() != current.image.read_only()
+            {
+                return Err(ErrorKind::DeviceError("can't switch to a different disk").into());
+            }
+
+            Arc::new(Disk {
+                engine: engine::open(image.clone(), self.depth, self.limits, self.stats.clone())?,
+                image,
+                id: current.id,
+            })
+        };
+
+        // The lock isn't held while the I/O thread finishes up, so the
+        // guest can go on notifying in the meantime.
+        let sender = self.io.lock().unwrap().as_ref().map(|io| io.sender.clone());
+        let (reply, result) = mpsc::channel();
+        let switched = match sender {
+            Some(sender) => sender
+                .send(Event::Switch(disk.clone(), reply))
+                .ok()
+                .and_then(|_| result.recv().ok()),
+            None => None,
+        };
+        match switched {
+            Some(result) => result?,
+            // Without an I/O thread, there's nothing in flight.
+            None => self.disk.lock().unwrap().image.flush()?,
+        }
+
+        *self.disk.lock().unwrap() = disk;
+        Ok(())
+    }
+
+    fn stop(&self) {
+        if let Some(io) = self.io.lock().unwrap().take() {
+            let _ = io.sender.send(Event::Stop);
+            let _ = io.thread.join();
+        }
+    }
+}
+
+impl Virtio for Block {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn class(&self) -> u32 {
+        0x010000
+    }
+
+    fn features(&self) -> u64 {
+        let image = self.image();
+        let features = VIRTIO_BLK_F_SIZE_MAX
+            | VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_BLK_SIZE
+            | VIRTIO_BLK_F_FLUSH
+            | VIRTIO_BLK_F_TOPOLOGY
+            | VIRTIO_F_RING_PACKED;
+        if image.read_only() {
+            features | VIRTIO_BLK_F_RO
+        } else if image.can_discard() {
+            features | VIRTIO_BLK_F_WRITE_ZEROES | VIRTIO_BLK_F_DISCARD
+        } else {
+            features | VIRTIO_BLK_F_WRITE_ZEROES
+        }
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn config_read(&self, offset: usize, data: &mut [u8]) {
+        let image = self.image();
+        let logical = image.alignment();
+        let physical = cmp::max(image.block_size(), logical);
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u64(&mut config[0..8], image.size() / SECTOR_SIZE);
+        LittleEndian::write_u32(&mut config[8..12], SIZE_MAX);
+        LittleEndian::write_u32(&mut config[12..16], SEG_MAX);
+        LittleEndian::write_u32(&mut config[20..24], logical);
+        // The topology: the physical block size as a power of two of
+        // the logical one, and the smallest efficient I/O in logical
+        // blocks.
+        config[24] = (physical / logical).trailing_zeros() as u8;
+        LittleEndian::write_u16(&mut config[26..28], (physical / logical) as u16);
+        LittleEndian::write_u16(&mut config[34..36], 1);
+        LittleEndian::write_u32(&mut config[36..40], MAX_SEGMENT_SECTORS);
+        LittleEndian::write_u32(&mut config[40..44], MAX_SEGMENTS);
+        LittleEndian::write_u32(&mut config[44..48], physical / SECTOR_SIZE as u32);
+        LittleEndian::write_u32(&mut config[48..52], MAX_SEGMENT_SECTORS);
+        LittleEndian::write_u32(&mut config[52..56], MAX_SEGMENTS);
+        config[56] = image.can_discard() as u8;
+        read_into(&config, offset, data);
+    }
+
+    fn activate(&self, activation: Activation) -> Result<()> {
+        self.stop();
+
+        let (sender, events) = mpsc::channel();
+        let worker = Worker {
+            disk: self.disk.lock().unwrap().clone(),
+            activation,
+            events,
+            sender: sender.clone(),
+            depth: self.depth as usize,
+            pending: HashMap::new(),
+            next: 0,
+            switching: None,
+        };
+        let thread = thread::Builder::new()
+            .name("block-io".to_owned())
+            .spawn(move || worker.run())?;
+        *self.io.lock().unwrap() = Some(Io { sender, thread });
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        if let Some(ref io) = *self.io.lock().unwrap() {
+            if queue == 0 {
+                let _ = io.sender.send(Event::Notify);
+            }
+        }
+    }
+
+    /// Waits for whatever is in flight, so nothing is written to guest
+    /// memory after the reset.
+    fn reset(&self) {
+        self.stop();
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use virtio::queue::tests::{Driver, Interrupts};
+    use virtio::queue::VIRTIO_F_EVENT_IDX;
+    use virtio::VIRTIO_F_VERSION_1;
+
+    /// A disk held in memory, which remembers what was discarded.
+    #[derive(Debug)]
+    struct MemoryDisk {
+        data: Mutex<Vec<u8>>,
+        read_only: bool,
+        discarded: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl MemoryDisk {
+        fn new(sectors: usize, read_only: bool) -> Arc<MemoryDisk> {
+            Arc::new(MemoryDisk {
+                data: Mutex::new(vec![0xaa; sectors * SECTOR_SIZE as usize]),
+                read_only,
+                discarded: Mutex::new(vec![]),
+            })
+        }
+    }
+
+    impl Image for MemoryDisk {
+        fn size(&self) -> u64 {
+            self.data.lock().unwrap().len() as u64
+        }
+
+        fn read_only(&self) -> bool {
+            self.read_only
+        }
+
+        fn block_size(&self) -> u32 {
+            4096
+        }
+
+        fn read_at(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+            ::block::check_range(self, offset, data.len() as u64)?;
+            let disk = self.data.lock().unwrap();
+            data.copy_from_slice(&disk[offset as usize..offset as usize + data.len()]);
+            Ok(())
+        }
+
+        fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+            ::block::check_range(self, offset, data.len() as u64)?;
+            let mut disk = self.data.lock().unwrap();
+            disk[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn can_discard(&self) -> bool {
+            true
+        }
+
+        fn discard(&self, offset: u64, length: u64) -> Result<()> {
+            self.discarded.lock().unwrap().push((offset, length));
+            Ok(())
+        }
+    }
+
+    fn start(block: &Block, features: u64) -> Driver {
+        let driver = Driver::new(1, features);
+        block
+            .activate(driver.activation(Arc::new(Interrupts::default())))
+            .unwrap();
+        driver
+    }
+
+    /// Makes a request available, laid out the way drivers do: the
+    /// header, then the data, then `room` bytes for the device to
+    /// write back, then the status.
+    fn submit(driver: &mut Driver, kind: u32, sector: u64, data: &[u8], room: u32) {
+        let mut header = [0u8; HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..4], kind);
+        LittleEndian::write_u64(&mut header[8..16], sector);
+
+        let mut buffers = vec![(&header[..], 0, false)];
+        if !data.is_empty() {
+            buffers.push((data, 0, false));
+        }
+        if room > 0 {
+            buffers.push((&[], room, true));
+        }
+        buffers.push((&[], 1, true));
+        driver.chain(0, &buffers);
+    }
+
+    /// The lengths of everything the device used since the last time,
+    /// along with the status of the last request.  Waits for the
+    /// device to use something, since it works in the background.
+    fn used(driver: &mut Driver) -> (Vec<u32>, u8) {
+        let used = driver.wait(0);
+        let status = *used.last().unwrap().last().unwrap();
+        (used.iter().map(|data| data.len() as u32).collect(), status)
+    }
+
+    fn segment(sector: u64, sectors: u32, flags: u32) -> Vec<u8> {
+        let mut segment = vec![0u8; SEGMENT_SIZE];
+        LittleEndian::write_u64(&mut segment[0..8], sector);
+        LittleEndian::write_u32(&mut segment[8..12], sectors);
+        LittleEndian::write_u32(&mut segment[12..16], flags);
+        segment
+    }
+
+    #[test]
+    fn it_reads_and_writes_sectors() {
+        let disk = MemoryDisk::new(16, false);
+        let block = Block::new(disk.clone(), "disk", 4, Limits::default()).unwrap();
+        let mut driver = start(&block, VIRTIO_F_VERSION_1);
+
+        submit(&mut driver, VIRTIO_BLK_T_OUT, 2, &[0x55; 1024], 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_OK));
+        assert_eq!(disk.data.lock().unwrap()[1023..1025], [0xaa, 0x55]);
+
+        submit(&mut driver, VIRTIO_BLK_T_IN, 3, &[], 1024);
+        block.notify(0);
+        let written = driver.wait(0);
+        assert_eq!(written[0].len(), 1025);
+        assert_eq!(written[0][1024], VIRTIO_BLK_S_OK);
+        let read = &written[0][..1024];
+        assert!(read[..512].iter().all(|&b| b == 0x55));
+        assert!(read[512..].iter().all(|&b| b == 0xaa));
+
+        let statistics = block.statistics();
+        assert_eq!(
+            (statistics.write.operations, statistics.write.bytes),
+            (1, 1024)
+        );
+        assert_eq!(
+            (statistics.read.operations, statistics.read.bytes),
+            (1, 1024)
+        );
+    }
+
+    #[test]
+    fn it_takes_requests_over_a_packed_queue() {
+        let disk = MemoryDisk::new(16, false);
+        let block = Block::new(disk.clone(), "disk", 2, Limits::default()).unwrap();
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED | VIRTIO_F_EVENT_IDX;
+        let mut driver = start(&block, features);
+
+        // Each request takes three entries, so the ring wraps a few
+        // times.
+        for round in 0..12u8 {
+            let sector = round as u64 % 16;
+            submit(&mut driver, VIRTIO_BLK_T_OUT, sector, &[round; 512], 0);
+            assert!(driver.kicks(0));
+            block.notify(0);
+            assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_OK));
+
+            submit(&mut driver, VIRTIO_BLK_T_IN, sector, &[], 512);
+            assert!(driver.kicks(0));
+            block.notify(0);
+            let written = driver.wait(0);
+            assert_eq!(written[0][..512], [round; 512][..]);
+            assert_eq!(written[0][512], VIRTIO_BLK_S_OK);
+        }
+    }
+
+    #[test]
+    fn it_keeps_several_requests_in_flight() {
+        let disk = MemoryDisk::new(16, false);
+        let block = Block::new(disk.clone(), "disk", 2, Limits::default()).unwrap();
+        let mut driver = start(&block, VIRTIO_F_VERSION_1);
+
+        for sector in 0..4 {
+            submit(
+                &mut driver,
+                VIRTIO_BLK_T_OUT,
+                sector * 2,
+                &[sector as u8; 512],
+                0,
+            );
+        }
+        block.notify(0);
+
+        let mut lengths = vec![];
+        while lengths.len() < 4 {
+            lengths.extend(used(&mut driver).0);
+        }
+        assert_eq!(lengths, vec![1; 4]);
+        let data = disk.data.lock().unwrap();
+        for sector in 0..4 {
+            assert_eq!(data[sector * 1024], sector as u8);
+            assert_eq!(data[sector * 1024 + 512], 0xaa);
+        }
+    }
+
+    #[test]
+    fn it_fails_requests_past_the_end() {
+        let block = Block::new(MemoryDisk::new(4, false), "disk", 4, Limits::default()).unwrap();
+        let mut driver = start(&block, VIRTIO_F_VERSION_1);
+
+        submit(&mut driver, VIRTIO_BLK_T_IN, 3, &[], 1024);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1025], VIRTIO_BLK_S_IOERR));
+
+        submit(&mut driver, VIRTIO_BLK_T_OUT, 0, &[0; 100], 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_IOERR));
+
+        submit(&mut driver, VIRTIO_BLK_T_OUT, !0 >> 9, &[0; 512], 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_IOERR));
+    }
+
+    #[test]
+    fn it_refuses_requests_too_big_to_take() {
+        let block = Block::new(MemoryDisk::new(4, false), "disk", 4, Limits::default()).unwrap();
+        let mut config = [0u8; CONFIG_SIZE];
+        block.config_read(0, &mut config);
+        assert_ne!(block.features() & VIRTIO_BLK_F_SIZE_MAX, 0);
+        assert_eq!(LittleEndian::read_u32(&config[8..12]), SIZE_MAX);
+
+        let mut driver = Driver::new(1, VIRTIO_F_VERSION_1);
+        let interrupts = Arc::new(Interrupts::resettable());
+        block
+            .activate(driver.activation(interrupts.clone()))
+            .unwrap();
+        let room = MAX_REQUEST_SIZE as u32 + SECTOR_SIZE as u32;
+        submit(&mut driver, VIRTIO_BLK_T_IN, 0, &[], room);
+        block.notify(0);
+        assert!(interrupts.wait_reset(Duration::from_secs(5)));
+        assert!(driver.used(0).is_empty());
+    }
+
+    #[test]
+    fn it_refuses_writes_when_read_only() {
+        let disk = MemoryDisk::new(4, true);
+        let block = Block::new(disk.clone(), "disk", 4, Limits::default()).unwrap();
+        assert_ne!(block.features() & VIRTIO_BLK_F_RO, 0);
+        assert_eq!(block.features() & VIRTIO_BLK_F_DISCARD, 0);
+        let mut driver = start(&block, VIRTIO_F_VERSION_1);
+
+        submit(&mut driver, VIRTIO_BLK_T_OUT, 0, &[0; 512], 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_IOERR));
+        assert!(disk.data.lock().unwrap().iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn it_reports_its_id() {
+        let block = Block::new(
+            MemoryDisk::new(4, false),
+            "a-rather-long-serial-number",
+            4,
+            Limits::default(),
+        )
+        .unwrap();
+        let mut driver = start(&block, VIRTIO_F_VERSION_1);
+
+        submit(&mut driver, VIRTIO_BLK_T_GET_ID, 0, &[], ID_SIZE as u32);
+        block.notify(0);
+        let written = driver.wait(0);
+        assert_eq!(&written[0][..], &b"a-rather-long-serial\0"[..]);
+
+        submit(&mut driver, 0x1234, 0, &[], 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_UNSUPP));
+    }
+
+    #[test]
+    fn it_discards_and_zeroes_ranges() {
+        let disk = MemoryDisk::new(16, false);
+        let block = Block::new(disk.clone(), "disk", 4, Limits::default()).unwrap();
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES;
+        let mut driver = start(&block, features);
+
+        let mut ranges = segment(1, 2, 0);
+        ranges.extend(segment(8, 1, 0));
+        submit(&mut driver, VIRTIO_BLK_T_DISCARD, 0, &ranges, 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_OK));
+        assert_eq!(
+            *disk.discarded.lock().unwrap(),
+            vec![(512, 1024), (4096, 512)]
+        );
+
+        submit(
+            &mut driver,
+            VIRTIO_BLK_T_WRITE_ZEROES,
+            0,
+            &segment(2, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            0,
+        );
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_OK));
+        let data = disk.data.lock().unwrap();
+        assert!(data[1024..1536].iter().all(|&b| b == 0));
+        assert_eq!((data[1023], data[1536]), (0xaa, 0xaa));
+    }
+
+    #[test]
+    fn it_switches_disks_between_requests() {
+        let old = MemoryDisk::new(16, false);
+        let new = MemoryDisk::new(16, false);
+        let block = Block::new(old.clone(), "disk", 4, Limits::default()).unwrap();
+        let mut driver = start(&block, VIRTIO_F_VERSION_1);
+
+        submit(&mut driver, VIRTIO_BLK_T_OUT, 0, &[0x11; 512], 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_OK));
+        block.switch(new.clone()).unwrap();
+        submit(&mut driver, VIRTIO_BLK_T_OUT, 1, &[0x22; 512], 0);
+        block.notify(0);
+        assert_eq!(used(&mut driver), (vec![1], VIRTIO_BLK_S_OK));
+
+        assert_eq!(
+            old.data.lock().unwrap()[..1024],
+            [&[0x11; 512][..], &[0xaa; 512][..]].concat()[..]
+        );
+        assert_eq!(
+            new.data.lock().unwrap()[..1024],
+            [&[0xaa; 512][..], &[0x22; 512][..]].concat()[..]
+        );
+        assert!(block.switch(MemoryDisk::new(8, false)).is_err());
+    }
+
+    #[test]
+    fn it_describes_the_disk() {
+        let block = Block::new(MemoryDisk::new(16, false), "disk", 4, Limits::default()).unwrap();
+        let mut config = [0u8; CONFIG_SIZE];
+        block.config_read(0, &mut config);
+        assert_eq!(LittleEndian::read_u64(&config[0..8]), 16);
+        assert_eq!(LittleEndian::read_u32(&config[20..24]), 512);
+        assert_eq!(config[24], 3);
+        assert_eq!(LittleEndian::read_u16(&config[26..28]), 8);
+        assert_eq!(LittleEndian::read_u32(&config[44..48]), 8);
+        assert_eq!(config[56], 1);
+    }
+}
@@ -1,76 +1,680 @@
 use byteorder::{ByteOrder, LittleEndian};
-use device::pci::{Address, Pci};
-use device::Device;
-use kvm;
-use std::sync::Mutex;
-use virtio::Virtio;
-
-#[cfg_attr(rustfmt, rustfmt_skip)]
-static DEFAULT_PCI_CONFIG: &[u8] = &[
-    0xf4, 0x1a, 0x43, 0x10, // 0x00
-    0x00, 0x00, 0x10, 0x00, // 0x04
-    0x00, 0x00, 0x80, 0x07, // 0x08
-    0x00, 0x00, 0x00, 0x00, // 0x0c
-    0x00, 0x00, 0x00, 0x00, // 0x10, BAR0
-    0x00, 0x00, 0x00, 0x00, // 0x14, BAR1
-    0x00, 0x00, 0x00, 0x00, // 0x18, BAR2
-    0x00, 0x00, 0x00, 0x00, // 0x1c, BAR3
-    0x00, 0x00, 0x00, 0x00, // 0x20, BAR4
-    0x00, 0x00, 0x00, 0x00, // 0x24, BAR5
-    0x00, 0x00, 0x00, 0x00, // 0x28
-    0x00, 0x00, 0x00, 0x00, // 0x2c
-    0x00, 0x00, 0x00, 0x00, // 0x30
-    0x41, 0x00, 0x00, 0x00, // 0x34
-    0x00, 0x00, 0x00, 0x00, // 0x38
-    0x00, 0x00, 0x00, 0x00, // 0x40
-    // I think this is where the capability list points.
-    0x09, 0x58, 0x14, 0x05, // 0x44, struct virtio_pci_cfg_cap (vndr, next, len, type)
-    0x00, 0x00, 0x00, 0x00, // 0x48, (bar, pad, pad, pad)
-    0x00, 0x00, 0x00, 0x00, // 0x4c, (offset)
-    0x00, 0x00, 0x00, 0x00, // 0x50, (length)
-    0x00, 0x00, 0x00, 0x00, // 0x54
-    // 0x09, 0x--, 0x--, 0x01, // 0x58, struct virtio_pci_common_cfg (vndr, next, len, type)
-    // 0x00, 0x00, 0x00, 0x00, // 0x5c, (bar, pad, pad, pad)
-    // 0x00, 0x00, 0x00, 0x00, // 0x60, (offset)
-    // 0x00, 0x00, 0x00, 0x00, // 0x64, (length)
-    // 0x00, 0x00, 0x00, 0x00, // 0x68, (device_feature_select)
-    // 0x00, 0x00, 0x00, 0x00, // 0x6c, ()
-];
+use device::chardev::{Chardev, Sink};
+use device::memory::GuestMemory;
+use device::pci::read_into;
+use error::*;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use virtio::queue::Queue;
+use virtio::{Activation, Virtio};
 
+const VIRTIO_ID_CONSOLE: u16 = 3;
+
+/// The configuration holds the console size.
+const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+/// There can be more than one port, set up through the control queues.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+/// The driver can write to the console through the configuration,
+/// before the queues are set up.
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// The columns, rows, maximum number of ports, and emergency write
+/// registers.
+const CONFIG_SIZE: usize = 12;
+const CONTROL_SIZE: usize = 8;
+
+const CONTROL_RECEIVE_QUEUE: u16 = 2;
+const CONTROL_TRANSMIT_QUEUE: u16 = 3;
+const QUEUE_SIZE: u16 = 256;
+
+/// How much input is held on to while the guest isn't reading it.
+const MAX_PENDING: usize = 1 << 20;
+
+/// The receive queue of the given port; the transmit queue is the one
+/// right after it.  The control queues sit between the first port's
+/// queues and the rest.
+fn receive_queue(port: usize) -> u16 {
+    match port {
+        0 => 0,
+        port => (2 + port * 2) as u16,
+    }
+}
+
+/// The port a queue belongs to, and whether it's the transmit queue.
+fn port_of(queue: u16) -> Option<(usize, bool)> {
+    match queue {
+        0 | 1 => Some((0, queue == 1)),
+        CONTROL_RECEIVE_QUEUE | CONTROL_TRANSMIT_QUEUE => None,
+        queue => Some(((queue as usize - 2) / 2, queue % 2 == 1)),
+    }
+}
+
+fn control(id: u32, event: u16, value: u16, extra: &[u8]) -> Vec<u8> {
+    let mut message = vec![0u8; CONTROL_SIZE];
+    LittleEndian::write_u32(&mut message[0..4], id);
+    LittleEndian::write_u16(&mut message[4..6], event);
+    LittleEndian::write_u16(&mut message[6..8], value);
+    message.extend_from_slice(extra);
+    message
+}
+
+/// A port on the console: somewhere for the guest to talk to, backed by
+/// a chardev.
 #[derive(Debug)]
-pub struct Console(Mutex<Vec<u8>>);
+pub struct Port {
+    name: Option<String>,
+    console: bool,
+    chardev: Arc<Chardev>,
+}
 
-impl Console {
-    pub fn new() -> Console {
-        Console(Mutex::new(DEFAULT_PCI_CONFIG.to_owned()))
+impl Port {
+    /// A console port, which the guest uses as a terminal (hvc0 and
+    /// so on).
+    pub fn console(chardev: Arc<Chardev>) -> Port {
+        Port {
+            name: None,
+            console: true,
+            chardev,
+        }
+    }
+
+    /// A generic port, which shows up in the guest under the given
+    /// name (as /dev/virtio-ports/<name> on Linux).
+    pub fn named(name: &str, chardev: Arc<Chardev>) -> Port {
+        Port {
+            name: Some(name.to_owned()),
+            console: false,
+            chardev,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Running {
+    activation: Activation,
+    multiport: bool,
+    /// Control messages waiting for the driver to make room for them.
+    control: VecDeque<Vec<u8>>,
+}
+
+impl Running {
+    fn memory(&self) -> Arc<GuestMemory> {
+        self.activation.memory.clone()
+    }
+
+    fn queue(&mut self, index: u16) -> Option<&mut Queue> {
+        match self.activation.queues.get_mut(index as usize) {
+            Some(&mut Some(ref mut queue)) => Some(queue),
+            _ => None,
+        }
+    }
+
+    fn notify(&mut self, index: u16) -> Result<()> {
+        let memory = self.memory();
+        let needed = match self.queue(index) {
+            Some(queue) => queue.needs_notification(memory.as_ref())?,
+            None => false,
+        };
+
+        if needed {
+            self.activation.interrupt.queue(index)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fills the given receive queue from `pending`, for as long as
+    /// there are both buffers and data.
+    fn fill(&mut self, index: u16, pending: &mut VecDeque<u8>) -> Result<()> {
+        let memory = self.memory();
+        {
+            let queue = match self.queue(index) {
+                Some(queue) => queue,
+                None => return Ok(()),
+            };
+
+            while !pending.is_empty() {
+                let chain = match queue.pop(memory.as_ref())? {
+                    Some(chain) => chain,
+                    None => break,
+                };
+
+                let mut writer = chain.writer(memory.as_ref());
+                let count = ::std::cmp::min(writer.remaining(), pending.len() as u64) as usize;
+                let data = pending.drain(..count).collect::<Vec<_>>();
+                writer.write_all(&data)?;
+                let written = writer.written();
+                queue.push(memory.as_ref(), chain.head(), written)?;
+            }
+        }
+
+        self.notify(index)
+    }
+
+    /// Hands everything the guest sent on the given transmit queue to
+    /// `out`, one chain at a time.
+    fn drain<F: FnMut(&[u8]) -> Result<()>>(&mut self, index: u16, mut out: F) -> Result<()> {
+        let memory = self.memory();
+        {
+            let queue = match self.queue(index) {
+                Some(queue) => queue,
+                None => return Ok(()),
+            };
+
+            while let Some(chain) = queue.pop(memory.as_ref())? {
+                let mut data = vec![];
+                chain.reader(memory.as_ref()).read_to_end(&mut data)?;
+                out(&data)?;
+                queue.push(memory.as_ref(), chain.head(), 0)?;
+            }
+        }
+
+        self.notify(index)
+    }
+
+    fn send_control(&mut self, message: Vec<u8>) -> Result<()> {
+        self.control.push_back(message);
+        self.flush_control()
+    }
+
+    fn flush_control(&mut self) -> Result<()> {
+        let memory = self.memory();
+        {
+            let control = &mut self.control;
+            let queue = match self
+                .activation
+                .queues
+                .get_mut(CONTROL_RECEIVE_QUEUE as usize)
+            {
+                Some(&mut Some(ref mut queue)) => queue,
+                _ => return Ok(()),
+            };
+
+            while !control.is_empty() {
+                let chain = match queue.pop(memory.as_ref())? {
+                    Some(chain) => chain,
+                    None => break,
+                };
+
+                let message = control.pop_front().unwrap();
+                let mut writer = chain.writer(memory.as_ref());
+                writer.write_all(&message)?;
+                queue.push(memory.as_ref(), chain.head(), writer.written())?;
+            }
+        }
+
+        self.notify(CONTROL_RECEIVE_QUEUE)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    running: Option<Running>,
+    /// Input from each port's chardev the guest hasn't taken yet.
+    pending: Vec<VecDeque<u8>>,
+    size: (u16, u16),
+}
+
+#[derive(Debug)]
+struct Inner {
+    ports: Vec<Port>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn receive(&self, port: usize, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let pending = &mut state.pending[port];
+        if pending.len() + data.len() > MAX_PENDING {
+            warn!("dropping console input for port {}", port);
+            return;
+        }
+
+        pending.extend(data.iter());
+        if let Some(ref mut running) = state.running {
+            if port == 0 || running.multiport {
+                if let Err(e) = running.fill(receive_queue(port), pending) {
+                    warn!("could not deliver console input: {}", e);
+                    running.activation.interrupt.needs_reset();
+                }
+            }
+        }
+    }
+
+    fn resize(&self, columns: u16, rows: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.size = (columns, rows);
+        let running = match state.running {
+            Some(ref mut running) => running,
+            None => return,
+        };
+
+        // With multiple ports, each console port is resized on its own;
+        // otherwise, the size is in the configuration.
+        let result = if running.multiport {
+            let mut size = [0u8; 4];
+            LittleEndian::write_u16(&mut size[0..2], rows);
+            LittleEndian::write_u16(&mut size[2..4], columns);
+            running.send_control(control(0, VIRTIO_CONSOLE_RESIZE, 0, &size))
+        } else if (running.activation.features & VIRTIO_CONSOLE_F_SIZE) != 0 {
+            running.activation.interrupt.config()
+        } else {
+            Ok(())
+        };
+
+        if let Err(e) = result {
+            warn!("could not resize console: {}", e);
+        }
+    }
+
+    fn handle_control(&self, running: &mut Running, message: &[u8]) -> Result<()> {
+        if message.len() < CONTROL_SIZE {
+            return Err(ErrorKind::QueueError("short console control message").into());
+        }
+
+        let id = LittleEndian::read_u32(&message[0..4]);
+        let event = LittleEndian::read_u16(&message[4..6]);
+        let value = LittleEndian::read_u16(&message[6..8]);
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for id in 0..self.ports.len() {
+                    running.send_control(control(id as u32, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]))?;
+                }
+            }
+            VIRTIO_CONSOLE_DEVICE_READY => warn!("console driver failed to set up"),
+            VIRTIO_CONSOLE_PORT_READY if value == 1 => {
+                let port = match self.ports.get(id as usize) {
+                    Some(port) => port,
+                    None => return Ok(()),
+                };
+
+                if port.console {
+                    running.send_control(control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]))?;
+                }
+                if let Some(ref name) = port.name {
+                    running.send_control(control(
+                        id,
+                        VIRTIO_CONSOLE_PORT_NAME,
+                        1,
+                        name.as_bytes(),
+                    ))?;
+                }
+                // The chardevs are always there for the guest to talk
+                // to.
+                running.send_control(control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]))?;
+            }
+            VIRTIO_CONSOLE_PORT_READY => warn!("console driver failed to set up port {}", id),
+            VIRTIO_CONSOLE_PORT_OPEN => debug!("console port {} open: {}", id, value == 1),
+            _ => debug!("unhandled console control event {}", event),
+        }
+
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let running = match state.running {
+            Some(ref mut running) => running,
+            None => return Ok(()),
+        };
+
+        match (queue, port_of(queue)) {
+            (CONTROL_RECEIVE_QUEUE, None) => running.flush_control(),
+            (CONTROL_TRANSMIT_QUEUE, None) => {
+                let mut messages = vec![];
+                running.drain(queue, |data| {
+                    messages.push(data.to_vec());
+                    Ok(())
+                })?;
+                messages
+                    .iter()
+                    .try_for_each(|message| self.handle_control(running, message))
+            }
+            (_, Some((port, true))) => match self.ports.get(port) {
+                Some(port) => running.drain(queue, |data| port.chardev.write(data)),
+                None => Ok(()),
+            },
+            (_, Some((port, false))) => match state.pending.get_mut(port) {
+                Some(pending) => running.fill(queue, pending),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
     }
 }
 
-impl Device for Console {
-    fn request(&self) -> Vec<kvm::core::IoAddress> {
-        vec![]
+/// Delivers a chardev's input to one of the console's ports.
+#[derive(Debug)]
+struct PortSink(Arc<Inner>, usize);
+
+impl Sink for PortSink {
+    fn receive(&self, data: &[u8]) {
+        self.0.receive(self.1, data);
     }
-    fn handle(&self, _io: kvm::core::IoAction, _memory: &mut [u8]) -> Option<()> {
-        None
+
+    fn resize(&self, columns: u16, rows: u16) {
+        if self.1 == 0 {
+            self.0.resize(columns, rows);
+        }
     }
 }
 
-impl Pci for Console {
-    fn config_read(&self, address: Address) -> Option<u32> {
-        let space = self.0.lock().unwrap();
-        let lower = address.register() as usize;
-        let upper = lower + 4;
-        if space.len() < upper {
-            Some(LittleEndian::read_u32(&space[lower..upper]))
+/// A virtio console.  The first port is always a console, and is the
+/// only one the driver uses unless it supports multiple ports.
+#[derive(Debug)]
+pub struct Console(Arc<Inner>);
+
+impl Console {
+    /// Creates a console with the given ports, and starts taking input
+    /// from their chardevs.
+    pub fn new(ports: Vec<Port>) -> Result<Console> {
+        if ports.is_empty() || !ports[0].console {
+            return Err(
+                ErrorKind::DeviceError("the first console port has to be a console").into(),
+            );
+        }
+
+        let size = ports[0].chardev.size().unwrap_or((80, 25));
+        let inner = Arc::new(Inner {
+            state: Mutex::new(State {
+                running: None,
+                pending: ports.iter().map(|_| VecDeque::new()).collect(),
+                size,
+            }),
+            ports,
+        });
+
+        for (i, port) in inner.ports.iter().enumerate() {
+            port.chardev.attach(Arc::new(PortSink(inner.clone(), i)))?;
+        }
+
+        Ok(Console(inner))
+    }
+
+    fn multiport(&self) -> bool {
+        self.0.ports.len() > 1
+    }
+}
+
+impl Virtio for Console {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn class(&self) -> u32 {
+        0x078000
+    }
+
+    fn features(&self) -> u64 {
+        let features = VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_EMERG_WRITE;
+        if self.multiport() {
+            features | VIRTIO_CONSOLE_F_MULTIPORT
         } else {
-            None
+            features
+        }
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        let count = if self.multiport() {
+            2 + self.0.ports.len() * 2
+        } else {
+            2
+        };
+        vec![QUEUE_SIZE; count]
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn config_read(&self, offset: usize, data: &mut [u8]) {
+        let (columns, rows) = self.0.state.lock().unwrap().size;
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u16(&mut config[0..2], columns);
+        LittleEndian::write_u16(&mut config[2..4], rows);
+        LittleEndian::write_u32(&mut config[4..8], self.0.ports.len() as u32);
+        read_into(&config, offset, data);
+    }
+
+    /// The only writable field is the emergency write register, which
+    /// goes straight out of the first port.
+    fn config_write(&self, offset: usize, data: &[u8]) {
+        if offset == 8 && !data.is_empty() {
+            if let Err(e) = self.0.ports[0].chardev.write(&data[..1]) {
+                warn!("could not write to console: {}", e);
+            }
+        }
+    }
+
+    fn activate(&self, activation: Activation) -> Result<()> {
+        let multiport = (activation.features & VIRTIO_CONSOLE_F_MULTIPORT) != 0;
+        let mut state = self.0.state.lock().unwrap();
+        state.running = Some(Running {
+            activation,
+            multiport,
+            control: VecDeque::new(),
+        });
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        if let Err(e) = self.0.notify(queue) {
+            warn!("could not process console queue {}: {}", queue, e);
+            if let Some(ref running) = self.0.state.lock().unwrap().running {
+                running.activation.interrupt.needs_reset();
+            }
         }
     }
 
-    fn config_write(&self, address: Address, _value: u32) -> Option<()> {
-        warn!("attempted write of {:?}", address);
-        None
+    fn reset(&self) {
+        self.0.state.lock().unwrap().running = None;
     }
 }
 
-impl Virtio for Console {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio::queue::tests::{Driver, Interrupts, DRIVER_QUEUE_SIZE};
+    use virtio::VIRTIO_F_VERSION_1;
+
+    #[derive(Debug, Default)]
+    struct Buffered {
+        written: Mutex<Vec<u8>>,
+        sink: Mutex<Option<Arc<Sink>>>,
+    }
+
+    impl Chardev for Buffered {
+        fn write(&self, data: &[u8]) -> Result<()> {
+            self.written.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+            *self.sink.lock().unwrap() = Some(sink);
+            Ok(())
+        }
+    }
+
+    impl Buffered {
+        fn send(&self, data: &[u8]) {
+            let sink = self.sink.lock().unwrap().clone().unwrap();
+            sink.receive(data);
+        }
+
+        fn resize(&self, columns: u16, rows: u16) {
+            let sink = self.sink.lock().unwrap().clone().unwrap();
+            sink.resize(columns, rows);
+        }
+    }
+
+    fn console(named: bool) -> (Console, Arc<Buffered>, Arc<Buffered>) {
+        let first = Arc::new(Buffered::default());
+        let second = Arc::new(Buffered::default());
+        let mut ports = vec![Port::console(first.clone())];
+        if named {
+            ports.push(Port::named("org.vent.agent.0", second.clone()));
+        }
+        (Console::new(ports).unwrap(), first, second)
+    }
+
+    #[test]
+    fn it_transmits_to_the_chardev() {
+        let (console, chardev, _) = console(false);
+        let interrupts = Arc::new(Interrupts::default());
+        let mut driver = Driver::new(2, VIRTIO_F_VERSION_1);
+        console
+            .activate(driver.activation(interrupts.clone()))
+            .unwrap();
+
+        driver.offer(1, b"hello, ", 0, false);
+        driver.offer(1, b"world", 0, false);
+        console.notify(1);
+
+        assert_eq!(&chardev.written.lock().unwrap()[..], b"hello, world");
+        assert_eq!(driver.used(1).len(), 2);
+        assert_eq!(interrupts.queues(), vec![1]);
+    }
+
+    #[test]
+    fn it_holds_input_until_the_guest_has_room() {
+        let (console, chardev, _) = console(false);
+        let interrupts = Arc::new(Interrupts::default());
+        chardev.send(b"before");
+        let mut driver = Driver::new(2, VIRTIO_F_VERSION_1);
+        console
+            .activate(driver.activation(interrupts.clone()))
+            .unwrap();
+        chardev.send(b" and after");
+        assert!(driver.used(0).is_empty());
+
+        driver.offer(0, &[], 10, true);
+        driver.offer(0, &[], 10, true);
+        console.notify(0);
+        assert_eq!(
+            driver.used(0),
+            vec![b"before and".to_vec(), b" after".to_vec()]
+        );
+        assert_eq!(interrupts.queues(), vec![0]);
+    }
+
+    #[test]
+    fn it_only_offers_multiport_with_more_than_one_port() {
+        let (single, _, _) = console(false);
+        assert_eq!(single.features() & VIRTIO_CONSOLE_F_MULTIPORT, 0);
+        assert_eq!(single.queues().len(), 2);
+
+        let (multiple, _, _) = console(true);
+        assert_ne!(multiple.features() & VIRTIO_CONSOLE_F_MULTIPORT, 0);
+        assert_eq!(multiple.queues().len(), 6);
+        let mut config = [0u8; 4];
+        multiple.config_read(4, &mut config);
+        assert_eq!(LittleEndian::read_u32(&config), 2);
+    }
+
+    #[test]
+    fn it_announces_its_ports() {
+        let (console, _, agent) = console(true);
+        let interrupts = Arc::new(Interrupts::default());
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_CONSOLE_F_MULTIPORT;
+        let mut driver = Driver::new(6, features);
+        console
+            .activate(driver.activation(interrupts.clone()))
+            .unwrap();
+        for _ in 0..DRIVER_QUEUE_SIZE {
+            driver.offer(CONTROL_RECEIVE_QUEUE, &[], 64, true);
+        }
+
+        driver.offer(
+            CONTROL_TRANSMIT_QUEUE,
+            &control(0, VIRTIO_CONSOLE_DEVICE_READY, 1, &[]),
+            0,
+            false,
+        );
+        console.notify(CONTROL_TRANSMIT_QUEUE);
+        assert_eq!(
+            driver.used(CONTROL_RECEIVE_QUEUE),
+            vec![
+                control(0, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]),
+                control(1, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]),
+            ]
+        );
+
+        driver.offer(
+            CONTROL_TRANSMIT_QUEUE,
+            &control(0, VIRTIO_CONSOLE_PORT_READY, 1, &[]),
+            0,
+            false,
+        );
+        driver.offer(
+            CONTROL_TRANSMIT_QUEUE,
+            &control(1, VIRTIO_CONSOLE_PORT_READY, 1, &[]),
+            0,
+            false,
+        );
+        console.notify(CONTROL_TRANSMIT_QUEUE);
+        assert_eq!(
+            driver.used(CONTROL_RECEIVE_QUEUE),
+            vec![
+                control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]),
+                control(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+                control(1, VIRTIO_CONSOLE_PORT_NAME, 1, b"org.vent.agent.0"),
+                control(1, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+            ]
+        );
+
+        // The second port's queues come after the control queues.
+        driver.offer(5, b"ping", 0, false);
+        console.notify(5);
+        assert_eq!(&agent.written.lock().unwrap()[..], b"ping");
+        driver.offer(4, &[], 16, true);
+        agent.send(b"pong");
+        assert_eq!(driver.used(4), vec![b"pong".to_vec()]);
+    }
+
+    #[test]
+    fn it_resizes_through_the_configuration() {
+        let (console, chardev, _) = console(false);
+        let interrupts = Arc::new(Interrupts::default());
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_CONSOLE_F_SIZE;
+        let driver = Driver::new(2, features);
+        console
+            .activate(driver.activation(interrupts.clone()))
+            .unwrap();
+
+        chardev.resize(132, 43);
+        let mut config = [0u8; 4];
+        console.config_read(0, &mut config);
+        assert_eq!(config, [132, 0, 43, 0]);
+        assert_eq!(interrupts.configs(), 1);
+    }
+
+    #[test]
+    fn it_resizes_through_the_control_queue_with_multiple_ports() {
+        let (console, chardev, _) = console(true);
+        let interrupts = Arc::new(Interrupts::default());
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_MULTIPORT;
+        let mut driver = Driver::new(6, features);
+        console
+            .activate(driver.activation(interrupts.clone()))
+            .unwrap();
+        driver.offer(CONTROL_RECEIVE_QUEUE, &[], 64, true);
+
+        chardev.resize(132, 43);
+        assert_eq!(
+            driver.used(CONTROL_RECEIVE_QUEUE),
+            vec![control(0, VIRTIO_CONSOLE_RESIZE, 0, &[43, 0, 132, 0])]
+        );
+        assert_eq!(interrupts.configs(), 0);
+    }
+}
@@ -1,2 +1,12 @@
+mod block;
 mod console;
-pub use self::console::Console;
+mod net;
+mod vhost_net;
+mod vhost_user;
+mod vsock;
+pub use self::block::Block;
+pub use self::console::{Console, Port};
+pub use self::net::Net;
+pub use self::vhost_net::VhostNet;
+pub use self::vhost_user::VhostUser;
+pub use self::vsock::Vsock;
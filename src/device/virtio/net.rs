@@ -0,0 +1,617 @@
+use byteorder::{ByteOrder, LittleEndian};
+use device::memory::GuestMemory;
+use device::pci::read_into;
+use error::*;
+use net::{Backend, MacAddress, Offloads, Sink, HEADER_SIZE, MAX_FRAME_SIZE};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use virtio::queue::{Chain, Queue, VIRTIO_F_RING_PACKED};
+use virtio::{Activation, Virtio};
+
+pub(super) const VIRTIO_ID_NET: u16 = 1;
+
+/// The device fills in checksums the guest leaves out.
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+/// The guest takes frames whose checksum is left out.
+const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+/// The configuration holds the MAC address.
+pub(super) const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_GUEST_TSO4: u64 = 1 << 7;
+const VIRTIO_NET_F_GUEST_TSO6: u64 = 1 << 8;
+const VIRTIO_NET_F_GUEST_ECN: u64 = 1 << 9;
+const VIRTIO_NET_F_HOST_TSO4: u64 = 1 << 11;
+const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
+const VIRTIO_NET_F_HOST_ECN: u64 = 1 << 13;
+/// A frame the guest is given can be spread over several chains.
+pub(super) const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+/// The configuration holds the link status.
+pub(super) const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// The offloads going either way, which need a backend that can take
+/// them.
+pub(super) const OFFLOAD_FEATURES: u64 = VIRTIO_NET_F_CSUM
+    | VIRTIO_NET_F_GUEST_CSUM
+    | VIRTIO_NET_F_GUEST_TSO4
+    | VIRTIO_NET_F_GUEST_TSO6
+    | VIRTIO_NET_F_GUEST_ECN
+    | VIRTIO_NET_F_HOST_TSO4
+    | VIRTIO_NET_F_HOST_TSO6
+    | VIRTIO_NET_F_HOST_ECN;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// The MAC address and the status.
+pub(super) const CONFIG_SIZE: usize = 8;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+pub(super) const QUEUE_SIZE: u16 = 256;
+
+/// How many frames are held on to while the guest has no room for
+/// them; past that, they're dropped, as they would be on the wire.
+const MAX_PENDING: usize = 256;
+
+/// The card's configuration: its address, and a link that's always
+/// up.
+pub(super) fn config(mac: MacAddress) -> [u8; CONFIG_SIZE] {
+    let mut config = [0u8; CONFIG_SIZE];
+    config[0..6].copy_from_slice(&mac.0);
+    LittleEndian::write_u16(&mut config[6..8], VIRTIO_NET_S_LINK_UP);
+    config
+}
+
+/// What the guest can take in the frames it's given, going by the
+/// features it accepted.
+pub(super) fn offloads(features: u64) -> Offloads {
+    Offloads {
+        checksum: (features & VIRTIO_NET_F_GUEST_CSUM) != 0,
+        tso4: (features & VIRTIO_NET_F_GUEST_TSO4) != 0,
+        tso6: (features & VIRTIO_NET_F_GUEST_TSO6) != 0,
+        ecn: (features & VIRTIO_NET_F_GUEST_ECN) != 0,
+    }
+}
+
+#[derive(Debug)]
+struct Running {
+    activation: Activation,
+    mergeable: bool,
+    /// Receive chains taken off the queue that weren't enough for the
+    /// next frame on their own.
+    held: VecDeque<Chain>,
+}
+
+impl Running {
+    fn memory(&self) -> Arc<GuestMemory> {
+        self.activation.memory.clone()
+    }
+
+    fn queue(&mut self, index: u16) -> Option<&mut Queue> {
+        match self.activation.queues.get_mut(index as usize) {
+            Some(&mut Some(ref mut queue)) => Some(queue),
+            _ => None,
+        }
+    }
+
+    fn notify(&mut self, index: u16) -> Result<()> {
+        let memory = self.memory();
+        let needed = match self.queue(index) {
+            Some(queue) => queue.needs_notification(memory.as_ref())?,
+            None => false,
+        };
+
+        if needed {
+            self.activation.interrupt.queue(index)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes chains off the receive queue until there's room for
+    /// `length` bytes, returning how many chains that takes, unless
+    /// the guest hasn't made enough available yet.
+    fn reserve(&mut self, length: usize) -> Result<Option<usize>> {
+        let memory = self.memory();
+        loop {
+            let mut room = 0;
+            for (i, chain) in self.held.iter().enumerate() {
+                room += chain.writable_len();
+                if room >= length as u64 {
+                    return Ok(Some(i + 1));
+                }
+                if !self.mergeable {
+                    return Ok(None);
+                }
+            }
+
+            let chain = match self.queue(RECEIVE_QUEUE) {
+                Some(queue) => queue.pop(memory.as_ref())?,
+                None => None,
+            };
+            match chain {
+                Some(chain) => self.held.push_back(chain),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Hands the guest as many of the `pending` frames as it has room
+    /// for.
+    fn fill(&mut self, pending: &mut VecDeque<Vec<u8>>) -> Result<()> {
+        let memory = self.memory();
+        let mut used = false;
+        while let Some(length) = pending.front().map(|frame| frame.len()) {
+            let count = match self.reserve(length)? {
+                Some(count) => count,
+                // A chain too small for a frame, when they can't be
+                // merged, could only ever take smaller ones.
+                None if !self.mergeable && !self.held.is_empty() => {
+                    debug!("dropping a {} byte frame the guest has no room for", length);
+                    pending.pop_front();
+                    continue;
+                }
+                None => break,
+            };
+
+            let mut frame = pending.pop_front().unwrap();
+            LittleEndian::write_u16(&mut frame[10..12], count as u16);
+            let mut rest = &frame[..];
+            for chain in self.held.drain(..count).collect::<Vec<_>>() {
+                let written = {
+                    let mut writer = chain.writer(memory.as_ref());
+                    let size = ::std::cmp::min(writer.remaining(), rest.len() as u64) as usize;
+                    writer.write_all(&rest[..size])?;
+                    rest = &rest[size..];
+                    writer.written()
+                };
+                if let Some(queue) = self.queue(RECEIVE_QUEUE) {
+                    queue.push(memory.as_ref(), chain.head(), written)?;
+                }
+            }
+            used = true;
+        }
+
+        if used {
+            self.notify(RECEIVE_QUEUE)?;
+        }
+        Ok(())
+    }
+
+    /// Takes everything the guest sent off the transmit queue.
+    fn drain(&mut self) -> Result<Vec<Vec<u8>>> {
+        let memory = self.memory();
+        let mut frames = vec![];
+        {
+            let queue = match self.queue(TRANSMIT_QUEUE) {
+                Some(queue) => queue,
+                None => return Ok(frames),
+            };
+
+            // No need for kicks until the queue's empty again.
+            queue.disable_notification(memory.as_ref())?;
+            while let Some(chain) = queue.pop(memory.as_ref())? {
+                // The length is the driver's say-so, so it's checked
+                // before anything's read.
+                let length = chain.readable_len();
+                if length > HEADER_SIZE as u64 && length <= MAX_FRAME_SIZE as u64 {
+                    let mut frame = Vec::with_capacity(length as usize);
+                    chain.reader(memory.as_ref()).read_to_end(&mut frame)?;
+                    frames.push(frame);
+                } else {
+                    debug!("dropping a transmitted frame of {} bytes", length);
+                }
+                queue.push(memory.as_ref(), chain.head(), 0)?;
+            }
+        }
+
+        self.notify(TRANSMIT_QUEUE)?;
+        Ok(frames)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    running: Option<Running>,
+    /// Frames from the backend the guest hasn't taken yet.
+    pending: VecDeque<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    mac: MacAddress,
+    backend: Arc<Backend>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn receive(&self, frame: &[u8]) {
+        if frame.len() < HEADER_SIZE {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let running = match state.running {
+            Some(ref mut running) => running,
+            // Nobody's listening.
+            None => return,
+        };
+        if state.pending.len() >= MAX_PENDING {
+            debug!("dropping a frame for {}", self.mac);
+            return;
+        }
+
+        state.pending.push_back(frame.to_vec());
+        if let Err(e) = running.fill(&mut state.pending) {
+            warn!("could not deliver a frame: {}", e);
+            running.activation.interrupt.needs_reset();
+        }
+    }
+
+    fn notify(&self, queue: u16) -> Result<()> {
+        // The frames are sent on without the lock held, since the
+        // backend might have something to say back right away.
+        let frames = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let running = match state.running {
+                Some(ref mut running) => running,
+                None => return Ok(()),
+            };
+
+            match queue {
+                RECEIVE_QUEUE => return running.fill(&mut state.pending),
+                TRANSMIT_QUEUE => running.drain()?,
+                _ => return Ok(()),
+            }
+        };
+
+        for frame in frames {
+            if let Err(e) = self.backend.send(&frame) {
+                debug!("could not send a frame: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Delivers a backend's frames to the device.
+#[derive(Debug)]
+struct NetSink(Arc<Inner>);
+
+impl Sink for NetSink {
+    fn receive(&self, frame: &[u8]) {
+        self.0.receive(frame);
+    }
+}
+
+/// A virtio network card, with a single pair of queues.  Its link is
+/// always up.
+#[derive(Debug)]
+pub struct Net(Arc<Inner>);
+
+impl Net {
+    /// Creates a network card with the given address, and starts
+    /// taking frames from its backend.
+    pub fn new(mac: MacAddress, backend: Arc<Backend>) -> Result<Net> {
+        let inner = Arc::new(Inner {
+            mac,
+            backend,
+            state: Mutex::new(State {
+                running: None,
+                pending: VecDeque::new(),
+            }),
+        });
+        inner.backend.attach(Arc::new(NetSink(inner.clone())))?;
+        Ok(Net(inner))
+    }
+}
+
+impl Virtio for Net {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_NET
+    }
+
+    fn class(&self) -> u32 {
+        0x020000
+    }
+
+    fn features(&self) -> u64 {
+        let features =
+            VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS | VIRTIO_NET_F_MRG_RXBUF | VIRTIO_F_RING_PACKED;
+        if self.0.backend.offloads() {
+            features | OFFLOAD_FEATURES
+        } else {
+            features
+        }
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 2]
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn config_read(&self, offset: usize, data: &mut [u8]) {
+        read_into(&config(self.0.mac), offset, data);
+    }
+
+    fn activate(&self, activation: Activation) -> Result<()> {
+        let features = activation.features;
+        self.0.backend.set_offloads(offloads(features))?;
+
+        let mut state = self.0.state.lock().unwrap();
+        state.running = Some(Running {
+            activation,
+            mergeable: (features & VIRTIO_NET_F_MRG_RXBUF) != 0,
+            held: VecDeque::new(),
+        });
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        if let Err(e) = self.0.notify(queue) {
+            warn!("could not process network queue {}: {}", queue, e);
+            if let Some(ref running) = self.0.state.lock().unwrap().running {
+                running.activation.interrupt.needs_reset();
+            }
+        }
+    }
+
+    fn reset(&self) {
+        {
+            let mut state = self.0.state.lock().unwrap();
+            state.running = None;
+            state.pending.clear();
+        }
+        if let Err(e) = self.0.backend.set_offloads(Offloads::default()) {
+            warn!("could not turn network offloads off: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio::queue::tests::{Driver, Interrupts};
+    use virtio::queue::VIRTIO_F_EVENT_IDX;
+    use virtio::VIRTIO_F_VERSION_1;
+
+    /// Keeps whatever it's sent, and sends whatever it's told to.
+    #[derive(Debug, Default)]
+    struct Loopback {
+        offloads: bool,
+        sent: Mutex<Vec<Vec<u8>>>,
+        set: Mutex<Vec<Offloads>>,
+        sink: Mutex<Option<Arc<Sink>>>,
+    }
+
+    impl Backend for Loopback {
+        fn send(&self, frame: &[u8]) -> Result<()> {
+            self.sent.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        }
+
+        fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+            *self.sink.lock().unwrap() = Some(sink);
+            Ok(())
+        }
+
+        fn offloads(&self) -> bool {
+            self.offloads
+        }
+
+        fn set_offloads(&self, offloads: Offloads) -> Result<()> {
+            self.set.lock().unwrap().push(offloads);
+            Ok(())
+        }
+    }
+
+    impl Loopback {
+        fn receive(&self, data: &[u8]) {
+            let mut frame = vec![0u8; HEADER_SIZE];
+            frame.extend_from_slice(data);
+            let sink = self.sink.lock().unwrap().clone().unwrap();
+            sink.receive(&frame);
+        }
+    }
+
+    fn net(offloads: bool) -> (Net, Arc<Loopback>) {
+        let backend = Arc::new(Loopback {
+            offloads,
+            ..Loopback::default()
+        });
+        let mac = MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        (Net::new(mac, backend.clone()).unwrap(), backend)
+    }
+
+    #[test]
+    fn it_describes_the_card() {
+        let (net, _) = net(false);
+        let mut config = [0u8; CONFIG_SIZE];
+        net.config_read(0, &mut config);
+        assert_eq!(&config[..6], &[0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        assert_eq!(LittleEndian::read_u16(&config[6..8]), VIRTIO_NET_S_LINK_UP);
+        assert_eq!(net.features() & OFFLOAD_FEATURES, 0);
+        assert_ne!(net.features() & VIRTIO_F_RING_PACKED, 0);
+
+        let (offloading, _) = self::net(true);
+        assert_eq!(offloading.features() & OFFLOAD_FEATURES, OFFLOAD_FEATURES);
+    }
+
+    #[test]
+    fn it_transmits_to_the_backend() {
+        let (net, backend) = net(false);
+        let mut driver = Driver::new(2, VIRTIO_F_VERSION_1);
+        let interrupts = Arc::new(Interrupts::default());
+        net.activate(driver.activation(interrupts.clone())).unwrap();
+
+        let mut frame = vec![0u8; HEADER_SIZE];
+        frame.extend_from_slice(b"a frame");
+        driver.offer(TRANSMIT_QUEUE, &frame, 0, false);
+        driver.offer(TRANSMIT_QUEUE, &[0u8; HEADER_SIZE], 0, false);
+        driver.offer(TRANSMIT_QUEUE, &vec![0u8; MAX_FRAME_SIZE + 1], 0, false);
+        net.notify(TRANSMIT_QUEUE);
+
+        assert_eq!(*backend.sent.lock().unwrap(), vec![frame]);
+        assert_eq!(driver.used(TRANSMIT_QUEUE).len(), 3);
+        assert_eq!(interrupts.queues(), vec![TRANSMIT_QUEUE]);
+    }
+
+    #[test]
+    fn it_asks_for_every_notification_with_event_indices() {
+        let (net, backend) = net(false);
+        let mut driver = Driver::new(2, VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX);
+        let interrupts = Arc::new(Interrupts::default());
+        net.activate(driver.activation(interrupts.clone())).unwrap();
+
+        let mut frame = vec![0u8; HEADER_SIZE];
+        frame.extend_from_slice(b"a frame");
+        for _ in 0..3 {
+            driver.offer(TRANSMIT_QUEUE, &frame, 0, false);
+            assert!(driver.kicks(TRANSMIT_QUEUE));
+            net.notify(TRANSMIT_QUEUE);
+            assert_eq!(driver.used(TRANSMIT_QUEUE).len(), 1);
+        }
+        assert_eq!(backend.sent.lock().unwrap().len(), 3);
+        assert_eq!(interrupts.queues(), vec![TRANSMIT_QUEUE; 3]);
+
+        // Receive buffers the device isn't waiting for aren't worth a
+        // notification, until it runs out.
+        driver.offer(RECEIVE_QUEUE, &[], 64, true);
+        assert!(driver.kicks(RECEIVE_QUEUE));
+        driver.offer(RECEIVE_QUEUE, &[], 64, true);
+        assert!(!driver.kicks(RECEIVE_QUEUE));
+        backend.receive(b"first");
+        backend.receive(b"second");
+        backend.receive(b"third");
+        driver.offer(RECEIVE_QUEUE, &[], 64, true);
+        assert!(driver.kicks(RECEIVE_QUEUE));
+        net.notify(RECEIVE_QUEUE);
+        assert_eq!(driver.used(RECEIVE_QUEUE).len(), 3);
+    }
+
+    #[test]
+    fn it_carries_frames_over_packed_queues() {
+        let (net, backend) = net(false);
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED | VIRTIO_F_EVENT_IDX;
+        let mut driver = Driver::new(2, features);
+        let interrupts = Arc::new(Interrupts::default());
+        net.activate(driver.activation(interrupts.clone())).unwrap();
+
+        // Enough rounds for the rings to wrap around a few times.
+        for round in 0..40u8 {
+            let mut frame = vec![0u8; HEADER_SIZE];
+            frame.push(round);
+            driver.chain(
+                TRANSMIT_QUEUE,
+                &[
+                    (&frame[..HEADER_SIZE], 0, false),
+                    (&frame[HEADER_SIZE..], 0, false),
+                ],
+            );
+            assert!(driver.kicks(TRANSMIT_QUEUE));
+            net.notify(TRANSMIT_QUEUE);
+            assert_eq!(driver.used(TRANSMIT_QUEUE).len(), 1);
+            assert_eq!(backend.sent.lock().unwrap().last(), Some(&frame));
+
+            driver.offer(RECEIVE_QUEUE, &[], 64, true);
+            assert!(driver.kicks(RECEIVE_QUEUE));
+            net.notify(RECEIVE_QUEUE);
+            backend.receive(&[round]);
+            let used = driver.used(RECEIVE_QUEUE);
+            assert_eq!(used.len(), 1);
+            assert_eq!(&used[0][HEADER_SIZE..], &[round]);
+        }
+        assert_eq!(interrupts.queues().len(), 80);
+    }
+
+    #[test]
+    fn it_holds_frames_until_the_guest_has_room() {
+        let (net, backend) = net(false);
+        let mut driver = Driver::new(2, VIRTIO_F_VERSION_1);
+        let interrupts = Arc::new(Interrupts::default());
+        // Frames from before the driver is there go nowhere.
+        backend.receive(b"too early");
+        net.activate(driver.activation(interrupts.clone())).unwrap();
+        backend.receive(b"first");
+        backend.receive(b"second");
+        assert!(driver.used(RECEIVE_QUEUE).is_empty());
+
+        driver.offer(RECEIVE_QUEUE, &[], 64, true);
+        driver.offer(RECEIVE_QUEUE, &[], 64, true);
+        net.notify(RECEIVE_QUEUE);
+        let used = driver.used(RECEIVE_QUEUE);
+        assert_eq!(used.len(), 2);
+        assert_eq!(&used[0][HEADER_SIZE..], b"first");
+        assert_eq!(LittleEndian::read_u16(&used[0][10..12]), 1);
+        assert_eq!(&used[1][HEADER_SIZE..], b"second");
+        assert_eq!(interrupts.queues(), vec![RECEIVE_QUEUE]);
+    }
+
+    #[test]
+    fn it_merges_receive_buffers() {
+        let (net, backend) = net(false);
+        let mut driver = Driver::new(2, VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MRG_RXBUF);
+        net.activate(driver.activation(Arc::new(Interrupts::default())))
+            .unwrap();
+
+        let data = (0..40).collect::<Vec<u8>>();
+        driver.offer(RECEIVE_QUEUE, &[], 20, true);
+        driver.offer(RECEIVE_QUEUE, &[], 20, true);
+        backend.receive(&data);
+        // Both buffers together aren't enough, so nothing's used yet.
+        assert!(driver.used(RECEIVE_QUEUE).is_empty());
+
+        driver.offer(RECEIVE_QUEUE, &[], 20, true);
+        driver.offer(RECEIVE_QUEUE, &[], 20, true);
+        net.notify(RECEIVE_QUEUE);
+        let used = driver.used(RECEIVE_QUEUE);
+        assert_eq!(
+            used.iter().map(|buffer| buffer.len()).collect::<Vec<_>>(),
+            vec![20, 20, 12]
+        );
+        assert_eq!(LittleEndian::read_u16(&used[0][10..12]), 3);
+        assert_eq!(used.concat()[HEADER_SIZE..], data[..]);
+    }
+
+    #[test]
+    fn it_drops_frames_too_big_for_a_buffer_without_merging() {
+        let (net, backend) = net(false);
+        let mut driver = Driver::new(2, VIRTIO_F_VERSION_1);
+        net.activate(driver.activation(Arc::new(Interrupts::default())))
+            .unwrap();
+
+        driver.offer(RECEIVE_QUEUE, &[], 20, true);
+        backend.receive(&[0xaa; 40]);
+        backend.receive(b"fits");
+        let used = driver.used(RECEIVE_QUEUE);
+        assert_eq!(used.len(), 1);
+        assert_eq!(&used[0][HEADER_SIZE..], b"fits");
+    }
+
+    #[test]
+    fn it_tells_the_backend_which_offloads_the_guest_takes() {
+        let (net, backend) = net(true);
+        let features = VIRTIO_F_VERSION_1 | VIRTIO_NET_F_GUEST_CSUM | VIRTIO_NET_F_GUEST_TSO4;
+        let driver = Driver::new(2, features);
+        net.activate(driver.activation(Arc::new(Interrupts::default())))
+            .unwrap();
+        net.reset();
+
+        assert_eq!(
+            *backend.set.lock().unwrap(),
+            vec![
+                Offloads {
+                    checksum: true,
+                    tso4: true,
+                    tso6: false,
+                    ecn: false,
+                },
+                Offloads::default(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use super::net::{
+    config, offloads, CONFIG_SIZE, OFFLOAD_FEATURES, QUEUE_SIZE, VIRTIO_ID_NET, VIRTIO_NET_F_MAC,
+    VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_STATUS,
+};
+use device::pci::read_into;
+use error::*;
+use net::{Backend, MacAddress, Offloads, Tap};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use vhost::{EventFd, Relay, Rings, Vhost};
+use virtio::queue::{Queue, QUEUE_FEATURES};
+use virtio::{Activation, Virtio, VIRTIO_F_VERSION_1};
+
+const VHOST_NET: &str = "/dev/vhost-net";
+
+#[derive(Debug)]
+struct Running {
+    /// The eventfds vhost-net waits on, by queue.  The driver's
+    /// notifications go to them through KVM, or through us.
+    kicks: Vec<(u16, EventFd)>,
+    /// The eventfds vhost-net interrupts the guest with.  They have to
+    /// stay open for KVM to keep raising interrupts from them.
+    _calls: Vec<EventFd>,
+    _relay: Option<Relay>,
+}
+
+/// A virtio network card on a TAP interface, whose frames are moved
+/// by the host kernel's vhost-net rather than by us.  It looks just
+/// like `Net` to the guest, but the guest's notifications and frames
+/// never reach us, as long as the transport can hook the queues up to
+/// KVM.
+#[derive(Debug)]
+pub struct VhostNet {
+    mac: MacAddress,
+    tap: Tap,
+    vhost: Vhost,
+    running: Mutex<Option<Running>>,
+}
+
+impl VhostNet {
+    /// Opens vhost-net for a TAP interface, if it can move the
+    /// interface's frames.  It needs the interface to carry virtio-net
+    /// headers, and to handle the queues the way the transport offers
+    /// them.
+    pub fn open(tap: &Tap) -> Result<Vhost> {
+        if !tap.offloads() {
+            return Err(ErrorKind::DeviceError("TAP interface has no virtio-net headers").into());
+        }
+
+        let vhost = Vhost::open(VHOST_NET)?;
+        let needed = VIRTIO_F_VERSION_1 | QUEUE_FEATURES;
+        if (vhost.features() & needed) != needed {
+            return Err(ErrorKind::DeviceError("vhost-net lacks needed features").into());
+        }
+        Ok(vhost)
+    }
+
+    /// Creates a network card with the given address, on a TAP
+    /// interface and the vhost-net device opened for it.
+    pub fn new(mac: MacAddress, tap: Tap, vhost: Vhost) -> VhostNet {
+        VhostNet {
+            mac,
+            tap,
+            vhost,
+            running: Mutex::new(None),
+        }
+    }
+
+    /// Hands the queues over to vhost-net, and has it start moving
+    /// frames.
+    fn start(&self, activation: Activation) -> Result<Running> {
+        self.vhost
+            .set_features(activation.features & self.vhost.features())?;
+        let regions = activation.memory.regions();
+        if regions.is_empty() {
+            return Err(ErrorKind::DeviceError("guest memory can't be shared").into());
+        }
+        self.vhost.set_memory(&regions)?;
+
+        let mut kicks = vec![];
+        let mut calls = vec![];
+        let mut relayed = vec![];
+        for (index, queue) in activation.queues.iter().enumerate() {
+            let index = index as u16;
+            let queue = match *queue {
+                Some(Queue::Split(ref queue)) => queue,
+                Some(Queue::Packed(_)) => {
+                    return Err(ErrorKind::DeviceError("packed queues weren't offered").into())
+                }
+                None => continue,
+            };
+
+            let rings = Rings::locate(&regions, queue.size(), queue.addresses())?;
+            let (kick, call) = (EventFd::new()?, EventFd::new()?);
+            self.vhost
+                .set_queue(index, queue.size(), &rings, 0, &kick, &call)?;
+            // If the transport can't hook the kick up, notifications
+            // come to `notify` instead.
+            activation.interrupt.ioeventfd(index, kick.as_raw_fd())?;
+            if !activation.interrupt.irqfd(index, call.as_raw_fd())? {
+                relayed.push((index, call.try_clone()?));
+            }
+            self.vhost
+                .set_net_backend(index, Some(self.tap.as_raw_fd()))?;
+
+            // Whatever the driver made available before vhost-net was
+            // there to be told still has to be picked up.
+            kick.signal()?;
+            kicks.push((index, kick));
+            calls.push(call);
+        }
+
+        let relay = if relayed.is_empty() {
+            None
+        } else {
+            debug!("relaying interrupts for {} vhost-net queues", relayed.len());
+            Some(Relay::start(
+                "vhost-net",
+                relayed,
+                activation.interrupt.clone(),
+            )?)
+        };
+        Ok(Running {
+            kicks,
+            _calls: calls,
+            _relay: relay,
+        })
+    }
+
+    /// Has vhost-net stop moving frames and working the rings.  The
+    /// queues are handed over afresh the next time.
+    fn stop(&self) {
+        for index in 0..self.queues().len() as u16 {
+            let stopped = self
+                .vhost
+                .set_net_backend(index, None)
+                .and_then(|_| self.vhost.stop_queue(index));
+            if let Err(e) = stopped {
+                warn!("could not stop vhost-net queue {}: {}", index, e);
+            }
+        }
+    }
+}
+
+impl Virtio for VhostNet {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_NET
+    }
+
+    fn class(&self) -> u32 {
+        0x020000
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_STATUS
+            | OFFLOAD_FEATURES
+            | (self.vhost.features() & VIRTIO_NET_F_MRG_RXBUF)
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; 2]
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn config_read(&self, offset: usize, data: &mut [u8]) {
+        read_into(&config(self.mac), offset, data);
+    }
+
+    fn activate(&self, activation: Activation) -> Result<()> {
+        self.tap.set_offloads(offloads(activation.features))?;
+        match self.start(activation) {
+            Ok(running) => {
+                *self.running.lock().unwrap() = Some(running);
+                Ok(())
+            }
+            Err(e) => {
+                self.stop();
+                Err(e)
+            }
+        }
+    }
+
+    /// Only reached when the transport couldn't have KVM kick
+    /// vhost-net itself.
+    fn notify(&self, queue: u16) {
+        if let Some(ref running) = *self.running.lock().unwrap() {
+            if let Some(&(_, ref kick)) = running.kicks.iter().find(|&&(q, _)| q == queue) {
+                if let Err(e) = kick.signal() {
+                    warn!("could not kick vhost-net queue {}: {}", queue, e);
+                }
+            }
+        }
+    }
+
+    fn reset(&self) {
+        self.stop();
+        *self.running.lock().unwrap() = None;
+        if let Err(e) = self.tap.set_offloads(Offloads::default()) {
+            warn!("could not turn network offloads off: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn it_offers_what_the_userspace_card_does() {
+        // Plenty of hosts have no vhost-net, or don't let us create
+        // interfaces at all.
+        let tap = match Tap::open(&format!("vhost{}", process::id() % 100_000)) {
+            Ok(tap) => tap,
+            Err(_) => return,
+        };
+        let vhost = match VhostNet::open(&tap) {
+            Ok(vhost) => vhost,
+            Err(_) => return,
+        };
+
+        let net = VhostNet::new(MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]), tap, vhost);
+        assert_eq!(net.device_type(), VIRTIO_ID_NET);
+        assert_eq!(net.queues(), vec![QUEUE_SIZE; 2]);
+        let wanted = VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS | OFFLOAD_FEATURES;
+        assert_eq!(net.features() & wanted, wanted);
+        let mut mac = [0u8; 6];
+        net.config_read(0, &mut mac);
+        assert_eq!(mac, [0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+    }
+}
@@ -0,0 +1,631 @@
+use configuration::VhostUserConfiguration;
+use device::memory::{GuestMemory, MemoryRegion};
+use error::*;
+use mio::unix::{EventedFd, UnixReady};
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use vhost::{Backend, EventFd, Relay, Rings, VHOST_USER_PROTOCOL_F_CONFIG};
+use virtio::queue::{Queue, QUEUE_FEATURES};
+use virtio::{Activation, Virtio, VIRTIO_F_VERSION_1};
+
+/// Device-specific features sit in the low 24 bits; the rest are the
+/// transport's to offer.
+const DEVICE_FEATURES: u64 = (1 << 24) - 1;
+
+/// How long to wait between attempts to get a backend back.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+const STOP: Token = Token(0);
+const BACKEND: Token = Token(1);
+
+/// A queue as handed over to the backend.
+#[derive(Debug)]
+struct Ring {
+    index: u16,
+    size: u16,
+    rings: Rings,
+    /// Where the used ring is in the guest's memory, which tells how
+    /// far the backend got.
+    used: u64,
+    /// The backend waits on `kick`, and bumps `call` to interrupt the
+    /// guest.  They outlive the backend, so a new one takes them over
+    /// along with however the transport hooked them up.
+    kick: EventFd,
+    call: EventFd,
+}
+
+/// Everything a backend needs to pick up where the last one left off.
+#[derive(Debug)]
+struct Running {
+    features: u64,
+    memory: Arc<GuestMemory>,
+    regions: Vec<MemoryRegion>,
+    rings: Vec<Ring>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// `None` while the backend's gone.
+    backend: Option<Backend>,
+    running: Option<Running>,
+}
+
+/// Watches the backend while the device runs, and gets a new one in
+/// its place once it goes away.
+#[derive(Debug)]
+struct Monitor {
+    stop: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Monitor {
+    fn start(socket: String, state: Arc<Mutex<State>>) -> Result<Monitor> {
+        let poll = Poll::new()?;
+        let stop = EventFd::new()?;
+        poll.register(
+            &EventedFd(&stop.as_raw_fd()),
+            STOP,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+
+        let thread = thread::Builder::new()
+            .name("vhost-user".to_owned())
+            .spawn(move || {
+                if let Err(e) = watch(&socket, &state, &poll) {
+                    warn!("stopped watching vhost-user backend at {}: {}", socket, e);
+                }
+            })?;
+
+        Ok(Monitor {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop.signal() {
+            warn!("could not stop watching vhost-user backend: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The threads that go along with a running device.
+#[derive(Debug)]
+struct Threads {
+    _relay: Option<Relay>,
+    _monitor: Monitor,
+}
+
+/// A virtio device whose queues another process works, over
+/// vhost-user.  The device is whatever the backend makes it; all we
+/// do is hand the queues and the guest's memory over, and get a new
+/// backend in place of one that goes away, which picks up from where
+/// the guest sees the last one got.
+#[derive(Debug)]
+pub struct VhostUser {
+    socket: String,
+    device_type: u16,
+    queues: Vec<u16>,
+    config_size: usize,
+    features: u64,
+    state: Arc<Mutex<State>>,
+    threads: Mutex<Option<Threads>>,
+}
+
+impl VhostUser {
+    /// Connects to the backend the configuration points to, which has
+    /// to be able to work the device it describes.
+    pub fn connect(config: &VhostUserConfiguration) -> Result<VhostUser> {
+        let backend = Backend::connect(&config.socket)?;
+        let needed = VIRTIO_F_VERSION_1 | QUEUE_FEATURES;
+        if (backend.features() & needed) != needed {
+            return Err(ErrorKind::DeviceError("vhost-user backend lacks needed features").into());
+        }
+        if backend
+            .queues()
+            .map(|queues| queues < config.queues as u64)
+            .unwrap_or(false)
+        {
+            return Err(ErrorKind::DeviceError("vhost-user backend has too few queues").into());
+        }
+        if config.config_size > 0
+            && (backend.protocol_features() & VHOST_USER_PROTOCOL_F_CONFIG) == 0
+        {
+            return Err(
+                ErrorKind::DeviceError("vhost-user backend keeps no device configuration").into(),
+            );
+        }
+
+        Ok(VhostUser {
+            socket: config.socket.clone(),
+            device_type: config.device_type,
+            queues: vec![config.queue_size; config.queues as usize],
+            config_size: config.config_size,
+            features: backend.features(),
+            state: Arc::new(Mutex::new(State {
+                backend: Some(backend),
+                running: None,
+            })),
+            threads: Mutex::new(None),
+        })
+    }
+}
+
+impl Virtio for VhostUser {
+    fn device_type(&self) -> u16 {
+        self.device_type
+    }
+
+    fn features(&self) -> u64 {
+        self.features & DEVICE_FEATURES
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        self.queues.clone()
+    }
+
+    fn config_size(&self) -> usize {
+        self.config_size
+    }
+
+    fn config_read(&self, offset: usize, data: &mut [u8]) {
+        let result = match self.state.lock().unwrap().backend {
+            Some(ref mut backend) => backend.config(offset, data),
+            None => Err(ErrorKind::DeviceError("vhost-user backend is gone").into()),
+        };
+        if let Err(e) = result {
+            warn!("could not read vhost-user device configuration: {}", e);
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+        }
+    }
+
+    fn config_write(&self, offset: usize, data: &[u8]) {
+        let result = match self.state.lock().unwrap().backend {
+            Some(ref mut backend) => backend.set_config(offset, data),
+            None => Err(ErrorKind::DeviceError("vhost-user backend is gone").into()),
+        };
+        if let Err(e) = result {
+            warn!("could not write vhost-user device configuration: {}", e);
+        }
+    }
+
+    fn activate(&self, activation: Activation) -> Result<()> {
+        let regions = activation.memory.regions();
+        if regions.is_empty() {
+            return Err(ErrorKind::DeviceError("guest memory can't be shared").into());
+        }
+
+        let mut rings = vec![];
+        let mut relayed = vec![];
+        for (index, queue) in activation.queues.iter().enumerate() {
+            let index = index as u16;
+            let queue = match *queue {
+                Some(Queue::Split(ref queue)) => queue,
+                Some(Queue::Packed(_)) => {
+                    return Err(ErrorKind::DeviceError("packed queues weren't offered").into())
+                }
+                None => continue,
+            };
+
+            let ring = Ring {
+                index,
+                size: queue.size(),
+                rings: Rings::locate(&regions, queue.size(), queue.addresses())?,
+                used: queue.addresses().2,
+                kick: EventFd::new()?,
+                call: EventFd::new()?,
+            };
+            // If the transport can't hook the kick up, notifications
+            // come to `notify` instead.
+            activation
+                .interrupt
+                .ioeventfd(index, ring.kick.as_raw_fd())?;
+            if !activation.interrupt.irqfd(index, ring.call.as_raw_fd())? {
+                relayed.push((index, ring.call.try_clone()?));
+            }
+            rings.push(ring);
+        }
+
+        let running = Running {
+            features: activation.features,
+            memory: activation.memory.clone(),
+            regions,
+            rings,
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            // The backend went away while the device was stopped, or
+            // setting it up went wrong the last time.
+            let mut backend = match state.backend.take() {
+                Some(backend) => backend,
+                None => reconnect(&self.socket, running.features)?,
+            };
+            restore(&mut backend, &running)?;
+            state.backend = Some(backend);
+            state.running = Some(running);
+        }
+
+        let relay = if relayed.is_empty() {
+            None
+        } else {
+            debug!(
+                "relaying interrupts for {} vhost-user queues",
+                relayed.len()
+            );
+            Some(Relay::start(
+                "vhost-user",
+                relayed,
+                activation.interrupt.clone(),
+            )?)
+        };
+        let monitor = Monitor::start(self.socket.clone(), self.state.clone())?;
+        *self.threads.lock().unwrap() = Some(Threads {
+            _relay: relay,
+            _monitor: monitor,
+        });
+        Ok(())
+    }
+
+    /// Only reached when the transport couldn't have KVM kick the
+    /// backend itself.
+    fn notify(&self, queue: u16) {
+        if let Some(ref running) = self.state.lock().unwrap().running {
+            if let Some(ring) = running.rings.iter().find(|ring| ring.index == queue) {
+                if let Err(e) = ring.kick.signal() {
+                    warn!("could not kick vhost-user queue {}: {}", queue, e);
+                }
+            }
+        }
+    }
+
+    fn reset(&self) {
+        // The monitor takes the state's lock, so it has to be stopped
+        // before that's held.
+        self.threads.lock().unwrap().take();
+
+        let mut state = self.state.lock().unwrap();
+        let running = match state.running.take() {
+            Some(running) => running,
+            None => return,
+        };
+        let stopped = match state.backend {
+            Some(ref mut backend) => running
+                .rings
+                .iter()
+                .try_for_each(|ring| backend.stop_queue(ring.index).map(|_| ())),
+            None => Ok(()),
+        };
+        // Whatever the backend was up to, the next one starts afresh.
+        if let Err(e) = stopped {
+            warn!("could not stop vhost-user backend: {}", e);
+            state.backend = None;
+        }
+    }
+}
+
+/// Connects to the backend at `socket` again, which has to still have
+/// the given features.
+fn reconnect(socket: &str, features: u64) -> Result<Backend> {
+    let backend = Backend::connect(socket)?;
+    if (features & !backend.features()) != 0 {
+        return Err(ErrorKind::DeviceError("vhost-user backend lost features").into());
+    }
+    Ok(backend)
+}
+
+/// Hands a running device over to a backend.  Each queue starts at
+/// the next chain the guest sees wasn't used yet, so a new backend
+/// takes up whatever the last one was in the middle of again.
+fn restore(backend: &mut Backend, running: &Running) -> Result<()> {
+    backend.set_features(running.features)?;
+    backend.set_memory(&running.regions)?;
+    for ring in &running.rings {
+        let next = running.memory.read_u16(ring.used + 2)?;
+        backend.set_queue(
+            ring.index,
+            ring.size,
+            &ring.rings,
+            next,
+            &ring.kick,
+            &ring.call,
+        )?;
+        // The driver may have made buffers available before the
+        // backend was there to be told.
+        ring.kick.signal()?;
+    }
+    Ok(())
+}
+
+/// Waits for the backend to hang up, and connects to a new one, over
+/// and over, until the poll's told to stop.
+fn watch(socket: &str, state: &Mutex<State>, poll: &Poll) -> io::Result<()> {
+    let mut events = Events::with_capacity(4);
+    loop {
+        let fd = state
+            .lock()
+            .unwrap()
+            .backend
+            .as_ref()
+            .map(|backend| backend.as_raw_fd());
+        if let Some(fd) = fd {
+            poll.register(
+                &EventedFd(&fd),
+                BACKEND,
+                Ready::readable() | UnixReady::hup(),
+                PollOpt::edge(),
+            )?;
+            let stopped = wait(poll, &mut events, None)?;
+            poll.deregister(&EventedFd(&fd))?;
+            if stopped {
+                return Ok(());
+            }
+            warn!("lost vhost-user backend at {}", socket);
+            state.lock().unwrap().backend = None;
+        }
+
+        loop {
+            let running = state
+                .lock()
+                .unwrap()
+                .running
+                .as_ref()
+                .map(|running| running.features);
+            let features = match running {
+                Some(features) => features,
+                None => return Ok(()),
+            };
+            match reconnect(socket, features) {
+                Ok(mut backend) => {
+                    let mut state = state.lock().unwrap();
+                    let result = match state.running {
+                        Some(ref running) => restore(&mut backend, running),
+                        None => return Ok(()),
+                    };
+                    match result {
+                        Ok(()) => {
+                            info!("reconnected to vhost-user backend at {}", socket);
+                            state.backend = Some(backend);
+                            break;
+                        }
+                        Err(e) => warn!("could not restore vhost-user backend: {}", e),
+                    }
+                }
+                Err(e) => debug!("could not reconnect to vhost-user backend: {}", e),
+            }
+            if wait(poll, &mut events, Some(RECONNECT_INTERVAL))? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Waits for the backend to hang up, or for `timeout` to pass,
+/// returning whether the poll was told to stop instead.
+fn wait(poll: &Poll, events: &mut Events, timeout: Option<Duration>) -> io::Result<bool> {
+    loop {
+        match poll.poll(events, timeout) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        let mut hung_up = false;
+        for event in events.iter() {
+            if event.token() == STOP {
+                return Ok(true);
+            }
+            // Replies make the socket readable too; only hanging up
+            // matters.
+            hung_up |= UnixReady::from(event.readiness()).is_hup();
+        }
+        if hung_up || timeout.is_some() {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, NativeEndian};
+    use scratch;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use virtio::queue::tests::{Interrupts, Ram};
+
+    const GET_FEATURES: u32 = 1;
+    const SET_FEATURES: u32 = 2;
+    const SET_OWNER: u32 = 3;
+    const SET_MEM_TABLE: u32 = 5;
+    const SET_VRING_NUM: u32 = 8;
+    const SET_VRING_ADDR: u32 = 9;
+    const SET_VRING_BASE: u32 = 10;
+    const GET_VRING_BASE: u32 = 11;
+    const SET_VRING_KICK: u32 = 12;
+    const SET_VRING_CALL: u32 = 13;
+
+    const HOST: u64 = 0x7f00_0000_0000;
+
+    /// A request as a backend got it, by the connection it came over.
+    type Request = (usize, u32, Vec<u8>);
+
+    /// Guest memory that makes out it's shared.
+    #[derive(Debug)]
+    struct Shared(Ram, File);
+
+    impl GuestMemory for Shared {
+        fn read(&self, address: u64, data: &mut [u8]) -> Result<()> {
+            self.0.read(address, data)
+        }
+
+        fn write(&self, address: u64, data: &[u8]) -> Result<()> {
+            self.0.write(address, data)
+        }
+
+        fn regions(&self) -> Vec<MemoryRegion> {
+            vec![MemoryRegion {
+                guest: 0,
+                size: 0x10000,
+                host: HOST,
+                fd: Some(self.1.as_raw_fd()),
+            }]
+        }
+    }
+
+    /// Plays a backend of a device with a single feature, for a
+    /// connection at a time.  The first connection is dropped once
+    /// `hang_up` comes over it.
+    fn serve(listener: UnixListener, hang_up: Option<u32>, requests: Sender<Request>) {
+        for connection in 0.. {
+            let mut socket: UnixStream = match listener.accept() {
+                Ok((socket, _)) => socket,
+                Err(_) => return,
+            };
+            let mut header = [0u8; 12];
+            while socket.read_exact(&mut header).is_ok() {
+                let request = NativeEndian::read_u32(&header[0..4]);
+                let mut body = vec![0u8; NativeEndian::read_u32(&header[8..12]) as usize];
+                socket.read_exact(&mut body).unwrap();
+
+                let reply = match request {
+                    GET_FEATURES => Some(1 | VIRTIO_F_VERSION_1 | QUEUE_FEATURES),
+                    GET_VRING_BASE => Some(0),
+                    _ => None,
+                };
+                if let Some(value) = reply {
+                    let mut message = [0u8; 20];
+                    message[0..4].copy_from_slice(&header[0..4]);
+                    NativeEndian::write_u32(&mut message[4..8], 1 | (1 << 2));
+                    NativeEndian::write_u32(&mut message[8..12], 8);
+                    NativeEndian::write_u64(&mut message[12..20], value);
+                    socket.write_all(&message).unwrap();
+                }
+                if requests.send((connection, request, body)).is_err() {
+                    return;
+                }
+                if connection == 0 && hang_up == Some(request) {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn start(name: &str, hang_up: Option<u32>) -> (VhostUser, Receiver<Request>, PathBuf) {
+        let path = scratch::path(name);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || serve(listener, hang_up, sender));
+
+        let config = VhostUserConfiguration {
+            socket: path.to_str().unwrap().to_owned(),
+            device_type: 26,
+            queues: 1,
+            queue_size: 8,
+            config_size: 0,
+        };
+        let device = VhostUser::connect(&config).unwrap();
+        for &expected in &[GET_FEATURES, SET_OWNER] {
+            assert_eq!(requests.recv().unwrap().1, expected);
+        }
+        (device, requests, path)
+    }
+
+    fn activation(memory: Arc<Shared>) -> Activation {
+        let features = 1 | VIRTIO_F_VERSION_1;
+        Activation {
+            features,
+            memory,
+            queues: vec![Some(
+                Queue::new(8, 0x1000, 0x2000, 0x3000, features).unwrap(),
+            )],
+            interrupt: Arc::new(Interrupts::default()),
+        }
+    }
+
+    /// Takes requests until the one after the queue's handed over.
+    fn handed_over(requests: &Receiver<Request>) -> Vec<Request> {
+        let mut taken = vec![];
+        loop {
+            let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            let done = request.1 == SET_VRING_CALL;
+            taken.push(request);
+            if done {
+                return taken;
+            }
+        }
+    }
+
+    #[test]
+    fn it_hands_the_queues_over() {
+        let (device, requests, path) = start("vhost-user-device", None);
+        assert_eq!(device.device_type(), 26);
+        assert_eq!(device.features(), 1);
+        assert_eq!(device.queues(), vec![8]);
+
+        let memory = Arc::new(Shared(
+            Ram::new(0, 0x10000),
+            File::open("/dev/null").unwrap(),
+        ));
+        device.activate(activation(memory)).unwrap();
+        let taken = handed_over(&requests);
+        assert_eq!(
+            taken.iter().map(|r| r.1).collect::<Vec<_>>(),
+            vec![
+                SET_FEATURES,
+                SET_MEM_TABLE,
+                SET_VRING_NUM,
+                SET_VRING_BASE,
+                SET_VRING_ADDR,
+                SET_VRING_KICK,
+                SET_VRING_CALL,
+            ]
+        );
+        let addr = &taken[4].2;
+        assert_eq!(NativeEndian::read_u64(&addr[8..16]), HOST + 0x1000);
+        assert_eq!(NativeEndian::read_u64(&addr[16..24]), HOST + 0x3000);
+        assert_eq!(NativeEndian::read_u64(&addr[24..32]), HOST + 0x2000);
+
+        device.reset();
+        assert_eq!(requests.recv().unwrap().1, GET_VRING_BASE);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_picks_up_where_a_lost_backend_left_off() {
+        let (device, requests, path) = start("vhost-user-reconnect", Some(SET_VRING_CALL));
+        let memory = Arc::new(Shared(
+            Ram::new(0, 0x10000),
+            File::open("/dev/null").unwrap(),
+        ));
+        // The guest sees five chains were used.
+        memory.write_u16(0x3002, 5).unwrap();
+        device.activate(activation(memory)).unwrap();
+        assert!(handed_over(&requests).iter().all(|r| r.0 == 0));
+
+        let taken = handed_over(&requests);
+        assert!(taken.iter().all(|r| r.0 == 1));
+        assert_eq!(taken[0].1, GET_FEATURES);
+        let base = taken.iter().find(|r| r.1 == SET_VRING_BASE).unwrap();
+        assert_eq!(NativeEndian::read_u32(&base.2[4..8]), 5);
+
+        device.reset();
+        assert_eq!(requests.recv().unwrap(), (1, GET_VRING_BASE, vec![0; 8]));
+        fs::remove_file(&path).unwrap();
+    }
+}
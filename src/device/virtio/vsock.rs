@@ -0,0 +1,455 @@
+use byteorder::{ByteOrder, LittleEndian};
+use device::memory::GuestMemory;
+use device::pci::read_into;
+use error::*;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use virtio::queue::{Chain, Queue};
+use virtio::{Activation, Virtio};
+use vsock::{Backend, Packet, Sink, VSOCK_OP_RST};
+
+const VIRTIO_ID_VSOCK: u16 = 19;
+
+/// The guest's context ID.
+const CONFIG_SIZE: usize = 8;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+/// Where the device would tell the guest its connections are gone,
+/// which never happens here.
+const EVENT_QUEUE: u16 = 2;
+const QUEUE_SIZE: u16 = 256;
+
+/// How many packets are held on to while the guest has no room for
+/// them.  The backend keeps to the guest's credit, so only the odd
+/// control packet should ever wait; past that, connections are reset.
+const MAX_PENDING: usize = 1024;
+
+#[derive(Debug)]
+struct Running {
+    activation: Activation,
+    /// A receive chain taken off the queue that wasn't enough for the
+    /// next packet.
+    held: Option<Chain>,
+    /// Packets for the backend, which are sent on once the lock's let
+    /// go of, since the backend might answer right away.
+    outgoing: Vec<Packet>,
+}
+
+impl Running {
+    fn memory(&self) -> Arc<GuestMemory> {
+        self.activation.memory.clone()
+    }
+
+    fn queue(&mut self, index: u16) -> Option<&mut Queue> {
+        match self.activation.queues.get_mut(index as usize) {
+            Some(&mut Some(ref mut queue)) => Some(queue),
+            _ => None,
+        }
+    }
+
+    fn notify(&mut self, index: u16) -> Result<()> {
+        let memory = self.memory();
+        let needed = match self.queue(index) {
+            Some(queue) => queue.needs_notification(memory.as_ref())?,
+            None => false,
+        };
+
+        if needed {
+            self.activation.interrupt.queue(index)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives up on the connection a packet for the guest is on, since
+    /// it can't be delivered: the backend's told the guest reset the
+    /// connection, and the guest that the host did, ahead of anything
+    /// else for the connection, which is dropped.  A reset fits in any
+    /// buffer; resets themselves are just dropped.
+    fn abandon(&mut self, packet: &Packet, pending: &mut VecDeque<Packet>) {
+        if packet.op == VSOCK_OP_RST {
+            return;
+        }
+
+        debug!(
+            "resetting the vsock connection to guest port {}",
+            packet.dst_port
+        );
+        let reset = packet.reset();
+        pending.retain(|other| other.reset() != reset);
+        pending.push_front(reset.reset());
+        self.outgoing.push(reset);
+    }
+
+    /// Hands the guest as many of the `pending` packets as it has room
+    /// for.
+    fn fill(&mut self, pending: &mut VecDeque<Packet>) -> Result<()> {
+        let memory = self.memory();
+        let mut used = false;
+        while !pending.is_empty() {
+            let chain = match self.held.take() {
+                Some(chain) => chain,
+                None => match self.queue(RECEIVE_QUEUE) {
+                    Some(queue) => match queue.pop(memory.as_ref())? {
+                        Some(chain) => chain,
+                        None => break,
+                    },
+                    None => break,
+                },
+            };
+
+            let packet = pending.pop_front().unwrap();
+            let bytes = packet.to_bytes();
+            if chain.writable_len() < bytes.len() as u64 {
+                // Linux's buffers always fit the biggest packet the
+                // backend sends, so this one's lost, and its connection
+                // with it.
+                self.held = Some(chain);
+                self.abandon(&packet, pending);
+                continue;
+            }
+
+            let written = {
+                let mut writer = chain.writer(memory.as_ref());
+                writer.write_all(&bytes)?;
+                writer.written()
+            };
+            if let Some(queue) = self.queue(RECEIVE_QUEUE) {
+                queue.push(memory.as_ref(), chain.head(), written)?;
+            }
+            used = true;
+        }
+
+        if used {
+            self.notify(RECEIVE_QUEUE)?;
+        }
+        Ok(())
+    }
+
+    /// Takes everything the guest sent off the transmit queue, for the
+    /// backend.
+    fn drain(&mut self) -> Result<()> {
+        let memory = self.memory();
+        let mut packets = vec![];
+        {
+            let queue = match self.queue(TRANSMIT_QUEUE) {
+                Some(queue) => queue,
+                None => return Ok(()),
+            };
+
+            while let Some(chain) = queue.pop(memory.as_ref())? {
+                let mut bytes = vec![];
+                chain.reader(memory.as_ref()).read_to_end(&mut bytes)?;
+                match Packet::parse(&bytes) {
+                    Some(packet) => packets.push(packet),
+                    None => debug!("dropping a {} byte vsock packet", bytes.len()),
+                }
+                queue.push(memory.as_ref(), chain.head(), 0)?;
+            }
+        }
+
+        self.outgoing.extend(packets);
+        self.notify(TRANSMIT_QUEUE)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    running: Option<Running>,
+    /// Packets from the backend the guest hasn't taken yet.
+    pending: VecDeque<Packet>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    cid: u64,
+    backend: Arc<Backend>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn receive(&self, packet: Packet) {
+        let packets = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let running = match state.running {
+                Some(ref mut running) => running,
+                None => return,
+            };
+
+            if state.pending.len() >= MAX_PENDING {
+                running.abandon(&packet, &mut state.pending);
+            } else {
+                state.pending.push_back(packet);
+            }
+            if let Err(e) = running.fill(&mut state.pending) {
+                warn!("could not deliver a vsock packet: {}", e);
+                running.activation.interrupt.needs_reset();
+            }
+            running.outgoing.drain(..).collect::<Vec<_>>()
+        };
+
+        self.send(packets);
+    }
+
+    fn notify(&self, queue: u16) -> Result<()> {
+        let packets = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let running = match state.running {
+                Some(ref mut running) => running,
+                None => return Ok(()),
+            };
+
+            match queue {
+                RECEIVE_QUEUE => running.fill(&mut state.pending)?,
+                TRANSMIT_QUEUE => running.drain()?,
+                _ => return Ok(()),
+            }
+            running.outgoing.drain(..).collect::<Vec<_>>()
+        };
+
+        self.send(packets);
+        Ok(())
+    }
+
+    /// Sends packets on to the backend, which mustn't be done with the
+    /// lock held: like network cards, the backend might answer right
+    /// away.
+    fn send(&self, packets: Vec<Packet>) {
+        for packet in packets {
+            if let Err(e) = self.backend.send(packet) {
+                debug!("could not send a vsock packet: {}", e);
+            }
+        }
+    }
+}
+
+/// Delivers a backend's packets to the device.
+#[derive(Debug)]
+struct VsockSink(Arc<Inner>);
+
+impl Sink for VsockSink {
+    fn receive(&self, packet: Packet) {
+        self.0.receive(packet);
+    }
+}
+
+/// A virtio socket device, which carries stream connections between
+/// the guest and its backend on the host.
+#[derive(Debug)]
+pub struct Vsock(Arc<Inner>);
+
+impl Vsock {
+    /// Creates a socket device for the guest with the given context
+    /// ID, and starts taking packets from its backend.
+    pub fn new(cid: u64, backend: Arc<Backend>) -> Result<Vsock> {
+        let inner = Arc::new(Inner {
+            cid,
+            backend,
+            state: Mutex::new(State {
+                running: None,
+                pending: VecDeque::new(),
+            }),
+        });
+        inner.backend.attach(Arc::new(VsockSink(inner.clone())))?;
+        Ok(Vsock(inner))
+    }
+}
+
+impl Virtio for Vsock {
+    fn device_type(&self) -> u16 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn class(&self) -> u32 {
+        0x078000
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; EVENT_QUEUE as usize + 1]
+    }
+
+    fn config_size(&self) -> usize {
+        CONFIG_SIZE
+    }
+
+    fn config_read(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u64(&mut config, self.0.cid);
+        read_into(&config, offset, data);
+    }
+
+    fn activate(&self, activation: Activation) -> Result<()> {
+        let mut state = self.0.state.lock().unwrap();
+        state.running = Some(Running {
+            activation,
+            held: None,
+            outgoing: vec![],
+        });
+        Ok(())
+    }
+
+    fn notify(&self, queue: u16) {
+        if let Err(e) = self.0.notify(queue) {
+            warn!("could not process vsock queue {}: {}", queue, e);
+            if let Some(ref running) = self.0.state.lock().unwrap().running {
+                running.activation.interrupt.needs_reset();
+            }
+        }
+    }
+
+    fn reset(&self) {
+        {
+            let mut state = self.0.state.lock().unwrap();
+            state.running = None;
+            state.pending.clear();
+        }
+        self.0.backend.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio::queue::tests::{Driver, Interrupts};
+    use virtio::VIRTIO_F_VERSION_1;
+    use vsock::{HEADER_SIZE, VSOCK_HOST_CID, VSOCK_OP_REQUEST, VSOCK_OP_RW, VSOCK_TYPE_STREAM};
+
+    /// Keeps whatever it's sent, and sends whatever it's told to.
+    #[derive(Debug, Default)]
+    struct Loopback {
+        sent: Mutex<Vec<Packet>>,
+        resets: Mutex<usize>,
+        sink: Mutex<Option<Arc<Sink>>>,
+    }
+
+    impl Backend for Loopback {
+        fn send(&self, packet: Packet) -> Result<()> {
+            self.sent.lock().unwrap().push(packet);
+            Ok(())
+        }
+
+        fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+            *self.sink.lock().unwrap() = Some(sink);
+            Ok(())
+        }
+
+        fn reset(&self) {
+            *self.resets.lock().unwrap() += 1;
+        }
+    }
+
+    fn packet(op: u16, data: &[u8]) -> Packet {
+        Packet {
+            src_cid: 3,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: 1024,
+            dst_port: 52,
+            kind: VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: 0x1000,
+            data: data.to_vec(),
+            ..Packet::default()
+        }
+    }
+
+    /// A packet from the host to one of the guest's ports.
+    fn to_guest(op: u16, port: u32, data: &[u8]) -> Packet {
+        Packet {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: 3,
+            src_port: 52,
+            dst_port: port,
+            ..packet(op, data)
+        }
+    }
+
+    #[test]
+    fn it_describes_the_guest() {
+        let vsock = Vsock::new(3, Arc::new(Loopback::default())).unwrap();
+        let mut config = [0u8; CONFIG_SIZE];
+        vsock.config_read(0, &mut config);
+        assert_eq!(LittleEndian::read_u64(&config), 3);
+        assert_eq!(vsock.queues().len(), 3);
+    }
+
+    #[test]
+    fn it_carries_packets_both_ways() {
+        let backend = Arc::new(Loopback::default());
+        let vsock = Vsock::new(3, backend.clone()).unwrap();
+        let mut driver = Driver::new(3, VIRTIO_F_VERSION_1);
+        let interrupts = Arc::new(Interrupts::default());
+        vsock
+            .activate(driver.activation(interrupts.clone()))
+            .unwrap();
+
+        let request = packet(VSOCK_OP_REQUEST, &[]);
+        driver.offer(TRANSMIT_QUEUE, &request.to_bytes(), 0, false);
+        driver.offer(TRANSMIT_QUEUE, &[0u8; HEADER_SIZE - 1], 0, false);
+        vsock.notify(TRANSMIT_QUEUE);
+        assert_eq!(*backend.sent.lock().unwrap(), vec![request.clone()]);
+        assert_eq!(driver.used(TRANSMIT_QUEUE).len(), 2);
+
+        // A buffer too small for the packet costs the connection, which
+        // both sides are told is reset.
+        let sink = backend.sink.lock().unwrap().clone().unwrap();
+        driver.offer(RECEIVE_QUEUE, &[], HEADER_SIZE as u32 + 4, true);
+        let long = to_guest(VSOCK_OP_RW, 1024, b"too long");
+        sink.receive(long.clone());
+        let used = driver.used(RECEIVE_QUEUE);
+        assert_eq!(used.len(), 1);
+        assert_eq!(Packet::parse(&used[0]), Some(long.reset().reset()));
+        assert_eq!(backend.sent.lock().unwrap()[1], long.reset());
+
+        driver.offer(RECEIVE_QUEUE, &[], HEADER_SIZE as u32 + 4, true);
+        let reply = request.reset();
+        sink.receive(reply.clone());
+        let used = driver.used(RECEIVE_QUEUE);
+        assert_eq!(used.len(), 1);
+        assert_eq!(Packet::parse(&used[0]), Some(reply));
+        assert_eq!(
+            interrupts.queues(),
+            vec![TRANSMIT_QUEUE, RECEIVE_QUEUE, RECEIVE_QUEUE]
+        );
+
+        vsock.reset();
+        assert_eq!(*backend.resets.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn it_resets_connections_the_guest_falls_behind_on() {
+        let backend = Arc::new(Loopback::default());
+        let vsock = Vsock::new(3, backend.clone()).unwrap();
+        let driver = Driver::new(3, VIRTIO_F_VERSION_1);
+        vsock
+            .activate(driver.activation(Arc::new(Interrupts::default())))
+            .unwrap();
+
+        // Whatever connection the guest's too far behind on when the
+        // packets pile up is reset, and whatever was on the way for it
+        // is dropped.
+        let sink = backend.sink.lock().unwrap().clone().unwrap();
+        sink.receive(to_guest(VSOCK_OP_RW, 1025, b"early"));
+        for _ in 1..MAX_PENDING {
+            sink.receive(to_guest(VSOCK_OP_RW, 1024, b"data"));
+        }
+        assert!(backend.sent.lock().unwrap().is_empty());
+        let late = to_guest(VSOCK_OP_RW, 1025, b"late");
+        sink.receive(late.clone());
+        let reset = late.reset();
+        sink.receive(reset.reset());
+        assert_eq!(*backend.sent.lock().unwrap(), vec![reset.clone()]);
+
+        let state = vsock.0.state.lock().unwrap();
+        assert_eq!(state.pending.len(), MAX_PENDING);
+        assert_eq!(state.pending[0], reset.reset());
+        assert!(state.pending.iter().skip(1).all(|p| p.dst_port == 1024));
+    }
+}
@@ -1,6 +1,7 @@
 error_chain!{
     foreign_links {
         KvmError(::kvm::Error);
+        IoError(::std::io::Error);
     }
 
     errors {
@@ -9,6 +10,41 @@ error_chain!{
             display("could not load instance firmware: {}", reason)
         }
 
+        InterruptError(reason: &'static str) {
+            description("could not deliver interrupt")
+            display("could not deliver interrupt: {}", reason)
+        }
+
+        BusError(reason: &'static str) {
+            description("could not map device")
+            display("could not map device: {}", reason)
+        }
+
+        GuestMemoryError(address: u64) {
+            description("invalid guest memory access")
+            display("invalid guest memory access at {:#x}", address)
+        }
+
+        QueueError(reason: &'static str) {
+            description("invalid virtqueue")
+            display("invalid virtqueue: {}", reason)
+        }
+
+        DeviceError(reason: &'static str) {
+            description("could not set up device")
+            display("could not set up device: {}", reason)
+        }
+
+        ImageError(reason: &'static str) {
+            description("invalid disk image")
+            display("invalid disk image: {}", reason)
+        }
+
+        UsageError(reason: &'static str) {
+            description("invalid usage")
+            display("invalid usage: {}", reason)
+        }
+
         UnknownError
     }
 }
@@ -0,0 +1,395 @@
+//! The `vent image` subcommands, which look after disk images with the
+//! same backends the disks use.
+
+use block::{self, Format, Image, Qcow2, Raw};
+use error::*;
+use std::cmp;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// How much is copied at once when converting.  Pieces that are all
+/// zeroes aren't written, so the copy stays sparse.
+const CONVERT_CHUNK: usize = 1 << 16;
+
+const USAGE: &str = "usage: vent image <command> ...
+
+commands:
+    create [--format raw|qcow2] [--backing FILE] [--backing-format raw|qcow2] FILE [SIZE]
+    info FILE
+    convert [--format raw|qcow2] [--to raw|qcow2] SOURCE DESTINATION
+    resize [--format raw|qcow2] FILE SIZE
+    check FILE
+
+sizes are in bytes, or with a K, M, G or T suffix";
+
+/// Runs the subcommand in `arguments`, which don't include `image`
+/// itself.
+pub fn run(arguments: &[String]) -> Result<()> {
+    let stdout = io::stdout();
+    execute(arguments, &mut stdout.lock())
+}
+
+fn execute(arguments: &[String], out: &mut Write) -> Result<()> {
+    let (command, rest) = match arguments.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => {
+            writeln!(out, "{}", USAGE)?;
+            return Err(ErrorKind::UsageError("no image command given").into());
+        }
+    };
+
+    match command {
+        "create" => create(&Arguments::parse(
+            rest,
+            &["format", "backing", "backing-format"],
+        )?),
+        "info" => info(&Arguments::parse(rest, &[])?, out),
+        "convert" => convert(&Arguments::parse(rest, &["format", "to"])?),
+        "resize" => resize(&Arguments::parse(rest, &["format"])?),
+        "check" => check(&Arguments::parse(rest, &[])?, out),
+        "help" => {
+            writeln!(out, "{}", USAGE)?;
+            Ok(())
+        }
+        _ => {
+            writeln!(out, "{}", USAGE)?;
+            Err(ErrorKind::UsageError("unknown image command").into())
+        }
+    }
+}
+
+/// A command's options, which all take a value, and what's left.
+#[derive(Debug, Default)]
+struct Arguments {
+    options: Vec<(String, String)>,
+    positional: Vec<String>,
+}
+
+impl Arguments {
+    fn parse(arguments: &[String], known: &[&str]) -> Result<Arguments> {
+        let mut parsed = Arguments::default();
+        let mut arguments = arguments.iter();
+        while let Some(argument) = arguments.next() {
+            if !argument.starts_with("--") {
+                parsed.positional.push(argument.clone());
+                continue;
+            }
+
+            let name = &argument[2..];
+            if !known.contains(&name) {
+                return Err(ErrorKind::UsageError("unknown option").into());
+            }
+            match arguments.next() {
+                Some(value) => parsed.options.push((name.to_owned(), value.clone())),
+                None => return Err(ErrorKind::UsageError("option without a value").into()),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|option| option.0 == name)
+            .map(|option| option.1.as_str())
+    }
+
+    fn format(&self, name: &str) -> Result<Option<Format>> {
+        match self.option(name) {
+            Some(format) => parse_format(format).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The positional arguments, of which there have to be between
+    /// `required` and `allowed`.
+    fn positional(&self, required: usize, allowed: usize) -> Result<&[String]> {
+        if self.positional.len() < required {
+            Err(ErrorKind::UsageError("missing arguments").into())
+        } else if self.positional.len() > allowed {
+            Err(ErrorKind::UsageError("too many arguments").into())
+        } else {
+            Ok(&self.positional)
+        }
+    }
+}
+
+fn parse_format(format: &str) -> Result<Format> {
+    match format {
+        "raw" => Ok(Format::Raw),
+        "qcow2" => Ok(Format::Qcow2),
+        _ => Err(ErrorKind::UsageError("unknown image format").into()),
+    }
+}
+
+fn format_name(format: Format) -> &'static str {
+    match format {
+        Format::Raw => "raw",
+        Format::Qcow2 => "qcow2",
+    }
+}
+
+/// Parses a size, which can have a binary suffix, and has to be a
+/// whole number of sectors.
+fn parse_size(size: &str) -> Result<u64> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 30),
+        Some('T') | Some('t') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    let size = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(1 << shift));
+    match size {
+        Some(size) if size % block::SECTOR_SIZE == 0 => Ok(size),
+        Some(_) => Err(ErrorKind::UsageError("size isn't a whole number of sectors").into()),
+        None => Err(ErrorKind::UsageError("invalid size").into()),
+    }
+}
+
+/// Writes a size the way people read it, in the biggest unit it has
+/// a whole one of.
+fn human(size: u64) -> String {
+    let units = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut unit = 0;
+    let mut scaled = size as f64;
+    while scaled >= 1024.0 && unit + 1 < units.len() {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+
+    if scaled.fract() == 0.0 {
+        format!("{} {}", scaled, units[unit])
+    } else {
+        format!("{:.1} {}", scaled, units[unit])
+    }
+}
+
+fn probe(path: &Path, format: Option<Format>) -> Result<Format> {
+    match format {
+        Some(format) => Ok(format),
+        None => Format::probe(path),
+    }
+}
+
+fn create(arguments: &Arguments) -> Result<()> {
+    let positional = arguments.positional(1, 2)?;
+    let path = Path::new(&positional[0]);
+    let format = arguments.format("format")?.unwrap_or(Format::Raw);
+    let backing = arguments.option("backing");
+
+    match (format, backing) {
+        (Format::Raw, Some(_)) => {
+            Err(ErrorKind::UsageError("only qcow2 images have backing files").into())
+        }
+        (Format::Raw, None) => match positional.get(1) {
+            Some(size) => Raw::create(path, parse_size(size)?),
+            None => Err(ErrorKind::UsageError("missing image size").into()),
+        },
+        (Format::Qcow2, Some(backing)) => {
+            // The name is kept as it's given, so it's relative to the
+            // new image rather than to here.
+            let found = match path.parent() {
+                Some(parent) if Path::new(backing).is_relative() => parent.join(backing),
+                _ => PathBuf::from(backing),
+            };
+            let backing_format = probe(&found, arguments.format("backing-format")?)?;
+            let size = match positional.get(1) {
+                Some(size) => parse_size(size)?,
+                None => block::open(&found, Some(backing_format), true, false)?.size(),
+            };
+            Qcow2::create(
+                path,
+                size,
+                block::DEFAULT_CLUSTER_BITS,
+                Some((backing, backing_format)),
+            )
+        }
+        (Format::Qcow2, None) => match positional.get(1) {
+            Some(size) => Qcow2::create(path, parse_size(size)?, block::DEFAULT_CLUSTER_BITS, None),
+            None => Err(ErrorKind::UsageError("missing image size").into()),
+        },
+    }
+}
+
+/// Describes an image, then everything down its backing chain.
+fn info(arguments: &Arguments, out: &mut Write) -> Result<()> {
+    let positional = arguments.positional(1, 1)?;
+    let mut next = Some((PathBuf::from(&positional[0]), None));
+    while let Some((path, format)) = next.take() {
+        let format = probe(&path, format)?;
+        let allocated = fs::metadata(&path)?.blocks() * 512;
+        writeln!(out, "image: {}", path.display())?;
+        writeln!(out, "format: {}", format_name(format))?;
+        match format {
+            Format::Raw => {
+                let size = Raw::open(&path, true, false)?.size();
+                writeln!(out, "virtual size: {} ({} bytes)", human(size), size)?;
+                writeln!(out, "disk size: {}", human(allocated))?;
+            }
+            Format::Qcow2 => {
+                let image = Qcow2::open(&path, true)?;
+                let size = image.size();
+                writeln!(out, "virtual size: {} ({} bytes)", human(size), size)?;
+                writeln!(out, "disk size: {}", human(allocated))?;
+                writeln!(out, "cluster size: {}", image.cluster_size())?;
+                if let Some(&(ref backing, backing_format)) = image.backing_file() {
+                    let named = backing_format.map(format_name).unwrap_or("probed");
+                    writeln!(out, "backing file: {} ({})", backing.display(), named)?;
+                    writeln!(out)?;
+                    next = Some((backing.clone(), backing_format));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn convert(arguments: &Arguments) -> Result<()> {
+    let positional = arguments.positional(2, 2)?;
+    let (source, destination) = (Path::new(&positional[0]), Path::new(&positional[1]));
+    let source = block::open(source, arguments.format("format")?, true, false)?;
+    let size = source.size();
+    let format = arguments.format("to")?.unwrap_or(Format::Raw);
+    match format {
+        Format::Raw => Raw::create(destination, size)?,
+        Format::Qcow2 => Qcow2::create(destination, size, block::DEFAULT_CLUSTER_BITS, None)?,
+    }
+
+    let target = block::open(destination, Some(format), false, false)?;
+    let mut chunk = vec![0u8; CONVERT_CHUNK];
+    let mut offset = 0;
+    while offset < size {
+        let count = cmp::min(size - offset, CONVERT_CHUNK as u64) as usize;
+        source.read_at(offset, &mut chunk[..count])?;
+        if chunk[..count].iter().any(|&b| b != 0) {
+            target.write_at(offset, &chunk[..count])?;
+        }
+        offset += count as u64;
+    }
+
+    target.flush()
+}
+
+fn resize(arguments: &Arguments) -> Result<()> {
+    let positional = arguments.positional(2, 2)?;
+    let path = Path::new(&positional[0]);
+    let size = parse_size(&positional[1])?;
+    match probe(path, arguments.format("format")?)? {
+        Format::Raw => Raw::open(path, false, false)?.resize(size),
+        Format::Qcow2 => Qcow2::open(path, false)?.resize(size),
+    }
+}
+
+/// Checks a qcow2 image's refcounts.  Leaks only waste space, so only
+/// errors make the check fail.
+fn check(arguments: &Arguments, out: &mut Write) -> Result<()> {
+    let positional = arguments.positional(1, 1)?;
+    let path = Path::new(&positional[0]);
+    if Format::probe(path)? != Format::Qcow2 {
+        writeln!(out, "raw images have nothing to check")?;
+        return Ok(());
+    }
+
+    let check = Qcow2::open(path, true)?.check()?;
+    writeln!(
+        out,
+        "{} errors, {} leaked clusters",
+        check.errors, check.leaks
+    )?;
+    if check.errors > 0 {
+        return Err(ErrorKind::ImageError("refcounts are inconsistent").into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch::Scratch;
+
+    /// A scratch path, as the command line would have it.
+    fn path(scratch: &mut Scratch, name: &str) -> String {
+        scratch.path(name).to_str().unwrap().to_owned()
+    }
+
+    fn execute(arguments: &[&str]) -> (Result<()>, String) {
+        let arguments = arguments.iter().map(|&a| a.to_owned()).collect::<Vec<_>>();
+        let mut out = vec![];
+        let result = super::execute(&arguments, &mut out);
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn it_parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("10G").unwrap(), 10 << 30);
+        assert!(parse_size("100").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+        assert_eq!(human(512), "512 bytes");
+        assert_eq!(human(10 << 30), "10 GiB");
+        assert_eq!(human(1536), "1.5 KiB");
+    }
+
+    #[test]
+    fn it_creates_and_describes_images() {
+        let mut scratch = Scratch::new();
+        let base = path(&mut scratch, "image-base.raw");
+        let top = path(&mut scratch, "image-top.qcow2");
+        execute(&["create", &base, "1M"]).0.unwrap();
+        let name = Path::new(&base).file_name().unwrap().to_str().unwrap();
+        execute(&["create", "--format", "qcow2", "--backing", name, &top])
+            .0
+            .unwrap();
+
+        let (result, out) = execute(&["info", &top]);
+        result.unwrap();
+        assert!(out.contains("format: qcow2\nvirtual size: 1 MiB (1048576 bytes)\n"));
+        assert!(out.contains("cluster size: 65536\n"));
+        assert!(out.contains(&format!("backing file: {} (raw)\n", base)));
+        assert!(out.contains(&format!("image: {}\nformat: raw\n", base)));
+
+        let (result, out) = execute(&["check", &top]);
+        result.unwrap();
+        assert_eq!(out, "0 errors, 0 leaked clusters\n");
+        assert!(execute(&["create", "--backing", name, &top]).0.is_err());
+        assert!(execute(&["frobnicate"]).0.is_err());
+    }
+
+    #[test]
+    fn it_converts_and_resizes_images() {
+        let mut scratch = Scratch::new();
+        let raw = path(&mut scratch, "convert.raw");
+        let qcow2 = path(&mut scratch, "convert.qcow2");
+        let back = path(&mut scratch, "convert-back.raw");
+        let mut contents = vec![0u8; 1 << 20];
+        contents[300_000..300_100].copy_from_slice(&[0x5a; 100]);
+        fs::write(&raw, &contents).unwrap();
+
+        execute(&["convert", "--to", "qcow2", &raw, &qcow2])
+            .0
+            .unwrap();
+        execute(&["resize", &qcow2, "8M"]).0.unwrap();
+        execute(&["convert", &qcow2, &back]).0.unwrap();
+
+        let converted = fs::read(&back).unwrap();
+        assert_eq!(converted.len(), 8 << 20);
+        assert_eq!(&converted[..1 << 20], &contents[..]);
+        // Only the one piece with anything in it was written.
+        assert!(fs::metadata(&back).unwrap().blocks() * 512 <= 1 << 20);
+        let (result, out) = execute(&["check", &qcow2]);
+        result.unwrap();
+        assert_eq!(out, "0 errors, 0 leaked clusters\n");
+        assert!(execute(&["resize", &qcow2, "1M"]).0.is_err());
+    }
+}
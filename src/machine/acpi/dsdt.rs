@@ -0,0 +1,425 @@
+use super::mcfg::Allocation;
+use super::sdt::Sdt;
+use byteorder::{ByteOrder, LittleEndian};
+use device::pci::{DEVICES_PER_BUS, ECAM_BUS_SIZE};
+use device::LEGACY_IRQS;
+
+pub const SIGNATURE: [u8; 4] = [b'D', b'S', b'D', b'T'];
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const DEVICE_OP: [u8; 2] = [0x5b, 0x82];
+
+const SYSTEM_BUS: [u8; 5] = [b'\\', b'_', b'S', b'B', b'_'];
+
+const MEMORY32_FIXED: u8 = 0x86;
+const MEMORY32_FIXED_LENGTH: u16 = 9;
+const MEMORY_WRITABLE: u8 = 1 << 0;
+const MEMORY_CACHEABLE: u8 = 1 << 1;
+const WORD_ADDRESS: u8 = 0x88;
+const DWORD_ADDRESS: u8 = 0x87;
+const QWORD_ADDRESS: u8 = 0x8a;
+const ADDRESS_MEMORY: u8 = 0;
+const ADDRESS_IO: u8 = 1;
+const ADDRESS_BUS: u8 = 2;
+// The bridge hands the range out to what's behind it, and the range
+// can't be moved.
+const ADDRESS_FIXED: u8 = 0x0c;
+const IO_ENTIRE_RANGE: u8 = 3;
+const EXTENDED_INTERRUPT: u8 = 0x89;
+const EXTENDED_INTERRUPT_LENGTH: u16 = 6;
+// A level triggered, active high line the device doesn't share.
+const INTERRUPT_CONSUMER: u8 = 1;
+const END_TAG: u8 = 0x79;
+
+/// A PCI Express host bridge: its ECAM window, and the I/O, 32-bit
+/// memory, and 64-bit memory windows it forwards to the devices behind
+/// it, as start and end pairs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HostBridge {
+    pub ecam: Allocation,
+    pub io: (u64, u64),
+    pub memory32: (u64, u64),
+    pub memory64: (u64, u64),
+}
+
+/// A virtio-mmio device: its window of registers, and its interrupt.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MmioDevice {
+    pub base: u64,
+    pub size: u64,
+    pub irq: u32,
+}
+
+/// The differentiated system description table.  It describes each
+/// PCI Express host bridge, with the buses and windows it decodes and
+/// how its slots' interrupts are wired, and reserves the ECAM windows
+/// as motherboard resources, which is how the guest checks that the
+/// MCFG table is telling the truth.  Virtio-mmio devices can't be
+/// enumerated, so each gets a device of its own, with the ID Linux
+/// matches them by.
+pub fn build(hosts: &[HostBridge], mmio: &[MmioDevice]) -> Vec<u8> {
+    let mut devices = vec![];
+    let mut reserved = vec![];
+    for (i, host) in hosts.iter().enumerate() {
+        let allocation = host.ecam;
+        devices.extend(device(
+            [b'P', b'C', b'I', b'0' + i as u8],
+            &[
+                name(b"_HID", &integer(eisa_id(b"PNP0A08") as u64)),
+                name(b"_CID", &integer(eisa_id(b"PNP0A03") as u64)),
+                name(b"_UID", &integer(i as u64)),
+                name(b"_SEG", &integer(allocation.segment as u64)),
+                name(b"_BBN", &integer(allocation.start as u64)),
+                name(b"_CRS", &resources(host_resources(host))),
+                name(b"_PRT", &routing()),
+            ],
+        ));
+
+        let buses = allocation.end as u64 - allocation.start as u64 + 1;
+        reserved.extend(memory32_fixed(
+            allocation.base as u32,
+            (buses * ECAM_BUS_SIZE) as u32,
+        ));
+    }
+
+    for (i, window) in mmio.iter().enumerate() {
+        devices.extend(mmio_device(i, window));
+    }
+
+    if !reserved.is_empty() {
+        devices.extend(device(
+            [b'M', b'R', b'E', b'S'],
+            &[
+                name(b"_HID", &integer(eisa_id(b"PNP0C02") as u64)),
+                name(b"_CRS", &resources(reserved)),
+            ],
+        ));
+    }
+
+    let mut table = Sdt::new(SIGNATURE, 2);
+    table.push(&package(&[SCOPE_OP], &[&SYSTEM_BUS, &devices]));
+    table.finish()
+}
+
+/// Encodes the length of a package, which counts the bytes of the
+/// encoding itself.  Past 63 bytes, the low nibble goes in the first
+/// byte, and the rest in up to three more.
+fn package_length(length: usize) -> Vec<u8> {
+    let extra = match length {
+        0..=62 => return vec![length as u8 + 1],
+        63..=0xffd => 1,
+        0xffe..=0xf_fffc => 2,
+        _ => 3,
+    };
+
+    let total = length + extra + 1;
+    let mut bytes = vec![((extra as u8) << 6) | (total & 0xf) as u8];
+    for i in 0..extra {
+        bytes.push((total >> (4 + 8 * i)) as u8);
+    }
+    bytes
+}
+
+fn package(op: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let body = parts.concat();
+    let mut bytes = op.to_vec();
+    bytes.extend(package_length(body.len()));
+    bytes.extend(body);
+    bytes
+}
+
+fn device(name: [u8; 4], objects: &[Vec<u8>]) -> Vec<u8> {
+    package(&DEVICE_OP, &[&name, &objects.concat()])
+}
+
+/// The buses and windows a host bridge decodes.
+fn host_resources(host: &HostBridge) -> Vec<u8> {
+    let buses = (host.ecam.start as u64, host.ecam.end as u64 + 1);
+    let mut descriptors = address(ADDRESS_BUS, 0, buses, 2);
+    descriptors.extend(address(ADDRESS_IO, IO_ENTIRE_RANGE, host.io, 2));
+    descriptors.extend(address(ADDRESS_MEMORY, MEMORY_WRITABLE, host.memory32, 4));
+    descriptors.extend(address(
+        ADDRESS_MEMORY,
+        MEMORY_WRITABLE | MEMORY_CACHEABLE,
+        host.memory64,
+        8,
+    ));
+    descriptors
+}
+
+/// The interrupt routing table for the root bus.  A device raises the
+/// legacy line for its slot, and the pins after INTA take the lines
+/// after it, the way bridges swizzle them.
+fn routing() -> Vec<u8> {
+    let mut entries = vec![];
+    for slot in 0..DEVICES_PER_BUS {
+        for pin in 0..4 {
+            let irq = LEGACY_IRQS[(slot + pin) % LEGACY_IRQS.len()];
+            entries.push(list(&[
+                integer((slot as u64) << 16 | 0xffff),
+                integer(pin as u64),
+                vec![ZERO_OP],
+                integer(irq as u64),
+            ]));
+        }
+    }
+    list(&entries)
+}
+
+fn mmio_device(index: usize, window: &MmioDevice) -> Vec<u8> {
+    let hex = |digit: usize| b"0123456789ABCDEF"[digit & 0xf];
+    let mut descriptors = memory32_fixed(window.base as u32, window.size as u32);
+    descriptors.extend(interrupt(window.irq));
+    device(
+        [b'V', b'R', hex(index >> 4), hex(index)],
+        &[
+            name(b"_HID", &string("LNRO0005")),
+            name(b"_UID", &integer(index as u64)),
+            name(b"_CRS", &resources(descriptors)),
+        ],
+    )
+}
+
+/// A package of up to 255 elements.
+fn list(elements: &[Vec<u8>]) -> Vec<u8> {
+    package(
+        &[PACKAGE_OP],
+        &[&[elements.len() as u8], &elements.concat()],
+    )
+}
+
+fn name(name: &[u8; 4], value: &[u8]) -> Vec<u8> {
+    [&[NAME_OP], &name[..], value].concat()
+}
+
+/// Encodes an integer in the fewest bytes it fits in.
+fn integer(value: u64) -> Vec<u8> {
+    let mut bytes = [0u8; 9];
+    let length = match value {
+        0 => return vec![ZERO_OP],
+        1 => return vec![ONE_OP],
+        2..=0xff => {
+            bytes[0] = BYTE_PREFIX;
+            1
+        }
+        0x100..=0xffff => {
+            bytes[0] = WORD_PREFIX;
+            2
+        }
+        0x1_0000..=0xffff_ffff => {
+            bytes[0] = DWORD_PREFIX;
+            4
+        }
+        _ => {
+            bytes[0] = QWORD_PREFIX;
+            8
+        }
+    };
+
+    LittleEndian::write_u64(&mut bytes[1..], value);
+    bytes[..length + 1].to_vec()
+}
+
+fn string(value: &str) -> Vec<u8> {
+    let mut bytes = vec![STRING_PREFIX];
+    bytes.extend_from_slice(value.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+/// Compresses a plug and play ID, like the ASL `EISAID` macro: three
+/// letters of five bits each, then four hex digits, stored big-endian.
+fn eisa_id(id: &[u8; 7]) -> u32 {
+    let letters = id[..3].iter().fold(0u32, |value, letter| {
+        (value << 5) | (*letter - 0x40) as u32 & 0x1f
+    });
+    let digits = id[3..].iter().fold(0u32, |value, digit| {
+        (value << 4) | (*digit as char).to_digit(16).unwrap_or(0)
+    });
+    ((letters << 16) | digits).swap_bytes()
+}
+
+fn memory32_fixed(base: u32, length: u32) -> Vec<u8> {
+    let mut bytes = [0u8; 12];
+    bytes[0] = MEMORY32_FIXED;
+    LittleEndian::write_u16(&mut bytes[1..3], MEMORY32_FIXED_LENGTH);
+    bytes[3] = MEMORY_WRITABLE;
+    LittleEndian::write_u32(&mut bytes[4..8], base);
+    LittleEndian::write_u32(&mut bytes[8..12], length);
+    bytes.to_vec()
+}
+
+/// A word, double word, or quad word address space descriptor, by the
+/// `width` of its fields, for the range from `start` up to `end`.
+fn address(kind: u8, flags: u8, (start, end): (u64, u64), width: usize) -> Vec<u8> {
+    let tag = match width {
+        2 => WORD_ADDRESS,
+        4 => DWORD_ADDRESS,
+        _ => QWORD_ADDRESS,
+    };
+    let length = 3 + 5 * width;
+    let mut bytes = vec![0u8; 3 + length];
+    bytes[0] = tag;
+    LittleEndian::write_u16(&mut bytes[1..3], length as u16);
+    bytes[3] = kind;
+    bytes[4] = ADDRESS_FIXED;
+    bytes[5] = flags;
+    // The granularity and the translation stay zero.
+    let fields = [(1, start), (2, end - 1), (4, end - start)];
+    for &(field, value) in fields.iter() {
+        let offset = 6 + width * field;
+        LittleEndian::write_uint(&mut bytes[offset..offset + width], value, width);
+    }
+    bytes
+}
+
+fn interrupt(irq: u32) -> Vec<u8> {
+    let mut bytes = [0u8; 9];
+    bytes[0] = EXTENDED_INTERRUPT;
+    LittleEndian::write_u16(&mut bytes[1..3], EXTENDED_INTERRUPT_LENGTH);
+    bytes[3] = INTERRUPT_CONSUMER;
+    bytes[4] = 1;
+    LittleEndian::write_u32(&mut bytes[5..9], irq);
+    bytes.to_vec()
+}
+
+/// A resource template: the descriptors, then an end tag with a zero
+/// checksum, which tells the guest not to check it.
+fn resources(descriptors: Vec<u8>) -> Vec<u8> {
+    let mut template = descriptors;
+    template.extend_from_slice(&[END_TAG, 0]);
+    let size = integer(template.len() as u64);
+    package(&[BUFFER_OP], &[&size, &template])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mcfg::Allocation;
+    use super::{build, eisa_id, integer, package_length, HostBridge, MmioDevice};
+
+    fn contains(table: &[u8], bytes: &[u8]) -> bool {
+        table.windows(bytes.len()).any(|window| window == bytes)
+    }
+
+    #[test]
+    fn it_encodes_aml_like_the_compiler() {
+        assert_eq!(eisa_id(b"PNP0A03"), 0x030a_d041);
+        assert_eq!(eisa_id(b"PNP0C02"), 0x020c_d041);
+        assert_eq!(package_length(10), vec![11]);
+        assert_eq!(package_length(62), vec![63]);
+        assert_eq!(package_length(63), vec![0x41, 0x04]);
+        assert_eq!(package_length(0x1000), vec![0x83, 0x00, 0x01]);
+        assert_eq!(integer(0), vec![0x00]);
+        assert_eq!(integer(0x1b36), vec![0x0b, 0x36, 0x1b]);
+        assert_eq!(integer(0x1_0000), vec![0x0c, 0x00, 0x00, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn it_describes_virtio_mmio_devices() {
+        let mmio = [
+            MmioDevice {
+                base: 0xc000_0000,
+                size: 0x1000,
+                irq: 5,
+            },
+            MmioDevice {
+                base: 0xc000_1000,
+                size: 0x1000,
+                irq: 9,
+            },
+        ];
+        let table = build(&[], &mmio);
+
+        // Device (VR01) {
+        //     Name (_HID, "LNRO0005")
+        //     Name (_UID, One)
+        //     Name (_CRS, ResourceTemplate () {
+        //         Memory32Fixed (ReadWrite, 0xC0001000, 0x1000)
+        //         Interrupt (ResourceConsumer, Level, ActiveHigh, Exclusive) { 9 }
+        //     })
+        // }
+        let expected = [
+            0x5b, 0x82, 0x3a, b'V', b'R', b'0', b'1', 0x08, b'_', b'H', b'I', b'D', 0x0d, b'L',
+            b'N', b'R', b'O', b'0', b'0', b'0', b'5', 0x00, 0x08, b'_', b'U', b'I', b'D', 0x01,
+            0x08, b'_', b'C', b'R', b'S', 0x11, 0x1a, 0x0a, 0x17, 0x86, 0x09, 0x00, 0x01, 0x00,
+            0x10, 0x00, 0xc0, 0x00, 0x10, 0x00, 0x00, 0x89, 0x06, 0x00, 0x01, 0x01, 0x09, 0x00,
+            0x00, 0x00, 0x79, 0x00,
+        ];
+        assert!(contains(&table, &expected));
+        assert!(contains(&table, b"VR00"));
+        // The header, then the scope around the two devices.
+        assert_eq!(table.len(), 36 + 8 + 2 * expected.len());
+    }
+
+    #[test]
+    fn it_describes_what_the_host_bridge_decodes() {
+        let host = HostBridge {
+            ecam: Allocation {
+                base: 0xc000_0000,
+                segment: 0,
+                start: 0,
+                end: 0xff,
+            },
+            io: (0xc000, 0x1_0000),
+            memory32: (0xd000_0000, 0xfec0_0000),
+            memory64: (0x1_0000_0000, 0x11_0000_0000),
+        };
+        let table = build(&[host], &[]);
+
+        // WordBusNumber (ResourceProducer, MinFixed, MaxFixed, ...,
+        //     0x0000, 0x0000, 0x00FF, 0x0000, 0x0100)
+        assert!(contains(
+            &table,
+            &[
+                0x88, 0x0d, 0x00, 0x02, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00,
+                0x00, 0x01,
+            ]
+        ));
+        // WordIO (ResourceProducer, MinFixed, MaxFixed, ..., EntireRange,
+        //     0x0000, 0xC000, 0xFFFF, 0x0000, 0x4000)
+        assert!(contains(
+            &table,
+            &[
+                0x88, 0x0d, 0x00, 0x01, 0x0c, 0x03, 0x00, 0x00, 0x00, 0xc0, 0xff, 0xff, 0x00, 0x00,
+                0x00, 0x40,
+            ]
+        ));
+        // DWordMemory (ResourceProducer, ..., MinFixed, MaxFixed,
+        //     NonCacheable, ReadWrite, 0x00000000, 0xD0000000,
+        //     0xFEBFFFFF, 0x00000000, 0x2EC00000)
+        assert!(contains(
+            &table,
+            &[
+                0x87, 0x17, 0x00, 0x00, 0x0c, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xd0,
+                0xff, 0xff, 0xbf, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x2e,
+            ]
+        ));
+        // The start of QWordMemory (ResourceProducer, ..., MinFixed,
+        //     MaxFixed, Cacheable, ReadWrite, 0x0, 0x100000000, ...)
+        assert!(contains(
+            &table,
+            &[
+                0x8a, 0x2b, 0x00, 0x00, 0x0c, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            ]
+        ));
+
+        // Package () { 0x0001FFFF, 2, Zero, 11 }: INTC of slot 1 goes
+        // where INTA of slot 3 does.
+        assert!(contains(
+            &table,
+            &[0x12, 0x0c, 0x04, 0x0c, 0xff, 0xff, 0x01, 0x00, 0x0a, 0x02, 0x00, 0x0a, 0x0b]
+        ));
+        assert!(contains(&table, b"_PRT\x12"));
+    }
+}
@@ -0,0 +1,46 @@
+use super::sdt::Sdt;
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const SIGNATURE: [u8; 4] = [b'F', b'A', b'C', b'P'];
+
+/// The ACPI 6.0 table is 276 bytes long, header included.
+const LENGTH: usize = 276;
+const REVISION: u8 = 6;
+
+// Offsets into the table, header included.
+const DSDT: usize = 40;
+const SCI_INT: usize = 46;
+const IAPC_BOOT_ARCH: usize = 109;
+const FLAGS: usize = 112;
+const MINOR_VERSION: usize = 131;
+const X_DSDT: usize = 140;
+
+const SCI_IRQ: u16 = 9;
+const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+const FLAGS_WBINVD: u32 = 1 << 0;
+const FLAGS_PROC_C1: u32 = 1 << 2;
+const FLAGS_PWR_BUTTON: u32 = 1 << 4;
+const FLAGS_SLP_BUTTON: u32 = 1 << 5;
+const FLAGS_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// The fixed ACPI description table.  We have none of the fixed
+/// hardware it describes, which the hardware-reduced flag tells the
+/// guest, so it is mostly there to point at the DSDT.  Without an SMI
+/// command port, the guest takes the machine to already be in ACPI
+/// mode.
+pub fn build(dsdt: u64) -> Vec<u8> {
+    let mut body = [0u8; LENGTH];
+    LittleEndian::write_u32(&mut body[DSDT..], dsdt as u32);
+    LittleEndian::write_u16(&mut body[SCI_INT..], SCI_IRQ);
+    LittleEndian::write_u16(&mut body[IAPC_BOOT_ARCH..], BOOT_ARCH_VGA_NOT_PRESENT);
+    LittleEndian::write_u32(
+        &mut body[FLAGS..],
+        FLAGS_WBINVD | FLAGS_PROC_C1 | FLAGS_PWR_BUTTON | FLAGS_SLP_BUTTON | FLAGS_HW_REDUCED_ACPI,
+    );
+    body[MINOR_VERSION] = 0;
+    LittleEndian::write_u64(&mut body[X_DSDT..], dsdt);
+
+    let mut table = Sdt::new(SIGNATURE, REVISION);
+    table.push(&body[36..]);
+    table.finish()
+}
@@ -0,0 +1,33 @@
+use super::sdt::Sdt;
+
+pub const SIGNATURE: [u8; 4] = [b'M', b'C', b'F', b'G'];
+
+/// One ECAM window: its base address, PCI segment group, and the
+/// range of buses it covers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Allocation {
+    pub base: u64,
+    pub segment: u16,
+    pub start: u8,
+    pub end: u8,
+}
+
+/// The PCI Express memory mapped configuration space table, which
+/// tells the guest where the ECAM windows are.
+pub fn build(allocations: &[Allocation]) -> Vec<u8> {
+    let mut table = Sdt::new(SIGNATURE, 1);
+    table.push(&[0u8; 8]);
+
+    for allocation in allocations {
+        table.push_u64(allocation.base);
+        table.push(&[
+            allocation.segment as u8,
+            (allocation.segment >> 8) as u8,
+            allocation.start,
+            allocation.end,
+        ]);
+        table.push_u32(0);
+    }
+
+    table.finish()
+}
@@ -1,9 +1,69 @@
-// use super::Machine;
-// use error::*;
+use super::{pci_windows, Machine};
+use super::{PCI_ECAM_BUSES, PCI_ECAM_START};
+use error::*;
 
-// mod rsdp;
-// use self::rsdp::Rsdp;
+mod dsdt;
+mod fadt;
+mod mcfg;
+mod rsdp;
+mod sdt;
 
-// pub(super) fn prepare(machine: &mut Machine) -> Result<()> {
-//     unreachable!();
-// }
+pub(super) use self::dsdt::MmioDevice;
+use self::rsdp::Rsdp;
+use self::sdt::Sdt;
+
+const XSDT_SIGNATURE: [u8; 4] = [b'X', b'S', b'D', b'T'];
+
+fn align(value: u64) -> u64 {
+    (value + 15) & !15
+}
+
+/// Lays out the ACPI tables right after the RSDP, at the bottom of the
+/// BIOS area.  The XSDT lists the FADT and the MCFG; the DSDT is only
+/// reachable through the FADT, so it goes first.
+pub(super) fn prepare(machine: &mut Machine, memory: u64) -> Result<()> {
+    let mut hosts = vec![];
+    if machine.pci().is_some() {
+        let (io, memory32, memory64) = pci_windows(memory);
+        hosts.push(dsdt::HostBridge {
+            ecam: mcfg::Allocation {
+                base: PCI_ECAM_START,
+                segment: 0,
+                start: 0,
+                end: (PCI_ECAM_BUSES - 1) as u8,
+            },
+            io,
+            memory32,
+            memory64,
+        });
+    }
+    let allocations = hosts.iter().map(|host| host.ecam).collect::<Vec<_>>();
+
+    let mut tables = vec![];
+    if !allocations.is_empty() {
+        tables.push(mcfg::build(&allocations));
+    }
+
+    let xsdt_address = align(rsdp::LOCATION + rsdp::LENGTH as u64);
+    // The FADT isn't built yet, since it needs the DSDT's address.
+    let xsdt_length = 36 + 8 * (tables.len() + 1) as u64;
+    let dsdt_address = align(xsdt_address + xsdt_length);
+    let dsdt = dsdt::build(&hosts, &machine.mmio);
+    machine.memory().write(dsdt_address, &dsdt)?;
+    tables.insert(0, fadt::build(dsdt_address));
+
+    let mut next = align(dsdt_address + dsdt.len() as u64);
+    let mut xsdt = Sdt::new(XSDT_SIGNATURE, 1);
+
+    for table in &tables {
+        machine.memory().write(next, table)?;
+        xsdt.push_u64(next);
+        next = align(next + table.len() as u64);
+    }
+
+    machine.memory().write(xsdt_address, &xsdt.finish())?;
+    machine
+        .memory()
+        .write(rsdp::LOCATION, &Rsdp::new(xsdt_address).to_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const OEM: [u8; 6] = [b'V', b'E', b'N', b'T', b'1', b'0'];
+pub const OEM_TABLE: [u8; 8] = [b'V', b'E', b'N', b'T', b'V', b'M', b' ', b' '];
+pub const CREATOR: [u8; 4] = [b'V', b'E', b'N', b'T'];
+
+const HEADER_LENGTH: usize = 36;
+
+/// Computes the value that makes the bytes sum to zero, which is how
+/// every ACPI checksum works.
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// A system description table: the standard 36 byte header, followed
+/// by a table-specific body.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Sdt(Vec<u8>);
+
+impl Sdt {
+    pub fn new(signature: [u8; 4], revision: u8) -> Sdt {
+        let mut bytes = vec![0u8; HEADER_LENGTH];
+        bytes[0..4].copy_from_slice(&signature);
+        bytes[8] = revision;
+        bytes[10..16].copy_from_slice(&OEM);
+        bytes[16..24].copy_from_slice(&OEM_TABLE);
+        LittleEndian::write_u32(&mut bytes[24..28], 1);
+        bytes[28..32].copy_from_slice(&CREATOR);
+        LittleEndian::write_u32(&mut bytes[32..36], 1);
+        Sdt(bytes)
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub fn push_u32(&mut self, value: u32) {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, value);
+        self.push(&bytes);
+    }
+
+    pub fn push_u64(&mut self, value: u64) {
+        let mut bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bytes, value);
+        self.push(&bytes);
+    }
+
+    /// Fills in the length and checksum, and returns the table.
+    pub fn finish(mut self) -> Vec<u8> {
+        let length = self.0.len() as u32;
+        LittleEndian::write_u32(&mut self.0[4..8], length);
+        self.0[9] = 0;
+        self.0[9] = checksum(&self.0);
+        self.0
+    }
+}
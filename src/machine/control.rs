@@ -0,0 +1,229 @@
+use super::super::block::engine::{Counters, Statistics};
+use super::super::configuration::DiskConfiguration;
+use super::super::device::interrupt::Interrupts;
+use super::super::device::memory::GuestMemory;
+use super::super::device::pci::{Host, Pci};
+use super::super::device::{Disk, Network};
+use super::super::error::*;
+use super::super::virtio::PciTransport;
+use super::Machine;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+/// What the control socket works on: the parts of the machine that can
+/// change while it runs.
+///
+/// Clients send one command a line, and get one line back for each,
+/// either `OK`, maybe followed by what they asked for, or `ERR` and
+/// what went wrong.  The commands are:
+///
+/// - `plug <slot> <path>`: plugs a disk with the image at `path` into
+///   the hot-plug slot with the given number.
+/// - `unplug <slot>`: asks the guest to give up the device in the
+///   slot; it's gone once the guest has powered the slot down.
+/// - `plugged <slot>`: `true` if the slot holds a device, and `false`
+///   if it doesn't.
+/// - `snapshot <disk> <path>`: takes an external snapshot of the disk
+///   at the given position in the configuration; the guest's writes go
+///   to a new overlay at `path` from then on.
+/// - `statistics <disk>`: what the guest did with the disk so far; see
+///   `describe`.
+/// - `capture <network> <path>`: records the frames going through the
+///   network card at the given position in the configuration to a
+///   pcapng file at `path`.
+/// - `uncapture <network>`: stops recording the card's frames.
+/// - `capturing <network>`: `true` if the card's frames are being
+///   recorded, and `false` if they aren't.
+#[derive(Debug)]
+struct Control {
+    disks: Vec<Arc<Disk>>,
+    networks: Vec<Arc<Network>>,
+    pci: Option<Arc<Host>>,
+    memory: Arc<GuestMemory>,
+    interrupts: Arc<Interrupts>,
+}
+
+/// Listens for control clients on a Unix socket at `path`, one at a
+/// time.
+pub fn serve<P: AsRef<Path>>(machine: &Machine, path: P) -> Result<()> {
+    let path = path.as_ref();
+    if fs::symlink_metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let control = Control {
+        disks: machine.disks().to_vec(),
+        networks: machine.networks().to_vec(),
+        pci: machine.pci(),
+        memory: machine.memory(),
+        interrupts: machine.interrupts(),
+    };
+
+    thread::Builder::new()
+        .name("control".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => control.attend(stream),
+                    Err(e) => warn!("could not accept control client: {}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+impl Control {
+    fn attend(&self, stream: UnixStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(e) => {
+                warn!("could not set up control client: {}", e);
+                return;
+            }
+        };
+
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+
+            let reply = match self.execute(&line) {
+                Ok(ref reply) if reply.is_empty() => "OK".to_owned(),
+                Ok(reply) => format!("OK {}", reply),
+                Err(e) => format!("ERR {}", e),
+            };
+            if writeln!(writer, "{}", reply).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn execute(&self, line: &str) -> Result<String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["plug", slot, path] => self.plug(number(slot)?, path).map(|_| String::new()),
+            ["unplug", slot] => self.host()?.unplug(number(slot)?).map(|_| String::new()),
+            ["plugged", slot] => self.host()?.plugged(number(slot)?).map(|p| p.to_string()),
+            ["snapshot", disk, path] => self.disk(disk)?.snapshot(path).map(|_| String::new()),
+            ["statistics", disk] => Ok(describe(&self.disk(disk)?.statistics())),
+            ["capture", network, path] => self
+                .network(network)?
+                .start_capture(path)
+                .map(|_| String::new()),
+            ["uncapture", network] => {
+                self.network(network)?.stop_capture();
+                Ok(String::new())
+            }
+            ["capturing", network] => Ok(self.network(network)?.capturing().to_string()),
+            _ => Err(ErrorKind::UsageError("unknown control command").into()),
+        }
+    }
+
+    fn host(&self) -> Result<&Host> {
+        match self.pci {
+            Some(ref host) => Ok(host),
+            None => Err(ErrorKind::UsageError("machine has no PCI").into()),
+        }
+    }
+
+    fn disk(&self, word: &str) -> Result<&Disk> {
+        self.disks
+            .get(number(word)? as usize)
+            .map(|disk| disk.as_ref())
+            .ok_or_else(|| ErrorKind::UsageError("no such disk").into())
+    }
+
+    fn network(&self, word: &str) -> Result<&Network> {
+        self.networks
+            .get(number(word)? as usize)
+            .map(|network| network.as_ref())
+            .ok_or_else(|| ErrorKind::UsageError("no such network card").into())
+    }
+
+    fn plug(&self, slot: u16, path: &str) -> Result<()> {
+        let host = self.host()?;
+        let config = DiskConfiguration {
+            path: path.to_owned(),
+            format: None,
+            read_only: false,
+            overlay: None,
+            ephemeral: false,
+            direct: false,
+            queue_depth: None,
+            serial: Some(format!("vent-slot-{}", slot)),
+            throttle: None,
+        };
+        let disk = Disk::open(&config, 0)?;
+        let transport = PciTransport::new(
+            disk.block(),
+            self.memory.clone(),
+            self.interrupts.clone(),
+            None,
+        )?;
+        host.plug(slot, Arc::new(transport) as Arc<Pci>)
+    }
+}
+
+fn number(word: &str) -> Result<u16> {
+    word.parse()
+        .map_err(|_| ErrorKind::UsageError("invalid number").into())
+}
+
+/// Puts a disk's statistics on one line: for each kind of operation,
+/// its name, then `ops=`, `bytes=`, and `errors=` with the counts, and
+/// `latency=` with the latency buckets, separated by commas.
+fn describe(statistics: &Statistics) -> String {
+    [
+        counts("read", &statistics.read),
+        counts("write", &statistics.write),
+        counts("flush", &statistics.flush),
+        counts("discard", &statistics.discard),
+    ]
+    .join(" ")
+}
+
+fn counts(name: &str, counters: &Counters) -> String {
+    let latency = counters
+        .latency
+        .iter()
+        .map(|count| count.to_string())
+        .collect::<Vec<_>>();
+    format!(
+        "{} ops={} bytes={} errors={} latency={}",
+        name,
+        counters.operations,
+        counters.bytes,
+        counters.errors,
+        latency.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::describe;
+    use block::engine::Statistics;
+
+    #[test]
+    fn it_describes_statistics_on_one_line() {
+        let mut statistics = Statistics::default();
+        statistics.read.operations = 2;
+        statistics.read.bytes = 1024;
+        statistics.read.latency[3] = 2;
+        statistics.write.errors = 1;
+
+        let description = describe(&statistics);
+        let kinds = description.split(' ').collect::<Vec<_>>();
+        assert_eq!(kinds.len(), 20);
+        assert_eq!(&kinds[..4], &["read", "ops=2", "bytes=1024", "errors=0"]);
+        assert!(kinds[4].starts_with("latency=0,0,0,2,0,"));
+        assert_eq!(kinds[8], "errors=1");
+        assert_eq!(kinds[15], "discard");
+    }
+}
@@ -1,5 +1,6 @@
 use super::super::error::*;
 use super::device;
+use super::device::bus::Bus;
 use super::Machine;
 use kvm;
 use kvm::core::Pause;
@@ -21,7 +22,11 @@ fn dump_core_data(core: &mut kvm::Core) {
     );
 }
 
-pub fn run(core: kvm::Core, devices: Vec<Arc<device::Device>>) -> thread::JoinHandle<()> {
+pub fn run(
+    core: kvm::Core,
+    devices: Vec<Arc<device::Device>>,
+    bus: Arc<Bus>,
+) -> thread::JoinHandle<()> {
     let core = Arc::new(Mutex::new(core));
     let mut reverse = HashMap::new();
 
@@ -56,7 +61,7 @@ pub fn run(core: kvm::Core, devices: Vec<Arc<device::Device>>) -> thread::JoinHa
                 for i in 0..count {
                     let start = data_offset as usize + (i as usize * size as usize);
                     let mut mem = vec![0; size as usize];
-                    core.value.read_bytes(data_offset as usize, &mut mem);
+                    core.value.read_bytes(start, &mut mem);
                     let addr = kvm::core::IoAddress::Port(port as u64);
                     let action = kvm::core::IoAction(addr, direction, size as usize);
 
@@ -65,6 +70,7 @@ pub fn run(core: kvm::Core, devices: Vec<Arc<device::Device>>) -> thread::JoinHa
                             device.handle(action, &mut mem);
                         }),
                         _ => {
+                            bus.dispatch(action, &mut mem);
                             // warn!("action: {:x?}: {:x?}", action, mem);
                         }
                     }
@@ -72,6 +78,23 @@ pub fn run(core: kvm::Core, devices: Vec<Arc<device::Device>>) -> thread::JoinHa
                     core.value.write_bytes(start, &mem);
                 }
             }
+            Pause::Mmio {
+                address,
+                direction,
+                size,
+                data_offset,
+            } => {
+                let mut mem = vec![0; size as usize];
+                core.value.read_bytes(data_offset as usize, &mut mem);
+                let addr = kvm::core::IoAddress::Memory(address);
+                let action = kvm::core::IoAction(addr, direction, size as usize);
+
+                if bus.dispatch(action, &mut mem).is_none() {
+                    debug!("unhandled mmio: {:x?}: {:x?}", action, mem);
+                }
+
+                core.value.write_bytes(data_offset as usize, &mem);
+            }
             p => {
                 error!("unknown pause reason {:x?}", p);
                 dump_core_data(&mut core);
@@ -0,0 +1,289 @@
+use super::super::device::interrupt::{Interrupts, Message};
+use super::super::error::*;
+use libc;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Mutex;
+
+const KVM_IRQ_LINE: libc::c_ulong = 0x4008_ae61;
+const KVM_SET_GSI_ROUTING: libc::c_ulong = 0x4008_ae6a;
+const KVM_SIGNAL_MSI: libc::c_ulong = 0x4020_aea5;
+const KVM_IRQFD: libc::c_ulong = 0x4020_ae76;
+const KVM_IOEVENTFD: libc::c_ulong = 0x4040_ae79;
+
+const KVM_IRQFD_FLAG_DEASSIGN: u32 = 1 << 0;
+const KVM_IOEVENTFD_FLAG_DATAMATCH: u32 = 1 << 0;
+const KVM_IOEVENTFD_FLAG_DEASSIGN: u32 = 1 << 2;
+
+const KVM_IRQ_ROUTING_IRQCHIP: u32 = 1;
+const KVM_IRQ_ROUTING_MSI: u32 = 2;
+
+const KVM_IRQCHIP_PIC_MASTER: u32 = 0;
+const KVM_IRQCHIP_PIC_SLAVE: u32 = 1;
+const KVM_IRQCHIP_IOAPIC: u32 = 2;
+
+/// The number of pins on the emulated IOAPIC.  GSIs below this are
+/// reserved for legacy interrupt lines; everything above is handed out
+/// for message signaled interrupts.
+const IOAPIC_PINS: u32 = 24;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct IrqLevel {
+    irq: u32,
+    level: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct Msi {
+    address_lo: u32,
+    address_hi: u32,
+    data: u32,
+    flags: u32,
+    devid: u32,
+    _pad: [u8; 12],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct Irqfd {
+    fd: u32,
+    gsi: u32,
+    flags: u32,
+    resamplefd: u32,
+    _pad: [u8; 16],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Ioeventfd {
+    datamatch: u64,
+    address: u64,
+    length: u32,
+    fd: i32,
+    flags: u32,
+    _pad: [u8; 36],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct RoutingEntry {
+    gsi: u32,
+    kind: u32,
+    flags: u32,
+    _pad: u32,
+    /// Either `{ irqchip, pin }` or `{ address_lo, address_hi, data,
+    /// devid }`, depending on `kind`.
+    u: [u32; 8],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct Routing {
+    nr: u32,
+    flags: u32,
+}
+
+#[derive(Debug)]
+struct State {
+    next: u32,
+    routes: BTreeMap<u32, Message>,
+}
+
+/// Delivers interrupts through the in-kernel irqchip.  KVM's GSI
+/// routing table can only be replaced as a whole, so we keep a copy of
+/// every MSI route here and resubmit the entire table on change.
+#[derive(Debug)]
+pub struct Controller(File, Mutex<State>);
+
+impl Controller {
+    pub fn new(machine: RawFd) -> Result<Controller> {
+        let fd = unsafe { libc::dup(machine) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Controller(
+            unsafe { File::from_raw_fd(fd) },
+            Mutex::new(State {
+                next: IOAPIC_PINS,
+                routes: BTreeMap::new(),
+            }),
+        ))
+    }
+
+    fn ioctl<T>(&self, request: libc::c_ulong, value: &T) -> Result<libc::c_int> {
+        let result = unsafe { libc::ioctl(self.0.as_raw_fd(), request, value as *const T) };
+        if result < 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// The routing entries KVM installs by default for the PIC and
+    /// IOAPIC.  Setting a routing table replaces these, so they have
+    /// to be included every time.
+    fn defaults() -> Vec<RoutingEntry> {
+        let mut entries = vec![];
+
+        for pin in 0..16 {
+            if pin == 2 {
+                continue;
+            }
+
+            let chip = if pin < 8 {
+                KVM_IRQCHIP_PIC_MASTER
+            } else {
+                KVM_IRQCHIP_PIC_SLAVE
+            };
+            let mut entry = RoutingEntry {
+                gsi: pin,
+                kind: KVM_IRQ_ROUTING_IRQCHIP,
+                ..Default::default()
+            };
+            entry.u[0] = chip;
+            entry.u[1] = pin % 8;
+            entries.push(entry);
+        }
+
+        for pin in 0..IOAPIC_PINS {
+            let mut entry = RoutingEntry {
+                gsi: pin,
+                kind: KVM_IRQ_ROUTING_IRQCHIP,
+                ..Default::default()
+            };
+            entry.u[0] = KVM_IRQCHIP_IOAPIC;
+            entry.u[1] = pin;
+            entries.push(entry);
+        }
+
+        entries
+    }
+
+    fn commit(&self, state: &State) -> Result<()> {
+        let mut entries = Controller::defaults();
+
+        for (gsi, message) in &state.routes {
+            let mut entry = RoutingEntry {
+                gsi: *gsi,
+                kind: KVM_IRQ_ROUTING_MSI,
+                ..Default::default()
+            };
+            entry.u[0] = message.address as u32;
+            entry.u[1] = (message.address >> 32) as u32;
+            entry.u[2] = message.data;
+            entries.push(entry);
+        }
+
+        // struct kvm_irq_routing ends in a flexible array member, so
+        // we lay the header and the entries out in a single buffer of
+        // entry-sized (and so suitably aligned) chunks.
+        let header = mem::size_of::<Routing>();
+        let mut buffer = vec![RoutingEntry::default(); entries.len() + 1];
+        unsafe {
+            let base = buffer.as_mut_ptr() as *mut u8;
+            *(base as *mut Routing) = Routing {
+                nr: entries.len() as u32,
+                flags: 0,
+            };
+            let start = base.offset(header as isize) as *mut RoutingEntry;
+            for (i, entry) in entries.iter().enumerate() {
+                *start.offset(i as isize) = *entry;
+            }
+        }
+
+        self.ioctl(KVM_SET_GSI_ROUTING, &buffer[0]).map(|_| ())
+    }
+}
+
+impl Interrupts for Controller {
+    fn line(&self, irq: u32, level: bool) -> Result<()> {
+        let level = IrqLevel {
+            irq,
+            level: level as u32,
+        };
+        self.ioctl(KVM_IRQ_LINE, &level).map(|_| ())
+    }
+
+    fn signal(&self, message: Message) -> Result<()> {
+        let msi = Msi {
+            address_lo: message.address as u32,
+            address_hi: (message.address >> 32) as u32,
+            data: message.data,
+            ..Default::default()
+        };
+
+        match self.ioctl(KVM_SIGNAL_MSI, &msi)? {
+            0 => Err(ErrorKind::InterruptError("message was blocked by the guest").into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn allocate(&self) -> Result<u32> {
+        let mut state = self.1.lock().unwrap();
+        let gsi = state.next;
+        state.next += 1;
+        Ok(gsi)
+    }
+
+    fn route(&self, gsi: u32, message: Option<Message>) -> Result<()> {
+        if gsi < IOAPIC_PINS {
+            return Err(ErrorKind::InterruptError("cannot reroute a legacy interrupt line").into());
+        }
+
+        let mut state = self.1.lock().unwrap();
+        let changed = match message {
+            Some(message) => state.routes.insert(gsi, message) != Some(message),
+            None => state.routes.remove(&gsi).is_some(),
+        };
+
+        if changed {
+            self.commit(&state)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn irqfd(&self, gsi: u32, fd: RawFd, attach: bool) -> Result<()> {
+        let irqfd = Irqfd {
+            fd: fd as u32,
+            gsi,
+            flags: if attach { 0 } else { KVM_IRQFD_FLAG_DEASSIGN },
+            ..Default::default()
+        };
+        self.ioctl(KVM_IRQFD, &irqfd).map(|_| ())
+    }
+
+    fn ioeventfd(
+        &self,
+        address: u64,
+        length: u32,
+        data: Option<u64>,
+        fd: RawFd,
+        attach: bool,
+    ) -> Result<()> {
+        let mut flags = if attach {
+            0
+        } else {
+            KVM_IOEVENTFD_FLAG_DEASSIGN
+        };
+        if data.is_some() {
+            flags |= KVM_IOEVENTFD_FLAG_DATAMATCH;
+        }
+
+        let ioeventfd = Ioeventfd {
+            datamatch: data.unwrap_or(0),
+            address,
+            length,
+            fd,
+            flags,
+            _pad: [0; 36],
+        };
+        self.ioctl(KVM_IOEVENTFD, &ioeventfd).map(|_| ())
+    }
+}
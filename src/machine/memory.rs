@@ -0,0 +1,122 @@
+use super::super::device::memory::{GuestMemory, MemoryRegion};
+use super::super::error::*;
+use kvm::memory::Slab;
+use libc;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+struct Region {
+    base: u64,
+    size: u64,
+    slab: Arc<Mutex<Slab>>,
+    /// The memfd the region is mapped from, once it's shared.
+    file: Option<Arc<File>>,
+}
+
+/// The guest's physical memory, as a set of regions that were handed
+/// to KVM.
+#[derive(Debug, Clone, Default)]
+pub struct Memory(Vec<Region>);
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory::default()
+    }
+
+    pub fn push(&mut self, base: u64, size: u64, slab: Arc<Mutex<Slab>>) {
+        self.0.push(Region {
+            base,
+            size,
+            slab,
+            file: None,
+        });
+    }
+
+    /// Moves the guest's memory into memfds, so other processes (like
+    /// vhost-user backends) can map it too.  Each region is mapped
+    /// afresh over where KVM already has it, so this has to happen
+    /// before anything's put in it.
+    pub fn share(&mut self) -> Result<()> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        for region in &mut self.0 {
+            if region.file.is_some() {
+                continue;
+            }
+
+            let mut slab = region.slab.lock().unwrap();
+            let (start, length) = (slab.as_mut_ptr(), slab.len());
+            if start as usize % page != 0 || length % page != 0 {
+                return Err(ErrorKind::GuestMemoryError(region.base).into());
+            }
+
+            let fd = unsafe {
+                libc::memfd_create(b"vent-memory\0".as_ptr() as *const _, libc::MFD_CLOEXEC)
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let file = unsafe { File::from_raw_fd(fd) };
+            file.set_len(length as u64)?;
+            let mapped = unsafe {
+                libc::mmap(
+                    start as *mut libc::c_void,
+                    length,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if mapped == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error().into());
+            }
+            region.file = Some(Arc::new(file));
+        }
+        Ok(())
+    }
+
+    /// Finds the region containing the given range, and the offset of
+    /// the range into it.  Ranges can't span regions.
+    fn find(&self, address: u64, length: usize) -> Result<(&Region, usize)> {
+        self.0
+            .iter()
+            .find(|region| {
+                address >= region.base
+                    && address
+                        .checked_add(length as u64)
+                        .map(|end| end <= region.base + region.size)
+                        .unwrap_or(false)
+            })
+            .map(|region| (region, (address - region.base) as usize))
+            .ok_or_else(|| ErrorKind::GuestMemoryError(address).into())
+    }
+}
+
+impl GuestMemory for Memory {
+    fn read(&self, address: u64, data: &mut [u8]) -> Result<()> {
+        let (region, offset) = self.find(address, data.len())?;
+        region.slab.lock().unwrap().read_bytes(offset, data);
+        Ok(())
+    }
+
+    fn write(&self, address: u64, data: &[u8]) -> Result<()> {
+        let (region, offset) = self.find(address, data.len())?;
+        region.slab.lock().unwrap().write_bytes(offset, data);
+        Ok(())
+    }
+
+    fn regions(&self) -> Vec<MemoryRegion> {
+        self.0
+            .iter()
+            .map(|region| MemoryRegion {
+                guest: region.base,
+                size: region.size,
+                host: region.slab.lock().unwrap().as_ptr() as u64,
+                fd: region.file.as_ref().map(|file| file.as_raw_fd()),
+            })
+            .collect()
+    }
+}
@@ -1,56 +1,185 @@
 use super::configuration::MachineConfiguration;
 use super::device;
+use super::device::bus::Bus;
+use super::device::interrupt::Interrupts;
+use super::device::memory::GuestMemory;
+use super::device::pci::{Allocator, Host, Window, ECAM_BUS_SIZE};
 use super::error::*;
 use kvm;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 
 mod acpi;
 mod bios;
+mod control;
 mod core;
+mod interrupt;
+mod memory;
 
 pub struct Machine {
     pub mach: kvm::Machine,
     cores: Vec<kvm::Core>,
     devices: Vec<Arc<device::Device>>,
+    disks: Vec<Arc<device::Disk>>,
+    networks: Vec<Arc<device::Network>>,
+    interrupts: Arc<interrupt::Controller>,
+    bus: Arc<Bus>,
+    pci: Option<Arc<Host>>,
+    mmio: Vec<acpi::MmioDevice>,
+    memory: memory::Memory,
 }
 
-// const MEMORY_GAP_START: u64 = 0xc0000000;
-const MEMORY_GAP_START: u64 = 0xff000000;
+// The gap has to be big enough to fit the 32-bit PCI memory window, on
+// top of the firmware and the APICs.
+const MEMORY_GAP_START: u64 = 0xc0000000;
 const MEMORY_GAP_END: u64 = 0xffffffff + 1;
 const MEMORY_RAM_START: u64 = 0x00100000;
 
+/// The memory mapped configuration space sits at the very start of
+/// the gap, with room for every bus.
+pub const PCI_ECAM_START: u64 = MEMORY_GAP_START;
+pub const PCI_ECAM_BUSES: u16 = 256;
+
+/// Without PCI, virtio-mmio devices take the place of the ECAM window.
+pub const VIRTIO_MMIO_START: u64 = MEMORY_GAP_START;
+
+const PCI_IO_START: u64 = 0xc000;
+const PCI_IO_END: u64 = 0x10000;
+const PCI_MMIO_START: u64 = PCI_ECAM_START + PCI_ECAM_BUSES as u64 * ECAM_BUS_SIZE;
+// This is where the IOAPIC lives.
+const PCI_MMIO_END: u64 = 0xfec00000;
+const PCI_MMIO64_ALIGN: u64 = 1 << 30;
+const PCI_MMIO64_SIZE: u64 = 1 << 36;
+
+/// The I/O, 32-bit memory, and 64-bit memory windows PCI BARs go in,
+/// as start and end pairs, for `memory` bytes of RAM.  The 64-bit
+/// window starts at the first gigabyte boundary past the end of guest
+/// memory.
+fn pci_windows(memory: u64) -> ((u64, u64), (u64, u64), (u64, u64)) {
+    let adjusted = memory + MEMORY_RAM_START;
+    let top = if adjusted > MEMORY_GAP_START {
+        MEMORY_GAP_END + (adjusted - MEMORY_GAP_START)
+    } else {
+        MEMORY_GAP_END
+    };
+    let start = (top + PCI_MMIO64_ALIGN - 1) & !(PCI_MMIO64_ALIGN - 1);
+
+    (
+        (PCI_IO_START, PCI_IO_END),
+        (PCI_MMIO_START, PCI_MMIO_END),
+        (start, start + PCI_MMIO64_SIZE),
+    )
+}
+
 impl Machine {
     pub fn new(mach: kvm::Machine) -> Result<Machine> {
+        let interrupts = Arc::new(interrupt::Controller::new(mach.as_raw_fd())?);
+
         Ok(Machine {
             mach,
             cores: vec![],
             devices: vec![],
+            disks: vec![],
+            networks: vec![],
+            interrupts,
+            bus: Arc::new(Bus::new()),
+            pci: None,
+            mmio: vec![],
+            memory: memory::Memory::new(),
         })
     }
 
+    /// The guest's memory, as devices see it.  It only covers the
+    /// regions created so far, so devices get it once the machine has
+    /// laid out its memory.
+    pub fn memory(&self) -> Arc<GuestMemory> {
+        Arc::new(self.memory.clone())
+    }
+
+    pub fn bus(&self) -> Arc<Bus> {
+        self.bus.clone()
+    }
+
+    /// Creates an allocator for PCI BARs.
+    pub fn pci_allocator(&self, config: &MachineConfiguration) -> Allocator {
+        let (io, memory32, memory64) = pci_windows(config.memory);
+        let window = |(start, end): (u64, u64)| Window::new(start, end);
+        Allocator::new(window(io), window(memory32), window(memory64))
+    }
+
+    /// The PCI host bridge, once the machine is prepared.  Devices are
+    /// hot-plugged through it while the machine runs.
+    pub fn pci(&self) -> Option<Arc<Host>> {
+        self.pci.clone()
+    }
+
+    pub fn set_pci(&mut self, host: Arc<Host>) {
+        self.pci = Some(host);
+    }
+
+    /// Records a virtio-mmio device, so that the guest can be told
+    /// where it is.
+    pub fn push_mmio(&mut self, base: u64, size: u64, irq: u32) {
+        self.mmio.push(acpi::MmioDevice { base, size, irq });
+    }
+
+    pub fn interrupts(&self) -> Arc<Interrupts> {
+        self.interrupts.clone()
+    }
+
     pub fn push(&mut self, device: Arc<device::Device>) -> Result<()> {
         self.devices.push(device);
         Ok(())
     }
 
+    pub fn push_disk(&mut self, disk: Arc<device::Disk>) {
+        self.disks.push(disk);
+    }
+
+    /// The machine's disks, in the order they were configured.  They
+    /// can be held on to, to take snapshots while the machine runs.
+    pub fn disks(&self) -> &[Arc<device::Disk>] {
+        &self.disks
+    }
+
+    pub fn push_network(&mut self, network: Arc<device::Network>) {
+        self.networks.push(network);
+    }
+
+    /// The machine's network cards, in the order they were configured.
+    /// They can be held on to, to capture their frames while the
+    /// machine runs.
+    pub fn networks(&self) -> &[Arc<device::Network>] {
+        &self.networks
+    }
+
     pub fn prepare(&mut self, config: &MachineConfiguration) -> Result<()> {
         info!("preparing machine...");
-        device::prepare(self)?;
-        self.create_irqchip()?;
-        self.create_pit()?;
-        self.set_tss_addr(None)?;
-        self.set_identity_map_addr(None)?;
         let adjusted = config.memory + MEMORY_RAM_START;
 
         if adjusted > MEMORY_GAP_START {
-            self.create_memory_region(0, MEMORY_GAP_START as usize)?;
-            self.create_memory_region(MEMORY_GAP_END, (adjusted - MEMORY_GAP_START) as usize)?;
+            let size = adjusted - MEMORY_GAP_START;
+            let low = self.create_memory_region(0, MEMORY_GAP_START as usize)?;
+            self.memory.push(0, MEMORY_GAP_START, low);
+            let high = self.create_memory_region(MEMORY_GAP_END, size as usize)?;
+            self.memory.push(MEMORY_GAP_END, size, high);
         } else {
-            self.create_memory_region(0, adjusted as usize)?;
+            let low = self.create_memory_region(0, adjusted as usize)?;
+            self.memory.push(0, adjusted, low);
+        }
+        // vhost-user backends map the guest's memory themselves.
+        if !config.vhost_user.is_empty() {
+            self.memory.share()?;
         }
 
+        device::prepare(self, config)?;
+        self.create_irqchip()?;
+        self.create_pit()?;
+        self.set_tss_addr(None)?;
+        self.set_identity_map_addr(None)?;
+
         let mut cores = vec![];
         (0..config.cores)
             .try_for_each(|id| self.mach.create_core(id).map(|core| cores.push(core)))?;
@@ -62,6 +191,10 @@ impl Machine {
         }
 
         bios::prepare(self)?;
+        acpi::prepare(self, config.memory)?;
+        if let Some(ref path) = config.control_socket {
+            control::serve(self, path)?;
+        }
 
         Ok(())
     }
@@ -69,12 +202,13 @@ impl Machine {
     pub fn run(self) -> () {
         let cores = self.cores;
         let devices = self.devices;
+        let bus = self.bus;
 
         let joins = cores
             .into_iter()
             .map(|core| {
                 let locals = devices.iter().cloned().collect::<Vec<_>>();
-                core::run(core, locals)
+                core::run(core, locals, bus.clone())
             })
             .collect::<Vec<_>>();
 
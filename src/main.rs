@@ -14,18 +14,36 @@ extern crate log;
 extern crate byteorder;
 extern crate env_logger;
 extern crate libc;
+extern crate miniz_oxide;
+extern crate mio;
 
 use kvm::capability::{Capability, CapabilityKind};
+use std::env;
 use std::error::Error;
 
+mod block;
 mod configuration;
 mod device;
 mod error;
+mod image;
 mod machine;
+mod net;
+#[cfg(test)]
+mod scratch;
+mod vhost;
 mod virtio;
+mod vsock;
 
 fn main() {
-    match run() {
+    env_logger::init();
+    let arguments = env::args().skip(1).collect::<Vec<_>>();
+    let result = match arguments.split_first() {
+        Some((command, rest)) if command == "image" => image::run(rest),
+        Some((command, rest)) if command == "switch" => net::switch::run(rest),
+        _ => run(),
+    };
+
+    match result {
         Ok(_) => {}
         Err(e) => {
             error!("error: {}", e);
@@ -38,7 +56,6 @@ fn main() {
 }
 
 fn run() -> Result<(), error::Error> {
-    env_logger::init();
     let mut system = kvm::System::new()?;
     assert_eq!(system.api_version()?, 12);
     system.check_capability(CapabilityKind::MemorySlotCount)?;
@@ -49,6 +66,14 @@ fn run() -> Result<(), error::Error> {
         uuid: None,
         cores: 1,
         memory: 1 << 31,
+        hotplug_slots: 4,
+        transport: configuration::Transport::Pci,
+        agent_socket: None,
+        control_socket: None,
+        disks: vec![],
+        networks: vec![],
+        vhost_user: vec![],
+        vsock: None,
     };
 
     machine.prepare(&config)?;
@@ -0,0 +1,334 @@
+use super::{Backend, MacAddress, Offloads, Sink, HEADER_SIZE};
+use byteorder::{ByteOrder, LittleEndian};
+use error::*;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPTION_END: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_DESCRIPTION: u16 = 3;
+const IF_MAC: u16 = 6;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+/// Timestamps are in nanoseconds.
+const NANOSECONDS: u8 = 9;
+
+/// Which way a frame went, as the guest sees it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Direction {
+    Inbound = 1,
+    Outbound = 2,
+}
+
+/// Records the frames going through a network card to a pcapng file,
+/// while it's started.  Clones record to the same file.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    name: String,
+    description: String,
+    mac: MacAddress,
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl Capture {
+    /// A capture that isn't started, for the card with the given name
+    /// and address.  Both go into the file, along with the description
+    /// of whatever the card's on.
+    pub fn new(name: &str, mac: MacAddress, description: &str) -> Capture {
+        Capture {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            mac,
+            file: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts recording to a new file at `path`, instead of wherever
+    /// frames were recorded to before.
+    pub fn start<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut file = File::create(path)?;
+
+        let mut header = vec![0u8; 16];
+        LittleEndian::write_u32(&mut header[0..4], BYTE_ORDER_MAGIC);
+        LittleEndian::write_u16(&mut header[4..6], 1);
+        LittleEndian::write_u16(&mut header[6..8], 0);
+        // The section's length isn't known.
+        LittleEndian::write_i64(&mut header[8..16], -1);
+        option(&mut header, SHB_USERAPPL, b"vent");
+        option(&mut header, OPTION_END, &[]);
+        file.write_all(&block(SECTION_HEADER, &header))?;
+
+        let mut interface = vec![0u8; 8];
+        LittleEndian::write_u16(&mut interface[0..2], LINKTYPE_ETHERNET);
+        option(&mut interface, IF_NAME, self.name.as_bytes());
+        option(&mut interface, IF_DESCRIPTION, self.description.as_bytes());
+        option(&mut interface, IF_MAC, &self.mac.0);
+        option(&mut interface, IF_TSRESOL, &[NANOSECONDS]);
+        option(&mut interface, OPTION_END, &[]);
+        file.write_all(&block(INTERFACE_DESCRIPTION, &interface))?;
+
+        *self.file.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    /// Stops recording; the file's complete as it is.
+    pub fn stop(&self) {
+        *self.file.lock().unwrap() = None;
+    }
+
+    pub fn running(&self) -> bool {
+        self.file.lock().unwrap().is_some()
+    }
+
+    /// Puts a backend behind the capture, so the frames going through
+    /// it are recorded.
+    pub fn wrap(&self, backend: Arc<Backend>) -> Captured {
+        Captured {
+            backend,
+            capture: self.clone(),
+        }
+    }
+
+    fn record(&self, frame: &[u8], direction: Direction) {
+        let mut file = self.file.lock().unwrap();
+        let result = match *file {
+            Some(ref mut file) => file.write_all(&packet(&frame[HEADER_SIZE..], direction)),
+            None => return,
+        };
+        if let Err(e) = result {
+            warn!("could not record a frame on {}, stopping: {}", self.name, e);
+            *file = None;
+        }
+    }
+}
+
+/// An enhanced packet block, for a frame on the only interface there
+/// is.
+fn packet(frame: &[u8], direction: Direction) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let timestamp = now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64;
+
+    let mut body = vec![0u8; 20];
+    LittleEndian::write_u32(&mut body[4..8], (timestamp >> 32) as u32);
+    LittleEndian::write_u32(&mut body[8..12], timestamp as u32);
+    LittleEndian::write_u32(&mut body[12..16], frame.len() as u32);
+    LittleEndian::write_u32(&mut body[16..20], frame.len() as u32);
+    body.extend_from_slice(frame);
+    pad(&mut body);
+    let mut flags = [0u8; 4];
+    LittleEndian::write_u32(&mut flags, direction as u32);
+    option(&mut body, EPB_FLAGS, &flags);
+    option(&mut body, OPTION_END, &[]);
+    block(ENHANCED_PACKET, &body)
+}
+
+fn pad(data: &mut Vec<u8>) {
+    let padded = (data.len() + 3) / 4 * 4;
+    data.resize(padded, 0);
+}
+
+fn option(data: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let mut header = [0u8; 4];
+    LittleEndian::write_u16(&mut header[0..2], code);
+    LittleEndian::write_u16(&mut header[2..4], value.len() as u16);
+    data.extend_from_slice(&header);
+    data.extend_from_slice(value);
+    pad(data);
+}
+
+/// A block around `body`, which is padded already, with its length
+/// on either side.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let length = (12 + body.len()) as u32;
+    let mut block = vec![0u8; 8];
+    LittleEndian::write_u32(&mut block[0..4], kind);
+    LittleEndian::write_u32(&mut block[4..8], length);
+    block.extend_from_slice(body);
+    let mut trailer = [0u8; 4];
+    LittleEndian::write_u32(&mut trailer, length);
+    block.extend_from_slice(&trailer);
+    block
+}
+
+/// A backend whose frames are recorded, both ways.
+#[derive(Debug)]
+pub struct Captured {
+    backend: Arc<Backend>,
+    capture: Capture,
+}
+
+#[derive(Debug)]
+struct CapturedSink {
+    sink: Arc<Sink>,
+    capture: Capture,
+}
+
+impl Sink for CapturedSink {
+    fn receive(&self, frame: &[u8]) {
+        self.capture.record(frame, Direction::Inbound);
+        self.sink.receive(frame);
+    }
+}
+
+impl Backend for Captured {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        self.capture.record(frame, Direction::Outbound);
+        self.backend.send(frame)
+    }
+
+    fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+        self.backend.attach(Arc::new(CapturedSink {
+            sink,
+            capture: self.capture.clone(),
+        }))
+    }
+
+    fn offloads(&self) -> bool {
+        self.backend.offloads()
+    }
+
+    fn set_offloads(&self, offloads: Offloads) -> Result<()> {
+        self.backend.set_offloads(offloads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch;
+    use std::fs;
+
+    #[derive(Debug, Default)]
+    struct Loopback(Mutex<Option<Arc<Sink>>>);
+
+    impl Backend for Loopback {
+        fn send(&self, frame: &[u8]) -> Result<()> {
+            if let Some(ref sink) = *self.0.lock().unwrap() {
+                sink.receive(frame);
+            }
+            Ok(())
+        }
+
+        fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+            *self.0.lock().unwrap() = Some(sink);
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Frames(Mutex<Vec<Vec<u8>>>);
+
+    impl Sink for Frames {
+        fn receive(&self, frame: &[u8]) {
+            self.0.lock().unwrap().push(frame.to_vec());
+        }
+    }
+
+    /// The blocks in a file, as their types and bodies.
+    fn blocks(data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = vec![];
+        let mut rest = data;
+        while !rest.is_empty() {
+            let length = LittleEndian::read_u32(&rest[4..8]) as usize;
+            assert_eq!(
+                LittleEndian::read_u32(&rest[length - 4..length]) as usize,
+                length
+            );
+            blocks.push((
+                LittleEndian::read_u32(&rest[0..4]),
+                rest[8..length - 4].to_vec(),
+            ));
+            rest = &rest[length..];
+        }
+        blocks
+    }
+
+    /// The options after `offset` in a body, as codes and values.
+    fn options(body: &[u8], offset: usize) -> Vec<(u16, Vec<u8>)> {
+        let mut options = vec![];
+        let mut rest = &body[offset..];
+        loop {
+            let code = LittleEndian::read_u16(&rest[0..2]);
+            let length = LittleEndian::read_u16(&rest[2..4]) as usize;
+            if code == OPTION_END {
+                return options;
+            }
+            options.push((code, rest[4..4 + length].to_vec()));
+            rest = &rest[4 + (length + 3) / 4 * 4..];
+        }
+    }
+
+    #[test]
+    fn it_records_frames_both_ways() {
+        let path = scratch::path("capture.pcapng");
+        let mac = MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        let capture = Capture::new("net0", mac, "loopback");
+        let captured = capture.wrap(Arc::new(Loopback::default()));
+        let frames = Arc::new(Frames::default());
+        captured.attach(frames.clone()).unwrap();
+
+        let mut frame = vec![0u8; HEADER_SIZE + 61];
+        frame[HEADER_SIZE..].copy_from_slice(&[0xaa; 61]);
+        // Nothing's recorded before it's started.
+        captured.send(&frame).unwrap();
+        capture.start(&path).unwrap();
+        assert!(capture.running());
+        captured.send(&frame).unwrap();
+        capture.stop();
+        captured.send(&frame).unwrap();
+        assert_eq!(frames.0.lock().unwrap().len(), 3);
+
+        let blocks = blocks(&fs::read(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        let kinds = blocks.iter().map(|&(kind, _)| kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                SECTION_HEADER,
+                INTERFACE_DESCRIPTION,
+                ENHANCED_PACKET,
+                ENHANCED_PACKET
+            ]
+        );
+        assert_eq!(LittleEndian::read_u32(&blocks[0].1[0..4]), BYTE_ORDER_MAGIC);
+        assert_eq!(
+            LittleEndian::read_u16(&blocks[1].1[0..2]),
+            LINKTYPE_ETHERNET
+        );
+        assert_eq!(
+            options(&blocks[1].1, 8),
+            vec![
+                (IF_NAME, b"net0".to_vec()),
+                (IF_DESCRIPTION, b"loopback".to_vec()),
+                (IF_MAC, mac.0.to_vec()),
+                (IF_TSRESOL, vec![NANOSECONDS]),
+            ]
+        );
+
+        // Out of the guest, and back in.
+        for (block, direction) in blocks[2..]
+            .iter()
+            .zip(&[Direction::Outbound, Direction::Inbound])
+        {
+            let body = &block.1;
+            assert_eq!(LittleEndian::read_u32(&body[12..16]), 61);
+            assert_eq!(&body[20..81], &[0xaa; 61][..]);
+            let mut flags = [0u8; 4];
+            LittleEndian::write_u32(&mut flags, *direction as u32);
+            assert_eq!(options(body, 84), vec![(EPB_FLAGS, flags.to_vec())]);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use error::*;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use uuid::Uuid;
+
+mod capture;
+mod socket;
+pub mod switch;
+mod tap;
+mod user;
+
+pub use self::capture::Capture;
+pub use self::socket::Socket;
+pub use self::tap::Tap;
+pub use self::user::{host_nameserver, Forward, Protocol, User};
+
+/// Every frame going between a device and a backend starts with a
+/// virtio-net header (`virtio_net_hdr_v1`), even if it's all zeroes.
+pub const HEADER_SIZE: usize = 12;
+
+/// The largest frame a backend hands over, header included: a whole
+/// segmentation-offloaded IP packet, behind an Ethernet header with a
+/// VLAN tag.
+pub const MAX_FRAME_SIZE: usize = HEADER_SIZE + 18 + 65535;
+
+/// What the guest can take in the frames it's given, besides complete
+/// ones.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Offloads {
+    /// Frames whose checksum is left to be filled in.
+    pub checksum: bool,
+    /// TCP segments over IPv4 bigger than the MTU.
+    pub tso4: bool,
+    /// TCP segments over IPv6 bigger than the MTU.
+    pub tso6: bool,
+    /// Those, with the ECN bit set.
+    pub ecn: bool,
+}
+
+/// Where a network device gets the frames that come in from its
+/// backend.
+pub trait Sink: Debug + Send + Sync {
+    /// A frame arrived, header first.
+    fn receive(&self, frame: &[u8]);
+}
+
+/// The host side of a network device: whatever the guest's frames go
+/// to, and come from.
+pub trait Backend: Debug + Send + Sync {
+    /// Sends a frame from the guest, header first.
+    fn send(&self, frame: &[u8]) -> Result<()>;
+    /// Starts delivering frames to the given sink.  Backends only have
+    /// one device, so this is only called once.
+    fn attach(&self, sink: Arc<Sink>) -> Result<()>;
+    /// Whether the backend goes by what the header asks for: filling
+    /// in checksums, and splitting up segments.  Without that, frames
+    /// have to be complete both ways.
+    fn offloads(&self) -> bool {
+        false
+    }
+    /// Tells the backend which offloads the guest can take in the
+    /// frames it's given.
+    fn set_offloads(&self, _offloads: Offloads) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An Ethernet address.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// Parses an address written as six colon-separated hexadecimal
+    /// bytes.
+    pub fn parse(address: &str) -> Result<MacAddress> {
+        let mut bytes = [0u8; 6];
+        let mut parts = address.split(':');
+        for byte in bytes.iter_mut() {
+            *byte = parts
+                .next()
+                .filter(|part| part.len() == 2)
+                .and_then(|part| u8::from_str_radix(part, 16).ok())
+                .ok_or(ErrorKind::DeviceError("invalid MAC address"))?;
+        }
+
+        if parts.next().is_some() {
+            return Err(ErrorKind::DeviceError("invalid MAC address").into());
+        }
+        Ok(MacAddress(bytes))
+    }
+
+    /// Makes up an address for a machine's `index`th network card,
+    /// which stays the same for as long as the machine keeps its
+    /// UUID.  It's a locally administered unicast address, so it can't
+    /// clash with a real card's.
+    pub fn derive(machine: &Uuid, index: usize) -> MacAddress {
+        let name = format!("vent-net-{}", index);
+        let hash = Uuid::new_v5(machine, &name);
+        let mut bytes = [0u8; 6];
+        bytes.copy_from_slice(&hash.as_bytes()[..6]);
+        bytes[0] = (bytes[0] & !0x01) | 0x02;
+        MacAddress(bytes)
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+impl Debug for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MacAddress({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_mac_addresses() {
+        let address = MacAddress::parse("52:54:00:ab:CD:0f").unwrap();
+        assert_eq!(address.0, [0x52, 0x54, 0x00, 0xab, 0xcd, 0x0f]);
+        assert_eq!(address.to_string(), "52:54:00:ab:cd:0f");
+
+        for invalid in &[
+            "52:54:00:ab:cd",
+            "52:54:00:ab:cd:0f:00",
+            "52:54:0:ab:cd:0f",
+            "zz:54:00:ab:cd:0f",
+        ] {
+            assert!(MacAddress::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn it_derives_local_unicast_addresses() {
+        let machine = Uuid::new_v4();
+        let first = MacAddress::derive(&machine, 0);
+        assert_eq!(first, MacAddress::derive(&machine, 0));
+        assert!(first != MacAddress::derive(&machine, 1));
+        assert!(first != MacAddress::derive(&Uuid::new_v4(), 0));
+        assert_eq!(first.0[0] & 0x03, 0x02);
+    }
+}
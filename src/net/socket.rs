@@ -0,0 +1,382 @@
+use super::{Backend, Sink, HEADER_SIZE, MAX_FRAME_SIZE};
+use byteorder::{BigEndian, ByteOrder};
+use error::*;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The largest frame that goes over a socket, which doesn't carry the
+/// virtio-net header.
+pub const MAX_SOCKET_FRAME: usize = MAX_FRAME_SIZE - HEADER_SIZE;
+
+/// How many frames can wait to be written to a stream's peer before
+/// more are dropped.
+const MAX_QUEUED: usize = 256;
+
+/// The other end of a stream socket.  A thread of its own writes the
+/// frames out, so that a peer that doesn't keep up loses frames, like
+/// it would on a switch, rather than holding up the machine.
+#[derive(Debug)]
+struct Peer {
+    stream: UnixStream,
+    frames: SyncSender<Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum Kind {
+    /// Frames go over a stream socket, each after its length as a
+    /// big-endian 32-bit number.  The peer is only there while it's
+    /// connected.
+    Stream {
+        peer: Arc<Mutex<Option<Peer>>>,
+        listener: Option<UnixListener>,
+    },
+    /// Frames are datagrams of their own.
+    Datagram {
+        socket: UnixDatagram,
+        remote: PathBuf,
+    },
+}
+
+/// A Unix socket that frames go over, the way they do with QEMU's
+/// `stream` and `dgram` network backends, so either can be on the
+/// other end.  Frames don't carry a virtio-net header, and are dropped
+/// while there's no one there to take them.
+#[derive(Debug)]
+pub struct Socket {
+    kind: Kind,
+    path: PathBuf,
+}
+
+impl Socket {
+    /// Connects to a stream socket, like a switch's.  Once it hangs up,
+    /// frames go nowhere.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Socket> {
+        let stream = UnixStream::connect(&path)?;
+        Ok(Socket {
+            kind: Kind::Stream {
+                peer: Arc::new(Mutex::new(Some(start(stream, path.as_ref())?))),
+                listener: None,
+            },
+            path: path.as_ref().to_owned(),
+        })
+    }
+
+    /// Listens on a stream socket, for a peer at a time.
+    pub fn listen<P: AsRef<Path>>(path: P) -> Result<Socket> {
+        let listener = UnixListener::bind(&path)?;
+        Ok(Socket {
+            kind: Kind::Stream {
+                peer: Arc::new(Mutex::new(None)),
+                listener: Some(listener),
+            },
+            path: path.as_ref().to_owned(),
+        })
+    }
+
+    /// Takes datagrams on a socket at `local`, and sends them to the
+    /// one at `remote`.
+    pub fn datagram<P: AsRef<Path>, Q: AsRef<Path>>(local: P, remote: Q) -> Result<Socket> {
+        let socket = UnixDatagram::bind(&local)?;
+        Ok(Socket {
+            kind: Kind::Datagram {
+                socket,
+                remote: remote.as_ref().to_owned(),
+            },
+            path: local.as_ref().to_owned(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Backend for Socket {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        let frame = &frame[HEADER_SIZE..];
+        match self.kind {
+            Kind::Stream { ref peer, .. } => {
+                let mut peer = peer.lock().unwrap();
+                let result = match *peer {
+                    Some(ref peer) => {
+                        let mut message = vec![0u8; 4];
+                        BigEndian::write_u32(&mut message, frame.len() as u32);
+                        message.extend_from_slice(frame);
+                        peer.frames.try_send(message)
+                    }
+                    None => return Ok(()),
+                };
+                // The writer only goes away once it's given up on the
+                // peer.
+                if let Err(TrySendError::Disconnected(_)) = result {
+                    *peer = None;
+                }
+                Ok(())
+            }
+            Kind::Datagram {
+                ref socket,
+                ref remote,
+            } => match socket.send_to(frame, remote) {
+                Ok(_) => Ok(()),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::NotFound
+                        || e.kind() == io::ErrorKind::ConnectionRefused =>
+                {
+                    Ok(())
+                }
+                Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+        let builder = thread::Builder::new().name("net-socket".to_owned());
+        let path = self.path.clone();
+        match self.kind {
+            Kind::Stream {
+                ref peer,
+                ref listener,
+            } => {
+                let peer = peer.clone();
+                let mut connected = match *peer.lock().unwrap() {
+                    Some(ref peer) => Some(peer.stream.try_clone()?),
+                    None => None,
+                };
+                let listener = match *listener {
+                    Some(ref listener) => Some(listener.try_clone()?),
+                    None => None,
+                };
+                builder.spawn(move || loop {
+                    let stream = match (connected.take(), listener.as_ref()) {
+                        (Some(stream), _) => stream,
+                        (None, Some(listener)) => match accept(listener, &peer, &path) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                warn!("could not accept a peer on {}: {}", path.display(), e);
+                                return;
+                            }
+                        },
+                        (None, None) => return,
+                    };
+                    receive(stream, sink.as_ref(), &path);
+                    *peer.lock().unwrap() = None;
+                })?;
+            }
+            Kind::Datagram { ref socket, .. } => {
+                let socket = socket.try_clone()?;
+                builder.spawn(move || {
+                    let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+                    loop {
+                        match socket.recv(&mut buffer[HEADER_SIZE..]) {
+                            Ok(count) => sink.receive(&buffer[..HEADER_SIZE + count]),
+                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                            Err(e) => {
+                                warn!("could not receive from {}: {}", path.display(), e);
+                                return;
+                            }
+                        }
+                    }
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Waits for a peer, which frames go to from then on.
+fn accept(listener: &UnixListener, peer: &Mutex<Option<Peer>>, path: &Path) -> Result<UnixStream> {
+    let (stream, _) = listener.accept()?;
+    let input = stream.try_clone()?;
+    *peer.lock().unwrap() = Some(start(stream, path)?);
+    Ok(input)
+}
+
+/// Starts the thread that writes frames out to a peer.  It stops once
+/// the peer is dropped.
+fn start(stream: UnixStream, path: &Path) -> Result<Peer> {
+    let (frames, queued) = mpsc::sync_channel::<Vec<u8>>(MAX_QUEUED);
+    let mut output = stream.try_clone()?;
+    let path = path.to_owned();
+    thread::Builder::new()
+        .name("net-socket".to_owned())
+        .spawn(move || {
+            for message in queued {
+                if let Err(e) = output.write_all(&message) {
+                    debug!("could not send to {}: {}", path.display(), e);
+                    // Whatever's left of a frame that didn't make it
+                    // would throw the peer off, so there's no going on
+                    // after that.
+                    let _ = output.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+        })?;
+    Ok(Peer { stream, frames })
+}
+
+/// Passes on the frames that come in on a stream, until it's over.
+fn receive(mut stream: UnixStream, sink: &Sink, path: &Path) {
+    // There's an empty header in front of every frame.
+    let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+    let mut length = [0u8; 4];
+    loop {
+        if let Err(e) = stream.read_exact(&mut length) {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                debug!("could not receive from {}: {}", path.display(), e);
+            }
+            return;
+        }
+        let length = BigEndian::read_u32(&length) as usize;
+        if length > MAX_SOCKET_FRAME {
+            warn!("frame of {} bytes on {} is too big", length, path.display());
+            return;
+        }
+        if let Err(e) = stream.read_exact(&mut buffer[HEADER_SIZE..HEADER_SIZE + length]) {
+            debug!("could not receive from {}: {}", path.display(), e);
+            return;
+        }
+        sink.receive(&buffer[..HEADER_SIZE + length]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch::path;
+    use std::fs;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    struct Frames(Mutex<Sender<Vec<u8>>>);
+
+    impl Sink for Frames {
+        fn receive(&self, frame: &[u8]) {
+            let _ = self.0.lock().unwrap().send(frame.to_vec());
+        }
+    }
+
+    fn attach(socket: &Socket) -> Receiver<Vec<u8>> {
+        let (sender, frames) = mpsc::channel();
+        socket.attach(Arc::new(Frames(Mutex::new(sender)))).unwrap();
+        frames
+    }
+
+    /// A frame with its header, and `byte` for a payload.
+    fn frame(byte: u8) -> Vec<u8> {
+        let mut frame = vec![0u8; HEADER_SIZE + 60];
+        frame[HEADER_SIZE..HEADER_SIZE + 6].copy_from_slice(&[0xff; 6]);
+        for b in &mut frame[HEADER_SIZE + 14..] {
+            *b = byte;
+        }
+        frame
+    }
+
+    #[test]
+    fn it_frames_streams_like_qemu() {
+        let path = path("socket-stream");
+        let socket = Socket::listen(&path).unwrap();
+        let frames = attach(&socket);
+        // Nobody's there yet.
+        socket.send(&frame(0)).unwrap();
+
+        let mut peer = UnixStream::connect(&path).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut message = vec![0, 0, 0, 60];
+        message.extend_from_slice(&frame(1)[HEADER_SIZE..]);
+        peer.write_all(&message).unwrap();
+        assert_eq!(
+            frames.recv_timeout(Duration::from_secs(5)).unwrap(),
+            frame(1)
+        );
+
+        socket.send(&frame(2)).unwrap();
+        let mut received = vec![0u8; 64];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..4], &[0, 0, 0, 60]);
+        assert_eq!(&received[4..], &frame(2)[HEADER_SIZE..]);
+
+        // It takes the next peer once this one's gone.
+        drop(peer);
+        let mut peer = UnixStream::connect(&path).unwrap();
+        peer.write_all(&message).unwrap();
+        assert_eq!(
+            frames.recv_timeout(Duration::from_secs(5)).unwrap(),
+            frame(1)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_drops_frames_a_peer_does_not_take() {
+        let path = path("socket-slow");
+        let socket = Socket::listen(&path).unwrap();
+        let frames = attach(&socket);
+        let mut peer = UnixStream::connect(&path).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut message = vec![0, 0, 0, 60];
+        message.extend_from_slice(&frame(1)[HEADER_SIZE..]);
+        peer.write_all(&message).unwrap();
+        frames.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Far more than the socket buffers, which would block if the
+        // frames weren't dropped.
+        let mut big = vec![0x42u8; MAX_FRAME_SIZE];
+        big[HEADER_SIZE..HEADER_SIZE + 6].copy_from_slice(&[0xff; 6]);
+        for _ in 0..MAX_QUEUED * 4 {
+            socket.send(&big).unwrap();
+        }
+
+        let mut received = vec![0u8; 4 + MAX_SOCKET_FRAME];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(
+            BigEndian::read_u32(&received[..4]) as usize,
+            MAX_SOCKET_FRAME
+        );
+        assert_eq!(&received[4..], &big[HEADER_SIZE..]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_connects_to_a_listening_socket() {
+        let path = path("socket-connect");
+        let listening = Socket::listen(&path).unwrap();
+        let connected = Socket::connect(&path).unwrap();
+        let (on_listening, on_connected) = (attach(&listening), attach(&connected));
+
+        connected.send(&frame(3)).unwrap();
+        assert_eq!(
+            on_listening.recv_timeout(Duration::from_secs(5)).unwrap(),
+            frame(3)
+        );
+        listening.send(&frame(4)).unwrap();
+        assert_eq!(
+            on_connected.recv_timeout(Duration::from_secs(5)).unwrap(),
+            frame(4)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_exchanges_datagrams() {
+        let (left, right) = (path("socket-left"), path("socket-right"));
+        let a = Socket::datagram(&left, &right).unwrap();
+        // The other end isn't there yet, so the frame's dropped.
+        a.send(&frame(5)).unwrap();
+        let b = Socket::datagram(&right, &left).unwrap();
+        let (on_a, on_b) = (attach(&a), attach(&b));
+
+        a.send(&frame(6)).unwrap();
+        assert_eq!(on_b.recv_timeout(Duration::from_secs(5)).unwrap(), frame(6));
+        b.send(&frame(7)).unwrap();
+        assert_eq!(on_a.recv_timeout(Duration::from_secs(5)).unwrap(), frame(7));
+        fs::remove_file(&left).unwrap();
+        fs::remove_file(&right).unwrap();
+    }
+}
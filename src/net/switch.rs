@@ -0,0 +1,345 @@
+use super::socket::MAX_SOCKET_FRAME;
+use byteorder::{BigEndian, ByteOrder};
+use error::*;
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+const USAGE: &str = "usage: vent switch PATH
+
+forwards frames between the machines connected to the stream socket at PATH";
+
+const LISTENER: Token = Token(0);
+
+/// How much can wait to be written to a port before frames for it are
+/// dropped.
+const MAX_QUEUED: usize = 1 << 20;
+
+/// Runs a switch on the socket in `arguments`, which don't include
+/// `switch` itself.
+pub fn run(arguments: &[String]) -> Result<()> {
+    if arguments.len() != 1 || arguments[0] == "help" {
+        println!("{}", USAGE);
+        return Err(ErrorKind::UsageError("expected the path of the switch's socket").into());
+    }
+
+    Switch::bind(&arguments[0])?.run()
+}
+
+/// A machine plugged into the switch.
+#[derive(Debug)]
+struct Port {
+    stream: UnixStream,
+    /// What came in that isn't a whole frame yet.
+    input: Vec<u8>,
+    /// What's waiting to go out.
+    output: Vec<u8>,
+}
+
+/// A learning switch for machines whose network cards are on Unix
+/// stream sockets, connected to the one it listens on.  Frames go the
+/// way they do with QEMU's `stream` backend, so QEMU machines can be
+/// plugged in too.
+#[derive(Debug)]
+pub struct Switch {
+    poll: Poll,
+    listener: UnixListener,
+    ports: HashMap<Token, Port>,
+    /// Which port each address was last seen on.
+    table: HashMap<[u8; 6], Token>,
+    next_token: usize,
+}
+
+impl Switch {
+    /// Listens on a socket at the given path, which mustn't be there.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Switch> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let poll = Poll::new()?;
+        poll.register(
+            &EventedFd(&listener.as_raw_fd()),
+            LISTENER,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+
+        Ok(Switch {
+            poll,
+            listener,
+            ports: HashMap::new(),
+            table: HashMap::new(),
+            next_token: 1,
+        })
+    }
+
+    /// Forwards frames, for good.
+    pub fn run(&mut self) -> Result<()> {
+        let mut events = Events::with_capacity(64);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+
+            // Machines that connected before others sent anything get
+            // what they sent.
+            self.accept();
+            for event in &events {
+                if event.token() == LISTENER {
+                    continue;
+                }
+                if event.readiness().is_readable() {
+                    self.read(event.token());
+                }
+                if event.readiness().is_writable() {
+                    self.flush(event.token());
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("could not accept a port: {}", e);
+                    return;
+                }
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let registered = stream.set_nonblocking(true).and_then(|_| {
+                self.poll.register(
+                    &EventedFd(&stream.as_raw_fd()),
+                    token,
+                    Ready::readable() | Ready::writable(),
+                    PollOpt::edge(),
+                )
+            });
+            if let Err(e) = registered {
+                warn!("could not set up a port: {}", e);
+                continue;
+            }
+
+            debug!("port {} connected", token.0);
+            self.ports.insert(
+                token,
+                Port {
+                    stream,
+                    input: vec![],
+                    output: vec![],
+                },
+            );
+        }
+    }
+
+    fn read(&mut self, token: Token) {
+        let mut frames = vec![];
+        let mut closed = false;
+        {
+            let port = match self.ports.get_mut(&token) {
+                Some(port) => port,
+                None => return,
+            };
+
+            let mut buffer = [0u8; 64 << 10];
+            loop {
+                match port.stream.read(&mut buffer) {
+                    Ok(0) => {
+                        closed = true;
+                        break;
+                    }
+                    Ok(count) => port.input.extend_from_slice(&buffer[..count]),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        debug!("could not read from port {}: {}", token.0, e);
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+
+            let mut start = 0;
+            while port.input.len() - start >= 4 {
+                let length = BigEndian::read_u32(&port.input[start..start + 4]) as usize;
+                if length > MAX_SOCKET_FRAME {
+                    warn!("frame of {} bytes on port {} is too big", length, token.0);
+                    closed = true;
+                    break;
+                }
+                if port.input.len() - start < 4 + length {
+                    break;
+                }
+                frames.push(port.input[start + 4..start + 4 + length].to_vec());
+                start += 4 + length;
+            }
+            port.input.drain(..start);
+        }
+
+        for frame in frames {
+            self.forward(token, &frame);
+        }
+        if closed {
+            self.disconnect(token);
+        }
+    }
+
+    /// Sends a frame from the given port where its destination was last
+    /// seen, or everywhere else if that's nowhere.
+    fn forward(&mut self, from: Token, frame: &[u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        let mut destination = [0u8; 6];
+        let mut source = [0u8; 6];
+        destination.copy_from_slice(&frame[0..6]);
+        source.copy_from_slice(&frame[6..12]);
+        if (source[0] & 1) == 0 {
+            self.table.insert(source, from);
+        }
+
+        let ports = match self.table.get(&destination) {
+            Some(&port) if (destination[0] & 1) == 0 => vec![port],
+            _ => self.ports.keys().cloned().collect(),
+        };
+        for port in ports {
+            if port != from {
+                self.queue(port, frame);
+            }
+        }
+    }
+
+    fn queue(&mut self, token: Token, frame: &[u8]) {
+        {
+            let port = match self.ports.get_mut(&token) {
+                Some(port) => port,
+                None => return,
+            };
+            // A machine that doesn't keep up loses frames, like it would
+            // on a real switch.
+            if port.output.len() + 4 + frame.len() > MAX_QUEUED {
+                return;
+            }
+            let mut length = [0u8; 4];
+            BigEndian::write_u32(&mut length, frame.len() as u32);
+            port.output.extend_from_slice(&length);
+            port.output.extend_from_slice(frame);
+        }
+        self.flush(token);
+    }
+
+    fn flush(&mut self, token: Token) {
+        let failed = {
+            let port = match self.ports.get_mut(&token) {
+                Some(port) => port,
+                None => return,
+            };
+
+            let mut written = 0;
+            let mut failed = false;
+            while written < port.output.len() {
+                match port.stream.write(&port.output[written..]) {
+                    Ok(count) => written += count,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        debug!("could not write to port {}: {}", token.0, e);
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            port.output.drain(..written);
+            failed
+        };
+
+        if failed {
+            self.disconnect(token);
+        }
+    }
+
+    fn disconnect(&mut self, token: Token) {
+        debug!("port {} disconnected", token.0);
+        self.ports.remove(&token);
+        self.table.retain(|_, port| *port != token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    fn frame(destination: u8, source: u8, byte: u8) -> Vec<u8> {
+        let mut frame = vec![byte; 64];
+        frame[..4].copy_from_slice(&[0, 0, 0, 60]);
+        frame[4..10].copy_from_slice(&[destination; 6]);
+        frame[10..16].copy_from_slice(&[0x02, 0, 0, 0, 0, source]);
+        if destination != 0xff {
+            frame[4] = 0x02;
+        }
+        frame
+    }
+
+    fn receive(stream: &mut UnixStream) -> Option<Vec<u8>> {
+        let mut frame = vec![0u8; 64];
+        match stream.read_exact(&mut frame) {
+            Ok(()) => Some(frame),
+            Err(_) => None,
+        }
+    }
+
+    #[test]
+    fn it_learns_where_machines_are() {
+        let path = scratch::path("switch");
+        let mut switch = Switch::bind(&path).unwrap();
+        thread::spawn(move || switch.run());
+
+        let mut ports = (0..3)
+            .map(|_| {
+                let stream = UnixStream::connect(&path).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                stream
+            })
+            .collect::<Vec<_>>();
+
+        // Nobody knows where anyone is yet, so it goes everywhere.
+        let broadcast = frame(0xff, 0, 1);
+        ports[0].write_all(&broadcast).unwrap();
+        assert_eq!(receive(&mut ports[1]), Some(broadcast.clone()));
+        assert_eq!(receive(&mut ports[2]), Some(broadcast));
+
+        // The first machine was seen on its port.
+        let reply = frame(0, 1, 2);
+        ports[1].write_all(&reply).unwrap();
+        assert_eq!(receive(&mut ports[0]), Some(reply));
+        ports[2]
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert_eq!(receive(&mut ports[2]), None);
+
+        // Frames that are too big cut a machine off.
+        ports[2]
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        ports[2].write_all(&[0xff; 4]).unwrap();
+        assert_eq!(receive(&mut ports[2]), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
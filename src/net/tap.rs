@@ -0,0 +1,262 @@
+use super::{Backend, Offloads, Sink, HEADER_SIZE, MAX_FRAME_SIZE};
+use error::*;
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::thread;
+
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNGETIFF: libc::c_ulong = 0x8004_54d2;
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
+const TUNSETVNETHDRSZ: libc::c_ulong = 0x4004_54d8;
+
+const IFF_TAP: libc::c_short = 0x0002;
+/// Frames aren't preceded by the packet information bytes.
+const IFF_NO_PI: libc::c_short = 0x1000;
+/// Frames are preceded by a virtio-net header instead.
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+
+const TUN_F_CSUM: libc::c_uint = 0x01;
+const TUN_F_TSO4: libc::c_uint = 0x02;
+const TUN_F_TSO6: libc::c_uint = 0x04;
+const TUN_F_TSO_ECN: libc::c_uint = 0x08;
+
+/// How long an interface name can be, without the terminating zero.
+const MAX_NAME: usize = 15;
+
+/// The bits of `struct ifreq` the TUN ioctls use.
+#[repr(C)]
+struct InterfaceRequest {
+    name: [u8; MAX_NAME + 1],
+    flags: libc::c_short,
+    _padding: [u8; 22],
+}
+
+impl InterfaceRequest {
+    fn new() -> InterfaceRequest {
+        InterfaceRequest {
+            name: [0; MAX_NAME + 1],
+            flags: 0,
+            _padding: [0; 22],
+        }
+    }
+
+    fn name(&self) -> String {
+        let length = self.name.iter().position(|&b| b == 0).unwrap_or(MAX_NAME);
+        String::from_utf8_lossy(&self.name[..length]).into_owned()
+    }
+}
+
+/// A TAP interface on the host.  Frames carry a virtio-net header
+/// through the kernel whenever the interface allows for that, so the
+/// guest's offloads go all the way to the host's network stack.
+#[derive(Debug)]
+pub struct Tap {
+    file: File,
+    name: String,
+    header: bool,
+}
+
+impl Tap {
+    /// Opens the TAP interface with the given name, creating it if
+    /// it's not there.  Either takes `CAP_NET_ADMIN`, unless the
+    /// interface belongs to us already.
+    pub fn open(name: &str) -> Result<Tap> {
+        if name.len() > MAX_NAME || name.contains('\0') {
+            return Err(ErrorKind::DeviceError("invalid TAP interface name").into());
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+        let mut request = InterfaceRequest::new();
+        request.name[..name.len()].copy_from_slice(name.as_bytes());
+        request.flags = IFF_TAP | IFF_NO_PI | IFF_VNET_HDR;
+        ioctl(&file, TUNSETIFF, &mut request as *mut _ as *mut libc::c_void)?;
+
+        Tap::set_up(file, &request)
+    }
+
+    /// Takes over a TAP interface someone else opened for us, like the
+    /// process that started us.  It has to be without packet
+    /// information.
+    pub fn from_fd(fd: RawFd) -> Result<Tap> {
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut request = InterfaceRequest::new();
+        ioctl(&file, TUNGETIFF, &mut request as *mut _ as *mut libc::c_void)?;
+        if (request.flags & IFF_TAP) == 0 {
+            return Err(ErrorKind::DeviceError("not a TAP interface").into());
+        }
+        if (request.flags & IFF_NO_PI) == 0 {
+            return Err(ErrorKind::DeviceError("TAP interface has packet information").into());
+        }
+
+        Tap::set_up(file, &request)
+    }
+
+    fn set_up(file: File, request: &InterfaceRequest) -> Result<Tap> {
+        let header = (request.flags & IFF_VNET_HDR) != 0;
+        if header {
+            let mut size = HEADER_SIZE as libc::c_int;
+            ioctl(&file, TUNSETVNETHDRSZ, &mut size as *mut _ as *mut libc::c_void)?;
+        }
+
+        let tap = Tap {
+            file,
+            name: request.name(),
+            header,
+        };
+        // Nothing the guest can't take comes in until it's said what
+        // it can.
+        tap.set_offloads(Offloads::default())?;
+        Ok(tap)
+    }
+
+    /// The interface's name, which the kernel made up if it was opened
+    /// with a pattern like `tap%d`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+fn ioctl(file: &File, request: libc::c_ulong, argument: *mut libc::c_void) -> Result<()> {
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request as _, argument) };
+    if result < 0 {
+        Err(io::Error::last_os_error().into())
+    } else {
+        Ok(())
+    }
+}
+
+impl Backend for Tap {
+    /// Frames are dropped if the interface is down, just like they
+    /// would be on the wire.
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        let frame = if self.header {
+            frame
+        } else {
+            &frame[HEADER_SIZE..]
+        };
+        // Each write is a frame of its own, so it can't be finished
+        // with another one.
+        if (&self.file).write(frame)? < frame.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "frame cut short").into());
+        }
+        Ok(())
+    }
+
+    fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+        let mut file = self.file.try_clone()?;
+        let header = self.header;
+        thread::Builder::new()
+            .name(format!("net-tap-{}", self.name))
+            .spawn(move || {
+                let mut buffer = vec![0u8; MAX_FRAME_SIZE];
+                // Without headers from the kernel, there's an empty
+                // one in front of every frame.
+                let start = if header { 0 } else { HEADER_SIZE };
+                loop {
+                    match file.read(&mut buffer[start..]) {
+                        Ok(0) => return,
+                        Ok(count) => sink.receive(&buffer[..start + count]),
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(e) => {
+                            warn!("could not read from TAP interface: {}", e);
+                            return;
+                        }
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn offloads(&self) -> bool {
+        self.header
+    }
+
+    fn set_offloads(&self, offloads: Offloads) -> Result<()> {
+        if !self.header {
+            return Ok(());
+        }
+
+        let mut flags = 0;
+        if offloads.checksum {
+            flags |= TUN_F_CSUM;
+            if offloads.tso4 {
+                flags |= TUN_F_TSO4;
+            }
+            if offloads.tso6 {
+                flags |= TUN_F_TSO6;
+            }
+            if offloads.ecn && (offloads.tso4 || offloads.tso6) {
+                flags |= TUN_F_TSO_ECN;
+            }
+        }
+        ioctl(&self.file, TUNSETOFFLOAD, flags as usize as *mut libc::c_void)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct Frames(Mutex<Vec<Vec<u8>>>);
+
+    impl Sink for Frames {
+        fn receive(&self, frame: &[u8]) {
+            self.0.lock().unwrap().push(frame.to_vec());
+        }
+    }
+
+    #[test]
+    fn it_opens_an_interface_and_takes_it_over_by_descriptor() {
+        // Plenty of hosts don't let us create interfaces at all.
+        let name = format!("vent{}", process::id() % 100_000);
+        let tap = match Tap::open(&name) {
+            Ok(tap) => tap,
+            Err(_) => return,
+        };
+        assert_eq!(tap.name(), name);
+        assert!(tap.offloads());
+        tap.set_offloads(Offloads {
+            checksum: true,
+            tso4: true,
+            tso6: true,
+            ecn: false,
+        })
+        .unwrap();
+        tap.attach(Arc::new(Frames::default())).unwrap();
+
+        // The interface is down, so frames don't go anywhere, but
+        // they're still taken.
+        let mut frame = vec![0u8; HEADER_SIZE + 60];
+        frame[HEADER_SIZE..HEADER_SIZE + 6].copy_from_slice(&[0xff; 6]);
+        let _ = tap.send(&frame);
+
+        let fd = unsafe { libc::dup(tap.file.as_raw_fd()) };
+        let inherited = Tap::from_fd(fd).unwrap();
+        assert_eq!(inherited.name(), name);
+        assert!(inherited.offloads());
+    }
+
+    #[test]
+    fn it_refuses_what_isnt_a_tap_interface() {
+        assert!(Tap::open("a-name-that-is-too-long").is_err());
+        let file = File::open("/dev/null").unwrap();
+        let fd = unsafe { libc::dup(file.as_raw_fd()) };
+        assert!(Tap::from_fd(fd).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use super::packet::address;
+use super::{GATEWAY, GUEST, NAMESERVER, NETMASK};
+use byteorder::{BigEndian, ByteOrder};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+/// Everything up to the options, magic cookie included.
+const FIXED_SIZE: usize = 240;
+/// The smallest message BOOTP clients take.
+const MIN_SIZE: usize = 300;
+const MAGIC: u32 = 0x6382_5363;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_NETMASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// A day; there's only ever the one address to hand out anyway.
+const LEASE_TIME: u32 = 86400;
+
+/// What the server says to a client's message, if anything.  The guest
+/// is always offered the same address, and can't have any other.
+pub fn reply(message: &[u8]) -> Option<Vec<u8>> {
+    if message.len() < FIXED_SIZE
+        || message[0] != BOOTREQUEST
+        || BigEndian::read_u32(&message[236..240]) != MAGIC
+    {
+        return None;
+    }
+
+    let mut kind = None;
+    let mut requested = None;
+    let mut options = &message[FIXED_SIZE..];
+    while let Some(&code) = options.first() {
+        match code {
+            OPTION_PAD => options = &options[1..],
+            OPTION_END => break,
+            _ => {
+                let size = match options.get(1) {
+                    Some(&size) if size as usize + 2 <= options.len() => size as usize,
+                    _ => break,
+                };
+                let value = &options[2..2 + size];
+                match code {
+                    OPTION_MESSAGE_TYPE if size == 1 => kind = Some(value[0]),
+                    OPTION_REQUESTED_ADDRESS if size == 4 => requested = Some(address(value)),
+                    _ => (),
+                }
+                options = &options[2 + size..];
+            }
+        }
+    }
+
+    let client = address(&message[12..16]);
+    let answer = match kind {
+        Some(DHCPDISCOVER) => DHCPOFFER,
+        Some(DHCPREQUEST) => match requested.unwrap_or(client) {
+            address if address == GUEST => DHCPACK,
+            _ => DHCPNAK,
+        },
+        _ => return None,
+    };
+
+    let mut reply = vec![0u8; FIXED_SIZE];
+    reply[0] = BOOTREPLY;
+    reply[1..3].copy_from_slice(&message[1..3]);
+    // The transaction, and the broadcast flag.
+    reply[4..8].copy_from_slice(&message[4..8]);
+    reply[10..12].copy_from_slice(&message[10..12]);
+    if answer != DHCPNAK {
+        reply[16..20].copy_from_slice(&GUEST.octets());
+    }
+    reply[20..24].copy_from_slice(&GATEWAY.octets());
+    reply[28..44].copy_from_slice(&message[28..44]);
+    BigEndian::write_u32(&mut reply[236..240], MAGIC);
+
+    option(&mut reply, OPTION_MESSAGE_TYPE, &[answer]);
+    option(&mut reply, OPTION_SERVER, &GATEWAY.octets());
+    if answer != DHCPNAK {
+        let mut lease = [0u8; 4];
+        BigEndian::write_u32(&mut lease, LEASE_TIME);
+        option(&mut reply, OPTION_LEASE_TIME, &lease);
+        option(&mut reply, OPTION_NETMASK, &NETMASK.octets());
+        option(&mut reply, OPTION_ROUTER, &GATEWAY.octets());
+        option(&mut reply, OPTION_DNS, &NAMESERVER.octets());
+    }
+    reply.push(OPTION_END);
+    if reply.len() < MIN_SIZE {
+        reply.resize(MIN_SIZE, 0);
+    }
+
+    Some(reply)
+}
+
+fn option(message: &mut Vec<u8>, code: u8, value: &[u8]) {
+    message.push(code);
+    message.push(value.len() as u8);
+    message.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn message(kind: u8, requested: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut message = vec![0u8; FIXED_SIZE];
+        message[0] = BOOTREQUEST;
+        message[1] = 1;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        message[28..34].copy_from_slice(&[0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        BigEndian::write_u32(&mut message[236..240], MAGIC);
+        option(&mut message, OPTION_MESSAGE_TYPE, &[kind]);
+        if let Some(requested) = requested {
+            option(&mut message, OPTION_REQUESTED_ADDRESS, &requested.octets());
+        }
+        message.push(OPTION_END);
+        message
+    }
+
+    /// The options of a reply, as codes and values.
+    fn options(reply: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut options = vec![];
+        let mut rest = &reply[FIXED_SIZE..];
+        while rest[0] != OPTION_END {
+            let size = rest[1] as usize;
+            options.push((rest[0], rest[2..2 + size].to_vec()));
+            rest = &rest[2 + size..];
+        }
+        options
+    }
+
+    #[test]
+    fn it_offers_and_acknowledges_the_guest_address() {
+        let offer = reply(&message(DHCPDISCOVER, None)).unwrap();
+        assert_eq!(offer[0], BOOTREPLY);
+        assert_eq!(&offer[4..8], &[1, 2, 3, 4]);
+        assert_eq!(address(&offer[16..20]), GUEST);
+        assert_eq!(&offer[28..34], &[0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+        assert!(offer.len() >= MIN_SIZE);
+        let options = options(&offer);
+        assert_eq!(options[0], (OPTION_MESSAGE_TYPE, vec![DHCPOFFER]));
+        assert!(options.contains(&(OPTION_ROUTER, GATEWAY.octets().to_vec())));
+        assert!(options.contains(&(OPTION_DNS, NAMESERVER.octets().to_vec())));
+
+        let ack = reply(&message(DHCPREQUEST, Some(GUEST))).unwrap();
+        assert_eq!(self::options(&ack)[0], (OPTION_MESSAGE_TYPE, vec![DHCPACK]));
+        assert_eq!(address(&ack[16..20]), GUEST);
+    }
+
+    #[test]
+    fn it_refuses_other_addresses() {
+        let nak = reply(&message(DHCPREQUEST, Some(Ipv4Addr::new(10, 0, 2, 99)))).unwrap();
+        assert_eq!(
+            options(&nak),
+            vec![
+                (OPTION_MESSAGE_TYPE, vec![DHCPNAK]),
+                (OPTION_SERVER, GATEWAY.octets().to_vec())
+            ]
+        );
+        assert_eq!(address(&nak[16..20]), Ipv4Addr::new(0, 0, 0, 0));
+
+        // Releases and the like don't get an answer.
+        assert!(reply(&message(7, None)).is_none());
+        assert!(reply(&[0u8; 100]).is_none());
+    }
+}
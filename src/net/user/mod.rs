@@ -0,0 +1,1053 @@
+use self::packet::{
+    Frame, Ipv4, Tcp, Udp, BROADCAST, ETHERTYPE_ARP, ETHERTYPE_IPV4, PROTOCOL_ICMP, PROTOCOL_TCP,
+    PROTOCOL_UDP, TCP_ACK, TCP_RST, TCP_SYN,
+};
+use self::tcp::Connection;
+use super::{Backend, Sink};
+use byteorder::{BigEndian, ByteOrder};
+use error::*;
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+mod dhcp;
+mod packet;
+mod tcp;
+
+/// The host, as the guest sees it.
+pub const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+/// Where the guest's DNS queries go, to be passed on.
+pub const NAMESERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+/// The only address handed out.
+pub const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+pub const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+/// The gateway's and the nameserver's Ethernet address.
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+/// How often connections and flows are looked after, while there are
+/// any.
+const TICK: Duration = Duration::from_millis(100);
+/// How long a UDP flow lasts with nothing going through it.
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many of the guest's frames wait to be looked at, at most.
+/// Anything beyond that is dropped, like a full queue on a switch.
+const MAX_INBOX: usize = 1024;
+/// How many TCP connections and UDP flows there can be at once, since
+/// each takes a socket on the host.  Past that, new connections are
+/// refused, and datagrams that would start a flow are dropped.
+const MAX_CONNECTIONS: usize = 256;
+const MAX_FLOWS: usize = 256;
+
+const WAKER: Token = Token(0);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// A port on the host that leads to one on the guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Forward {
+    pub protocol: Protocol,
+    /// Where to listen on the host.
+    pub host: SocketAddr,
+    /// The guest's port it leads to.
+    pub guest: u16,
+}
+
+/// The host's first IPv4 nameserver, if it has one.
+pub fn host_nameserver() -> Option<SocketAddr> {
+    let file = File::open("/etc/resolv.conf").ok()?;
+    for line in BufReader::new(file).lines() {
+        let line = line.ok()?;
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            continue;
+        }
+        if let Some(Ok(address)) = words.next().map(|word| word.parse::<Ipv4Addr>()) {
+            return Some(SocketAddr::new(IpAddr::V4(address), 53));
+        }
+    }
+    None
+}
+
+/// What's shared between the backend and its thread.
+#[derive(Debug, Default)]
+struct Shared {
+    /// The guest's frames, waiting to be looked at.
+    inbox: Mutex<VecDeque<Vec<u8>>>,
+    stopping: AtomicBool,
+}
+
+/// What's set up before the backend is attached.
+#[derive(Debug)]
+struct Setup {
+    poll: Poll,
+    registration: Registration,
+    forwards: Vec<Listener>,
+    nameserver: Option<SocketAddr>,
+}
+
+/// A network of the guest's own, behind NAT on the host.  The guest
+/// gets its address over DHCP, and whatever it sends out goes through
+/// ordinary sockets on the host, so it needs no privileges at all.
+/// Only TCP and UDP make it out; ICMP only reaches the gateway.
+#[derive(Debug)]
+pub struct User {
+    shared: Arc<Shared>,
+    waker: SetReadiness,
+    setup: Mutex<Option<Setup>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl User {
+    /// Sets up a network whose DNS queries go to the given nameserver,
+    /// if any, listening on the host for the given forwards right away.
+    pub fn new(forwards: &[Forward], nameserver: Option<SocketAddr>) -> Result<User> {
+        let poll = Poll::new()?;
+        let (registration, waker) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())?;
+
+        let mut listeners = vec![];
+        for (i, forward) in forwards.iter().enumerate() {
+            let token = Token(1 + i);
+            let listener = match forward.protocol {
+                Protocol::Tcp => {
+                    let listener = TcpListener::bind(&forward.host)?;
+                    poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
+                    Listener::Tcp(listener, forward.guest)
+                }
+                Protocol::Udp => {
+                    let socket = UdpSocket::bind(&forward.host)?;
+                    poll.register(&socket, token, Ready::readable(), PollOpt::edge())?;
+                    Listener::Udp(socket, forward.guest)
+                }
+            };
+            listeners.push(listener);
+        }
+        debug!(
+            "user network with {} forwards, nameserver {:?}",
+            forwards.len(),
+            nameserver
+        );
+
+        Ok(User {
+            shared: Arc::new(Shared::default()),
+            waker,
+            setup: Mutex::new(Some(Setup {
+                poll,
+                registration,
+                forwards: listeners,
+                nameserver,
+            })),
+            thread: Mutex::new(None),
+        })
+    }
+}
+
+impl Backend for User {
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        {
+            let mut inbox = self.shared.inbox.lock().unwrap();
+            if inbox.len() >= MAX_INBOX {
+                return Ok(());
+            }
+            inbox.push_back(frame.to_vec());
+        }
+        self.waker.set_readiness(Ready::readable())?;
+        Ok(())
+    }
+
+    fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+        let setup = self
+            .setup
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(ErrorKind::DeviceError("network backend attached twice"))?;
+        let mut stack = Stack {
+            next_token: 1 + setup.forwards.len(),
+            poll: setup.poll,
+            _registration: setup.registration,
+            waker: self.waker.clone(),
+            sink,
+            guest_mac: BROADCAST,
+            forwards: setup.forwards,
+            nameserver: setup.nameserver,
+            connections: HashMap::new(),
+            flows: HashMap::new(),
+            owners: HashMap::new(),
+        };
+        let shared = self.shared.clone();
+        let thread = thread::Builder::new()
+            .name("net-user".to_owned())
+            .spawn(move || stack.run(&shared))?;
+        *self.thread.lock().unwrap() = Some(thread);
+        Ok(())
+    }
+}
+
+impl Drop for User {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        let _ = self.waker.set_readiness(Ready::readable());
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A socket on the host for a forward, along with the guest's port.
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener, u16),
+    Udp(UdpSocket, u16),
+}
+
+/// A connection or a flow, as the guest's address and the one it's
+/// talking to.
+type Key = (SocketAddrV4, SocketAddrV4);
+
+/// What a token belongs to, besides the waker and the forwards.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Owner {
+    Tcp(Key),
+    Udp(Key),
+}
+
+#[derive(Debug)]
+enum Route {
+    /// A socket of the flow's own, connected to where it goes.
+    Socket(UdpSocket, Token),
+    /// The forward with the given index, and the peer on the host.
+    Forward(usize, SocketAddr),
+}
+
+/// UDP datagrams going between the guest and some address.
+#[derive(Debug)]
+struct Flow {
+    route: Route,
+    used: Instant,
+}
+
+/// The network itself, run on a thread of its own.
+#[derive(Debug)]
+struct Stack {
+    poll: Poll,
+    _registration: Registration,
+    waker: SetReadiness,
+    sink: Arc<Sink>,
+    /// Where frames for the guest go, which is learned from its own.
+    guest_mac: [u8; 6],
+    forwards: Vec<Listener>,
+    nameserver: Option<SocketAddr>,
+    connections: HashMap<Key, (Token, Connection)>,
+    flows: HashMap<Key, Flow>,
+    owners: HashMap<Token, Owner>,
+    next_token: usize,
+}
+
+impl Stack {
+    fn run(&mut self, shared: &Shared) {
+        let mut events = Events::with_capacity(64);
+        loop {
+            let busy = !self.connections.is_empty() || !self.flows.is_empty();
+            if let Err(e) = self
+                .poll
+                .poll(&mut events, if busy { Some(TICK) } else { None })
+            {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                warn!("could not wait on the user network: {}", e);
+                return;
+            }
+            if shared.stopping.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let tokens = events.iter().map(|event| event.token()).collect::<Vec<_>>();
+            for token in tokens {
+                if token == WAKER {
+                    let _ = self.waker.set_readiness(Ready::empty());
+                } else {
+                    self.ready(token);
+                }
+            }
+            loop {
+                let frame = shared.inbox.lock().unwrap().pop_front();
+                match frame {
+                    Some(frame) => self.guest(&frame),
+                    None => break,
+                }
+            }
+            self.tick();
+        }
+    }
+
+    fn token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        token
+    }
+
+    /// Sends the guest an IPv4 packet.
+    fn emit(&self, packet: &[u8]) {
+        self.sink.receive(&packet::frame(
+            self.guest_mac,
+            GATEWAY_MAC,
+            ETHERTYPE_IPV4,
+            packet,
+        ));
+    }
+
+    fn emit_all(&self, packets: Vec<Vec<u8>>) {
+        for packet in packets {
+            self.emit(&packet);
+        }
+    }
+
+    /// Where something the guest sends to `remote` goes on the host.
+    /// The gateway is the host itself, and nothing else on the
+    /// guest's network is reachable.
+    fn host_address(&self, remote: SocketAddrV4) -> Option<SocketAddr> {
+        let ip = *remote.ip();
+        let mask = u32::from(NETMASK);
+        if ip == GATEWAY {
+            Some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                remote.port(),
+            ))
+        } else if ip == NAMESERVER {
+            if remote.port() == 53 {
+                self.nameserver
+            } else {
+                None
+            }
+        } else if ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_unspecified()
+            || (u32::from(ip) & mask) == (u32::from(GATEWAY) & mask)
+        {
+            None
+        } else {
+            Some(SocketAddr::V4(remote))
+        }
+    }
+
+    /// The guest sent a frame.
+    fn guest(&mut self, frame: &[u8]) {
+        let frame = match Frame::parse(frame) {
+            Some(frame) => frame,
+            None => return,
+        };
+        if (frame.source[0] & 1) == 0 {
+            self.guest_mac = frame.source;
+        }
+
+        match frame.ethertype {
+            ETHERTYPE_ARP => self.arp(frame.payload),
+            ETHERTYPE_IPV4 => {
+                let ip = match Ipv4::parse(frame.payload) {
+                    Some(ip) => ip,
+                    None => return,
+                };
+                match ip.protocol {
+                    PROTOCOL_ICMP => self.icmp(&ip),
+                    PROTOCOL_UDP => self.udp(&ip),
+                    PROTOCOL_TCP => self.tcp(&ip),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Answers who has the gateway's and the nameserver's addresses.
+    fn arp(&self, message: &[u8]) {
+        if message.len() < 28
+            || BigEndian::read_u16(&message[0..2]) != 1
+            || BigEndian::read_u16(&message[2..4]) != ETHERTYPE_IPV4
+            || BigEndian::read_u16(&message[6..8]) != 1
+        {
+            return;
+        }
+        let target = packet::address(&message[24..28]);
+        if target != GATEWAY && target != NAMESERVER {
+            return;
+        }
+
+        let mut reply = message[..28].to_vec();
+        BigEndian::write_u16(&mut reply[6..8], 2);
+        reply[8..14].copy_from_slice(&GATEWAY_MAC);
+        reply[14..18].copy_from_slice(&target.octets());
+        reply[18..28].copy_from_slice(&message[8..18]);
+        self.sink.receive(&packet::frame(
+            self.guest_mac,
+            GATEWAY_MAC,
+            ETHERTYPE_ARP,
+            &reply,
+        ));
+    }
+
+    /// Answers pings to the gateway and the nameserver.
+    fn icmp(&self, ip: &Ipv4) {
+        if (ip.destination != GATEWAY && ip.destination != NAMESERVER)
+            || ip.payload.len() < 8
+            || ip.payload[0] != 8
+        {
+            return;
+        }
+
+        let mut reply = ip.payload.to_vec();
+        reply[0] = 0;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let checksum = packet::checksum(&reply);
+        BigEndian::write_u16(&mut reply[2..4], checksum);
+        self.emit(&packet::ipv4(
+            ip.destination,
+            ip.source,
+            PROTOCOL_ICMP,
+            &reply,
+        ));
+    }
+
+    fn udp(&mut self, ip: &Ipv4) {
+        let udp = match Udp::parse(ip.payload) {
+            Some(udp) => udp,
+            None => return,
+        };
+        if udp.destination == dhcp::SERVER_PORT {
+            if let Some(reply) = dhcp::reply(udp.payload) {
+                let datagram = packet::udp(
+                    SocketAddrV4::new(GATEWAY, dhcp::SERVER_PORT),
+                    SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), dhcp::CLIENT_PORT),
+                    &reply,
+                );
+                self.sink.receive(&packet::frame(
+                    BROADCAST,
+                    GATEWAY_MAC,
+                    ETHERTYPE_IPV4,
+                    &datagram,
+                ));
+            }
+            return;
+        }
+
+        let key = (
+            SocketAddrV4::new(ip.source, udp.source),
+            SocketAddrV4::new(ip.destination, udp.destination),
+        );
+        if !self.flows.contains_key(&key) {
+            if self.flows.len() >= MAX_FLOWS {
+                debug!("too many UDP flows, dropping a datagram to {}", key.1);
+                return;
+            }
+            let host = match self.host_address(key.1) {
+                Some(host) => host,
+                None => return,
+            };
+            match self.open_flow(key, host) {
+                Ok(route) => {
+                    self.flows.insert(
+                        key,
+                        Flow {
+                            route,
+                            used: Instant::now(),
+                        },
+                    );
+                }
+                Err(e) => {
+                    debug!("could not open a UDP flow to {}: {}", host, e);
+                    return;
+                }
+            }
+        }
+
+        let flow = self.flows.get_mut(&key).unwrap();
+        flow.used = Instant::now();
+        let result = match flow.route {
+            Route::Socket(ref socket, _) => socket.send(udp.payload),
+            Route::Forward(i, ref peer) => match self.forwards[i] {
+                Listener::Udp(ref socket, _) => socket.send_to(udp.payload, peer),
+                Listener::Tcp(..) => return,
+            },
+        };
+        if let Err(e) = result {
+            debug!("could not send a datagram from the guest: {}", e);
+        }
+    }
+
+    fn open_flow(&mut self, key: Key, host: SocketAddr) -> io::Result<Route> {
+        let any = match host {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(0.into()), 0),
+        };
+        let socket = UdpSocket::bind(&any)?;
+        socket.connect(host)?;
+        let token = self.token();
+        self.poll
+            .register(&socket, token, Ready::readable(), PollOpt::edge())?;
+        self.owners.insert(token, Owner::Udp(key));
+        Ok(Route::Socket(socket, token))
+    }
+
+    /// Passes on what came in for a flow.
+    fn receive_flow(&mut self, key: Key) {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let size = match self.flows.get(&key) {
+                Some(&Flow {
+                    route: Route::Socket(ref socket, _),
+                    ..
+                }) => match socket.recv(&mut buffer) {
+                    Ok(size) => size,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) => {
+                        debug!("could not receive a datagram for the guest: {}", e);
+                        return;
+                    }
+                },
+                _ => return,
+            };
+            self.emit(&packet::udp(key.1, key.0, &buffer[..size]));
+        }
+    }
+
+    fn tcp(&mut self, ip: &Ipv4) {
+        let tcp = match Tcp::parse(ip.payload) {
+            Some(tcp) => tcp,
+            None => return,
+        };
+        let key = (
+            SocketAddrV4::new(ip.source, tcp.source),
+            SocketAddrV4::new(ip.destination, tcp.destination),
+        );
+
+        let packets = match self.connections.get_mut(&key) {
+            Some(&mut (_, ref mut connection)) => connection.segment(&tcp, Instant::now()),
+            None if (tcp.flags & (TCP_SYN | TCP_ACK | TCP_RST)) == TCP_SYN => {
+                return self.connect(key, &tcp)
+            }
+            None => tcp::refuse(key.0, key.1, &tcp).into_iter().collect(),
+        };
+        self.emit_tcp(key, packets);
+    }
+
+    /// Connects on the host for the guest.
+    fn connect(&mut self, key: Key, syn: &Tcp) {
+        if self.connections.len() >= MAX_CONNECTIONS {
+            debug!("too many connections, refusing the guest's to {}", key.1);
+            self.emit_all(tcp::refuse(key.0, key.1, syn).into_iter().collect());
+            return;
+        }
+
+        let stream = self
+            .host_address(key.1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "unreachable"))
+            .and_then(|host| TcpStream::connect(&host));
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("could not connect the guest to {}: {}", key.1, e);
+                self.emit_all(tcp::refuse(key.0, key.1, syn).into_iter().collect());
+                return;
+            }
+        };
+
+        let connection = Connection::connect(stream, key.0, key.1, syn, Instant::now());
+        self.add(key, connection, vec![]);
+    }
+
+    fn add(&mut self, key: Key, connection: Connection, packets: Vec<Vec<u8>>) {
+        let token = self.token();
+        let interest = Ready::readable() | Ready::writable();
+        if let Err(e) = self
+            .poll
+            .register(connection.stream(), token, interest, PollOpt::edge())
+        {
+            warn!("could not watch a connection for the guest: {}", e);
+            return;
+        }
+        self.owners.insert(token, Owner::Tcp(key));
+        self.connections.insert(key, (token, connection));
+        self.emit_tcp(key, packets);
+    }
+
+    /// Sends the guest what a connection has for it, and lets the
+    /// connection go if it's finished.
+    fn emit_tcp(&mut self, key: Key, packets: Vec<Vec<u8>>) {
+        self.emit_all(packets);
+
+        let token = match self.connections.get(&key) {
+            Some(&(token, ref connection)) if connection.finished() => token,
+            _ => return,
+        };
+        self.connections.remove(&key);
+        self.owners.remove(&token);
+    }
+
+    /// A socket on the host has something for us.
+    fn ready(&mut self, token: Token) {
+        if token.0 <= self.forwards.len() {
+            return self.forward(token.0 - 1);
+        }
+
+        match self.owners.get(&token).cloned() {
+            Some(Owner::Tcp(key)) => {
+                let packets = match self.connections.get_mut(&key) {
+                    Some(&mut (_, ref mut connection)) => connection.ready(Instant::now()),
+                    None => return,
+                };
+                self.emit_tcp(key, packets);
+            }
+            Some(Owner::Udp(key)) => self.receive_flow(key),
+            None => (),
+        }
+    }
+
+    /// Someone on the host went through a forward.
+    fn forward(&mut self, i: usize) {
+        match self.forwards[i] {
+            Listener::Tcp(ref listener, port) => {
+                let mut accepted = vec![];
+                loop {
+                    match listener.accept() {
+                        Ok((stream, peer)) => accepted.push((stream, peer)),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            warn!("could not accept a connection for the guest: {}", e);
+                            break;
+                        }
+                    }
+                }
+                for (stream, peer) in accepted {
+                    self.accept(stream, peer, port);
+                }
+            }
+            Listener::Udp(ref socket, port) => {
+                let mut buffer = vec![0u8; 65536];
+                loop {
+                    let (size, peer) = match socket.recv_from(&mut buffer) {
+                        Ok(received) => received,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            debug!("could not receive a datagram for the guest: {}", e);
+                            break;
+                        }
+                    };
+                    let key = (
+                        SocketAddrV4::new(GUEST, port),
+                        SocketAddrV4::new(GATEWAY, peer.port()),
+                    );
+                    if !self.flows.contains_key(&key) && self.flows.len() >= MAX_FLOWS {
+                        debug!("too many UDP flows, dropping a datagram from {}", peer);
+                        continue;
+                    }
+                    let now = Instant::now();
+                    self.flows
+                        .entry(key)
+                        .or_insert(Flow {
+                            route: Route::Forward(i, peer),
+                            used: now,
+                        })
+                        .used = now;
+                    let datagram = packet::udp(key.1, key.0, &buffer[..size]);
+                    self.sink.receive(&packet::frame(
+                        self.guest_mac,
+                        GATEWAY_MAC,
+                        ETHERTYPE_IPV4,
+                        &datagram,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Passes a connection to a forward on to the guest, which sees it
+    /// coming from the gateway.
+    fn accept(&mut self, stream: TcpStream, peer: SocketAddr, port: u16) {
+        let key = (
+            SocketAddrV4::new(GUEST, port),
+            SocketAddrV4::new(GATEWAY, peer.port()),
+        );
+        if self.connections.contains_key(&key) {
+            return;
+        }
+        if self.connections.len() >= MAX_CONNECTIONS {
+            debug!("too many connections, hanging up on {}", peer);
+            return;
+        }
+
+        let (connection, packets) = Connection::accept(stream, key.0, key.1, Instant::now());
+        self.add(key, connection, packets);
+    }
+
+    /// Retransmits what needs to be, and lets go of idle flows.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let keys = self.connections.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            let packets = match self.connections.get_mut(&key) {
+                Some(&mut (_, ref mut connection)) => connection.tick(now),
+                None => continue,
+            };
+            self.emit_tcp(key, packets);
+        }
+
+        let expired = self
+            .flows
+            .iter()
+            .filter(|&(_, flow)| now.duration_since(flow.used) >= UDP_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in expired {
+            if let Some(Flow {
+                route: Route::Socket(_, token),
+                ..
+            }) = self.flows.remove(&key)
+            {
+                self.owners.remove(&token);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::packet::{Segment, TCP_FIN, TCP_PSH};
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    #[derive(Debug)]
+    struct Frames(Mutex<Sender<Vec<u8>>>);
+
+    impl Sink for Frames {
+        fn receive(&self, frame: &[u8]) {
+            let _ = self.0.lock().unwrap().send(frame.to_vec());
+        }
+    }
+
+    /// A TCP segment the guest got.
+    #[derive(Debug)]
+    struct Received {
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        flags: u8,
+        sequence: u32,
+        acknowledgement: u32,
+        payload: Vec<u8>,
+    }
+
+    struct Guest {
+        user: User,
+        frames: Receiver<Vec<u8>>,
+    }
+
+    impl Guest {
+        fn new(forwards: &[Forward], nameserver: Option<SocketAddr>) -> Guest {
+            let user = User::new(forwards, nameserver).unwrap();
+            let (sender, frames) = mpsc::channel();
+            user.attach(Arc::new(Frames(Mutex::new(sender)))).unwrap();
+            Guest { user, frames }
+        }
+
+        fn send(&self, ethertype: u16, payload: &[u8]) {
+            let frame = packet::frame(GATEWAY_MAC, MAC, ethertype, payload);
+            self.user.send(&frame).unwrap();
+        }
+
+        fn send_tcp(
+            &self,
+            source: SocketAddrV4,
+            destination: SocketAddrV4,
+            sequence: u32,
+            acknowledgement: u32,
+            flags: u8,
+            payload: &[u8],
+        ) {
+            let segment = Segment {
+                sequence,
+                acknowledgement,
+                flags,
+                window: 0xffff,
+                mss: if (flags & TCP_SYN) != 0 {
+                    Some(1460)
+                } else {
+                    None
+                },
+            };
+            self.send(
+                ETHERTYPE_IPV4,
+                &packet::tcp(source, destination, segment, payload),
+            );
+        }
+
+        /// The next frame, as where it's going, its ethertype, and its
+        /// payload.
+        fn receive(&self) -> ([u8; 6], u16, Vec<u8>) {
+            let frame = self.frames.recv_timeout(Duration::from_secs(5)).unwrap();
+            let frame = Frame::parse(&frame).unwrap();
+            (frame.destination, frame.ethertype, frame.payload.to_vec())
+        }
+
+        fn datagram(&self) -> (SocketAddrV4, SocketAddrV4, Vec<u8>) {
+            let (_, _, packet) = self.receive();
+            let ip = Ipv4::parse(&packet).unwrap();
+            let udp = Udp::parse(ip.payload).unwrap();
+            (
+                SocketAddrV4::new(ip.source, udp.source),
+                SocketAddrV4::new(ip.destination, udp.destination),
+                udp.payload.to_vec(),
+            )
+        }
+
+        fn segment(&self) -> Received {
+            let (_, _, packet) = self.receive();
+            let ip = Ipv4::parse(&packet).unwrap();
+            let tcp = Tcp::parse(ip.payload).unwrap();
+            Received {
+                source: SocketAddrV4::new(ip.source, tcp.source),
+                destination: SocketAddrV4::new(ip.destination, tcp.destination),
+                flags: tcp.flags,
+                sequence: tcp.sequence,
+                acknowledgement: tcp.acknowledgement,
+                payload: tcp.payload.to_vec(),
+            }
+        }
+    }
+
+    #[test]
+    fn it_answers_for_the_gateway() {
+        let guest = Guest::new(&[], None);
+        let mut request = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        request.extend_from_slice(&MAC);
+        request.extend_from_slice(&GUEST.octets());
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&GATEWAY.octets());
+        guest.send(ETHERTYPE_ARP, &request);
+        let (destination, ethertype, reply) = guest.receive();
+        assert_eq!((destination, ethertype), (MAC, ETHERTYPE_ARP));
+        assert_eq!(&reply[6..8], &[0, 2]);
+        assert_eq!(&reply[8..14], &GATEWAY_MAC);
+        assert_eq!(packet::address(&reply[14..18]), GATEWAY);
+        assert_eq!(&reply[18..24], &MAC);
+
+        let mut echo = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, b'h', b'i'];
+        let checksum = packet::checksum(&echo);
+        BigEndian::write_u16(&mut echo[2..4], checksum);
+        guest.send(
+            ETHERTYPE_IPV4,
+            &packet::ipv4(GUEST, GATEWAY, PROTOCOL_ICMP, &echo),
+        );
+        let (_, _, packet) = guest.receive();
+        let ip = Ipv4::parse(&packet).unwrap();
+        assert_eq!(
+            (ip.source, ip.destination, ip.protocol),
+            (GATEWAY, GUEST, PROTOCOL_ICMP)
+        );
+        assert_eq!(ip.payload[0], 0);
+        assert_eq!(&ip.payload[4..], &echo[4..]);
+        assert_eq!(packet::checksum(ip.payload), 0);
+    }
+
+    #[test]
+    fn it_hands_out_the_guest_address() {
+        let guest = Guest::new(&[], None);
+        let mut discover = vec![0u8; 240];
+        discover[0] = 1;
+        discover[1] = 1;
+        discover[2] = 6;
+        discover[28..34].copy_from_slice(&MAC);
+        BigEndian::write_u32(&mut discover[236..240], 0x6382_5363);
+        discover.extend_from_slice(&[53, 1, 1, 255]);
+        guest.send(
+            ETHERTYPE_IPV4,
+            &packet::udp(
+                SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), dhcp::CLIENT_PORT),
+                SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), dhcp::SERVER_PORT),
+                &discover,
+            ),
+        );
+
+        let (destination, _, packet) = guest.receive();
+        assert_eq!(destination, BROADCAST);
+        let ip = Ipv4::parse(&packet).unwrap();
+        let udp = Udp::parse(ip.payload).unwrap();
+        assert_eq!(
+            (udp.source, udp.destination),
+            (dhcp::SERVER_PORT, dhcp::CLIENT_PORT)
+        );
+        assert_eq!(packet::address(&udp.payload[16..20]), GUEST);
+    }
+
+    #[test]
+    fn it_forwards_udp_to_the_host_and_dns_to_the_nameserver() {
+        let echo = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for socket in &[&echo, &resolver] {
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        let guest = Guest::new(&[], Some(resolver.local_addr().unwrap()));
+        let local = SocketAddrV4::new(GUEST, 40000);
+        let mut buffer = [0u8; 64];
+
+        let remote = SocketAddrV4::new(GATEWAY, echo.local_addr().unwrap().port());
+        guest.send(ETHERTYPE_IPV4, &packet::udp(local, remote, b"hello"));
+        let (size, peer) = echo.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hello");
+        echo.send_to(b"world", peer).unwrap();
+        assert_eq!(guest.datagram(), (remote, local, b"world".to_vec()));
+
+        let remote = SocketAddrV4::new(NAMESERVER, 53);
+        guest.send(ETHERTYPE_IPV4, &packet::udp(local, remote, b"query"));
+        let (size, peer) = resolver.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"query");
+        resolver.send_to(b"answer", peer).unwrap();
+        assert_eq!(guest.datagram(), (remote, local, b"answer".to_vec()));
+    }
+
+    #[test]
+    fn it_drops_datagrams_past_the_last_flow() {
+        let echo = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        echo.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let guest = Guest::new(&[], None);
+        let remote = SocketAddrV4::new(GATEWAY, echo.local_addr().unwrap().port());
+        for port in 0..MAX_FLOWS as u16 + 1 {
+            let local = SocketAddrV4::new(GUEST, 41000 + port);
+            guest.send(ETHERTYPE_IPV4, &packet::udp(local, remote, b"hello"));
+        }
+
+        let mut buffer = [0u8; 64];
+        for _ in 0..MAX_FLOWS {
+            echo.recv_from(&mut buffer).unwrap();
+        }
+        echo.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(echo.recv_from(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn it_refuses_connections_past_the_last_one() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let guest = Guest::new(&[], None);
+        let remote = SocketAddrV4::new(GATEWAY, listener.local_addr().unwrap().port());
+        for port in 0..MAX_CONNECTIONS as u16 + 1 {
+            let local = SocketAddrV4::new(GUEST, 42000 + port);
+            guest.send_tcp(local, remote, 1000, 0, TCP_SYN, b"");
+        }
+
+        // The others are accepted, as the host gets around to them.
+        let last = SocketAddrV4::new(GUEST, 42000 + MAX_CONNECTIONS as u16);
+        let refused = loop {
+            let segment = guest.segment();
+            if segment.destination == last {
+                break segment;
+            }
+            assert_eq!(segment.flags, TCP_SYN | TCP_ACK);
+        };
+        assert_eq!(refused.flags, TCP_RST | TCP_ACK);
+        assert_eq!(refused.acknowledgement, 1001);
+    }
+
+    #[test]
+    fn it_connects_the_guest_to_the_host() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let guest = Guest::new(&[], None);
+        let local = SocketAddrV4::new(GUEST, 40001);
+        let remote = SocketAddrV4::new(GATEWAY, listener.local_addr().unwrap().port());
+
+        guest.send_tcp(local, remote, 1000, 0, TCP_SYN, b"");
+        let accepted = guest.segment();
+        assert_eq!((accepted.source, accepted.destination), (remote, local));
+        assert_eq!(accepted.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(accepted.acknowledgement, 1001);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let start = accepted.sequence.wrapping_add(1);
+
+        guest.send_tcp(local, remote, 1001, start, TCP_ACK | TCP_PSH, b"hello");
+        let mut buffer = [0u8; 5];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+        assert_eq!(guest.segment().acknowledgement, 1006);
+
+        stream.write_all(b"world").unwrap();
+        let data = guest.segment();
+        assert_eq!((data.sequence, &data.payload[..]), (start, &b"world"[..]));
+        guest.send_tcp(local, remote, 1006, start.wrapping_add(5), TCP_ACK, b"");
+
+        drop(stream);
+        let finished = guest.segment();
+        assert_eq!(finished.flags & TCP_FIN, TCP_FIN);
+        assert_eq!(finished.sequence, start.wrapping_add(5));
+    }
+
+    #[test]
+    fn it_forwards_host_ports_to_the_guest() {
+        let port = net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let host = SocketAddr::from(([127, 0, 0, 1], port));
+        let guest = Guest::new(
+            &[Forward {
+                protocol: Protocol::Tcp,
+                host,
+                guest: 22,
+            }],
+            None,
+        );
+
+        let mut stream = net::TcpStream::connect(host).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let syn = guest.segment();
+        assert_eq!(syn.flags, TCP_SYN);
+        assert_eq!(syn.destination, SocketAddrV4::new(GUEST, 22));
+        assert_eq!(*syn.source.ip(), GATEWAY);
+        let (local, remote) = (syn.destination, syn.source);
+        let start = syn.sequence.wrapping_add(1);
+
+        guest.send_tcp(local, remote, 5000, start, TCP_SYN | TCP_ACK, b"");
+        let established = guest.segment();
+        assert_eq!(
+            (established.flags, established.acknowledgement),
+            (TCP_ACK, 5001)
+        );
+
+        stream.write_all(b"ping").unwrap();
+        let data = guest.segment();
+        assert_eq!((data.sequence, &data.payload[..]), (start, &b"ping"[..]));
+        guest.send_tcp(
+            local,
+            remote,
+            5001,
+            start.wrapping_add(4),
+            TCP_ACK | TCP_PSH,
+            b"pong",
+        );
+        let mut buffer = [0u8; 4];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"pong");
+    }
+}
@@ -0,0 +1,399 @@
+use byteorder::{BigEndian, ByteOrder};
+use net::HEADER_SIZE;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const BROADCAST: [u8; 6] = [0xff; 6];
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const IPV4_HEADER_SIZE: usize = 20;
+const UDP_HEADER_SIZE: usize = 8;
+const TCP_HEADER_SIZE: usize = 20;
+/// Whatever goes past us is at most this old.
+const TTL: u8 = 64;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+/// Adds up `data` as big-endian 16-bit words, the way the Internet
+/// checksum does.  Only the last part summed can be of odd length.
+fn sum(data: &[u8], mut sum: u32) -> u32 {
+    for word in data.chunks(2) {
+        let low = word.get(1).cloned().unwrap_or(0);
+        sum += (word[0] as u32) << 8 | low as u32;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The Internet checksum of `data`.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(data, 0))
+}
+
+/// The checksum of a TCP or UDP segment, along with the addresses it
+/// goes between.
+fn transport_checksum(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    segment: &[u8],
+) -> u16 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&source.octets());
+    pseudo[4..8].copy_from_slice(&destination.octets());
+    pseudo[9] = protocol;
+    BigEndian::write_u16(&mut pseudo[10..12], segment.len() as u16);
+    fold(sum(segment, sum(&pseudo, 0)))
+}
+
+/// An Ethernet frame, as it goes between the device and its backend.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Frame<'a> {
+    pub destination: [u8; 6],
+    pub source: [u8; 6],
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Takes a frame apart, virtio-net header and all.
+    pub fn parse(frame: &'a [u8]) -> Option<Frame<'a>> {
+        if frame.len() < HEADER_SIZE + ETHERNET_HEADER_SIZE {
+            return None;
+        }
+
+        let ethernet = &frame[HEADER_SIZE..];
+        let mut destination = [0u8; 6];
+        let mut source = [0u8; 6];
+        destination.copy_from_slice(&ethernet[0..6]);
+        source.copy_from_slice(&ethernet[6..12]);
+        Some(Frame {
+            destination,
+            source,
+            ethertype: BigEndian::read_u16(&ethernet[12..14]),
+            payload: &ethernet[ETHERNET_HEADER_SIZE..],
+        })
+    }
+}
+
+/// Puts a frame together, behind an empty virtio-net header.
+pub fn frame(destination: [u8; 6], source: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; HEADER_SIZE + ETHERNET_HEADER_SIZE];
+    frame[HEADER_SIZE..HEADER_SIZE + 6].copy_from_slice(&destination);
+    frame[HEADER_SIZE + 6..HEADER_SIZE + 12].copy_from_slice(&source);
+    BigEndian::write_u16(&mut frame[HEADER_SIZE + 12..HEADER_SIZE + 14], ethertype);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// An IPv4 packet.  Fragments aren't put back together; they're just
+/// not understood.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Ipv4<'a> {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4<'a> {
+    pub fn parse(packet: &'a [u8]) -> Option<Ipv4<'a>> {
+        if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+
+        let length = (packet[0] & 0x0f) as usize * 4;
+        let total = BigEndian::read_u16(&packet[2..4]) as usize;
+        let fragment = BigEndian::read_u16(&packet[6..8]);
+        // Either more fragments, or an offset.
+        if length < IPV4_HEADER_SIZE
+            || total < length
+            || total > packet.len()
+            || fragment & 0x3fff != 0
+        {
+            return None;
+        }
+
+        Some(Ipv4 {
+            source: address(&packet[12..16]),
+            destination: address(&packet[16..20]),
+            protocol: packet[9],
+            payload: &packet[length..total],
+        })
+    }
+}
+
+pub fn address(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+/// Puts an IPv4 packet together around `payload`.
+pub fn ipv4(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; IPV4_HEADER_SIZE];
+    packet[0] = 0x45;
+    BigEndian::write_u16(&mut packet[2..4], (IPV4_HEADER_SIZE + payload.len()) as u16);
+    // Don't fragment.
+    packet[6] = 0x40;
+    packet[8] = TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&source.octets());
+    packet[16..20].copy_from_slice(&destination.octets());
+    let sum = checksum(&packet);
+    BigEndian::write_u16(&mut packet[10..12], sum);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// A UDP datagram.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Udp<'a> {
+    pub source: u16,
+    pub destination: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Udp<'a> {
+    pub fn parse(datagram: &'a [u8]) -> Option<Udp<'a>> {
+        if datagram.len() < UDP_HEADER_SIZE {
+            return None;
+        }
+
+        let length = BigEndian::read_u16(&datagram[4..6]) as usize;
+        if length < UDP_HEADER_SIZE || length > datagram.len() {
+            return None;
+        }
+
+        Some(Udp {
+            source: BigEndian::read_u16(&datagram[0..2]),
+            destination: BigEndian::read_u16(&datagram[2..4]),
+            payload: &datagram[UDP_HEADER_SIZE..length],
+        })
+    }
+}
+
+/// Puts an IPv4 packet with a UDP datagram in it together.
+pub fn udp(source: SocketAddrV4, destination: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0u8; UDP_HEADER_SIZE];
+    BigEndian::write_u16(&mut datagram[0..2], source.port());
+    BigEndian::write_u16(&mut datagram[2..4], destination.port());
+    BigEndian::write_u16(
+        &mut datagram[4..6],
+        (UDP_HEADER_SIZE + payload.len()) as u16,
+    );
+    datagram.extend_from_slice(payload);
+    let sum = match transport_checksum(*source.ip(), *destination.ip(), PROTOCOL_UDP, &datagram) {
+        // Zero means there's no checksum at all.
+        0 => 0xffff,
+        sum => sum,
+    };
+    BigEndian::write_u16(&mut datagram[6..8], sum);
+    ipv4(*source.ip(), *destination.ip(), PROTOCOL_UDP, &datagram)
+}
+
+/// A TCP segment.  Of the options, only the maximum segment size is
+/// looked at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Tcp<'a> {
+    pub source: u16,
+    pub destination: u16,
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Tcp<'a> {
+    pub fn parse(segment: &'a [u8]) -> Option<Tcp<'a>> {
+        if segment.len() < TCP_HEADER_SIZE {
+            return None;
+        }
+
+        let length = (segment[12] >> 4) as usize * 4;
+        if length < TCP_HEADER_SIZE || length > segment.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &segment[TCP_HEADER_SIZE..length];
+        while let Some(&kind) = options.first() {
+            match kind {
+                0 => break,
+                1 => options = &options[1..],
+                _ => {
+                    let size = match options.get(1) {
+                        Some(&size) if size >= 2 && size as usize <= options.len() => size as usize,
+                        _ => break,
+                    };
+                    if kind == 2 && size == 4 {
+                        mss = Some(BigEndian::read_u16(&options[2..4]));
+                    }
+                    options = &options[size..];
+                }
+            }
+        }
+
+        Some(Tcp {
+            source: BigEndian::read_u16(&segment[0..2]),
+            destination: BigEndian::read_u16(&segment[2..4]),
+            sequence: BigEndian::read_u32(&segment[4..8]),
+            acknowledgement: BigEndian::read_u32(&segment[8..12]),
+            flags: segment[13],
+            window: BigEndian::read_u16(&segment[14..16]),
+            mss,
+            payload: &segment[length..],
+        })
+    }
+
+    /// How much of the sequence space the segment takes up.
+    pub fn length(&self) -> u32 {
+        let mut length = self.payload.len() as u32;
+        if (self.flags & TCP_SYN) != 0 {
+            length += 1;
+        }
+        if (self.flags & TCP_FIN) != 0 {
+            length += 1;
+        }
+        length
+    }
+}
+
+/// The parts of a TCP segment that vary, besides where it goes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Segment {
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+}
+
+/// Puts an IPv4 packet with a TCP segment in it together.
+pub fn tcp(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    segment: Segment,
+    payload: &[u8],
+) -> Vec<u8> {
+    let options = if segment.mss.is_some() { 4 } else { 0 };
+    let mut data = vec![0u8; TCP_HEADER_SIZE + options];
+    BigEndian::write_u16(&mut data[0..2], source.port());
+    BigEndian::write_u16(&mut data[2..4], destination.port());
+    BigEndian::write_u32(&mut data[4..8], segment.sequence);
+    BigEndian::write_u32(&mut data[8..12], segment.acknowledgement);
+    data[12] = (((TCP_HEADER_SIZE + options) / 4) << 4) as u8;
+    data[13] = segment.flags;
+    BigEndian::write_u16(&mut data[14..16], segment.window);
+    if let Some(mss) = segment.mss {
+        data[20] = 2;
+        data[21] = 4;
+        BigEndian::write_u16(&mut data[22..24], mss);
+    }
+    data.extend_from_slice(payload);
+    let sum = transport_checksum(*source.ip(), *destination.ip(), PROTOCOL_TCP, &data);
+    BigEndian::write_u16(&mut data[16..18], sum);
+    ipv4(*source.ip(), *destination.ip(), PROTOCOL_TCP, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_checksums_headers() {
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0xb861);
+        assert_eq!(checksum(&[0x01]), !0x0100);
+    }
+
+    #[test]
+    fn it_builds_what_it_parses() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 80);
+        let destination = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000);
+        let segment = Segment {
+            sequence: 7,
+            acknowledgement: 9,
+            flags: TCP_SYN | TCP_ACK,
+            window: 1000,
+            mss: Some(1460),
+        };
+        let packet = tcp(source, destination, segment, b"data");
+        assert_eq!(checksum(&packet[..IPV4_HEADER_SIZE]), 0);
+
+        let ip = Ipv4::parse(&packet).unwrap();
+        assert_eq!(
+            (ip.source, ip.destination),
+            (*source.ip(), *destination.ip())
+        );
+        assert_eq!(
+            transport_checksum(ip.source, ip.destination, ip.protocol, ip.payload),
+            0
+        );
+        let parsed = Tcp::parse(ip.payload).unwrap();
+        assert_eq!(
+            (
+                parsed.source,
+                parsed.destination,
+                parsed.sequence,
+                parsed.acknowledgement
+            ),
+            (80, 40000, 7, 9)
+        );
+        assert_eq!(
+            (parsed.mss, parsed.payload, parsed.length()),
+            (Some(1460), &b"data"[..], 5)
+        );
+
+        let packet = udp(destination, source, b"query");
+        let ip = Ipv4::parse(&packet).unwrap();
+        assert_eq!(
+            transport_checksum(ip.source, ip.destination, ip.protocol, ip.payload),
+            0
+        );
+        let parsed = Udp::parse(ip.payload).unwrap();
+        assert_eq!(
+            (parsed.source, parsed.destination, parsed.payload),
+            (40000, 80, &b"query"[..])
+        );
+
+        let frame = frame(BROADCAST, [2; 6], ETHERTYPE_IPV4, &packet);
+        let parsed = Frame::parse(&frame).unwrap();
+        assert_eq!(
+            (parsed.destination, parsed.source, parsed.ethertype),
+            (BROADCAST, [2; 6], ETHERTYPE_IPV4)
+        );
+        assert_eq!(parsed.payload, &packet[..]);
+    }
+
+    #[test]
+    fn it_leaves_fragments_alone() {
+        let mut packet = ipv4(
+            Ipv4Addr::new(10, 0, 2, 15),
+            Ipv4Addr::new(1, 1, 1, 1),
+            PROTOCOL_UDP,
+            &[0; 8],
+        );
+        assert!(Ipv4::parse(&packet).is_some());
+        packet[6] = 0x20;
+        assert!(Ipv4::parse(&packet).is_none());
+    }
+}
@@ -0,0 +1,453 @@
+use super::packet::{self, Segment, Tcp, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+use mio::net::TcpStream;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The biggest segment we ask for: a whole frame's worth, at the usual
+/// MTU.
+const MSS: u16 = 1460;
+/// What the guest is taken to be able to receive if it doesn't say.
+const DEFAULT_MSS: u16 = 536;
+/// How much is held on to either way, on top of what the host socket
+/// holds.
+const BUFFER_SIZE: usize = 256 << 10;
+/// How long the guest has to acknowledge something before it's sent
+/// again.  The guest is close by, so it's short.
+const RETRANSMIT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum State {
+    /// The guest asked for a connection, which is being made on the
+    /// host.
+    Connecting,
+    /// The host connection is there, and the guest was told so.
+    Accepted,
+    /// A connection to a forward came in on the host, and the guest was
+    /// asked to take it.
+    Opening,
+    Established,
+    /// It's over, one way or another.
+    Closed,
+}
+
+/// A TCP connection between the guest and a socket on the host.  The
+/// guest sees it going to `remote`; the host sees a connection of our
+/// own.
+#[derive(Debug)]
+pub struct Connection {
+    stream: TcpStream,
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+    state: State,
+    initial: u32,
+    /// The next sequence number expected from the guest.
+    received: u32,
+    /// What the guest sent that isn't written to the host yet.
+    to_host: Vec<u8>,
+    /// The guest sent everything it's going to.
+    guest_done: bool,
+    /// The host was told the guest is done.
+    host_shut: bool,
+    /// The oldest sequence number the guest hasn't acknowledged.
+    unacknowledged: u32,
+    /// The sequence number of what's sent next.
+    next: u32,
+    /// What the host sent that the guest hasn't acknowledged yet,
+    /// starting at `unacknowledged`.
+    to_guest: VecDeque<u8>,
+    /// The host sent everything it's going to.
+    host_done: bool,
+    fin_sent: bool,
+    /// How much the guest can take.
+    window: u32,
+    mss: u16,
+    /// When what the guest has yet to acknowledge was sent.
+    sent: Instant,
+    /// The window we last told the guest about.
+    advertised: u16,
+}
+
+impl Connection {
+    fn new(
+        stream: TcpStream,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        state: State,
+        now: Instant,
+    ) -> Connection {
+        let initial = initial_sequence();
+        Connection {
+            stream,
+            guest,
+            remote,
+            state,
+            initial,
+            received: 0,
+            to_host: vec![],
+            guest_done: false,
+            host_shut: false,
+            unacknowledged: initial,
+            next: initial,
+            to_guest: VecDeque::new(),
+            host_done: false,
+            fin_sent: false,
+            window: 0,
+            mss: DEFAULT_MSS,
+            sent: now,
+            advertised: 0,
+        }
+    }
+
+    /// The guest sent `syn` to `remote`, and `stream` is connecting to
+    /// where that leads on the host.  The guest hears back once it's
+    /// connected.
+    pub fn connect(
+        stream: TcpStream,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        syn: &Tcp,
+        now: Instant,
+    ) -> Connection {
+        let mut connection = Connection::new(stream, guest, remote, State::Connecting, now);
+        connection.received = syn.sequence.wrapping_add(1);
+        connection.window = syn.window as u32;
+        connection.mss = cmp::min(syn.mss.unwrap_or(DEFAULT_MSS), MSS);
+        connection
+    }
+
+    /// Someone connected to a forward on the host through `stream`;
+    /// the guest is asked to take the connection, on its `guest` port.
+    pub fn accept(
+        stream: TcpStream,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        now: Instant,
+    ) -> (Connection, Vec<Vec<u8>>) {
+        let mut connection = Connection::new(stream, guest, remote, State::Opening, now);
+        let syn = connection.packet(connection.initial, TCP_SYN, &[]);
+        (connection, vec![syn])
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Whether the connection is over, so it can go.
+    pub fn finished(&self) -> bool {
+        self.state == State::Closed
+            || (self.guest_done
+                && self.host_shut
+                && self.fin_sent
+                && self.next == self.unacknowledged)
+    }
+
+    fn receive_window(&self) -> u16 {
+        cmp::min(BUFFER_SIZE - self.to_host.len(), 0xffff) as u16
+    }
+
+    /// A segment for the guest.
+    fn packet(&mut self, sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let window = self.receive_window();
+        self.advertised = window;
+        let segment = Segment {
+            sequence,
+            acknowledgement: if (flags & TCP_ACK) != 0 {
+                self.received
+            } else {
+                0
+            },
+            flags,
+            window,
+            mss: if (flags & TCP_SYN) != 0 {
+                Some(MSS)
+            } else {
+                None
+            },
+        };
+        packet::tcp(self.remote, self.guest, segment, payload)
+    }
+
+    /// Gives up on the connection, telling the guest so.
+    fn reset(&mut self) -> Vec<Vec<u8>> {
+        self.state = State::Closed;
+        let next = self.next;
+        vec![self.packet(next, TCP_RST | TCP_ACK, &[])]
+    }
+
+    fn establish(&mut self) {
+        self.state = State::Established;
+        self.unacknowledged = self.initial.wrapping_add(1);
+        self.next = self.unacknowledged;
+    }
+
+    /// Something happened on the host socket.
+    pub fn ready(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        match self.state {
+            State::Connecting => self.connected(now),
+            State::Established => self.pump(now),
+            _ => vec![],
+        }
+    }
+
+    /// Answers the guest, if the host connection went through.
+    fn connected(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        match self.stream.take_error() {
+            Ok(None) => (),
+            Ok(Some(e)) | Err(e) => {
+                debug!("could not connect to {}: {}", self.remote, e);
+                return self.reset();
+            }
+        }
+        match self.stream.peer_addr() {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => return vec![],
+            Err(e) => {
+                debug!("could not connect to {}: {}", self.remote, e);
+                return self.reset();
+            }
+        }
+
+        self.state = State::Accepted;
+        self.sent = now;
+        let initial = self.initial;
+        vec![self.packet(initial, TCP_SYN | TCP_ACK, &[])]
+    }
+
+    /// The guest sent a segment on this connection.
+    pub fn segment(&mut self, tcp: &Tcp, now: Instant) -> Vec<Vec<u8>> {
+        if (tcp.flags & TCP_RST) != 0 {
+            self.state = State::Closed;
+            return vec![];
+        }
+
+        let initial = self.initial;
+        match self.state {
+            State::Connecting | State::Closed => return vec![],
+            State::Opening => {
+                let answered = (tcp.flags & (TCP_SYN | TCP_ACK)) == TCP_SYN | TCP_ACK
+                    && tcp.acknowledgement == initial.wrapping_add(1);
+                if !answered {
+                    return vec![];
+                }
+
+                self.received = tcp.sequence.wrapping_add(1);
+                self.window = tcp.window as u32;
+                self.mss = cmp::min(tcp.mss.unwrap_or(DEFAULT_MSS), MSS);
+                self.establish();
+                let next = self.next;
+                let mut packets = vec![self.packet(next, TCP_ACK, &[])];
+                packets.extend(self.pump(now));
+                return packets;
+            }
+            State::Accepted => {
+                // Our answer got lost.
+                if (tcp.flags & TCP_SYN) != 0 {
+                    return vec![self.packet(initial, TCP_SYN | TCP_ACK, &[])];
+                }
+                if (tcp.flags & TCP_ACK) == 0 || tcp.acknowledgement != initial.wrapping_add(1) {
+                    return vec![];
+                }
+                self.establish();
+            }
+            State::Established => (),
+        }
+
+        let mut packets = vec![];
+        if (tcp.flags & TCP_ACK) != 0 {
+            self.acknowledge(tcp.acknowledgement, now);
+            self.window = tcp.window as u32;
+        }
+
+        if tcp.length() > 0 {
+            let room = self.to_host.len() + tcp.payload.len() <= BUFFER_SIZE;
+            // Anything out of order is dropped, for the guest to send
+            // again.
+            if tcp.sequence == self.received && !self.guest_done && room {
+                self.to_host.extend_from_slice(tcp.payload);
+                self.received = self.received.wrapping_add(tcp.payload.len() as u32);
+                if (tcp.flags & TCP_FIN) != 0 {
+                    self.received = self.received.wrapping_add(1);
+                    self.guest_done = true;
+                }
+            }
+            let next = self.next;
+            packets.push(self.packet(next, TCP_ACK, &[]));
+        }
+
+        packets.extend(self.pump(now));
+        packets
+    }
+
+    /// The guest acknowledged everything before `acknowledgement`.
+    fn acknowledge(&mut self, acknowledgement: u32, now: Instant) {
+        let acknowledged = acknowledgement.wrapping_sub(self.unacknowledged);
+        if acknowledged == 0 || acknowledged > self.next.wrapping_sub(self.unacknowledged) {
+            return;
+        }
+
+        let data = cmp::min(acknowledged as usize, self.to_guest.len());
+        self.to_guest.drain(..data);
+        self.unacknowledged = acknowledgement;
+        self.sent = now;
+    }
+
+    /// Moves whatever can be moved between the guest and the host.
+    fn pump(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if self.state != State::Established {
+            return vec![];
+        }
+
+        let mut buffer = [0u8; 16 << 10];
+        while !self.host_done && self.to_guest.len() < BUFFER_SIZE {
+            let size = cmp::min(buffer.len(), BUFFER_SIZE - self.to_guest.len());
+            match self.stream.read(&mut buffer[..size]) {
+                Ok(0) => self.host_done = true,
+                Ok(count) => self.to_guest.extend(&buffer[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("connection to {} failed: {}", self.remote, e);
+                    return self.reset();
+                }
+            }
+        }
+
+        let mut written = false;
+        while !self.to_host.is_empty() {
+            match self.stream.write(&self.to_host) {
+                Ok(count) => {
+                    self.to_host.drain(..count);
+                    written = true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("connection to {} failed: {}", self.remote, e);
+                    return self.reset();
+                }
+            }
+        }
+        if self.guest_done && self.to_host.is_empty() && !self.host_shut {
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.host_shut = true;
+        }
+
+        let mut packets = vec![];
+        // Tell the guest if it can send a lot more than it thinks.
+        if written && self.receive_window() as usize >= self.advertised as usize + 2 * MSS as usize
+        {
+            let next = self.next;
+            packets.push(self.packet(next, TCP_ACK, &[]));
+        }
+        packets.extend(self.transmit(now));
+        packets
+    }
+
+    /// Sends the guest as much of what the host sent as it can take.
+    fn transmit(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        let idle = self.next == self.unacknowledged;
+        let mut offset = cmp::min(
+            self.next.wrapping_sub(self.unacknowledged) as usize,
+            self.to_guest.len(),
+        );
+        while offset < self.to_guest.len() && (offset as u32) < self.window {
+            let size = cmp::min(
+                cmp::min(self.mss as usize, self.to_guest.len() - offset),
+                (self.window - offset as u32) as usize,
+            );
+            let payload = self
+                .to_guest
+                .range(offset..offset + size)
+                .cloned()
+                .collect::<Vec<_>>();
+            let next = self.next;
+            packets.push(self.packet(next, TCP_ACK | TCP_PSH, &payload));
+            self.next = self.next.wrapping_add(size as u32);
+            offset += size;
+        }
+
+        if self.host_done && !self.fin_sent && offset == self.to_guest.len() {
+            let next = self.next;
+            packets.push(self.packet(next, TCP_FIN | TCP_ACK, &[]));
+            self.next = self.next.wrapping_add(1);
+            self.fin_sent = true;
+        }
+
+        if idle && !packets.is_empty() {
+            self.sent = now;
+        }
+        packets
+    }
+
+    /// Whether the connection is waiting on the guest, and might need
+    /// to send something again.
+    pub fn waiting(&self) -> bool {
+        match self.state {
+            State::Opening | State::Accepted => true,
+            State::Established => self.next != self.unacknowledged,
+            _ => false,
+        }
+    }
+
+    /// Sends again whatever the guest should have acknowledged by now.
+    pub fn tick(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if !self.waiting() || now.duration_since(self.sent) < RETRANSMIT {
+            return vec![];
+        }
+
+        self.sent = now;
+        let initial = self.initial;
+        match self.state {
+            State::Opening => vec![self.packet(initial, TCP_SYN, &[])],
+            State::Accepted => vec![self.packet(initial, TCP_SYN | TCP_ACK, &[])],
+            _ => {
+                // Go back to the oldest thing the guest doesn't have.
+                self.next = self.unacknowledged;
+                self.fin_sent = false;
+                self.transmit(now)
+            }
+        }
+    }
+}
+
+/// What to tell the guest about a segment for a connection that isn't
+/// there.
+pub fn refuse(guest: SocketAddrV4, remote: SocketAddrV4, tcp: &Tcp) -> Option<Vec<u8>> {
+    if (tcp.flags & TCP_RST) != 0 {
+        return None;
+    }
+
+    let segment = if (tcp.flags & TCP_ACK) != 0 {
+        Segment {
+            sequence: tcp.acknowledgement,
+            acknowledgement: 0,
+            flags: TCP_RST,
+            window: 0,
+            mss: None,
+        }
+    } else {
+        Segment {
+            sequence: 0,
+            acknowledgement: tcp.sequence.wrapping_add(tcp.length()),
+            flags: TCP_RST | TCP_ACK,
+            window: 0,
+            mss: None,
+        }
+    };
+    Some(packet::tcp(remote, guest, segment, &[]))
+}
+
+/// An initial sequence number off a clock ticking every four
+/// microseconds, the way RFC 793 has it.
+fn initial_sequence() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() as u32)
+        .wrapping_mul(250_000)
+        .wrapping_add(now.subsec_nanos() / 4000)
+}
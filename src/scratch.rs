@@ -0,0 +1,40 @@
+//! Places in the temporary directory for tests to put files and
+//! sockets.  Their names have the process ID in them, so that test runs
+//! going at once keep out of each other's way.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// A path in the temporary directory for `name`, with whatever was
+/// there removed.
+pub fn path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("vent-{}-{}", process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// Files in the temporary directory, removed when they go away.
+#[derive(Debug, Default)]
+pub struct Scratch(Vec<PathBuf>);
+
+impl Scratch {
+    pub fn new() -> Scratch {
+        Scratch::default()
+    }
+
+    pub fn path(&mut self, name: &str) -> PathBuf {
+        let path = path(name);
+        self.0.push(path.clone());
+        path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use super::{EventFd, Rings};
+use device::memory::MemoryRegion;
+use error::*;
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+const VHOST_GET_FEATURES: libc::c_ulong = 0x8008_af00;
+const VHOST_SET_FEATURES: libc::c_ulong = 0x4008_af00;
+const VHOST_SET_OWNER: libc::c_ulong = 0x0000_af01;
+const VHOST_SET_MEM_TABLE: libc::c_ulong = 0x4008_af03;
+const VHOST_SET_VRING_NUM: libc::c_ulong = 0x4008_af10;
+const VHOST_SET_VRING_ADDR: libc::c_ulong = 0x4028_af11;
+const VHOST_SET_VRING_BASE: libc::c_ulong = 0x4008_af12;
+const VHOST_GET_VRING_BASE: libc::c_ulong = 0xc008_af12;
+const VHOST_SET_VRING_KICK: libc::c_ulong = 0x4008_af20;
+const VHOST_SET_VRING_CALL: libc::c_ulong = 0x4008_af21;
+const VHOST_NET_SET_BACKEND: libc::c_ulong = 0x4008_af30;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct Memory {
+    nregions: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct Region {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct VringState {
+    index: u32,
+    num: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct VringAddr {
+    index: u32,
+    flags: u32,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+struct VringFile {
+    index: u32,
+    fd: i32,
+}
+
+/// A device of one of the kernel's vhost drivers, which works a
+/// device's queues in the kernel.  We stay its owner for as long as
+/// it's open.
+#[derive(Debug)]
+pub struct Vhost {
+    file: File,
+    features: u64,
+}
+
+impl Vhost {
+    /// Opens the device at `path`, like `/dev/vhost-net`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Vhost> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut vhost = Vhost { file, features: 0 };
+        vhost.ioctl(VHOST_SET_OWNER, 0)?;
+        let mut features = 0u64;
+        vhost.ioctl(VHOST_GET_FEATURES, &mut features as *mut u64 as usize)?;
+        vhost.features = features;
+        Ok(vhost)
+    }
+
+    /// The features the driver can work with.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        self.ioctl(VHOST_SET_FEATURES, &features as *const u64 as usize)
+    }
+
+    /// Tells the driver where the guest's memory is.  Buffers have to
+    /// be in one of the regions.
+    pub fn set_memory(&self, regions: &[MemoryRegion]) -> Result<()> {
+        // struct vhost_memory ends in a flexible array member, so the
+        // header and the regions go in a single buffer of region-sized
+        // (and so suitably aligned) chunks.
+        let mut buffer = vec![Region::default(); regions.len() + 1];
+        unsafe {
+            let base = buffer.as_mut_ptr() as *mut u8;
+            *(base as *mut Memory) = Memory {
+                nregions: regions.len() as u32,
+                padding: 0,
+            };
+            let start = base.add(mem::size_of::<Memory>()) as *mut Region;
+            for (i, region) in regions.iter().enumerate() {
+                *start.add(i) = Region {
+                    guest_phys_addr: region.guest,
+                    memory_size: region.size,
+                    userspace_addr: region.host,
+                    flags_padding: 0,
+                };
+            }
+        }
+        self.ioctl(VHOST_SET_MEM_TABLE, buffer.as_ptr() as usize)
+    }
+
+    /// Hands over a queue of the given size, whose rings are at
+    /// `rings`.  The driver takes the next available chain from
+    /// `next`, waits on `kick` to find out there are more, and bumps
+    /// `call` to interrupt the guest.
+    pub fn set_queue(
+        &self,
+        index: u16,
+        size: u16,
+        rings: &Rings,
+        next: u16,
+        kick: &EventFd,
+        call: &EventFd,
+    ) -> Result<()> {
+        let index = index as u32;
+        let num = VringState {
+            index,
+            num: size as u32,
+        };
+        self.ioctl(VHOST_SET_VRING_NUM, &num as *const _ as usize)?;
+        let base = VringState {
+            index,
+            num: next as u32,
+        };
+        self.ioctl(VHOST_SET_VRING_BASE, &base as *const _ as usize)?;
+        let addr = VringAddr {
+            index,
+            desc_user_addr: rings.descriptors,
+            used_user_addr: rings.used,
+            avail_user_addr: rings.available,
+            ..VringAddr::default()
+        };
+        self.ioctl(VHOST_SET_VRING_ADDR, &addr as *const _ as usize)?;
+        self.set_file(VHOST_SET_VRING_KICK, index, kick.as_raw_fd())?;
+        self.set_file(VHOST_SET_VRING_CALL, index, call.as_raw_fd())
+    }
+
+    /// Stops the driver working a queue, returning the next available
+    /// chain it would have taken.
+    pub fn stop_queue(&self, index: u16) -> Result<u16> {
+        let mut state = VringState {
+            index: index as u32,
+            num: 0,
+        };
+        self.ioctl(VHOST_GET_VRING_BASE, &mut state as *mut _ as usize)?;
+        Ok(state.num as u16)
+    }
+
+    /// Has vhost-net move a queue's frames to and from the TAP
+    /// interface `fd`, or stops it, with no interface.
+    pub fn set_net_backend(&self, index: u16, fd: Option<RawFd>) -> Result<()> {
+        self.set_file(VHOST_NET_SET_BACKEND, index as u32, fd.unwrap_or(-1))
+    }
+
+    fn set_file(&self, request: libc::c_ulong, index: u32, fd: RawFd) -> Result<()> {
+        let file = VringFile { index, fd };
+        self.ioctl(request, &file as *const _ as usize)
+    }
+
+    fn ioctl(&self, request: libc::c_ulong, argument: usize) -> Result<()> {
+        let result = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, argument) };
+        if result < 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use byteorder::{ByteOrder, NativeEndian};
+use device::memory::MemoryRegion;
+use error::*;
+use libc;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+mod kernel;
+mod relay;
+mod user;
+
+pub use self::kernel::Vhost;
+pub use self::relay::Relay;
+pub use self::user::{Backend, VHOST_USER_PROTOCOL_F_CONFIG};
+
+/// Where a queue's rings are, in our address space.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Rings {
+    pub descriptors: u64,
+    pub available: u64,
+    pub used: u64,
+}
+
+impl Rings {
+    /// Finds the rings of a split queue of the given size, from where
+    /// the driver put them.
+    pub fn locate(
+        regions: &[MemoryRegion],
+        size: u16,
+        (descriptors, available, used): (u64, u64, u64),
+    ) -> Result<Rings> {
+        let size = size as u64;
+        Ok(Rings {
+            descriptors: host_address(regions, descriptors, 16 * size)?,
+            available: host_address(regions, available, 6 + 2 * size)?,
+            used: host_address(regions, used, 6 + 8 * size)?,
+        })
+    }
+}
+
+/// Where `length` bytes of the guest's memory at `address` are in our
+/// address space.  They have to be in a single region.
+pub fn host_address(regions: &[MemoryRegion], address: u64, length: u64) -> Result<u64> {
+    regions
+        .iter()
+        .find(|region| {
+            address >= region.guest
+                && address
+                    .checked_add(length)
+                    .map(|end| end <= region.guest + region.size)
+                    .unwrap_or(false)
+        })
+        .map(|region| region.host + (address - region.guest))
+        .ok_or_else(|| ErrorKind::GuestMemoryError(address).into())
+}
+
+/// A counter the kernel can wait on, or bump, in place of a
+/// notification or an interrupt.  Reads never block.
+#[derive(Debug)]
+pub struct EventFd(File);
+
+impl EventFd {
+    pub fn new() -> Result<EventFd> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(EventFd(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    pub fn try_clone(&self) -> Result<EventFd> {
+        Ok(EventFd(self.0.try_clone()?))
+    }
+
+    /// Bumps the counter, waking up whoever's waiting on it.
+    pub fn signal(&self) -> Result<()> {
+        let mut value = [0u8; 8];
+        NativeEndian::write_u64(&mut value, 1);
+        (&self.0).write_all(&value)?;
+        Ok(())
+    }
+
+    /// Takes the counter, which is zero if it wasn't bumped since the
+    /// last time.
+    pub fn take(&self) -> Result<u64> {
+        let mut value = [0u8; 8];
+        match (&self.0).read(&mut value) {
+            Ok(_) => Ok(NativeEndian::read_u64(&value)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_finds_rings_in_host_memory() {
+        let regions = [
+            MemoryRegion {
+                guest: 0,
+                size: 0x10000,
+                host: 0x7f00_0000_0000,
+                fd: None,
+            },
+            MemoryRegion {
+                guest: 0x10_0000,
+                size: 0x10000,
+                host: 0x7f00_1000_0000,
+                fd: None,
+            },
+        ];
+
+        let rings = Rings::locate(&regions, 16, (0x1000, 0x10_0000, 0x10_2000)).unwrap();
+        assert_eq!(
+            rings,
+            Rings {
+                descriptors: 0x7f00_0000_1000,
+                available: 0x7f00_1000_0000,
+                used: 0x7f00_1000_2000,
+            }
+        );
+        // Rings can't run off the end of a region.
+        assert!(Rings::locate(&regions, 16, (0xff80, 0x10_0000, 0x10_2000)).is_err());
+        assert!(host_address(&regions, 0x2_0000, 1).is_err());
+    }
+
+    #[test]
+    fn it_counts_signals() {
+        let eventfd = EventFd::new().unwrap();
+        assert_eq!(eventfd.take().unwrap(), 0);
+        eventfd.signal().unwrap();
+        eventfd.try_clone().unwrap().signal().unwrap();
+        assert_eq!(eventfd.take().unwrap(), 2);
+        assert_eq!(eventfd.take().unwrap(), 0);
+    }
+}
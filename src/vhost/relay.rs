@@ -0,0 +1,120 @@
+use super::EventFd;
+use error::*;
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use virtio::Interrupt;
+
+const STOP: Token = Token(0);
+
+/// Passes the interrupts a vhost backend raises on to the guest, for
+/// the queues whose interrupts the transport couldn't have KVM raise.
+#[derive(Debug)]
+pub struct Relay {
+    stop: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Relay {
+    pub fn start(
+        name: &str,
+        calls: Vec<(u16, EventFd)>,
+        interrupt: Arc<Interrupt>,
+    ) -> Result<Relay> {
+        let poll = Poll::new()?;
+        let stop = EventFd::new()?;
+        poll.register(
+            &EventedFd(&stop.as_raw_fd()),
+            STOP,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        for &(queue, ref call) in &calls {
+            poll.register(
+                &EventedFd(&call.as_raw_fd()),
+                Token(queue as usize + 1),
+                Ready::readable(),
+                PollOpt::edge(),
+            )?;
+        }
+
+        let what = name.to_owned();
+        let thread = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                let mut events = Events::with_capacity(8);
+                loop {
+                    if let Err(e) = poll.poll(&mut events, None) {
+                        if e.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        warn!("could not wait on {}: {}", what, e);
+                        return;
+                    }
+
+                    for event in &events {
+                        if event.token() == STOP {
+                            return;
+                        }
+                        let queue = (event.token().0 - 1) as u16;
+                        let call = calls.iter().find(|&&(q, _)| q == queue).map(|c| &c.1);
+                        let raised = match call {
+                            Some(call) => call.take(),
+                            None => continue,
+                        };
+                        let result = raised.and_then(|count| {
+                            if count > 0 {
+                                interrupt.queue(queue)
+                            } else {
+                                Ok(())
+                            }
+                        });
+                        if let Err(e) = result {
+                            warn!("could not relay a {} interrupt: {}", what, e);
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Relay {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop.signal() {
+            warn!("could not stop relaying interrupts: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use virtio::queue::tests::Interrupts;
+
+    #[test]
+    fn it_relays_interrupts() {
+        let calls = vec![(0, EventFd::new().unwrap()), (1, EventFd::new().unwrap())];
+        let call = calls[1].1.try_clone().unwrap();
+        let interrupts = Arc::new(Interrupts::default());
+        let relay = Relay::start("relay", calls, interrupts.clone()).unwrap();
+
+        call.signal().unwrap();
+        assert_eq!(interrupts.wait(1, Duration::from_secs(5)), vec![1]);
+        drop(relay);
+        call.signal().unwrap();
+        assert_eq!(interrupts.wait(2, Duration::from_millis(100)), vec![1]);
+    }
+}
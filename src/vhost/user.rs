@@ -0,0 +1,483 @@
+use super::{EventFd, Rings};
+use byteorder::{ByteOrder, NativeEndian};
+use device::memory::MemoryRegion;
+use error::*;
+use libc;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+
+/// Offered by backends that speak protocol features; it isn't a virtio
+/// feature, and never reaches the guest.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+/// The backend can tell how many queues it works.
+pub const VHOST_USER_PROTOCOL_F_MQ: u64 = 1 << 0;
+/// The backend acknowledges requests that have no reply of their own.
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+/// The backend keeps the device configuration.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+
+/// The protocol features we speak.
+const PROTOCOL_FEATURES: u64 =
+    VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG;
+
+const VHOST_USER_GET_FEATURES: u32 = 1;
+const VHOST_USER_SET_FEATURES: u32 = 2;
+const VHOST_USER_SET_OWNER: u32 = 3;
+const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+const VHOST_USER_SET_VRING_NUM: u32 = 8;
+const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+const VHOST_USER_SET_VRING_BASE: u32 = 10;
+const VHOST_USER_GET_VRING_BASE: u32 = 11;
+const VHOST_USER_SET_VRING_KICK: u32 = 12;
+const VHOST_USER_SET_VRING_CALL: u32 = 13;
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+const VHOST_USER_GET_CONFIG: u32 = 24;
+const VHOST_USER_SET_CONFIG: u32 = 25;
+
+const VHOST_USER_VERSION: u32 = 1;
+const VHOST_USER_FLAG_REPLY: u32 = 1 << 2;
+const VHOST_USER_FLAG_NEED_REPLY: u32 = 1 << 3;
+
+/// The most memory regions, and file descriptors, a message can carry.
+const MAX_REGIONS: usize = 8;
+
+/// Every message starts with the request, its flags, and the size of
+/// what follows.
+const HEADER_SIZE: usize = 12;
+/// The config request's offset, size, and flags come before the
+/// configuration itself.
+const CONFIG_HEADER_SIZE: usize = 12;
+/// The most configuration a backend keeps.
+const CONFIG_MAX: usize = 256;
+
+/// A vhost-user backend, like virtiofsd, which works a device's queues
+/// in another process.  We're its frontend, and own it for as long as
+/// we're connected.  It maps the guest's memory itself, so that has to
+/// be shared.
+#[derive(Debug)]
+pub struct Backend {
+    socket: UnixStream,
+    features: u64,
+    protocol: u64,
+    queues: Option<u64>,
+}
+
+impl Backend {
+    /// Connects to the backend listening at `path`, and finds out what
+    /// it can do.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Backend> {
+        let socket = UnixStream::connect(path)?;
+        let mut backend = Backend {
+            socket,
+            features: 0,
+            protocol: 0,
+            queues: None,
+        };
+
+        backend.features = backend.get_u64(VHOST_USER_GET_FEATURES)?;
+        if backend.features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            let protocol = backend.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)? & PROTOCOL_FEATURES;
+            backend.set_u64(VHOST_USER_SET_PROTOCOL_FEATURES, protocol)?;
+            backend.protocol = protocol;
+            if protocol & VHOST_USER_PROTOCOL_F_MQ != 0 {
+                backend.queues = Some(backend.get_u64(VHOST_USER_GET_QUEUE_NUM)?);
+            }
+        }
+        backend.request(VHOST_USER_SET_OWNER, &[], &[])?;
+        Ok(backend)
+    }
+
+    /// The virtio features the backend offers.
+    pub fn features(&self) -> u64 {
+        self.features & !VHOST_USER_F_PROTOCOL_FEATURES
+    }
+
+    /// The protocol features both of us speak.
+    pub fn protocol_features(&self) -> u64 {
+        self.protocol
+    }
+
+    /// How many queues the backend works, if it can tell.
+    pub fn queues(&self) -> Option<u64> {
+        self.queues
+    }
+
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        let features = features | (self.features & VHOST_USER_F_PROTOCOL_FEATURES);
+        self.set_u64(VHOST_USER_SET_FEATURES, features)
+    }
+
+    /// Tells the backend where the guest's memory is, and hands it the
+    /// files to map it from.  Every region has to be shared.
+    pub fn set_memory(&mut self, regions: &[MemoryRegion]) -> Result<()> {
+        if regions.len() > MAX_REGIONS {
+            return Err(ErrorKind::DeviceError("too many memory regions for vhost-user").into());
+        }
+
+        let mut body = vec![0u8; 8 + 32 * regions.len()];
+        NativeEndian::write_u32(&mut body[0..4], regions.len() as u32);
+        let mut fds = vec![];
+        for (i, region) in regions.iter().enumerate() {
+            let fd = region
+                .fd
+                .ok_or_else(|| Error::from(ErrorKind::DeviceError("guest memory isn't shared")))?;
+            let entry = &mut body[8 + 32 * i..8 + 32 * (i + 1)];
+            NativeEndian::write_u64(&mut entry[0..8], region.guest);
+            NativeEndian::write_u64(&mut entry[8..16], region.size);
+            NativeEndian::write_u64(&mut entry[16..24], region.host);
+            // Regions are mapped from the start of their files.
+            NativeEndian::write_u64(&mut entry[24..32], 0);
+            fds.push(fd);
+        }
+        self.request(VHOST_USER_SET_MEM_TABLE, &body, &fds)
+    }
+
+    /// Hands over a queue of the given size, whose rings are at
+    /// `rings`.  The backend takes the next available chain from
+    /// `next`, waits on `kick` to find out there are more, and bumps
+    /// `call` to interrupt the guest.
+    pub fn set_queue(
+        &mut self,
+        index: u16,
+        size: u16,
+        rings: &Rings,
+        next: u16,
+        kick: &EventFd,
+        call: &EventFd,
+    ) -> Result<()> {
+        self.request(VHOST_USER_SET_VRING_NUM, &state(index, size as u32), &[])?;
+        self.request(VHOST_USER_SET_VRING_BASE, &state(index, next as u32), &[])?;
+
+        let mut addr = [0u8; 40];
+        NativeEndian::write_u32(&mut addr[0..4], index as u32);
+        NativeEndian::write_u64(&mut addr[8..16], rings.descriptors);
+        NativeEndian::write_u64(&mut addr[16..24], rings.used);
+        NativeEndian::write_u64(&mut addr[24..32], rings.available);
+        self.request(VHOST_USER_SET_VRING_ADDR, &addr, &[])?;
+
+        self.set_file(VHOST_USER_SET_VRING_KICK, index, kick.as_raw_fd())?;
+        self.set_file(VHOST_USER_SET_VRING_CALL, index, call.as_raw_fd())?;
+        // With protocol features, queues start out disabled.
+        if self.features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            self.request(VHOST_USER_SET_VRING_ENABLE, &state(index, 1), &[])?;
+        }
+        Ok(())
+    }
+
+    /// Stops the backend working a queue, returning the next available
+    /// chain it would have taken.
+    pub fn stop_queue(&mut self, index: u16) -> Result<u16> {
+        let reply = self.call(VHOST_USER_GET_VRING_BASE, &state(index, 0), 8)?;
+        Ok(NativeEndian::read_u32(&reply[4..8]) as u16)
+    }
+
+    /// Reads the device configuration the backend keeps.
+    pub fn config(&mut self, offset: usize, data: &mut [u8]) -> Result<()> {
+        if data.len() > CONFIG_MAX {
+            return Err(ErrorKind::DeviceError("device configuration is too big").into());
+        }
+
+        let mut body = vec![0u8; CONFIG_HEADER_SIZE + data.len()];
+        NativeEndian::write_u32(&mut body[0..4], offset as u32);
+        NativeEndian::write_u32(&mut body[4..8], data.len() as u32);
+        let reply = self.call(VHOST_USER_GET_CONFIG, &body, body.len())?;
+        data.copy_from_slice(&reply[CONFIG_HEADER_SIZE..]);
+        Ok(())
+    }
+
+    /// Writes to the device configuration the backend keeps.
+    pub fn set_config(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        if data.len() > CONFIG_MAX {
+            return Err(ErrorKind::DeviceError("device configuration is too big").into());
+        }
+
+        let mut body = vec![0u8; CONFIG_HEADER_SIZE + data.len()];
+        NativeEndian::write_u32(&mut body[0..4], offset as u32);
+        NativeEndian::write_u32(&mut body[4..8], data.len() as u32);
+        body[CONFIG_HEADER_SIZE..].copy_from_slice(data);
+        self.request(VHOST_USER_SET_CONFIG, &body, &[])
+    }
+
+    fn set_file(&mut self, request: u32, index: u16, fd: RawFd) -> Result<()> {
+        let mut body = [0u8; 8];
+        NativeEndian::write_u64(&mut body, index as u64);
+        self.request(request, &body, &[fd])
+    }
+
+    fn get_u64(&mut self, request: u32) -> Result<u64> {
+        let reply = self.call(request, &[], 8)?;
+        Ok(NativeEndian::read_u64(&reply))
+    }
+
+    fn set_u64(&mut self, request: u32, value: u64) -> Result<()> {
+        let mut body = [0u8; 8];
+        NativeEndian::write_u64(&mut body, value);
+        self.request(request, &body, &[])
+    }
+
+    /// Sends a request that has no reply of its own, and waits for the
+    /// backend to acknowledge it, if it can.
+    fn request(&mut self, request: u32, body: &[u8], fds: &[RawFd]) -> Result<()> {
+        if self.protocol & VHOST_USER_PROTOCOL_F_REPLY_ACK == 0 {
+            return self.send(request, 0, body, fds);
+        }
+
+        self.send(request, VHOST_USER_FLAG_NEED_REPLY, body, fds)?;
+        let reply = self.receive(request, 8)?;
+        if NativeEndian::read_u64(&reply) != 0 {
+            return Err(ErrorKind::DeviceError("vhost-user backend refused a request").into());
+        }
+        Ok(())
+    }
+
+    /// Sends a request, and waits for its reply, which has to be
+    /// `size` bytes.
+    fn call(&mut self, request: u32, body: &[u8], size: usize) -> Result<Vec<u8>> {
+        self.send(request, 0, body, &[])?;
+        self.receive(request, size)
+    }
+
+    fn send(&mut self, request: u32, flags: u32, body: &[u8], fds: &[RawFd]) -> Result<()> {
+        let mut message = vec![0u8; HEADER_SIZE + body.len()];
+        NativeEndian::write_u32(&mut message[0..4], request);
+        NativeEndian::write_u32(&mut message[4..8], VHOST_USER_VERSION | flags);
+        NativeEndian::write_u32(&mut message[8..12], body.len() as u32);
+        message[HEADER_SIZE..].copy_from_slice(body);
+
+        let mut iov = libc::iovec {
+            iov_base: message.as_mut_ptr() as *mut libc::c_void,
+            iov_len: message.len(),
+        };
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+
+        // The control message is made of u64s, so it's aligned the way
+        // struct cmsghdr needs.
+        let length = mem::size_of_val(fds);
+        let space = unsafe { libc::CMSG_SPACE(length as u32) } as usize;
+        let mut control = vec![0u64; (space + 7) / 8];
+        if !fds.is_empty() {
+            header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            header.msg_controllen = space as _;
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&header);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(length as u32) as _;
+                ptr::copy_nonoverlapping(
+                    fds.as_ptr(),
+                    libc::CMSG_DATA(cmsg) as *mut RawFd,
+                    fds.len(),
+                );
+            }
+        }
+
+        let sent = unsafe { libc::sendmsg(self.socket.as_raw_fd(), &header, libc::MSG_NOSIGNAL) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if sent as usize != message.len() {
+            return Err(
+                io::Error::new(io::ErrorKind::WriteZero, "short vhost-user message").into(),
+            );
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, request: u32, size: usize) -> Result<Vec<u8>> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.socket.read_exact(&mut header)?;
+        let mut body = vec![0u8; NativeEndian::read_u32(&header[8..12]) as usize];
+        self.socket.read_exact(&mut body)?;
+
+        let flags = NativeEndian::read_u32(&header[4..8]);
+        if NativeEndian::read_u32(&header[0..4]) != request
+            || flags & VHOST_USER_FLAG_REPLY == 0
+            || body.len() != size
+        {
+            return Err(ErrorKind::DeviceError("vhost-user backend sent a bad reply").into());
+        }
+        Ok(body)
+    }
+}
+
+impl AsRawFd for Backend {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// A queue's index, and a number that goes with it.
+fn state(index: u16, num: u32) -> [u8; 8] {
+    let mut state = [0u8; 8];
+    NativeEndian::write_u32(&mut state[0..4], index as u32);
+    NativeEndian::write_u32(&mut state[4..8], num);
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::{self, Sender};
+    use std::thread;
+
+    /// A request as the backend got it: its type, flags, body, and how
+    /// many file descriptors came with it.
+    type Request = (u32, u32, Vec<u8>, usize);
+
+    const FEATURES: u64 = 1 | VHOST_USER_F_PROTOCOL_FEATURES;
+    const OFFERED_PROTOCOL_FEATURES: u64 = PROTOCOL_FEATURES | (1 << 5);
+
+    /// Takes the next request off the socket.  Only its header is
+    /// read along with the file descriptors, since the next request
+    /// may well be right behind it.
+    fn receive(mut socket: &UnixStream) -> Option<Request> {
+        let mut message = [0u8; HEADER_SIZE];
+        let mut iov = libc::iovec {
+            iov_base: message.as_mut_ptr() as *mut libc::c_void,
+            iov_len: message.len(),
+        };
+        let mut control = [0u64; 16];
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = mem::size_of_val(&control) as _;
+        let length = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+        if length < HEADER_SIZE as isize {
+            return None;
+        }
+
+        let mut fds = 0;
+        let cmsg = unsafe { libc::CMSG_FIRSTHDR(&header) };
+        if !cmsg.is_null() {
+            let length =
+                unsafe { (*cmsg).cmsg_len } as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+            fds = length / mem::size_of::<RawFd>();
+            for i in 0..fds {
+                unsafe { libc::close(*(libc::CMSG_DATA(cmsg) as *const RawFd).add(i)) };
+            }
+        }
+        let mut body = vec![0u8; NativeEndian::read_u32(&message[8..12]) as usize];
+        socket.read_exact(&mut body).ok()?;
+        Some((
+            NativeEndian::read_u32(&message[0..4]),
+            NativeEndian::read_u32(&message[4..8]),
+            body,
+            fds,
+        ))
+    }
+
+    fn reply(mut socket: &UnixStream, request: u32, body: &[u8]) {
+        let mut message = vec![0u8; HEADER_SIZE + body.len()];
+        NativeEndian::write_u32(&mut message[0..4], request);
+        NativeEndian::write_u32(
+            &mut message[4..8],
+            VHOST_USER_VERSION | VHOST_USER_FLAG_REPLY,
+        );
+        NativeEndian::write_u32(&mut message[8..12], body.len() as u32);
+        message[HEADER_SIZE..].copy_from_slice(body);
+        socket.write_all(&message).unwrap();
+    }
+
+    /// Plays a backend with two queues, and a configuration that reads
+    /// as its offsets.
+    fn serve(socket: UnixStream, requests: Sender<Request>) {
+        while let Some((request, flags, body, fds)) = receive(&socket) {
+            let mut value = [0u8; 8];
+            match request {
+                VHOST_USER_GET_FEATURES => {
+                    NativeEndian::write_u64(&mut value, FEATURES);
+                    reply(&socket, request, &value);
+                }
+                VHOST_USER_GET_PROTOCOL_FEATURES => {
+                    NativeEndian::write_u64(&mut value, OFFERED_PROTOCOL_FEATURES);
+                    reply(&socket, request, &value);
+                }
+                VHOST_USER_GET_QUEUE_NUM => {
+                    NativeEndian::write_u64(&mut value, 2);
+                    reply(&socket, request, &value);
+                }
+                VHOST_USER_GET_CONFIG => {
+                    let mut config = body.clone();
+                    let offset = NativeEndian::read_u32(&body[0..4]) as usize;
+                    for (i, byte) in config[CONFIG_HEADER_SIZE..].iter_mut().enumerate() {
+                        *byte = (offset + i) as u8;
+                    }
+                    reply(&socket, request, &config);
+                }
+                _ if flags & VHOST_USER_FLAG_NEED_REPLY != 0 => reply(&socket, request, &value),
+                _ => {}
+            }
+            requests.send((request, flags, body, fds)).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_negotiates_with_the_backend() {
+        let path = scratch::path("vhost-user");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || serve(listener.accept().unwrap().0, sender));
+
+        let mut backend = Backend::connect(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(backend.features(), 1);
+        assert_eq!(backend.protocol_features(), PROTOCOL_FEATURES);
+        assert_eq!(backend.queues(), Some(2));
+        let negotiated: Vec<_> = (0..5).map(|_| requests.recv().unwrap()).collect();
+        assert_eq!(
+            negotiated.iter().map(|r| r.0).collect::<Vec<_>>(),
+            vec![
+                VHOST_USER_GET_FEATURES,
+                VHOST_USER_GET_PROTOCOL_FEATURES,
+                VHOST_USER_SET_PROTOCOL_FEATURES,
+                VHOST_USER_GET_QUEUE_NUM,
+                VHOST_USER_SET_OWNER,
+            ]
+        );
+        assert_eq!(NativeEndian::read_u64(&negotiated[2].2), PROTOCOL_FEATURES);
+        // Once acknowledgements are negotiated, everything wants one.
+        assert_ne!(negotiated[4].1 & VHOST_USER_FLAG_NEED_REPLY, 0);
+
+        backend.set_features(1).unwrap();
+        let (request, _, body, _) = requests.recv().unwrap();
+        assert_eq!(request, VHOST_USER_SET_FEATURES);
+        assert_eq!(NativeEndian::read_u64(&body), FEATURES);
+
+        let file = File::open("/dev/null").unwrap();
+        let region = MemoryRegion {
+            guest: 0x10_0000,
+            size: 0x1000,
+            host: 0x7f00_0000_0000,
+            fd: Some(file.as_raw_fd()),
+        };
+        backend.set_memory(&[region]).unwrap();
+        let (request, _, body, fds) = requests.recv().unwrap();
+        assert_eq!(request, VHOST_USER_SET_MEM_TABLE);
+        assert_eq!(fds, 1);
+        assert_eq!(NativeEndian::read_u32(&body[0..4]), 1);
+        assert_eq!(NativeEndian::read_u64(&body[8..16]), 0x10_0000);
+        assert_eq!(NativeEndian::read_u64(&body[24..32]), 0x7f00_0000_0000);
+        assert!(backend
+            .set_memory(&[MemoryRegion { fd: None, ..region }])
+            .is_err());
+
+        let mut config = [0u8; 4];
+        backend.config(4, &mut config).unwrap();
+        assert_eq!(config, [4, 5, 6, 7]);
+    }
+}
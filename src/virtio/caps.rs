@@ -1,3 +1,46 @@
+use byteorder::{ByteOrder, LittleEndian};
+use device::pci::{read_into, write_masked, Capability as PciCapability};
+use std::sync::Mutex;
+
+/// Virtio's structures all live in vendor-specific capabilities.
+pub const VENDOR_CAPABILITY_ID: u8 = 0x09;
+
+const CAPABILITY_LENGTH: usize = 16;
+const NOTIFY_LENGTH: usize = 20;
+const CONFIG_ACCESS_LENGTH: usize = 20;
+
+/// Common configuration.
+pub const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+/// Notifications.
+pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+/// ISR status.
+pub const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+/// Device-specific configuration.
+pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// PCI configuration access.
+pub const VIRTIO_PCI_CAP_PCI_CFG: u8 = 5;
+
+/// Offsets of the fields of `Common`, as laid out in the BAR.
+pub const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+#[cfg(test)]
+pub const COMMON_DEVICE_FEATURE: usize = 0x04;
+pub const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+pub const COMMON_DRIVER_FEATURE: usize = 0x0c;
+pub const COMMON_MSIX_CONFIG: usize = 0x10;
+pub const COMMON_DEVICE_STATUS: usize = 0x14;
+pub const COMMON_QUEUE_SELECT: usize = 0x16;
+pub const COMMON_QUEUE_SIZE: usize = 0x18;
+pub const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1a;
+pub const COMMON_QUEUE_ENABLE: usize = 0x1c;
+pub const COMMON_QUEUE_DESC: usize = 0x20;
+pub const COMMON_QUEUE_AVAIL: usize = 0x28;
+pub const COMMON_QUEUE_USED: usize = 0x30;
+pub const COMMON_LENGTH: usize = 0x38;
+
+/// Where the data window of the PCI configuration access capability
+/// starts.
+pub const CONFIG_ACCESS_DATA: usize = 16;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct Capability {
@@ -11,7 +54,54 @@ pub struct Capability {
     length: u32,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+impl Capability {
+    /// Describes a structure of the given type, found `length` bytes
+    /// into the given BAR at `offset`.
+    pub fn new(cfg_type: u8, bar: u8, offset: u32, length: u32) -> Capability {
+        Capability {
+            cap_vndr: VENDOR_CAPABILITY_ID,
+            cap_next: 0,
+            cap_len: CAPABILITY_LENGTH as u8,
+            cfg_type,
+            bar,
+            _pad: [0; 3],
+            offset,
+            length,
+        }
+    }
+
+    fn serialize(&self, registers: &mut [u8]) {
+        registers[0] = self.cap_vndr;
+        registers[1] = self.cap_next;
+        registers[2] = self.cap_len;
+        registers[3] = self.cfg_type;
+        registers[4] = self.bar;
+        LittleEndian::write_u32(&mut registers[8..12], self.offset);
+        LittleEndian::write_u32(&mut registers[12..16], self.length);
+    }
+}
+
+impl PciCapability for Capability {
+    fn id(&self) -> u8 {
+        self.cap_vndr
+    }
+
+    fn len(&self) -> usize {
+        self.cap_len as usize
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        let mut registers = [0u8; CAPABILITY_LENGTH];
+        self.serialize(&mut registers);
+        read_into(&registers, offset, data);
+    }
+
+    fn write(&self, _offset: usize, _data: &[u8]) {}
+}
+
+/// The common configuration structure.  The fields about a specific
+/// virtqueue are those of the one selected by `queue_select`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
 #[repr(C)]
 pub struct Common {
     /// The driver uses this to select which feature bits
@@ -19,92 +109,209 @@ pub struct Common {
     /// 31, `0x1` selects Feature Bits 32 to 63, etc.
     ///
     /// The driver may read and write to this.
-    device_feature_select: u32,
+    pub device_feature_select: u32,
     /// The device uses this to report which feature bits it is
     /// offering to the driver: the driver writes to
     /// [`device_feature_select`] to select which feature bits are
     /// presented.
     ///
     /// The driver may only read from this.
-    device_feature: u32,
+    pub device_feature: u32,
     /// The driver uses this to select which feature bits
     /// `driver_feature` shows. Value `0x0` selects Feature Bits 0 to
     /// 31, `0x1` selects Feature Bits 32 to 63, etc.
     ///
     /// The driver may read and write to this.
-    driver_feature_select: u32,
+    pub driver_feature_select: u32,
     /// The driver writes this to accept feature bits offered by the
     /// device. Driver Feature Bits selected by driver_feature_select.
     ///
     /// The driver may read and write to this.
-    driver_feature: u32,
+    pub driver_feature: u32,
     /// The driver sets the Configuration Vector for MSI-X.
     ///
     /// The driver may read and write to this.
-    msix_config: u16,
+    pub msix_config: u16,
     /// The device specifies the maximum number of virtqueues
     /// supported here.
     ///
     /// The driver may only read from this.
-    num_queues: u16,
+    pub num_queues: u16,
     /// The driver writes the device status here. Writing 0 into this
     /// field resets the device.
     ///
     /// The driver may read and write to this.
-    device_status: u8;
+    pub device_status: u8,
     /// Configuration atomicity value. The device changes this every
     /// time the configuration noticeably changes.
     ///
     /// The driver may only read from this.
-    config_generation: u8;
+    pub config_generation: u8,
 
     /* About a specific virtqueue. */
     /// Queue Select. The driver selects which virtqueue the following
     /// fields refer to.
     ///
     /// The driver may read and write to this.
-    queue_select: u16,
+    pub queue_select: u16,
     /// Queue Size. On reset, specifies the maximum queue size
     /// supported by the hypervisor. This can be modified by driver to
     /// reduce memory requirements. A 0 means the queue is unavailable.
     ///
     /// The driver may read and write to this.  It must be either a
     /// power of two, or zero.
-    queue_size: u16,
+    pub queue_size: u16,
     /// The driver uses this to specify the queue vector for MSI-X.
     ///
     /// The driver may read and write to this.
-    queue_msix_vector: u16,
+    pub queue_msix_vector: u16,
     /// The driver uses this to selectively prevent the device from
     /// executing requests from this virtqueue. 1 - enabled;
     /// 0 - disabled.
     ///
     /// The driver may read and write to this.
-    queue_enable: u16,
+    pub queue_enable: u16,
     /// The driver reads this to calculate the offset from start of
     /// Notification structure at which this virtqueue is located.
     /// Note: this is not an offset in bytes.
     ///
     /// The driver may only read from this.
-    queue_notify_off: u16,
+    pub queue_notify_off: u16,
     /// The driver writes the physical address of Descriptor Table
     /// here.
     ///
     /// The driver may read and write to this.
-    queue_desc: u64,
+    pub queue_desc: u64,
     /// The driver writes the physical address of Available Ring here.
     ///
     /// The driver may read and write to this.
-    queue_avail: u64,
+    pub queue_avail: u64,
     /// The driver writes the physical address of Used Ring here.
     ///
     /// The driver may read and write to this.
-    queue_used: u64,
+    pub queue_used: u64,
+}
+
+impl Common {
+    pub fn to_bytes(self) -> [u8; COMMON_LENGTH] {
+        let mut registers = [0u8; COMMON_LENGTH];
+        {
+            let r = &mut registers;
+            LittleEndian::write_u32(&mut r[0x00..0x04], self.device_feature_select);
+            LittleEndian::write_u32(&mut r[0x04..0x08], self.device_feature);
+            LittleEndian::write_u32(&mut r[0x08..0x0c], self.driver_feature_select);
+            LittleEndian::write_u32(&mut r[0x0c..0x10], self.driver_feature);
+            LittleEndian::write_u16(&mut r[0x10..0x12], self.msix_config);
+            LittleEndian::write_u16(&mut r[0x12..0x14], self.num_queues);
+            r[0x14] = self.device_status;
+            r[0x15] = self.config_generation;
+            LittleEndian::write_u16(&mut r[0x16..0x18], self.queue_select);
+            LittleEndian::write_u16(&mut r[0x18..0x1a], self.queue_size);
+            LittleEndian::write_u16(&mut r[0x1a..0x1c], self.queue_msix_vector);
+            LittleEndian::write_u16(&mut r[0x1c..0x1e], self.queue_enable);
+            LittleEndian::write_u16(&mut r[0x1e..0x20], self.queue_notify_off);
+            LittleEndian::write_u64(&mut r[0x20..0x28], self.queue_desc);
+            LittleEndian::write_u64(&mut r[0x28..0x30], self.queue_avail);
+            LittleEndian::write_u64(&mut r[0x30..0x38], self.queue_used);
+        }
+        registers
+    }
 }
 
+/// The notification capability.  The driver notifies queue `n` by
+/// writing to `offset + queue_notify_off(n) * notify_off_multiplier`.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct Notify {
     cap: Capability,
     notify_off_multiplier: u32,
 }
+
+impl Notify {
+    pub fn new(bar: u8, offset: u32, length: u32, notify_off_multiplier: u32) -> Notify {
+        let mut cap = Capability::new(VIRTIO_PCI_CAP_NOTIFY_CFG, bar, offset, length);
+        cap.cap_len = NOTIFY_LENGTH as u8;
+        Notify {
+            cap,
+            notify_off_multiplier,
+        }
+    }
+}
+
+impl PciCapability for Notify {
+    fn id(&self) -> u8 {
+        self.cap.cap_vndr
+    }
+
+    fn len(&self) -> usize {
+        NOTIFY_LENGTH
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        let mut registers = [0u8; NOTIFY_LENGTH];
+        self.cap.serialize(&mut registers);
+        LittleEndian::write_u32(&mut registers[16..20], self.notify_off_multiplier);
+        read_into(&registers, offset, data);
+    }
+
+    fn write(&self, _offset: usize, _data: &[u8]) {}
+}
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static CONFIG_ACCESS_WRITABLE: [u8; CONFIG_ACCESS_LENGTH] = [
+    0x00, 0x00, 0x00, 0x00, // 0x00, (vndr, next, len, cfg_type)
+    0xff, 0x00, 0x00, 0x00, // 0x04, (bar, padding)
+    0xff, 0xff, 0xff, 0xff, // 0x08, (offset)
+    0xff, 0xff, 0xff, 0xff, // 0x0c, (length)
+    0xff, 0xff, 0xff, 0xff, // 0x10, (pci_cfg_data)
+];
+
+/// The PCI configuration access capability, an alternative way into
+/// the BARs for drivers that can't map them.  The driver picks a BAR,
+/// offset, and length, and then accesses `pci_cfg_data`; forwarding
+/// those accesses to the BAR is up to whoever owns the capability.
+#[derive(Debug)]
+pub struct ConfigAccess(Mutex<[u8; CONFIG_ACCESS_LENGTH]>);
+
+impl ConfigAccess {
+    pub fn new() -> ConfigAccess {
+        let mut registers = [0u8; CONFIG_ACCESS_LENGTH];
+        let mut cap = Capability::new(VIRTIO_PCI_CAP_PCI_CFG, 0, 0, 0);
+        cap.cap_len = CONFIG_ACCESS_LENGTH as u8;
+        cap.serialize(&mut registers);
+        ConfigAccess(Mutex::new(registers))
+    }
+
+    /// The BAR, offset, and length the driver selected.
+    pub fn window(&self) -> (u8, u32, u32) {
+        let registers = self.0.lock().unwrap();
+        (
+            registers[4],
+            LittleEndian::read_u32(&registers[8..12]),
+            LittleEndian::read_u32(&registers[12..16]),
+        )
+    }
+}
+
+impl PciCapability for ConfigAccess {
+    fn id(&self) -> u8 {
+        VENDOR_CAPABILITY_ID
+    }
+
+    fn len(&self) -> usize {
+        CONFIG_ACCESS_LENGTH
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) {
+        read_into(&*self.0.lock().unwrap(), offset, data);
+    }
+
+    fn write(&self, offset: usize, data: &[u8]) {
+        write_masked(
+            &mut *self.0.lock().unwrap(),
+            &CONFIG_ACCESS_WRITABLE,
+            offset,
+            data,
+        );
+    }
+}
@@ -0,0 +1,536 @@
+use super::transport::{self, Eventfds, State};
+use super::{
+    Interrupt, Virtio, VIRTIO_STATUS_DRIVER_OK, VIRTIO_STATUS_FEATURES_OK,
+    VIRTIO_STATUS_NEEDS_RESET,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use device::interrupt::Interrupts;
+use device::memory::GuestMemory;
+use device::pci::read_into;
+use device::Device;
+use error::*;
+use kvm::core::{IoAction, IoAddress, IoDirection};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// "virt", in little endian.
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_MMIO_VERSION: u32 = 2;
+const VIRTIO_MMIO_VENDOR: u32 = 0x1af4;
+
+const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+const VIRTIO_MMIO_VERSION_REGISTER: u64 = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+const VIRTIO_MMIO_STATUS: u64 = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+const VIRTIO_MMIO_INT_VRING: usize = 1 << 0;
+const VIRTIO_MMIO_INT_CONFIG: usize = 1 << 1;
+
+/// The size of each device's register window.
+pub const MMIO_SIZE: u64 = 0x1000;
+
+/// Replaces the low or high half of a 64-bit register.
+fn set_half(value: u64, high: bool, half: u32) -> u64 {
+    if high {
+        (value & 0xffff_ffff) | ((half as u64) << 32)
+    } else {
+        (value & !0xffff_ffff) | half as u64
+    }
+}
+
+/// The interrupt side of the transport.  There's a single, level
+/// triggered line per device; it stays up until the driver has
+/// acknowledged every cause in the interrupt status.
+#[derive(Debug)]
+struct Signals {
+    interrupts: Arc<Interrupts>,
+    irq: u32,
+    /// The address of the queue notification register.
+    notify: u64,
+    pending: AtomicUsize,
+    status: AtomicUsize,
+    generation: AtomicUsize,
+    eventfds: Eventfds,
+}
+
+impl Signals {
+    fn status(&self) -> u8 {
+        self.status.load(Ordering::SeqCst) as u8
+    }
+
+    fn raise(&self, cause: usize) -> Result<()> {
+        self.pending.fetch_or(cause, Ordering::SeqCst);
+        self.interrupts.line(self.irq, true)
+    }
+
+    fn acknowledge(&self, causes: usize) {
+        let previous = self.pending.fetch_and(!causes, Ordering::SeqCst);
+        if previous != 0 && (previous & !causes) == 0 {
+            self.lower();
+        }
+    }
+
+    fn lower(&self) {
+        if let Err(e) = self.interrupts.line(self.irq, false) {
+            warn!("could not lower virtio interrupt: {}", e);
+        }
+    }
+
+    fn reset(&self) {
+        self.status.store(0, Ordering::SeqCst);
+        if self.pending.swap(0, Ordering::SeqCst) != 0 {
+            self.lower();
+        }
+    }
+}
+
+impl Interrupt for Signals {
+    fn queue(&self, _queue: u16) -> Result<()> {
+        self.raise(VIRTIO_MMIO_INT_VRING)
+    }
+
+    fn config(&self) -> Result<()> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.raise(VIRTIO_MMIO_INT_CONFIG)
+    }
+
+    fn needs_reset(&self) {
+        self.status
+            .fetch_or(VIRTIO_STATUS_NEEDS_RESET as usize, Ordering::SeqCst);
+        if let Err(e) = self.config() {
+            warn!("could not signal virtio reset: {}", e);
+        }
+    }
+
+    // There's no irqfd: the interrupt status has to be set along with
+    // the line, and only we can do that.
+
+    /// Every queue shares the notification register, which the driver
+    /// writes the queue's index to.
+    fn ioeventfd(&self, queue: u16, fd: RawFd) -> Result<bool> {
+        self.eventfds.ioeventfd(
+            self.interrupts.as_ref(),
+            self.notify,
+            4,
+            Some(queue as u64),
+            fd,
+        )?;
+        Ok(true)
+    }
+}
+
+/// The virtio-mmio transport (version 2, i.e. not the legacy one),
+/// which exposes a device as a window of registers in the physical
+/// address space and a single interrupt line.  There's no way to
+/// enumerate these, so the guest has to be told where they are, which
+/// the DSDT does.
+#[derive(Debug)]
+pub struct MmioTransport {
+    device: Arc<Virtio>,
+    memory: Arc<GuestMemory>,
+    base: u64,
+    signals: Arc<Signals>,
+    state: Mutex<State>,
+}
+
+impl MmioTransport {
+    /// Places a device's registers at `base`, with its interrupt on
+    /// the given GSI.
+    pub fn new(
+        device: Arc<Virtio>,
+        memory: Arc<GuestMemory>,
+        interrupts: Arc<Interrupts>,
+        base: u64,
+        irq: u32,
+    ) -> MmioTransport {
+        let state = State::new(&device.queues());
+        MmioTransport {
+            device,
+            memory,
+            base,
+            signals: Arc::new(Signals {
+                interrupts,
+                irq,
+                notify: base + VIRTIO_MMIO_QUEUE_NOTIFY,
+                pending: AtomicUsize::new(0),
+                status: AtomicUsize::new(0),
+                generation: AtomicUsize::new(0),
+                eventfds: Eventfds::default(),
+            }),
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        MMIO_SIZE
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.signals.irq
+    }
+
+    fn register(&self, offset: u64) -> u32 {
+        let state = self.state.lock().unwrap();
+        let queue = state.selected();
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            VIRTIO_MMIO_VERSION_REGISTER => VIRTIO_MMIO_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_type() as u32,
+            VIRTIO_MMIO_VENDOR_ID => VIRTIO_MMIO_VENDOR,
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                state.device_features(transport::offered(self.device.as_ref()))
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => queue.map_or(0, |q| q.max as u32),
+            VIRTIO_MMIO_QUEUE_READY => queue.map_or(0, |q| q.enabled as u32),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.signals.pending.load(Ordering::SeqCst) as u32,
+            VIRTIO_MMIO_STATUS => self.signals.status() as u32,
+            VIRTIO_MMIO_CONFIG_GENERATION => self.signals.generation.load(Ordering::SeqCst) as u32,
+            _ => 0,
+        }
+    }
+
+    fn set_register(&self, offset: u64, value: u32) {
+        let mut state = self.state.lock().unwrap();
+        let status = self.signals.status();
+        let running = (status & VIRTIO_STATUS_DRIVER_OK) != 0;
+
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => state.device_feature_select = value,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => state.driver_feature_select = value,
+            // The features are fixed once the driver has accepted them.
+            VIRTIO_MMIO_DRIVER_FEATURES if (status & VIRTIO_STATUS_FEATURES_OK) == 0 => {
+                state.set_driver_features(value)
+            }
+            VIRTIO_MMIO_QUEUE_SEL => state.queue_select = value as u16,
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                let count = state.queues.len();
+                drop(state);
+                if running && (value as usize) < count {
+                    self.device.notify(value as u16);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => self.signals.acknowledge(value as usize),
+            VIRTIO_MMIO_STATUS => self.set_status(&mut state, value as u8),
+            // The queues are fixed once the device is running.
+            _ if !running => {
+                if let Some(queue) = state.selected_mut() {
+                    match offset {
+                        VIRTIO_MMIO_QUEUE_NUM => queue.resize(value as u16),
+                        // Queues can't be disabled, short of resetting
+                        // the device.
+                        VIRTIO_MMIO_QUEUE_READY if value == 1 => queue.enabled = true,
+                        VIRTIO_MMIO_QUEUE_DESC_LOW | VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                            let high = offset == VIRTIO_MMIO_QUEUE_DESC_HIGH;
+                            queue.descriptors = set_half(queue.descriptors, high, value);
+                        }
+                        VIRTIO_MMIO_QUEUE_DRIVER_LOW | VIRTIO_MMIO_QUEUE_DRIVER_HIGH => {
+                            let high = offset == VIRTIO_MMIO_QUEUE_DRIVER_HIGH;
+                            queue.driver = set_half(queue.driver, high, value);
+                        }
+                        VIRTIO_MMIO_QUEUE_DEVICE_LOW | VIRTIO_MMIO_QUEUE_DEVICE_HIGH => {
+                            let high = offset == VIRTIO_MMIO_QUEUE_DEVICE_HIGH;
+                            queue.device = set_half(queue.device, high, value);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn set_status(&self, state: &mut State, value: u8) {
+        if value == 0 {
+            self.signals
+                .eventfds
+                .detach(self.signals.interrupts.as_ref());
+            self.device.reset();
+            *state = State::new(&self.device.queues());
+            self.signals.reset();
+            return;
+        }
+
+        let old = self.signals.status();
+        let offered = transport::offered(self.device.as_ref());
+        let status = state.update_status(offered, old, value);
+        self.signals.status.store(status as usize, Ordering::SeqCst);
+
+        if State::starting(old, status) {
+            let interrupt = self.signals.clone();
+            if let Err(e) = state.activate(self.device.as_ref(), self.memory.clone(), interrupt) {
+                warn!("could not activate virtio device: {}", e);
+                self.signals.needs_reset();
+            }
+        }
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            let offset = (offset - VIRTIO_MMIO_CONFIG) as usize;
+            if offset < self.device.config_size() {
+                self.device.config_read(offset, data);
+            } else {
+                read_into(&[], 0, data);
+            }
+        } else if data.len() == 4 && offset % 4 == 0 {
+            LittleEndian::write_u32(data, self.register(offset));
+        } else {
+            // The registers below the configuration can only be
+            // accessed a dword at a time.
+            read_into(&[], 0, data);
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        if offset >= VIRTIO_MMIO_CONFIG {
+            let offset = (offset - VIRTIO_MMIO_CONFIG) as usize;
+            if offset < self.device.config_size() {
+                self.device.config_write(offset, data);
+            }
+        } else if data.len() == 4 && offset % 4 == 0 {
+            self.set_register(offset, LittleEndian::read_u32(data));
+        }
+    }
+}
+
+impl Device for MmioTransport {
+    fn request(&self) -> Vec<IoAddress> {
+        vec![]
+    }
+
+    fn handle(&self, io: IoAction, memory: &mut [u8]) -> Option<()> {
+        let IoAction(address, direction, size) = io;
+        let offset = match address {
+            IoAddress::Memory(address) if address >= self.base => address - self.base,
+            _ => return None,
+        };
+
+        if offset >= MMIO_SIZE {
+            return None;
+        }
+
+        match direction {
+            IoDirection::In => self.read(offset, &mut memory[..size]),
+            IoDirection::Out => self.write(offset, &memory[..size]),
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::queue::tests::Ram;
+    use super::super::*;
+    use super::*;
+    use device::interrupt::Message;
+
+    #[derive(Debug, Default)]
+    struct Backend(Mutex<Option<Activation>>);
+
+    impl Virtio for Backend {
+        fn device_type(&self) -> u16 {
+            2
+        }
+
+        fn features(&self) -> u64 {
+            0
+        }
+
+        fn queues(&self) -> Vec<u16> {
+            vec![128]
+        }
+
+        fn activate(&self, activation: Activation) -> Result<()> {
+            *self.0.lock().unwrap() = Some(activation);
+            Ok(())
+        }
+
+        fn notify(&self, _queue: u16) {}
+
+        fn reset(&self) {
+            *self.0.lock().unwrap() = None;
+        }
+    }
+
+    /// Records the line's levels, and the ioeventfds hooked up.
+    #[derive(Debug, Default)]
+    struct Lines(
+        Mutex<Vec<(u32, bool)>>,
+        Mutex<Vec<(u64, Option<u64>, bool)>>,
+    );
+
+    impl Interrupts for Lines {
+        fn line(&self, irq: u32, level: bool) -> Result<()> {
+            self.0.lock().unwrap().push((irq, level));
+            Ok(())
+        }
+
+        fn signal(&self, _message: Message) -> Result<()> {
+            Ok(())
+        }
+
+        fn allocate(&self) -> Result<u32> {
+            Ok(0)
+        }
+
+        fn route(&self, _gsi: u32, _message: Option<Message>) -> Result<()> {
+            Ok(())
+        }
+
+        fn ioeventfd(
+            &self,
+            address: u64,
+            _length: u32,
+            data: Option<u64>,
+            _fd: RawFd,
+            attach: bool,
+        ) -> Result<()> {
+            self.1.lock().unwrap().push((address, data, attach));
+            Ok(())
+        }
+    }
+
+    fn transport() -> (MmioTransport, Arc<Backend>, Arc<Lines>) {
+        let backend = Arc::new(Backend::default());
+        let lines = Arc::new(Lines::default());
+        let transport = MmioTransport::new(
+            backend.clone(),
+            Arc::new(Ram::new(0, 0x10000)),
+            lines.clone(),
+            0xd000_0000,
+            5,
+        );
+        (transport, backend, lines)
+    }
+
+    fn read(transport: &MmioTransport, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        let io = IoAddress::Memory(0xd000_0000 + offset).inw();
+        transport.handle(io, &mut data).unwrap();
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write(transport: &MmioTransport, offset: u64, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        let io = IoAddress::Memory(0xd000_0000 + offset).outw();
+        transport.handle(io, &mut data).unwrap();
+    }
+
+    #[test]
+    fn it_identifies_itself() {
+        let (transport, _, _) = transport();
+        assert_eq!(read(&transport, VIRTIO_MMIO_MAGIC_VALUE), 0x7472_6976);
+        assert_eq!(read(&transport, VIRTIO_MMIO_VERSION_REGISTER), 2);
+        assert_eq!(read(&transport, VIRTIO_MMIO_DEVICE_ID), 2);
+    }
+
+    #[test]
+    fn it_ignores_accesses_outside_of_its_window() {
+        let (transport, _, _) = transport();
+        let mut data = [0u8; 4];
+        let io = IoAddress::Memory(0xd000_0000 + MMIO_SIZE).inw();
+        assert_eq!(transport.handle(io, &mut data), None);
+        let io = IoAddress::Memory(0xcfff_fffc).inw();
+        assert_eq!(transport.handle(io, &mut data), None);
+    }
+
+    #[test]
+    fn it_activates_the_queues_the_driver_set_up() {
+        let (transport, backend, _) = transport();
+        write(&transport, VIRTIO_MMIO_STATUS, 0x3);
+        write(&transport, VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1);
+        assert_eq!(read(&transport, VIRTIO_MMIO_DEVICE_FEATURES) & 1, 1);
+        write(&transport, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+        write(&transport, VIRTIO_MMIO_DRIVER_FEATURES, 1);
+        write(&transport, VIRTIO_MMIO_STATUS, 0xb);
+        assert_eq!(read(&transport, VIRTIO_MMIO_STATUS), 0xb);
+
+        write(&transport, VIRTIO_MMIO_QUEUE_SEL, 0);
+        assert_eq!(read(&transport, VIRTIO_MMIO_QUEUE_NUM_MAX), 128);
+        write(&transport, VIRTIO_MMIO_QUEUE_NUM, 64);
+        write(&transport, VIRTIO_MMIO_QUEUE_DESC_LOW, 0x1000);
+        write(&transport, VIRTIO_MMIO_QUEUE_DRIVER_LOW, 0x2000);
+        write(&transport, VIRTIO_MMIO_QUEUE_DEVICE_LOW, 0x3000);
+        write(&transport, VIRTIO_MMIO_QUEUE_READY, 1);
+        write(&transport, VIRTIO_MMIO_STATUS, 0xf);
+
+        let activated = backend.0.lock().unwrap();
+        let activation = activated.as_ref().unwrap();
+        assert_eq!(activation.features, VIRTIO_F_VERSION_1);
+        assert_eq!(
+            activation.queues[0],
+            Some(Queue::new(64, 0x1000, 0x2000, 0x3000, VIRTIO_F_VERSION_1).unwrap())
+        );
+    }
+
+    #[test]
+    fn it_combines_both_halves_of_queue_addresses() {
+        let (transport, _, _) = transport();
+        write(&transport, VIRTIO_MMIO_QUEUE_DESC_LOW, 0x1000);
+        write(&transport, VIRTIO_MMIO_QUEUE_DESC_HIGH, 0x2);
+        write(&transport, VIRTIO_MMIO_QUEUE_DESC_LOW, 0x3000);
+        let state = transport.state.lock().unwrap();
+        assert_eq!(state.queues[0].descriptors, 0x2_0000_3000);
+    }
+
+    #[test]
+    fn it_unhooks_eventfds_on_reset() {
+        let (transport, _, lines) = transport();
+        assert!(transport.signals.ioeventfd(1, 7).unwrap());
+        assert!(!transport.signals.irqfd(1, 8).unwrap());
+        assert_eq!(*lines.1.lock().unwrap(), vec![(0xd000_0050, Some(1), true)]);
+
+        write(&transport, VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(
+            *lines.1.lock().unwrap(),
+            vec![(0xd000_0050, Some(1), true), (0xd000_0050, Some(1), false)]
+        );
+    }
+
+    #[test]
+    fn it_holds_the_line_until_every_cause_is_acknowledged() {
+        let (transport, _, lines) = transport();
+        transport.signals.queue(0).unwrap();
+        transport.signals.config().unwrap();
+        assert_eq!(read(&transport, VIRTIO_MMIO_INTERRUPT_STATUS), 0x3);
+        assert_eq!(read(&transport, VIRTIO_MMIO_CONFIG_GENERATION), 1);
+
+        write(&transport, VIRTIO_MMIO_INTERRUPT_ACK, 0x1);
+        assert_eq!(read(&transport, VIRTIO_MMIO_INTERRUPT_STATUS), 0x2);
+        assert_eq!(*lines.0.lock().unwrap(), vec![(5, true), (5, true)]);
+
+        write(&transport, VIRTIO_MMIO_INTERRUPT_ACK, 0x2);
+        assert_eq!(read(&transport, VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+        assert_eq!(
+            *lines.0.lock().unwrap(),
+            vec![(5, true), (5, true), (5, false)]
+        );
+    }
+}
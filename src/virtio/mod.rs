@@ -1,3 +1,113 @@
-use super::device::pci::Pci;
+use device::memory::GuestMemory;
+use error::*;
+use std::fmt::Debug;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
 
-pub trait Virtio: Pci {}
+mod caps;
+mod mmio;
+mod pci;
+pub mod queue;
+mod transport;
+
+pub use self::mmio::MmioTransport;
+pub use self::pci::PciTransport;
+use self::queue::Queue;
+
+/// The guest has noticed the device.
+#[cfg(test)]
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
+/// The guest knows how to drive the device.
+#[cfg(test)]
+pub const VIRTIO_STATUS_DRIVER: u8 = 2;
+/// The driver is set up, and the device may be used.
+pub const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
+/// Feature negotiation is complete.
+pub const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
+/// The device hit an error it can't recover from without a reset.
+pub const VIRTIO_STATUS_NEEDS_RESET: u8 = 0x40;
+
+/// The device follows version 1 of the specification, rather than the
+/// legacy interface.  We only implement the former.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// How a device raises interrupts, whichever transport it sits behind.
+pub trait Interrupt: Debug + Send + Sync {
+    /// Tells the driver there are used buffers in the given queue.
+    fn queue(&self, queue: u16) -> Result<()>;
+    /// Tells the driver the device configuration changed.
+    fn config(&self) -> Result<()>;
+    /// Tells the driver the device needs to be reset before it can be
+    /// used again.
+    fn needs_reset(&self);
+
+    /// Has every write to the eventfd `fd` raise the given queue's
+    /// interrupt without going through us, returning whether the
+    /// transport can do that.  Devices whose queues are used elsewhere
+    /// (like the kernel's vhost drivers) relay the writes themselves
+    /// if it can't.  It lasts until the device is reset.
+    fn irqfd(&self, _queue: u16, _fd: RawFd) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Has the driver's notifications for the given queue write to the
+    /// eventfd `fd` instead of reaching the device, returning whether
+    /// the transport can do that.  It lasts until the device is reset.
+    fn ioeventfd(&self, _queue: u16, _fd: RawFd) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Everything a device gets once the driver has finished setting it
+/// up.
+#[derive(Debug)]
+pub struct Activation {
+    /// The features the driver accepted.
+    pub features: u64,
+    pub memory: Arc<GuestMemory>,
+    /// One entry per queue the device offered; the ones the driver
+    /// left disabled are `None`.
+    pub queues: Vec<Option<Queue>>,
+    pub interrupt: Arc<Interrupt>,
+}
+
+/// A virtio device.  The transport takes care of the feature, status,
+/// and queue negotiation, and hands the result to the device.
+pub trait Virtio: Debug + Send + Sync {
+    /// The virtio device ID.
+    fn device_type(&self) -> u16;
+
+    /// The PCI class code, for transports that have one.
+    fn class(&self) -> u32 {
+        0xff0000
+    }
+
+    /// The device-specific features offered.  The transport adds
+    /// `VIRTIO_F_VERSION_1` and the queue features itself.
+    fn features(&self) -> u64;
+
+    /// The maximum size of each of the device's queues.
+    fn queues(&self) -> Vec<u16>;
+
+    /// The size of the device-specific configuration, in bytes.
+    fn config_size(&self) -> usize {
+        0
+    }
+
+    fn config_read(&self, _offset: usize, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    fn config_write(&self, _offset: usize, _data: &[u8]) {}
+
+    /// Starts the device, once the driver has set it up.
+    fn activate(&self, activation: Activation) -> Result<()>;
+
+    /// The driver made new buffers available in the given queue.
+    fn notify(&self, queue: u16);
+
+    /// Stops the device, dropping everything it got from `activate`.
+    fn reset(&self);
+}
@@ -0,0 +1,884 @@
+use super::caps::*;
+use super::transport::{self, Eventfds, State};
+use super::{
+    Interrupt, Virtio, VIRTIO_STATUS_DRIVER_OK, VIRTIO_STATUS_FEATURES_OK,
+    VIRTIO_STATUS_NEEDS_RESET,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use device::interrupt::Interrupts;
+use device::memory::GuestMemory;
+use device::pci::{
+    read_into, Address, Bar, Bars, ConfigSpace, Delivery, Express, Header, Msix, Pci, PortType,
+    EXTENDED_CONFIG_SPACE_SIZE, PCI_INTERRUPT_LINE,
+};
+use device::Device;
+use error::*;
+use kvm::core::{IoAction, IoAddress, IoDirection};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const VIRTIO_PCI_VENDOR: u16 = 0x1af4;
+/// Modern devices are numbered from here, by device type.
+const VIRTIO_PCI_DEVICE_BASE: u16 = 0x1040;
+const VIRTIO_PCI_SUBSYSTEM: u16 = 0x0040;
+
+const VIRTIO_PCI_ISR_QUEUE: usize = 1 << 0;
+const VIRTIO_PCI_ISR_CONFIG: usize = 1 << 1;
+
+/// Written to a vector register to turn off the interrupt.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// Every structure lives in a single 64-bit BAR, each in its own page.
+const BAR_INDEX: u8 = 4;
+const BAR_SIZE: u64 = 0x8000;
+const REGION_SIZE: u64 = 0x1000;
+const COMMON_OFFSET: u64 = 0x0000;
+const ISR_OFFSET: u64 = 0x1000;
+const DEVICE_OFFSET: u64 = 0x2000;
+const NOTIFY_OFFSET: u64 = 0x3000;
+const MSIX_TABLE_OFFSET: u64 = 0x4000;
+const MSIX_PBA_OFFSET: u64 = 0x6000;
+
+const NOTIFY_MULTIPLIER: u32 = 4;
+
+/// One vector per queue, and one for configuration changes, have to
+/// fit in the table.
+const MAX_QUEUES: usize = ((MSIX_PBA_OFFSET - MSIX_TABLE_OFFSET) / 0x10) as usize - 1;
+
+/// A region of the BAR, and the offset into it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Region {
+    Common(usize),
+    Isr(usize),
+    Device(usize),
+    Notify(usize),
+    Table(usize),
+    Pba(usize),
+}
+
+impl Region {
+    fn locate(offset: u64) -> Option<Region> {
+        let within = (offset % REGION_SIZE) as usize;
+        let region = match offset - offset % REGION_SIZE {
+            COMMON_OFFSET => Region::Common(within),
+            ISR_OFFSET => Region::Isr(within),
+            DEVICE_OFFSET => Region::Device(within),
+            NOTIFY_OFFSET => Region::Notify(within),
+            o if (MSIX_TABLE_OFFSET..MSIX_PBA_OFFSET).contains(&o) => {
+                Region::Table((offset - MSIX_TABLE_OFFSET) as usize)
+            }
+            o if (MSIX_PBA_OFFSET..BAR_SIZE).contains(&o) => {
+                Region::Pba((offset - MSIX_PBA_OFFSET) as usize)
+            }
+            _ => return None,
+        };
+
+        Some(region)
+    }
+}
+
+/// The interrupt side of the transport, which the device holds on to
+/// once it's activated.  Interrupts go through MSI-X when the driver
+/// enabled it, and otherwise through the ISR and INTx.
+#[derive(Debug)]
+struct Signals {
+    interrupts: Arc<Interrupts>,
+    space: Arc<Mutex<ConfigSpace>>,
+    msix: Arc<Msix>,
+    irq: Option<u8>,
+    isr: AtomicUsize,
+    status: AtomicUsize,
+    generation: AtomicUsize,
+    config_vector: AtomicUsize,
+    queue_vectors: Vec<AtomicUsize>,
+    eventfds: Eventfds,
+}
+
+impl Signals {
+    fn status(&self) -> u8 {
+        self.status.load(Ordering::SeqCst) as u8
+    }
+
+    fn queue_vector(&self, queue: usize) -> u16 {
+        self.queue_vectors
+            .get(queue)
+            .map_or(VIRTIO_MSI_NO_VECTOR, |v| v.load(Ordering::SeqCst) as u16)
+    }
+
+    /// Checks that a vector the driver picked exists, turning it into
+    /// `VIRTIO_MSI_NO_VECTOR` if it doesn't, as the driver expects.
+    fn validate(&self, vector: u16) -> u16 {
+        if (vector as usize) < self.msix.vectors() {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+
+    fn raise(&self, vector: u16, cause: usize) -> Result<()> {
+        if self.msix.enabled() {
+            return match vector {
+                VIRTIO_MSI_NO_VECTOR => Ok(()),
+                vector => self.msix.notify(vector as usize),
+            };
+        }
+
+        self.isr.fetch_or(cause, Ordering::SeqCst);
+        match self.irq {
+            Some(irq) => {
+                self.space.lock().unwrap().set_interrupt_status(true);
+                self.interrupts.line(irq as u32, true)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the ISR, which clears it and deasserts INTx.
+    fn acknowledge(&self) -> u8 {
+        let isr = self.isr.swap(0, Ordering::SeqCst);
+        if isr != 0 {
+            self.lower();
+        }
+        isr as u8
+    }
+
+    fn lower(&self) {
+        if let Some(irq) = self.irq {
+            self.space.lock().unwrap().set_interrupt_status(false);
+            if let Err(e) = self.interrupts.line(irq as u32, false) {
+                warn!("could not lower virtio interrupt: {}", e);
+            }
+        }
+    }
+
+    fn reset(&self) {
+        self.status.store(0, Ordering::SeqCst);
+        self.config_vector
+            .store(VIRTIO_MSI_NO_VECTOR as usize, Ordering::SeqCst);
+        for vector in &self.queue_vectors {
+            vector.store(VIRTIO_MSI_NO_VECTOR as usize, Ordering::SeqCst);
+        }
+        if self.isr.swap(0, Ordering::SeqCst) != 0 {
+            self.lower();
+        }
+    }
+}
+
+impl Interrupt for Signals {
+    fn queue(&self, queue: u16) -> Result<()> {
+        self.raise(self.queue_vector(queue as usize), VIRTIO_PCI_ISR_QUEUE)
+    }
+
+    fn config(&self) -> Result<()> {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let vector = self.config_vector.load(Ordering::SeqCst) as u16;
+        self.raise(vector, VIRTIO_PCI_ISR_CONFIG)
+    }
+
+    fn needs_reset(&self) {
+        self.status
+            .fetch_or(VIRTIO_STATUS_NEEDS_RESET as usize, Ordering::SeqCst);
+        if let Err(e) = self.config() {
+            warn!("could not signal virtio reset: {}", e);
+        }
+    }
+
+    /// Only MSI-X vectors have a GSI of their own.  KVM goes by the
+    /// vector's routing entry, which is gone while the vector's
+    /// masked, so interrupts raised then are lost rather than left
+    /// pending; drivers don't mask vectors their queues are using.
+    fn irqfd(&self, queue: u16, fd: RawFd) -> Result<bool> {
+        let vector = self.queue_vector(queue as usize);
+        if !self.msix.enabled() || vector == VIRTIO_MSI_NO_VECTOR {
+            return Ok(false);
+        }
+
+        match self.msix.gsi(vector as usize) {
+            Some(gsi) => {
+                self.eventfds.irqfd(self.interrupts.as_ref(), gsi, fd)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Each queue has a notification register of its own, which the
+    /// driver writes the queue's index to, as 16 bits.
+    fn ioeventfd(&self, queue: u16, fd: RawFd) -> Result<bool> {
+        let bar = {
+            let space = self.space.lock().unwrap();
+            space
+                .bars()
+                .get(BAR_INDEX as usize)
+                .map_or(0, |bar| bar.address())
+        };
+        if bar == 0 {
+            return Ok(false);
+        }
+
+        let address = bar + NOTIFY_OFFSET + queue as u64 * NOTIFY_MULTIPLIER as u64;
+        self.eventfds
+            .ioeventfd(self.interrupts.as_ref(), address, 2, None, fd)?;
+        Ok(true)
+    }
+}
+
+/// The modern virtio-pci transport, which exposes a device through the
+/// capabilities and BAR layout of version 1 of the specification.
+#[derive(Debug)]
+pub struct PciTransport {
+    device: Arc<Virtio>,
+    memory: Arc<GuestMemory>,
+    space: Arc<Mutex<ConfigSpace>>,
+    access: (usize, Arc<ConfigAccess>),
+    signals: Arc<Signals>,
+    state: Mutex<State>,
+}
+
+impl PciTransport {
+    /// Wraps a device in a PCI function.  `irq` is the GSI that INTx
+    /// is wired to; without one, the driver has to use MSI-X.
+    pub fn new(
+        device: Arc<Virtio>,
+        memory: Arc<GuestMemory>,
+        interrupts: Arc<Interrupts>,
+        irq: Option<u8>,
+    ) -> Result<PciTransport> {
+        let sizes = device.queues();
+        if sizes.len() > MAX_QUEUES {
+            return Err(ErrorKind::DeviceError("too many virtqueues").into());
+        }
+
+        let header = Header {
+            vendor: VIRTIO_PCI_VENDOR,
+            device: VIRTIO_PCI_DEVICE_BASE + device.device_type(),
+            revision: 0x01,
+            class: device.class(),
+            subsystem_vendor: VIRTIO_PCI_VENDOR,
+            subsystem: VIRTIO_PCI_SUBSYSTEM,
+            interrupt_pin: if irq.is_some() { 0x01 } else { 0x00 },
+        };
+        let mut bars = Bars::new();
+        bars.set(BAR_INDEX as usize, Bar::memory64(BAR_SIZE, false));
+        let mut space = ConfigSpace::with_size(header, bars, EXTENDED_CONFIG_SPACE_SIZE);
+        if let Some(irq) = irq {
+            space.write(PCI_INTERRUPT_LINE, &[irq]);
+        }
+
+        // Vectors are routed, so devices can raise them through irqfds.
+        let msix = Arc::new(Msix::new(
+            interrupts.clone(),
+            sizes.len() + 1,
+            (BAR_INDEX, MSIX_TABLE_OFFSET as u32),
+            (BAR_INDEX, MSIX_PBA_OFFSET as u32),
+            Delivery::Routed,
+        )?);
+        let access = Arc::new(ConfigAccess::new());
+        let region = |cfg_type, offset, length: usize| {
+            Arc::new(Capability::new(
+                cfg_type,
+                BAR_INDEX,
+                offset as u32,
+                length as u32,
+            ))
+        };
+
+        space.push(Arc::new(Express::new(PortType::IntegratedEndpoint)));
+        space.push(msix.clone());
+        space.push(region(
+            VIRTIO_PCI_CAP_COMMON_CFG,
+            COMMON_OFFSET,
+            COMMON_LENGTH,
+        ));
+        space.push(region(VIRTIO_PCI_CAP_ISR_CFG, ISR_OFFSET, 1));
+        if device.config_size() > 0 {
+            space.push(region(
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                DEVICE_OFFSET,
+                device.config_size(),
+            ));
+        }
+        space.push(Arc::new(Notify::new(
+            BAR_INDEX,
+            NOTIFY_OFFSET as u32,
+            ::std::cmp::max(sizes.len(), 1) as u32 * NOTIFY_MULTIPLIER,
+            NOTIFY_MULTIPLIER,
+        )));
+        let base = space.push(access.clone());
+
+        let space = Arc::new(Mutex::new(space));
+        let signals = Arc::new(Signals {
+            interrupts,
+            space: space.clone(),
+            msix,
+            irq,
+            isr: AtomicUsize::new(0),
+            status: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            config_vector: AtomicUsize::new(VIRTIO_MSI_NO_VECTOR as usize),
+            queue_vectors: sizes
+                .iter()
+                .map(|_| AtomicUsize::new(VIRTIO_MSI_NO_VECTOR as usize))
+                .collect(),
+            eventfds: Eventfds::default(),
+        });
+
+        Ok(PciTransport {
+            device,
+            memory,
+            space,
+            access: (base, access),
+            signals,
+            state: Mutex::new(State::new(&sizes)),
+        })
+    }
+
+    fn common(&self, state: &State) -> Common {
+        let queue = state.selected();
+        Common {
+            device_feature_select: state.device_feature_select,
+            device_feature: state.device_features(transport::offered(self.device.as_ref())),
+            driver_feature_select: state.driver_feature_select,
+            driver_feature: state.driver_features(),
+            msix_config: self.signals.config_vector.load(Ordering::SeqCst) as u16,
+            num_queues: state.queues.len() as u16,
+            device_status: self.signals.status(),
+            config_generation: self.signals.generation.load(Ordering::SeqCst) as u8,
+            queue_select: state.queue_select,
+            queue_size: queue.map_or(0, |q| q.size),
+            queue_msix_vector: self.signals.queue_vector(state.queue_select as usize),
+            queue_enable: queue.map_or(0, |q| q.enabled as u16),
+            queue_notify_off: queue.map_or(0, |_| state.queue_select),
+            queue_desc: queue.map_or(0, |q| q.descriptors),
+            queue_avail: queue.map_or(0, |q| q.driver),
+            queue_used: queue.map_or(0, |q| q.device),
+        }
+    }
+
+    fn common_write(&self, offset: usize, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let mut registers = self.common(&state).to_bytes();
+        for (i, byte) in data.iter().enumerate() {
+            if let Some(register) = registers.get_mut(offset + i) {
+                *register = *byte;
+            }
+        }
+
+        let written =
+            |start: usize, length: usize| start < offset + data.len() && offset < start + length;
+        let u16_at = |start: usize| LittleEndian::read_u16(&registers[start..start + 2]);
+        let u32_at = |start: usize| LittleEndian::read_u32(&registers[start..start + 4]);
+        let u64_at = |start: usize| LittleEndian::read_u64(&registers[start..start + 8]);
+        let status = self.signals.status();
+
+        if written(COMMON_DEVICE_FEATURE_SELECT, 4) {
+            state.device_feature_select = u32_at(COMMON_DEVICE_FEATURE_SELECT);
+        }
+
+        if written(COMMON_DRIVER_FEATURE_SELECT, 4) {
+            state.driver_feature_select = u32_at(COMMON_DRIVER_FEATURE_SELECT);
+        }
+
+        // The features are fixed once the driver has accepted them.
+        if written(COMMON_DRIVER_FEATURE, 4) && (status & VIRTIO_STATUS_FEATURES_OK) == 0 {
+            state.set_driver_features(u32_at(COMMON_DRIVER_FEATURE));
+        }
+
+        if written(COMMON_MSIX_CONFIG, 2) {
+            let vector = self.signals.validate(u16_at(COMMON_MSIX_CONFIG));
+            self.signals
+                .config_vector
+                .store(vector as usize, Ordering::SeqCst);
+        }
+
+        if written(COMMON_QUEUE_SELECT, 2) {
+            state.queue_select = u16_at(COMMON_QUEUE_SELECT);
+        }
+
+        // The queues are fixed once the device is running.
+        let select = state.queue_select as usize;
+        if (status & VIRTIO_STATUS_DRIVER_OK) == 0 && select < state.queues.len() {
+            if written(COMMON_QUEUE_MSIX_VECTOR, 2) {
+                let vector = self.signals.validate(u16_at(COMMON_QUEUE_MSIX_VECTOR));
+                self.signals.queue_vectors[select].store(vector as usize, Ordering::SeqCst);
+            }
+
+            let queue = &mut state.queues[select];
+            if written(COMMON_QUEUE_SIZE, 2) {
+                queue.resize(u16_at(COMMON_QUEUE_SIZE));
+            }
+
+            // Queues can't be disabled, short of resetting the device.
+            if written(COMMON_QUEUE_ENABLE, 2) && u16_at(COMMON_QUEUE_ENABLE) == 1 {
+                queue.enabled = true;
+            }
+
+            if written(COMMON_QUEUE_DESC, 8) {
+                queue.descriptors = u64_at(COMMON_QUEUE_DESC);
+            }
+
+            if written(COMMON_QUEUE_AVAIL, 8) {
+                queue.driver = u64_at(COMMON_QUEUE_AVAIL);
+            }
+
+            if written(COMMON_QUEUE_USED, 8) {
+                queue.device = u64_at(COMMON_QUEUE_USED);
+            }
+        }
+
+        if written(COMMON_DEVICE_STATUS, 1) {
+            self.set_status(&mut state, registers[COMMON_DEVICE_STATUS]);
+        }
+    }
+
+    fn set_status(&self, state: &mut State, value: u8) {
+        if value == 0 {
+            self.signals
+                .eventfds
+                .detach(self.signals.interrupts.as_ref());
+            self.device.reset();
+            *state = State::new(&self.device.queues());
+            self.signals.reset();
+            return;
+        }
+
+        let old = self.signals.status();
+        let offered = transport::offered(self.device.as_ref());
+        let status = state.update_status(offered, old, value);
+        self.signals.status.store(status as usize, Ordering::SeqCst);
+
+        if State::starting(old, status) {
+            let interrupt = self.signals.clone();
+            if let Err(e) = state.activate(self.device.as_ref(), self.memory.clone(), interrupt) {
+                warn!("could not activate virtio device: {}", e);
+                self.signals.needs_reset();
+            }
+        }
+    }
+
+    fn bar_read(&self, offset: u64, data: &mut [u8]) {
+        match Region::locate(offset) {
+            Some(Region::Common(offset)) => {
+                let state = self.state.lock().unwrap();
+                read_into(&self.common(&state).to_bytes(), offset, data);
+            }
+            Some(Region::Isr(0)) => {
+                read_into(&[self.signals.acknowledge()], 0, data);
+            }
+            Some(Region::Device(offset)) if offset < self.device.config_size() => {
+                self.device.config_read(offset, data);
+            }
+            Some(Region::Table(offset)) => self.signals.msix.table_read(offset, data),
+            Some(Region::Pba(offset)) => self.signals.msix.pba_read(offset, data),
+            _ => read_into(&[], 0, data),
+        }
+    }
+
+    fn bar_write(&self, offset: u64, data: &[u8]) {
+        match Region::locate(offset) {
+            Some(Region::Common(offset)) => self.common_write(offset, data),
+            Some(Region::Device(offset)) if offset < self.device.config_size() => {
+                self.device.config_write(offset, data);
+            }
+            Some(Region::Notify(offset)) => {
+                let queue = offset / NOTIFY_MULTIPLIER as usize;
+                let running = (self.signals.status() & VIRTIO_STATUS_DRIVER_OK) != 0;
+                if running && queue < self.signals.queue_vectors.len() {
+                    self.device.notify(queue as u16);
+                }
+            }
+            Some(Region::Table(offset)) => self.signals.msix.table_write(offset, data),
+            _ => (),
+        }
+    }
+
+    /// If the register is the data window of the PCI configuration
+    /// access capability, the part of our BAR the driver pointed it at.
+    fn window(&self, register: usize) -> Option<(u64, usize)> {
+        if register != self.access.0 + CONFIG_ACCESS_DATA {
+            return None;
+        }
+
+        match self.access.1.window() {
+            (BAR_INDEX, offset, length) if length == 1 || length == 2 || length == 4 => {
+                Some((offset as u64, length as usize))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Device for PciTransport {
+    fn request(&self) -> Vec<IoAddress> {
+        vec![]
+    }
+
+    fn handle(&self, io: IoAction, memory: &mut [u8]) -> Option<()> {
+        let IoAction(address, direction, size) = io;
+        let offset = match self.space.lock().unwrap().bars().locate(address) {
+            Some((index, offset)) if index == BAR_INDEX as usize => offset,
+            _ => return None,
+        };
+
+        match direction {
+            IoDirection::In => self.bar_read(offset, &mut memory[..size]),
+            IoDirection::Out => self.bar_write(offset, &memory[..size]),
+        }
+
+        Some(())
+    }
+}
+
+impl Pci for PciTransport {
+    fn config_read(&self, address: Address, data: &mut [u8]) -> Option<()> {
+        let register = address.register() as usize;
+        match self.window(register) {
+            Some((offset, length)) => {
+                let mut value = [0u8; 4];
+                self.bar_read(offset, &mut value[..length]);
+                read_into(&value, 0, data);
+            }
+            None => self.space.lock().unwrap().read(register, data),
+        }
+
+        Some(())
+    }
+
+    fn config_write(&self, address: Address, data: &[u8]) -> Option<()> {
+        let register = address.register() as usize;
+        self.space.lock().unwrap().write(register, data);
+        if let Some((offset, length)) = self.window(register) {
+            let length = ::std::cmp::min(length, data.len());
+            self.bar_write(offset, &data[..length]);
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::queue::tests::Ram;
+    use super::super::*;
+    use super::*;
+    use device::interrupt::Message;
+
+    #[derive(Debug, Default)]
+    struct Backend {
+        activated: Mutex<Option<Activation>>,
+        notified: Mutex<Vec<u16>>,
+        resets: AtomicUsize,
+    }
+
+    impl Virtio for Backend {
+        fn device_type(&self) -> u16 {
+            3
+        }
+
+        fn features(&self) -> u64 {
+            1 << 0
+        }
+
+        fn queues(&self) -> Vec<u16> {
+            vec![256, 16]
+        }
+
+        fn config_size(&self) -> usize {
+            4
+        }
+
+        fn config_read(&self, offset: usize, data: &mut [u8]) {
+            read_into(&[0x11, 0x22, 0x33, 0x44], offset, data);
+        }
+
+        fn activate(&self, activation: Activation) -> Result<()> {
+            *self.activated.lock().unwrap() = Some(activation);
+            Ok(())
+        }
+
+        fn notify(&self, queue: u16) {
+            self.notified.lock().unwrap().push(queue);
+        }
+
+        fn reset(&self) {
+            *self.activated.lock().unwrap() = None;
+            self.resets.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Lines(Mutex<Vec<(u32, bool)>>);
+
+    impl Interrupts for Lines {
+        fn line(&self, irq: u32, level: bool) -> Result<()> {
+            self.0.lock().unwrap().push((irq, level));
+            Ok(())
+        }
+
+        fn signal(&self, _message: Message) -> Result<()> {
+            Ok(())
+        }
+
+        fn allocate(&self) -> Result<u32> {
+            Ok(0)
+        }
+
+        fn route(&self, _gsi: u32, _message: Option<Message>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn transport() -> (PciTransport, Arc<Backend>, Arc<Lines>) {
+        let backend = Arc::new(Backend::default());
+        let lines = Arc::new(Lines::default());
+        let transport = PciTransport::new(
+            backend.clone(),
+            Arc::new(Ram::new(0, 0x10000)),
+            lines.clone(),
+            Some(10),
+        )
+        .unwrap();
+        (transport, backend, lines)
+    }
+
+    fn config_u8(transport: &PciTransport, register: usize) -> u8 {
+        let mut data = [0u8; 1];
+        transport.config_read(Address::from_ecam(0, register as u64), &mut data);
+        data[0]
+    }
+
+    fn read_u32(transport: &PciTransport, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        transport.bar_read(offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write_u16(transport: &PciTransport, offset: u64, value: u16) {
+        let mut data = [0u8; 2];
+        LittleEndian::write_u16(&mut data, value);
+        transport.bar_write(offset, &data);
+    }
+
+    fn write_u32(transport: &PciTransport, offset: u64, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        transport.bar_write(offset, &data);
+    }
+
+    fn status(transport: &PciTransport) -> u8 {
+        let mut data = [0u8; 1];
+        transport.bar_read(COMMON_DEVICE_STATUS as u64, &mut data);
+        data[0]
+    }
+
+    fn set_status(transport: &PciTransport, status: u8) {
+        transport.bar_write(COMMON_DEVICE_STATUS as u64, &[status]);
+    }
+
+    /// Goes through the usual driver initialization, accepting the
+    /// given features and setting up the first queue.
+    fn initialize(transport: &PciTransport, features: u64) {
+        set_status(transport, VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER);
+        write_u32(transport, COMMON_DRIVER_FEATURE_SELECT as u64, 0);
+        write_u32(transport, COMMON_DRIVER_FEATURE as u64, features as u32);
+        write_u32(transport, COMMON_DRIVER_FEATURE_SELECT as u64, 1);
+        write_u32(
+            transport,
+            COMMON_DRIVER_FEATURE as u64,
+            (features >> 32) as u32,
+        );
+        set_status(transport, VIRTIO_STATUS_FEATURES_OK);
+
+        write_u16(transport, COMMON_QUEUE_SELECT as u64, 0);
+        write_u16(transport, COMMON_QUEUE_SIZE as u64, 16);
+        write_u32(transport, COMMON_QUEUE_DESC as u64, 0x1000);
+        write_u32(transport, COMMON_QUEUE_AVAIL as u64, 0x2000);
+        write_u32(transport, COMMON_QUEUE_USED as u64, 0x3000);
+        write_u16(transport, COMMON_QUEUE_ENABLE as u64, 1);
+    }
+
+    #[test]
+    fn it_describes_its_structures_in_capabilities() {
+        let (transport, _, _) = transport();
+        let mut types = vec![];
+        let mut next = config_u8(&transport, 0x34) as usize;
+        while next != 0 {
+            if config_u8(&transport, next) == VENDOR_CAPABILITY_ID {
+                types.push(config_u8(&transport, next + 3));
+            }
+            next = config_u8(&transport, next + 1) as usize;
+        }
+
+        assert_eq!(
+            types,
+            vec![
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                VIRTIO_PCI_CAP_ISR_CFG,
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                VIRTIO_PCI_CAP_PCI_CFG,
+            ]
+        );
+    }
+
+    #[test]
+    fn it_offers_version_1_with_the_device_features() {
+        let (transport, _, _) = transport();
+        assert_eq!(read_u32(&transport, COMMON_DEVICE_FEATURE as u64) & 1, 1);
+        write_u32(&transport, COMMON_DEVICE_FEATURE_SELECT as u64, 1);
+        assert_eq!(read_u32(&transport, COMMON_DEVICE_FEATURE as u64) & 1, 1);
+        write_u32(&transport, COMMON_DEVICE_FEATURE_SELECT as u64, 2);
+        assert_eq!(read_u32(&transport, COMMON_DEVICE_FEATURE as u64), 0);
+    }
+
+    #[test]
+    fn it_rejects_features_it_did_not_offer() {
+        let (transport, _, _) = transport();
+        initialize(&transport, VIRTIO_F_VERSION_1 | (1 << 1));
+        assert_eq!(status(&transport) & VIRTIO_STATUS_FEATURES_OK, 0);
+
+        set_status(&transport, 0);
+        initialize(&transport, 1 << 0);
+        assert_eq!(status(&transport) & VIRTIO_STATUS_FEATURES_OK, 0);
+
+        set_status(&transport, 0);
+        initialize(&transport, VIRTIO_F_VERSION_1 | (1 << 0));
+        assert_eq!(
+            status(&transport) & VIRTIO_STATUS_FEATURES_OK,
+            VIRTIO_STATUS_FEATURES_OK
+        );
+    }
+
+    #[test]
+    fn it_activates_the_enabled_queues() {
+        let (transport, backend, _) = transport();
+        initialize(&transport, VIRTIO_F_VERSION_1);
+        assert!(backend.activated.lock().unwrap().is_none());
+        set_status(&transport, VIRTIO_STATUS_DRIVER_OK);
+
+        let activated = backend.activated.lock().unwrap();
+        let activation = activated.as_ref().unwrap();
+        assert_eq!(activation.features, VIRTIO_F_VERSION_1);
+        assert_eq!(activation.queues.len(), 2);
+        assert_eq!(
+            activation.queues[0],
+            Some(Queue::new(16, 0x1000, 0x2000, 0x3000, VIRTIO_F_VERSION_1).unwrap())
+        );
+        assert!(activation.queues[1].is_none());
+    }
+
+    #[test]
+    fn it_ignores_queue_changes_once_running() {
+        let (transport, _, _) = transport();
+        initialize(&transport, VIRTIO_F_VERSION_1);
+        set_status(&transport, VIRTIO_STATUS_DRIVER_OK);
+        write_u16(&transport, COMMON_QUEUE_SIZE as u64, 8);
+        write_u16(&transport, COMMON_QUEUE_SELECT as u64, 1);
+        write_u16(&transport, COMMON_QUEUE_ENABLE as u64, 1);
+
+        let state = transport.state.lock().unwrap();
+        assert_eq!(state.queues[0].size, 16);
+        assert!(!state.queues[1].enabled);
+    }
+
+    #[test]
+    fn it_needs_a_reset_when_the_queues_are_invalid() {
+        let (transport, backend, _) = transport();
+        initialize(&transport, VIRTIO_F_VERSION_1);
+        write_u16(&transport, COMMON_QUEUE_SIZE as u64, 3);
+        set_status(&transport, VIRTIO_STATUS_DRIVER_OK);
+
+        assert!(backend.activated.lock().unwrap().is_none());
+        assert_eq!(
+            status(&transport) & VIRTIO_STATUS_NEEDS_RESET,
+            VIRTIO_STATUS_NEEDS_RESET
+        );
+    }
+
+    #[test]
+    fn it_resets_when_the_status_is_cleared() {
+        let (transport, backend, _) = transport();
+        initialize(&transport, VIRTIO_F_VERSION_1);
+        set_status(&transport, VIRTIO_STATUS_DRIVER_OK);
+        set_status(&transport, 0);
+
+        assert_eq!(status(&transport), 0);
+        assert_eq!(backend.resets.load(Ordering::SeqCst), 1);
+        assert!(backend.activated.lock().unwrap().is_none());
+        let state = transport.state.lock().unwrap();
+        assert_eq!(state.driver_features, 0);
+        assert_eq!(state.queues[0].size, 256);
+        assert!(!state.queues[0].enabled);
+    }
+
+    #[test]
+    fn it_forwards_notifications_once_running() {
+        let (transport, backend, _) = transport();
+        write_u16(&transport, NOTIFY_OFFSET, 0);
+        initialize(&transport, VIRTIO_F_VERSION_1);
+        set_status(&transport, VIRTIO_STATUS_DRIVER_OK);
+        write_u16(&transport, NOTIFY_OFFSET + NOTIFY_MULTIPLIER as u64, 1);
+        write_u16(&transport, NOTIFY_OFFSET + 2 * NOTIFY_MULTIPLIER as u64, 2);
+
+        assert_eq!(*backend.notified.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn it_interrupts_through_the_isr_without_msix() {
+        let (transport, backend, lines) = transport();
+        initialize(&transport, VIRTIO_F_VERSION_1);
+        set_status(&transport, VIRTIO_STATUS_DRIVER_OK);
+        let interrupt = backend
+            .activated
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .interrupt
+            .clone();
+
+        interrupt.queue(0).unwrap();
+        assert_eq!(*lines.0.lock().unwrap(), vec![(10, true)]);
+        assert_eq!(config_u8(&transport, 0x06) & 0x08, 0x08);
+
+        let mut isr = [0u8; 1];
+        transport.bar_read(ISR_OFFSET, &mut isr);
+        assert_eq!(isr[0], VIRTIO_PCI_ISR_QUEUE as u8);
+        assert_eq!(*lines.0.lock().unwrap(), vec![(10, true), (10, false)]);
+        assert_eq!(config_u8(&transport, 0x06) & 0x08, 0);
+
+        transport.bar_read(ISR_OFFSET, &mut isr);
+        assert_eq!(isr[0], 0);
+    }
+
+    #[test]
+    fn it_rejects_vectors_it_does_not_have() {
+        let (transport, _, _) = transport();
+        write_u16(&transport, COMMON_MSIX_CONFIG as u64, 2);
+        write_u16(&transport, COMMON_QUEUE_MSIX_VECTOR as u64, 3);
+        let value = read_u32(&transport, COMMON_MSIX_CONFIG as u64);
+        assert_eq!(value & 0xffff, 2);
+        let value = read_u32(&transport, COMMON_QUEUE_SELECT as u64 + 2);
+        assert_eq!(value >> 16, VIRTIO_MSI_NO_VECTOR as u32);
+    }
+
+    #[test]
+    fn it_reaches_the_bar_through_configuration_space() {
+        let (transport, _, _) = transport();
+        let base = transport.access.0;
+        let mut window = [0u8; 12];
+        window[0] = BAR_INDEX;
+        LittleEndian::write_u32(&mut window[4..8], DEVICE_OFFSET as u32 + 1);
+        LittleEndian::write_u32(&mut window[8..12], 2);
+        for (i, chunk) in window.chunks(4).enumerate() {
+            let address = Address::from_ecam(0, (base + 4 + i * 4) as u64);
+            transport.config_write(address, chunk);
+        }
+
+        let mut data = [0u8; 4];
+        let address = Address::from_ecam(0, (base + CONFIG_ACCESS_DATA) as u64);
+        transport.config_read(address, &mut data);
+        assert_eq!(data, [0x22, 0x33, 0x00, 0x00]);
+    }
+}
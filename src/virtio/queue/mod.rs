@@ -0,0 +1,888 @@
+use device::memory::GuestMemory;
+use error::*;
+use std::io;
+
+mod packed;
+mod split;
+
+pub use self::packed::PackedQueue;
+pub use self::split::SplitQueue;
+
+/// The driver may use indirect descriptor tables.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+/// The driver and device suppress notifications with event indices,
+/// rather than flags.
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+/// The queues use the packed layout, rather than the split one.
+pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
+/// The queue features every device can offer, since they're handled
+/// entirely here.  The packed layout is also handled here, but is left
+/// for devices to offer themselves, since devices that hand their
+/// queues to a backend elsewhere can only offer it if that can too.
+pub const QUEUE_FEATURES: u64 = VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX;
+
+/// The largest queue size the specification allows.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+/// A virtqueue, in whichever layout the driver negotiated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Queue {
+    Split(SplitQueue),
+    Packed(PackedQueue),
+}
+
+impl Queue {
+    /// Creates a queue from the three addresses the driver configured.
+    /// For split queues, these are the descriptor table, available
+    /// ring, and used ring; for packed queues, the descriptor ring,
+    /// and the driver and device event suppression structures.
+    pub fn new(
+        size: u16,
+        descriptors: u64,
+        driver: u64,
+        device: u64,
+        features: u64,
+    ) -> Result<Queue> {
+        if (features & VIRTIO_F_RING_PACKED) != 0 {
+            PackedQueue::new(size, descriptors, driver, device, features).map(Queue::Packed)
+        } else {
+            SplitQueue::new(size, descriptors, driver, device, features).map(Queue::Split)
+        }
+    }
+
+    /// Takes the next chain the driver made available, if there is
+    /// one.  Once there isn't, the driver's asked to notify us about
+    /// the next one, so devices can take everything there is and wait
+    /// for a notification.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<Chain>> {
+        let chain = self.take(memory)?;
+        if chain.is_some() || !self.enable_notification(memory)? {
+            return Ok(chain);
+        }
+
+        // The driver made one available before it saw our request.
+        self.take(memory)
+    }
+
+    fn take(&mut self, memory: &GuestMemory) -> Result<Option<Chain>> {
+        match *self {
+            Queue::Split(ref mut queue) => queue.pop(memory),
+            Queue::Packed(ref mut queue) => queue.pop(memory),
+        }
+    }
+
+    /// Hands a chain back to the driver, noting how many bytes were
+    /// written into it.
+    pub fn push(&mut self, memory: &GuestMemory, head: u16, written: u32) -> Result<()> {
+        match *self {
+            Queue::Split(ref mut queue) => queue.push(memory, head, written),
+            Queue::Packed(ref mut queue) => queue.push(memory, head, written),
+        }
+    }
+
+    /// Whether the driver wants an interrupt for the chains used since
+    /// the last time this was asked.
+    pub fn needs_notification(&mut self, memory: &GuestMemory) -> Result<bool> {
+        match *self {
+            Queue::Split(ref mut queue) => queue.needs_notification(memory),
+            Queue::Packed(ref mut queue) => queue.needs_notification(memory),
+        }
+    }
+
+    /// Asks the driver to notify us about new chains, returning whether
+    /// there already are some.
+    pub fn enable_notification(&mut self, memory: &GuestMemory) -> Result<bool> {
+        match *self {
+            Queue::Split(ref mut queue) => queue.enable_notification(memory),
+            Queue::Packed(ref mut queue) => queue.enable_notification(memory),
+        }
+    }
+
+    /// Tells the driver it needn't notify us about new chains.
+    pub fn disable_notification(&mut self, memory: &GuestMemory) -> Result<()> {
+        match *self {
+            Queue::Split(ref mut queue) => queue.disable_notification(memory),
+            Queue::Packed(ref mut queue) => queue.disable_notification(memory),
+        }
+    }
+}
+
+/// A buffer in guest memory, described by a single descriptor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Buffer {
+    pub address: u64,
+    pub length: u32,
+}
+
+/// A descriptor chain the driver made available: some buffers for the
+/// device to read, followed by some for it to write.  Once the device
+/// is done with it, it hands `head` back through the used ring.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Chain {
+    head: u16,
+    readable: Vec<Buffer>,
+    writable: Vec<Buffer>,
+}
+
+impl Chain {
+    fn new(head: u16) -> Chain {
+        Chain {
+            head,
+            readable: vec![],
+            writable: vec![],
+        }
+    }
+
+    /// Appends a buffer to the chain.  All of the readable buffers have
+    /// to come before the writable ones.
+    fn push(&mut self, address: u64, length: u32, writable: bool) -> Result<()> {
+        if address.checked_add(length as u64).is_none() {
+            return Err(ErrorKind::QueueError("buffer wraps around the address space").into());
+        }
+
+        let buffer = Buffer { address, length };
+        if writable {
+            self.writable.push(buffer);
+        } else if self.writable.is_empty() {
+            self.readable.push(buffer);
+        } else {
+            return Err(ErrorKind::QueueError("readable buffer after a writable one").into());
+        }
+
+        Ok(())
+    }
+
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// The total size of the readable buffers.
+    pub fn readable_len(&self) -> u64 {
+        self.readable.iter().map(|b| b.length as u64).sum()
+    }
+
+    /// The total size of the writable buffers.
+    pub fn writable_len(&self) -> u64 {
+        self.writable.iter().map(|b| b.length as u64).sum()
+    }
+
+    /// Reads through the readable buffers, in order, as one stream.
+    pub fn reader<'a>(&'a self, memory: &'a GuestMemory) -> Reader<'a> {
+        Reader(Cursor::new(memory, &self.readable))
+    }
+
+    /// Writes through the writable buffers, in order, as one stream.
+    pub fn writer<'a>(&'a self, memory: &'a GuestMemory) -> Writer<'a> {
+        Writer(Cursor::new(memory, &self.writable))
+    }
+}
+
+/// A position in a list of buffers.
+#[derive(Debug)]
+struct Cursor<'a> {
+    memory: &'a GuestMemory,
+    buffers: &'a [Buffer],
+    index: usize,
+    offset: u32,
+    total: u64,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(memory: &'a GuestMemory, buffers: &'a [Buffer]) -> Cursor<'a> {
+        Cursor {
+            memory,
+            buffers,
+            index: 0,
+            offset: 0,
+            total: 0,
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        let rest = self.buffers[self.index.min(self.buffers.len())..]
+            .iter()
+            .map(|b| b.length as u64)
+            .sum::<u64>();
+        rest - self.offset as u64
+    }
+
+    /// The next piece of guest memory to access, at most `length`
+    /// bytes long, skipping over any exhausted buffers.
+    fn next(&mut self, length: usize) -> Option<(u64, usize)> {
+        while let Some(buffer) = self.buffers.get(self.index) {
+            if self.offset < buffer.length {
+                let available = (buffer.length - self.offset) as usize;
+                return Some((buffer.address + self.offset as u64, available.min(length)));
+            }
+
+            self.index += 1;
+            self.offset = 0;
+        }
+
+        None
+    }
+
+    fn advance(&mut self, length: usize) {
+        self.offset += length as u32;
+        self.total += length as u64;
+    }
+}
+
+fn io_error(error: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error.to_string())
+}
+
+/// Reads from the readable buffers of a chain.
+#[derive(Debug)]
+pub struct Reader<'a>(Cursor<'a>);
+
+impl<'a> Reader<'a> {
+    /// How many bytes are left to read.
+    pub fn remaining(&self) -> u64 {
+        self.0.remaining()
+    }
+}
+
+impl<'a> io::Read for Reader<'a> {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < data.len() {
+            let (address, length) = match self.0.next(data.len() - done) {
+                Some(next) => next,
+                None => break,
+            };
+
+            self.0
+                .memory
+                .read(address, &mut data[done..done + length])
+                .map_err(io_error)?;
+            self.0.advance(length);
+            done += length;
+        }
+
+        Ok(done)
+    }
+}
+
+/// Writes to the writable buffers of a chain.
+#[derive(Debug)]
+pub struct Writer<'a>(Cursor<'a>);
+
+impl<'a> Writer<'a> {
+    /// How many more bytes fit.
+    pub fn remaining(&self) -> u64 {
+        self.0.remaining()
+    }
+
+    /// How many bytes have been written so far; this is what gets
+    /// reported back in the used ring.
+    pub fn written(&self) -> u32 {
+        self.0.total as u32
+    }
+}
+
+impl<'a> io::Write for Writer<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < data.len() {
+            let (address, length) = match self.0.next(data.len() - done) {
+                Some(next) => next,
+                None => break,
+            };
+
+            self.0
+                .memory
+                .write(address, &data[done..done + length])
+                .map_err(io_error)?;
+            self.0.advance(length);
+            done += length;
+        }
+
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::super::device::memory::GuestMemory;
+    use super::super::super::error::*;
+    use super::super::{Activation, Interrupt};
+    use super::{Buffer, Chain, Queue, SplitQueue, VIRTIO_F_EVENT_IDX, VIRTIO_F_RING_PACKED};
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Guest memory backed by a plain vector, starting at `base`.
+    #[derive(Debug)]
+    pub struct Ram {
+        base: u64,
+        data: Mutex<Vec<u8>>,
+    }
+
+    impl Ram {
+        pub fn new(base: u64, size: usize) -> Ram {
+            Ram {
+                base,
+                data: Mutex::new(vec![0u8; size]),
+            }
+        }
+
+        fn range(&self, address: u64, length: usize) -> Result<::std::ops::Range<usize>> {
+            let size = self.data.lock().unwrap().len() as u64;
+            match address.checked_sub(self.base) {
+                Some(offset) if offset + length as u64 <= size => {
+                    Ok(offset as usize..offset as usize + length)
+                }
+                _ => Err(ErrorKind::GuestMemoryError(address).into()),
+            }
+        }
+    }
+
+    impl GuestMemory for Ram {
+        fn read(&self, address: u64, data: &mut [u8]) -> Result<()> {
+            let range = self.range(address, data.len())?;
+            data.copy_from_slice(&self.data.lock().unwrap()[range]);
+            Ok(())
+        }
+
+        fn write(&self, address: u64, data: &[u8]) -> Result<()> {
+            let range = self.range(address, data.len())?;
+            self.data.lock().unwrap()[range].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// Keeps track of the interrupts a device raises.  A device asking
+    /// for a reset fails the test, unless it's expected to.
+    #[derive(Debug, Default)]
+    pub struct Interrupts {
+        queues: Mutex<Vec<u16>>,
+        configs: Mutex<usize>,
+        raised: Condvar,
+        resets: Option<Mutex<usize>>,
+    }
+
+    impl Interrupts {
+        /// Keeps track of interrupts for a device that's expected to
+        /// ask for a reset.
+        pub fn resettable() -> Interrupts {
+            Interrupts {
+                resets: Some(Mutex::new(0)),
+                ..Interrupts::default()
+            }
+        }
+
+        /// Waits a while for the device to ask for a reset, returning
+        /// whether it did.
+        pub fn wait_reset(&self, timeout: Duration) -> bool {
+            let started = Instant::now();
+            let resets = self.resets.as_ref().expect("not expecting a reset");
+            while *resets.lock().unwrap() == 0 {
+                if started.elapsed() >= timeout {
+                    return false;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            true
+        }
+
+        /// The queues interrupts were raised for, in order.
+        pub fn queues(&self) -> Vec<u16> {
+            self.queues.lock().unwrap().clone()
+        }
+
+        /// How many times the configuration was said to change.
+        pub fn configs(&self) -> usize {
+            *self.configs.lock().unwrap()
+        }
+
+        /// Waits a while for `count` queue interrupts in all, for
+        /// devices that raise them in the background.
+        pub fn wait(&self, count: usize, timeout: Duration) -> Vec<u16> {
+            let started = Instant::now();
+            let mut queues = self.queues.lock().unwrap();
+            while queues.len() < count {
+                let elapsed = started.elapsed();
+                if elapsed >= timeout {
+                    break;
+                }
+                queues = self
+                    .raised
+                    .wait_timeout(queues, timeout - elapsed)
+                    .unwrap()
+                    .0;
+            }
+            queues.clone()
+        }
+    }
+
+    impl Interrupt for Interrupts {
+        fn queue(&self, queue: u16) -> Result<()> {
+            self.queues.lock().unwrap().push(queue);
+            self.raised.notify_all();
+            Ok(())
+        }
+
+        fn config(&self) -> Result<()> {
+            *self.configs.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn needs_reset(&self) {
+            match self.resets {
+                Some(ref resets) => *resets.lock().unwrap() += 1,
+                None => panic!("device needs a reset"),
+            }
+        }
+    }
+
+    /// How many entries each of the driver's queues has.
+    pub const DRIVER_QUEUE_SIZE: u16 = 16;
+    /// Where the buffers the driver hands out start.
+    const DRIVER_DATA: u64 = 0x40000;
+
+    /// A descriptor chain the driver handed out, as the buffers it's
+    /// made of and whether the device writes them.
+    type Offered = Vec<(Buffer, bool)>;
+
+    /// A chain the device hasn't used yet, and the descriptors it's in.
+    #[derive(Debug)]
+    struct Outstanding {
+        descriptors: Vec<u16>,
+        buffers: Offered,
+    }
+
+    /// The driver's view of one of its queues.  For packed queues, the
+    /// indices count ring entries rather than chains, so the position
+    /// and wrap counter are the index modulo the size and twice that.
+    #[derive(Debug, Default)]
+    struct DriverQueue {
+        available: u16,
+        used: u16,
+        /// The available index as of the last notification.
+        kicked: u16,
+        /// The descriptors that aren't part of a chain, or for packed
+        /// queues, the ring entries that aren't, whose numbers serve
+        /// as buffer IDs.
+        free: Vec<u16>,
+        chains: HashMap<u16, Outstanding>,
+    }
+
+    /// Plays the driver's part for a device, with a queue of
+    /// `DRIVER_QUEUE_SIZE` entries per virtqueue, each in a region of
+    /// memory of its own.  The queues are packed if the features say
+    /// so, and split otherwise.
+    #[derive(Debug)]
+    pub struct Driver {
+        pub ram: Arc<Ram>,
+        features: u64,
+        queues: Vec<DriverQueue>,
+        next: u64,
+    }
+
+    impl Driver {
+        pub fn new(queues: u16, features: u64) -> Driver {
+            Driver {
+                ram: Arc::new(Ram::new(0, 0x100000)),
+                features,
+                queues: (0..queues)
+                    .map(|_| DriverQueue {
+                        free: (0..DRIVER_QUEUE_SIZE).rev().collect(),
+                        ..DriverQueue::default()
+                    })
+                    .collect(),
+                next: DRIVER_DATA,
+            }
+        }
+
+        /// The descriptor table; the available ring and the used ring
+        /// follow, a page apart.  For packed queues, the descriptor
+        /// ring is followed by the driver and device event suppression
+        /// structures instead.
+        fn base(queue: u16) -> u64 {
+            0x1000 + queue as u64 * 0x3000
+        }
+
+        /// What the device gets once the driver's set it up.
+        pub fn activation(&self, interrupt: Arc<Interrupt>) -> Activation {
+            let queues = (0..self.queues.len() as u16)
+                .map(|i| {
+                    let base = Driver::base(i);
+                    let queue = Queue::new(
+                        DRIVER_QUEUE_SIZE,
+                        base,
+                        base + 0x1000,
+                        base + 0x2000,
+                        self.features,
+                    );
+                    Some(queue.unwrap())
+                })
+                .collect();
+            Activation {
+                features: self.features,
+                memory: self.ram.clone(),
+                queues,
+                interrupt,
+            }
+        }
+
+        /// Makes a buffer available, filled with `data` if it's for the
+        /// device to read, or `length` bytes long otherwise.
+        pub fn offer(&mut self, queue: u16, data: &[u8], length: u32, writable: bool) {
+            self.chain(queue, &[(data, length, writable)]);
+        }
+
+        /// Makes a chain of buffers available, each given like the one
+        /// in `offer`.
+        pub fn chain(&mut self, queue: u16, buffers: &[(&[u8], u32, bool)]) {
+            let offered = buffers
+                .iter()
+                .map(|&(data, length, writable)| {
+                    let address = self.next;
+                    let length = ::std::cmp::max(length, data.len() as u32);
+                    self.next += ::std::cmp::max((length as u64 + 0xfff) & !0xfff, 0x1000);
+                    if !data.is_empty() {
+                        self.ram.write(address, data).unwrap();
+                    }
+                    (Buffer { address, length }, writable)
+                })
+                .collect::<Offered>();
+
+            let base = Driver::base(queue);
+            let packed = self.packed();
+            let state = &mut self.queues[queue as usize];
+            let descriptors = (0..offered.len())
+                .map(|_| state.free.pop().expect("out of descriptors"))
+                .collect::<Vec<_>>();
+            if packed {
+                Driver::packed_chain(&self.ram, base, state, &offered, descriptors);
+                return;
+            }
+
+            for (i, &(buffer, writable)) in offered.iter().enumerate() {
+                let descriptor = base + descriptors[i] as u64 * 16;
+                let more = i + 1 < offered.len();
+                let flags = if more { 1 } else { 0 } | if writable { 2 } else { 0 };
+                self.ram.write_u64(descriptor, buffer.address).unwrap();
+                self.ram.write_u32(descriptor + 8, buffer.length).unwrap();
+                self.ram.write_u16(descriptor + 12, flags).unwrap();
+                let next = if more { descriptors[i + 1] } else { 0 };
+                self.ram.write_u16(descriptor + 14, next).unwrap();
+            }
+
+            let head = descriptors[0];
+            let ring = base + 0x1000;
+            let slot = (state.available % DRIVER_QUEUE_SIZE) as u64;
+            self.ram.write_u16(ring + 4 + 2 * slot, head).unwrap();
+            state.available = state.available.wrapping_add(1);
+            self.ram.write_u16(ring + 2, state.available).unwrap();
+            state.chains.insert(
+                head,
+                Outstanding {
+                    descriptors,
+                    buffers: offered,
+                },
+            );
+        }
+
+        /// Writes a chain into the next entries of a packed ring, with
+        /// the number of the first free entry it took as its buffer ID.
+        /// The first descriptor's flags go last, as the device takes the
+        /// chain once it sees them.
+        fn packed_chain(
+            ram: &Ram,
+            base: u64,
+            state: &mut DriverQueue,
+            offered: &Offered,
+            descriptors: Vec<u16>,
+        ) {
+            let id = descriptors[0];
+            let mut first = 0;
+            for (i, &(buffer, writable)) in offered.iter().enumerate() {
+                let (index, wrap) = Driver::position(state.available);
+                let descriptor = base + index as u64 * 16;
+                let more = i + 1 < offered.len();
+                let mut flags = if more { 1 } else { 0 } | if writable { 2 } else { 0 };
+                flags |= if wrap { 1 << 7 } else { 1 << 15 };
+                ram.write_u64(descriptor, buffer.address).unwrap();
+                ram.write_u32(descriptor + 8, buffer.length).unwrap();
+                ram.write_u16(descriptor + 12, id).unwrap();
+                if i == 0 {
+                    first = flags;
+                } else {
+                    ram.write_u16(descriptor + 14, flags).unwrap();
+                }
+                state.available = state.available.wrapping_add(1);
+            }
+
+            let (index, _) = Driver::position(state.available.wrapping_sub(offered.len() as u16));
+            ram.write_u16(base + index as u64 * 16 + 14, first).unwrap();
+            state.chains.insert(
+                id,
+                Outstanding {
+                    descriptors,
+                    buffers: offered.clone(),
+                },
+            );
+        }
+
+        /// The packed ring entry and wrap counter an index stands for.
+        fn position(index: u16) -> (u16, bool) {
+            let index = index % (2 * DRIVER_QUEUE_SIZE);
+            (index % DRIVER_QUEUE_SIZE, index < DRIVER_QUEUE_SIZE)
+        }
+
+        fn packed(&self) -> bool {
+            (self.features & VIRTIO_F_RING_PACKED) != 0
+        }
+
+        fn event_idx(&self) -> bool {
+            (self.features & VIRTIO_F_EVENT_IDX) != 0
+        }
+
+        /// Whether the driver would notify the device about the chains
+        /// it made available since the last time this was asked, going
+        /// by what the device asked for.
+        pub fn kicks(&mut self, queue: u16) -> bool {
+            let ring = Driver::base(queue) + 0x2000;
+            let (packed, event_idx) = (self.packed(), self.event_idx());
+            let state = &mut self.queues[queue as usize];
+            let (old, new) = (state.kicked, state.available);
+            state.kicked = new;
+            if old == new {
+                false
+            } else if packed {
+                match self.ram.read_u16(ring + 2).unwrap() & 0x3 {
+                    1 => false,
+                    2 if event_idx => {
+                        // Going by the device's event as an index like
+                        // ours, is it in [old, new)?
+                        let event = self.ram.read_u16(ring).unwrap();
+                        let lap = if (event & 0x8000) != 0 {
+                            0
+                        } else {
+                            DRIVER_QUEUE_SIZE
+                        };
+                        let event = (event & 0x7fff) + lap;
+                        let laps = 2 * DRIVER_QUEUE_SIZE;
+                        let distance = |to: u16| to.wrapping_sub(old) % laps;
+                        distance(event) < distance(new)
+                    }
+                    _ => true,
+                }
+            } else if event_idx {
+                let offset = 4 + 8 * DRIVER_QUEUE_SIZE as u64;
+                let event = self.ram.read_u16(ring + offset).unwrap();
+                new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+            } else {
+                (self.ram.read_u16(ring).unwrap() & 1) == 0
+            }
+        }
+
+        /// Takes what the device wrote into every chain it used since
+        /// the last time.
+        pub fn used(&mut self, queue: u16) -> Vec<Vec<u8>> {
+            if self.packed() {
+                return self.packed_used(queue);
+            }
+
+            let ring = Driver::base(queue) + 0x2000;
+            let event_idx = self.event_idx();
+            let state = &mut self.queues[queue as usize];
+            let used = self.ram.read_u16(ring + 2).unwrap();
+            let mut contents = vec![];
+            while state.used != used {
+                let element = ring + 4 + (state.used % DRIVER_QUEUE_SIZE) as u64 * 8;
+                let head = self.ram.read_u32(element).unwrap() as u16;
+                let length = self.ram.read_u32(element + 4).unwrap() as usize;
+                let chain = state.chains.remove(&head).expect("unknown chain used");
+                contents.push(Driver::written(&self.ram, &chain.buffers, length));
+                state.free.extend(chain.descriptors);
+                state.used = state.used.wrapping_add(1);
+            }
+
+            // Like a driver that's done, ask for an interrupt for the
+            // next chain the device uses.
+            if event_idx {
+                let offset = 0x1000 + 4 + 2 * DRIVER_QUEUE_SIZE as u64;
+                let event = Driver::base(queue) + offset;
+                self.ram.write_u16(event, state.used).unwrap();
+            }
+            contents
+        }
+
+        /// Takes the used chains off a packed ring, which are there once
+        /// both flags of the descriptor match the wrap counter.
+        fn packed_used(&mut self, queue: u16) -> Vec<Vec<u8>> {
+            let base = Driver::base(queue);
+            let event_idx = self.event_idx();
+            let state = &mut self.queues[queue as usize];
+            let mut contents = vec![];
+            loop {
+                let (index, wrap) = Driver::position(state.used);
+                let descriptor = base + index as u64 * 16;
+                let flags = self.ram.read_u16(descriptor + 14).unwrap();
+                let expected = if wrap { (1 << 7) | (1 << 15) } else { 0 };
+                if (flags & ((1 << 7) | (1 << 15))) != expected || state.used == state.available {
+                    break;
+                }
+
+                let length = self.ram.read_u32(descriptor + 8).unwrap() as usize;
+                let id = self.ram.read_u16(descriptor + 12).unwrap();
+                let chain = state.chains.remove(&id).expect("unknown chain used");
+                contents.push(Driver::written(&self.ram, &chain.buffers, length));
+                state.used = state.used.wrapping_add(chain.descriptors.len() as u16);
+                state.free.extend(chain.descriptors);
+            }
+
+            if event_idx {
+                let (index, wrap) = Driver::position(state.used);
+                let event = index | if wrap { 0x8000 } else { 0 };
+                self.ram.write_u16(base + 0x1000, event).unwrap();
+                self.ram.write_u16(base + 0x1000 + 2, 2).unwrap();
+            }
+            contents
+        }
+
+        /// Waits a while for the device to use something, for devices
+        /// that work in the background.
+        pub fn wait(&mut self, queue: u16) -> Vec<Vec<u8>> {
+            let started = Instant::now();
+            loop {
+                let used = self.used(queue);
+                if !used.is_empty() {
+                    return used;
+                }
+                assert!(started.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        /// The first `length` bytes of the writable buffers of a chain.
+        fn written(ram: &Ram, chain: &Offered, length: usize) -> Vec<u8> {
+            let mut data = vec![];
+            for &(buffer, _) in chain.iter().filter(|&&(_, writable)| writable) {
+                let size = ::std::cmp::min(buffer.length as usize, length - data.len());
+                let mut part = vec![0u8; size];
+                ram.read(buffer.address, &mut part).unwrap();
+                data.extend(part);
+            }
+            data
+        }
+    }
+
+    fn chain() -> Chain {
+        let mut chain = Chain::new(3);
+        chain.push(0x1000, 4, false).unwrap();
+        chain.push(0x2000, 0, false).unwrap();
+        chain.push(0x3000, 6, false).unwrap();
+        chain.push(0x4000, 3, true).unwrap();
+        chain.push(0x5000, 5, true).unwrap();
+        chain
+    }
+
+    #[test]
+    fn it_orders_readable_before_writable() {
+        let mut chain = chain();
+        assert_eq!(chain.head(), 3);
+        assert_eq!(chain.readable.len(), 3);
+        assert_eq!(chain.writable.len(), 2);
+        assert_eq!(chain.readable_len(), 10);
+        assert_eq!(chain.writable_len(), 8);
+        assert!(chain.push(0x6000, 1, false).is_err());
+    }
+
+    #[test]
+    fn it_rejects_buffers_that_wrap() {
+        let mut chain = Chain::new(0);
+        assert!(chain.push(!0 - 1, 2, false).is_err());
+        assert!(chain.push(!0 - 1, 1, false).is_ok());
+    }
+
+    #[test]
+    fn it_reads_across_buffers() {
+        let ram = Ram::new(0x1000, 0x5000);
+        ram.write(0x1000, b"abcd").unwrap();
+        ram.write(0x3000, b"efghij").unwrap();
+
+        let chain = chain();
+        let mut reader = chain.reader(&ram);
+        let mut data = [0u8; 7];
+        assert_eq!(reader.read(&mut data).unwrap(), 7);
+        assert_eq!(&data, b"abcdefg");
+        assert_eq!(reader.remaining(), 3);
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"hij");
+        assert_eq!(reader.read(&mut data).unwrap(), 0);
+    }
+
+    #[test]
+    fn it_writes_across_buffers() {
+        let ram = Ram::new(0x1000, 0x5000);
+        let chain = chain();
+        let mut writer = chain.writer(&ram);
+        assert_eq!(writer.write(b"0123456789").unwrap(), 8);
+        assert_eq!(writer.written(), 8);
+        assert_eq!(writer.remaining(), 0);
+        assert!(writer.write_all(b"x").is_err());
+
+        let mut data = [0u8; 5];
+        ram.read(0x4000, &mut data[..3]).unwrap();
+        assert_eq!(&data[..3], b"012");
+        ram.read(0x5000, &mut data).unwrap();
+        assert_eq!(&data, b"34567");
+    }
+
+    #[test]
+    fn it_fails_outside_guest_memory() {
+        let ram = Ram::new(0x1000, 0x1000);
+        let chain = chain();
+        let mut data = [0u8; 10];
+        assert!(chain.reader(&ram).read(&mut data).is_err());
+    }
+
+    #[test]
+    fn it_hands_chains_between_driver_and_device() {
+        let mut driver = Driver::new(1, 0);
+        let base = Driver::base(0);
+        let mut queue = SplitQueue::new(
+            DRIVER_QUEUE_SIZE,
+            base,
+            base + 0x1000,
+            base + 0x2000,
+            driver.features,
+        )
+        .unwrap();
+        let memory = driver.ram.clone();
+
+        // Going round the ring a few times hands every descriptor back
+        // to the driver more than once.
+        let buffers: &[(&[u8], u32, bool)] = &[(b"ping", 0, false), (&[], 2, true), (&[], 4, true)];
+        for _ in 0..2 * DRIVER_QUEUE_SIZE {
+            driver.chain(0, buffers);
+            let chain = queue.pop(memory.as_ref()).unwrap().unwrap();
+            let mut request = vec![];
+            chain
+                .reader(memory.as_ref())
+                .read_to_end(&mut request)
+                .unwrap();
+            assert_eq!(request, b"ping");
+
+            let mut writer = chain.writer(memory.as_ref());
+            writer.write_all(b"pong").unwrap();
+            let written = writer.written();
+            queue.push(memory.as_ref(), chain.head(), written).unwrap();
+            assert_eq!(driver.used(0), vec![b"pong".to_vec()]);
+        }
+        assert_eq!(queue.pop(memory.as_ref()).unwrap(), None);
+    }
+}
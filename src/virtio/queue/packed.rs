@@ -0,0 +1,737 @@
+use super::{Chain, MAX_QUEUE_SIZE, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
+use byteorder::{ByteOrder, LittleEndian};
+use device::memory::GuestMemory;
+use error::*;
+use std::sync::atomic::{fence, Ordering};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const DESCRIPTOR_LENGTH: u64 = 8;
+const DESCRIPTOR_ID: u64 = 12;
+const DESCRIPTOR_FLAGS: u64 = 14;
+
+/// Offsets into the driver and device event suppression structures.
+const EVENT_OFFSET_WRAP: u64 = 0;
+const EVENT_FLAGS: u64 = 2;
+
+const EVENT_WRAP: u16 = 1 << 15;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    id: u16,
+    flags: u16,
+}
+
+impl Descriptor {
+    fn read(memory: &GuestMemory, table: u64, index: u16) -> Result<Descriptor> {
+        let address = table + index as u64 * DESCRIPTOR_SIZE;
+        let mut data = [0u8; DESCRIPTOR_SIZE as usize];
+        memory.read(address, &mut data)?;
+
+        Ok(Descriptor {
+            address: LittleEndian::read_u64(&data[0..8]),
+            length: LittleEndian::read_u32(&data[8..12]),
+            id: LittleEndian::read_u16(&data[12..14]),
+            flags: LittleEndian::read_u16(&data[14..16]),
+        })
+    }
+
+    fn has(&self, flag: u16) -> bool {
+        (self.flags & flag) != 0
+    }
+
+    /// Whether the driver has made this descriptor available in the
+    /// lap of the ring with the given wrap counter.
+    fn is_available(&self, wrap: bool) -> bool {
+        self.has(VIRTQ_DESC_F_AVAIL) == wrap && self.has(VIRTQ_DESC_F_USED) != wrap
+    }
+}
+
+fn error<T>(reason: &'static str) -> Result<T> {
+    Err(ErrorKind::QueueError(reason).into())
+}
+
+/// A position in the ring: an index, and the wrap counter for the lap
+/// of the ring it's in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Position {
+    index: u16,
+    wrap: bool,
+}
+
+impl Position {
+    fn start() -> Position {
+        Position {
+            index: 0,
+            wrap: true,
+        }
+    }
+
+    fn advance(&mut self, by: u16, size: u16) {
+        let next = self.index as u32 + by as u32;
+        if next >= size as u32 {
+            self.index = (next - size as u32) as u16;
+            self.wrap = !self.wrap;
+        } else {
+            self.index = next as u16;
+        }
+    }
+
+    /// The position as a single number counting through two laps of
+    /// the ring, which is as far as the wrap counter can tell apart.
+    fn linear(&self, size: u16) -> u32 {
+        self.index as u32 + if self.wrap { size as u32 } else { 0 }
+    }
+
+    fn event(&self) -> u16 {
+        self.index | if self.wrap { EVENT_WRAP } else { 0 }
+    }
+}
+
+/// The device's side of a packed virtqueue: a single descriptor ring
+/// that the driver and device both write to, told apart by wrap
+/// counters, plus an event suppression structure for each side.
+/// Chains are identified by the buffer ID the driver gave them, which
+/// is what `Chain::head` holds.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PackedQueue {
+    size: u16,
+    descriptors: u64,
+    driver: u64,
+    device: u64,
+    indirect: bool,
+    event_idx: bool,
+    next_available: Position,
+    next_used: Position,
+    /// The used position as of the last time we checked whether to
+    /// interrupt the driver.
+    signalled: Position,
+    /// How many ring entries each outstanding buffer takes up, by
+    /// buffer ID, so that the used position can skip over them.
+    outstanding: Vec<u16>,
+}
+
+impl PackedQueue {
+    /// Creates a queue over the given descriptor ring and driver and
+    /// device event suppression structures, as configured by the
+    /// driver, with the given negotiated features.
+    pub fn new(
+        size: u16,
+        descriptors: u64,
+        driver: u64,
+        device: u64,
+        features: u64,
+    ) -> Result<PackedQueue> {
+        if size == 0 || size > MAX_QUEUE_SIZE {
+            return error("queue size is out of range");
+        }
+
+        if descriptors % 16 != 0 || driver % 4 != 0 || device % 4 != 0 {
+            return error("rings are misaligned");
+        }
+
+        Ok(PackedQueue {
+            size,
+            descriptors,
+            driver,
+            device,
+            indirect: (features & VIRTIO_F_INDIRECT_DESC) != 0,
+            event_idx: (features & VIRTIO_F_EVENT_IDX) != 0,
+            next_available: Position::start(),
+            next_used: Position::start(),
+            signalled: Position::start(),
+            outstanding: vec![0; size as usize],
+        })
+    }
+
+    /// Takes the next chain the driver made available, if there is
+    /// one.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<Chain>> {
+        let first = Descriptor::read(memory, self.descriptors, self.next_available.index)?;
+        if !first.is_available(self.next_available.wrap) {
+            return Ok(None);
+        }
+
+        // The driver makes the first descriptor of a chain available
+        // last; don't read the rest of the chain before its flags.
+        fence(Ordering::Acquire);
+
+        let mut chain = Chain::new(0);
+        let mut position = self.next_available;
+        let mut descriptor = first;
+        let mut count = 0;
+
+        loop {
+            count += 1;
+            if count > self.size {
+                return error("descriptor chain is longer than the ring");
+            }
+
+            if descriptor.has(VIRTQ_DESC_F_INDIRECT) {
+                if !self.indirect {
+                    return error("indirect descriptors were not negotiated");
+                }
+
+                if descriptor.has(VIRTQ_DESC_F_NEXT) {
+                    return error("indirect descriptor has a next descriptor");
+                }
+
+                self.walk_indirect(memory, descriptor, &mut chain)?;
+            } else {
+                chain.push(
+                    descriptor.address,
+                    descriptor.length,
+                    descriptor.has(VIRTQ_DESC_F_WRITE),
+                )?;
+            }
+
+            position.advance(1, self.size);
+            if !descriptor.has(VIRTQ_DESC_F_NEXT) {
+                break;
+            }
+
+            descriptor = Descriptor::read(memory, self.descriptors, position.index)?;
+        }
+
+        // The buffer ID is the one in the last descriptor of the chain.
+        let id = descriptor.id;
+        if id >= self.size {
+            return error("buffer ID out of range");
+        }
+
+        if self.outstanding[id as usize] != 0 {
+            return error("buffer ID is already in use");
+        }
+
+        self.outstanding[id as usize] = count;
+        self.next_available = position;
+        chain.head = id;
+        Ok(Some(chain))
+    }
+
+    /// Follows an indirect table, whose entries form a single chain in
+    /// the order they appear.
+    fn walk_indirect(
+        &self,
+        memory: &GuestMemory,
+        table: Descriptor,
+        chain: &mut Chain,
+    ) -> Result<()> {
+        let entries = table.length as u64 / DESCRIPTOR_SIZE;
+        if table.length == 0
+            || table.length as u64 % DESCRIPTOR_SIZE != 0
+            || entries > MAX_QUEUE_SIZE as u64
+        {
+            return error("indirect table has an invalid length");
+        }
+
+        for index in 0..entries as u16 {
+            let descriptor = Descriptor::read(memory, table.address, index)?;
+            if descriptor.has(VIRTQ_DESC_F_INDIRECT) {
+                return error("indirect table refers to another indirect table");
+            }
+
+            chain.push(
+                descriptor.address,
+                descriptor.length,
+                descriptor.has(VIRTQ_DESC_F_WRITE),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Hands a buffer back to the driver by writing a used descriptor,
+    /// noting how many bytes were written into it.
+    pub fn push(&mut self, memory: &GuestMemory, id: u16, written: u32) -> Result<()> {
+        let count = match self.outstanding.get(id as usize) {
+            Some(&count) if count != 0 => count,
+            _ => return error("buffer ID is not in use"),
+        };
+
+        let address = self.descriptors + self.next_used.index as u64 * DESCRIPTOR_SIZE;
+        memory.write_u32(address + DESCRIPTOR_LENGTH, written)?;
+        memory.write_u16(address + DESCRIPTOR_ID, id)?;
+
+        // The driver mustn't see the descriptor as used before its
+        // contents.
+        fence(Ordering::Release);
+        let flags = if self.next_used.wrap {
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+        } else {
+            0
+        };
+        memory.write_u16(address + DESCRIPTOR_FLAGS, flags)?;
+
+        self.outstanding[id as usize] = 0;
+        self.next_used.advance(count, self.size);
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for the buffers used since
+    /// the last time this was asked.
+    pub fn needs_notification(&mut self, memory: &GuestMemory) -> Result<bool> {
+        fence(Ordering::SeqCst);
+
+        let new = self.next_used;
+        let old = self.signalled;
+        self.signalled = new;
+        if old == new {
+            return Ok(false);
+        }
+
+        let flags = memory.read_u16(self.driver + EVENT_FLAGS)?;
+        match flags & 0x3 {
+            RING_EVENT_FLAGS_DISABLE => Ok(false),
+            RING_EVENT_FLAGS_DESC if self.event_idx => {
+                // Notify if the driver's event falls in [old, new).
+                let event = memory.read_u16(self.driver + EVENT_OFFSET_WRAP)?;
+                let event = Position {
+                    index: event & !EVENT_WRAP,
+                    wrap: (event & EVENT_WRAP) != 0,
+                };
+                let laps = 2 * self.size as u32;
+                let distance =
+                    |to: Position| (to.linear(self.size) + laps - old.linear(self.size)) % laps;
+                Ok(distance(event) < distance(new))
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Asks the driver to notify us when it makes more chains
+    /// available.  Returns whether some already are, in which case
+    /// the caller should go and process them rather than wait.
+    pub fn enable_notification(&mut self, memory: &GuestMemory) -> Result<bool> {
+        if self.event_idx {
+            memory.write_u16(self.device + EVENT_OFFSET_WRAP, self.next_available.event())?;
+            memory.write_u16(self.device + EVENT_FLAGS, RING_EVENT_FLAGS_DESC)?;
+        } else {
+            memory.write_u16(self.device + EVENT_FLAGS, RING_EVENT_FLAGS_ENABLE)?;
+        }
+
+        fence(Ordering::SeqCst);
+        let next = Descriptor::read(memory, self.descriptors, self.next_available.index)?;
+        Ok(next.is_available(self.next_available.wrap))
+    }
+
+    /// Tells the driver it needn't notify us about new chains, because
+    /// we're already processing the queue.
+    pub fn disable_notification(&mut self, memory: &GuestMemory) -> Result<()> {
+        memory.write_u16(self.device + EVENT_FLAGS, RING_EVENT_FLAGS_DISABLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::super::device::memory::GuestMemory;
+    use super::super::tests::Ram;
+    use super::super::{
+        Buffer, Queue, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_RING_PACKED,
+    };
+    use super::{
+        PackedQueue, RING_EVENT_FLAGS_DESC, RING_EVENT_FLAGS_DISABLE, RING_EVENT_FLAGS_ENABLE,
+        VIRTQ_DESC_F_AVAIL, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_USED,
+        VIRTQ_DESC_F_WRITE,
+    };
+
+    const DESCRIPTORS: u64 = 0x1000;
+    const DRIVER: u64 = 0x2000;
+    const DEVICE: u64 = 0x3000;
+    const INDIRECT: u64 = 0x4000;
+    const DATA: u64 = 0x8000;
+
+    /// Plays the driver's part, filling in the descriptor ring.
+    struct Driver {
+        ram: Ram,
+        size: u16,
+        next: u16,
+        wrap: bool,
+    }
+
+    impl Driver {
+        fn new(size: u16) -> Driver {
+            Driver {
+                ram: Ram::new(0, 0x10000),
+                size,
+                next: 0,
+                wrap: true,
+            }
+        }
+
+        fn queue(&self, features: u64) -> PackedQueue {
+            PackedQueue::new(self.size, DESCRIPTORS, DRIVER, DEVICE, features).unwrap()
+        }
+
+        fn write(&self, table: u64, index: u16, address: u64, length: u32, id: u16, flags: u16) {
+            let base = table + index as u64 * 16;
+            self.ram.write_u64(base, address).unwrap();
+            self.ram.write_u32(base + 8, length).unwrap();
+            self.ram.write_u16(base + 12, id).unwrap();
+            self.ram.write_u16(base + 14, flags).unwrap();
+        }
+
+        /// Makes a chain of buffers available, chaining them together
+        /// and making the first one available last, like a driver
+        /// would.
+        fn offer(&mut self, buffers: &[(u64, u32, u16)], id: u16) {
+            let mut first = None;
+            for (i, &(address, length, flags)) in buffers.iter().enumerate() {
+                let mut flags = flags;
+                if i + 1 < buffers.len() {
+                    flags |= VIRTQ_DESC_F_NEXT;
+                }
+                flags |= if self.wrap {
+                    VIRTQ_DESC_F_AVAIL
+                } else {
+                    VIRTQ_DESC_F_USED
+                };
+
+                if i == 0 {
+                    self.write(DESCRIPTORS, self.next, address, length, id, 0);
+                    first = Some((self.next, flags));
+                } else {
+                    self.write(DESCRIPTORS, self.next, address, length, id, flags);
+                }
+
+                self.next += 1;
+                if self.next == self.size {
+                    self.next = 0;
+                    self.wrap = !self.wrap;
+                }
+            }
+
+            let (index, flags) = first.unwrap();
+            self.ram
+                .write_u16(DESCRIPTORS + index as u64 * 16 + 14, flags)
+                .unwrap();
+        }
+
+        /// The buffer ID, length, and whether the descriptor at the
+        /// given index was marked used in the given lap.
+        fn used(&self, index: u16, wrap: bool) -> (u16, u32, bool) {
+            let base = DESCRIPTORS + index as u64 * 16;
+            let flags = self.ram.read_u16(base + 14).unwrap();
+            let used = ((flags & VIRTQ_DESC_F_AVAIL) != 0) == wrap
+                && ((flags & VIRTQ_DESC_F_USED) != 0) == wrap;
+            (
+                self.ram.read_u16(base + 12).unwrap(),
+                self.ram.read_u32(base + 8).unwrap(),
+                used,
+            )
+        }
+
+        fn set_event(&self, flags: u16, index: u16, wrap: bool) {
+            let wrap = if wrap { 1 << 15 } else { 0 };
+            self.ram.write_u16(DRIVER, index | wrap).unwrap();
+            self.ram.write_u16(DRIVER + 2, flags).unwrap();
+        }
+    }
+
+    fn buffer(address: u64, length: u32) -> Buffer {
+        Buffer { address, length }
+    }
+
+    #[test]
+    fn it_rejects_invalid_layouts() {
+        assert!(PackedQueue::new(0, DESCRIPTORS, DRIVER, DEVICE, 0).is_err());
+        assert!(PackedQueue::new(32769, DESCRIPTORS, DRIVER, DEVICE, 0).is_err());
+        assert!(PackedQueue::new(5, DESCRIPTORS, DRIVER, DEVICE, 0).is_ok());
+        assert!(PackedQueue::new(8, DESCRIPTORS + 8, DRIVER, DEVICE, 0).is_err());
+        assert!(PackedQueue::new(8, DESCRIPTORS, DRIVER + 2, DEVICE, 0).is_err());
+        assert!(PackedQueue::new(8, DESCRIPTORS, DRIVER, DEVICE + 2, 0).is_err());
+    }
+
+    #[test]
+    fn it_is_chosen_by_feature() {
+        let packed = Queue::new(8, DESCRIPTORS, DRIVER, DEVICE, VIRTIO_F_RING_PACKED).unwrap();
+        let split = Queue::new(8, DESCRIPTORS, DRIVER, DEVICE, 0).unwrap();
+        match (packed, split) {
+            (Queue::Packed(_), Queue::Split(_)) => (),
+            other => panic!("wrong layouts: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_pops_nothing_from_an_empty_ring() {
+        let driver = Driver::new(8);
+        let mut queue = driver.queue(0);
+        assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+
+        // A descriptor marked for the wrong lap isn't available either.
+        driver.write(DESCRIPTORS, 0, DATA, 1, 0, VIRTQ_DESC_F_USED);
+        assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+        driver.write(
+            DESCRIPTORS,
+            0,
+            DATA,
+            1,
+            0,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED,
+        );
+        assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+    }
+
+    #[test]
+    fn it_pops_a_chain() {
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(0);
+        driver.offer(
+            &[
+                (DATA, 0x10, 0),
+                (DATA + 0x10, 0x20, 0),
+                (DATA + 0x100, 0x40, VIRTQ_DESC_F_WRITE),
+            ],
+            6,
+        );
+
+        let chain = queue.pop(&driver.ram).unwrap().unwrap();
+        assert_eq!(chain.head(), 6);
+        assert_eq!(
+            chain.readable,
+            vec![buffer(DATA, 0x10), buffer(DATA + 0x10, 0x20)]
+        );
+        assert_eq!(chain.writable, vec![buffer(DATA + 0x100, 0x40)]);
+        assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+    }
+
+    #[test]
+    fn it_goes_around_the_ring_many_times() {
+        // An odd size, so chains straddle the end of the ring.
+        let mut driver = Driver::new(5);
+        let mut queue = driver.queue(0);
+        let mut used = (0, true);
+
+        for round in 0..20u16 {
+            let id = round % 5;
+            driver.offer(&[(DATA, 1, 0), (DATA + 1, 1, VIRTQ_DESC_F_WRITE)], id);
+            let chain = queue.pop(&driver.ram).unwrap().unwrap();
+            assert_eq!(chain.head(), id);
+            assert_eq!(chain.readable_len(), 1);
+            assert_eq!(chain.writable_len(), 1);
+            assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+
+            queue.push(&driver.ram, id, round as u32).unwrap();
+            assert_eq!(driver.used(used.0, used.1), (id, round as u32, true));
+            used.0 += 2;
+            if used.0 >= 5 {
+                used.0 -= 5;
+                used.1 = !used.1;
+            }
+        }
+    }
+
+    #[test]
+    fn it_uses_buffers_out_of_order() {
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(0);
+        driver.offer(&[(DATA, 1, 0), (DATA, 1, 0)], 1);
+        driver.offer(&[(DATA, 1, 0)], 2);
+        queue.pop(&driver.ram).unwrap().unwrap();
+        queue.pop(&driver.ram).unwrap().unwrap();
+
+        queue.push(&driver.ram, 2, 0x22).unwrap();
+        assert_eq!(driver.used(0, true), (2, 0x22, true));
+        queue.push(&driver.ram, 1, 0x11).unwrap();
+        assert_eq!(driver.used(1, true), (1, 0x11, true));
+
+        // Both buffers took three entries between them.
+        driver.offer(&[(DATA, 1, 0)], 1);
+        queue.pop(&driver.ram).unwrap().unwrap();
+        queue.push(&driver.ram, 1, 0x33).unwrap();
+        assert_eq!(driver.used(3, true), (1, 0x33, true));
+    }
+
+    #[test]
+    fn it_rejects_bad_buffer_ids() {
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(0);
+        assert!(queue.push(&driver.ram, 0, 0).is_err());
+        assert!(queue.push(&driver.ram, 8, 0).is_err());
+
+        driver.offer(&[(DATA, 1, 0)], 8);
+        assert!(queue.pop(&driver.ram).is_err());
+
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(0);
+        driver.offer(&[(DATA, 1, 0)], 3);
+        driver.offer(&[(DATA, 1, 0)], 3);
+        queue.pop(&driver.ram).unwrap().unwrap();
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_rejects_chains_longer_than_the_ring() {
+        let mut driver = Driver::new(4);
+        let mut queue = driver.queue(0);
+        driver.offer(&[(DATA, 1, 0); 4], 0);
+
+        // Chain the last descriptor back around to the first.
+        let flags = driver.ram.read_u16(DESCRIPTORS + 3 * 16 + 14).unwrap();
+        driver
+            .ram
+            .write_u16(DESCRIPTORS + 3 * 16 + 14, flags | VIRTQ_DESC_F_NEXT)
+            .unwrap();
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_rejects_readable_after_writable() {
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(0);
+        driver.offer(&[(DATA, 1, VIRTQ_DESC_F_WRITE), (DATA, 1, 0)], 0);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_follows_indirect_tables() {
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(VIRTIO_F_INDIRECT_DESC);
+        driver.write(INDIRECT, 0, DATA + 0x10, 0x10, 0, 0);
+        driver.write(INDIRECT, 1, DATA + 0x20, 0x20, 0, VIRTQ_DESC_F_WRITE);
+        driver.offer(&[(INDIRECT, 32, VIRTQ_DESC_F_INDIRECT)], 4);
+
+        let chain = queue.pop(&driver.ram).unwrap().unwrap();
+        assert_eq!(chain.head(), 4);
+        assert_eq!(chain.readable, vec![buffer(DATA + 0x10, 0x10)]);
+        assert_eq!(chain.writable, vec![buffer(DATA + 0x20, 0x20)]);
+
+        // The whole chain takes a single entry in the ring.
+        queue.push(&driver.ram, 4, 0).unwrap();
+        driver.offer(&[(DATA, 1, 0)], 5);
+        queue.pop(&driver.ram).unwrap().unwrap();
+        queue.push(&driver.ram, 5, 0).unwrap();
+        assert_eq!(driver.used(1, true), (5, 0, true));
+    }
+
+    #[test]
+    fn it_rejects_malformed_indirect_tables() {
+        let cases: &[(u32, u16, u64, bool)] = &[
+            // Not negotiated.
+            (16, VIRTQ_DESC_F_INDIRECT, 0, false),
+            // Empty table.
+            (0, VIRTQ_DESC_F_INDIRECT, VIRTIO_F_INDIRECT_DESC, false),
+            // Length not a multiple of the descriptor size.
+            (24, VIRTQ_DESC_F_INDIRECT, VIRTIO_F_INDIRECT_DESC, false),
+            // Nested indirect table.
+            (16, VIRTQ_DESC_F_INDIRECT, VIRTIO_F_INDIRECT_DESC, true),
+        ];
+
+        for &(length, flags, features, nested) in cases {
+            let mut driver = Driver::new(8);
+            let mut queue = driver.queue(features);
+            let entry = if nested { VIRTQ_DESC_F_INDIRECT } else { 0 };
+            driver.write(INDIRECT, 0, DATA, 1, 0, entry);
+            driver.offer(&[(INDIRECT, length, flags)], 0);
+            assert!(
+                queue.pop(&driver.ram).is_err(),
+                "{:?}",
+                (length, flags, features)
+            );
+        }
+
+        // Both indirect and next.
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(VIRTIO_F_INDIRECT_DESC);
+        driver.write(INDIRECT, 0, DATA, 1, 0, 0);
+        driver.offer(&[(INDIRECT, 16, VIRTQ_DESC_F_INDIRECT), (DATA, 1, 0)], 0);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_honours_driver_event_flags() {
+        let mut driver = Driver::new(8);
+        let mut queue = driver.queue(0);
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+
+        for &(flags, expected) in &[
+            (RING_EVENT_FLAGS_ENABLE, true),
+            (RING_EVENT_FLAGS_DISABLE, false),
+            // Without event indices, this is treated as enabled.
+            (RING_EVENT_FLAGS_DESC, true),
+        ] {
+            driver.set_event(flags, 0, false);
+            driver.offer(&[(DATA, 1, 0)], 0);
+            queue.pop(&driver.ram).unwrap().unwrap();
+            queue.push(&driver.ram, 0, 0).unwrap();
+            assert_eq!(queue.needs_notification(&driver.ram).unwrap(), expected);
+            assert!(!queue.needs_notification(&driver.ram).unwrap());
+        }
+    }
+
+    #[test]
+    fn it_honours_driver_event_descriptors() {
+        let mut driver = Driver::new(4);
+        let mut queue = driver.queue(VIRTIO_F_EVENT_IDX);
+        let mut cycle = |driver: &mut Driver, queue: &mut PackedQueue| {
+            driver.offer(&[(DATA, 1, 0)], 0);
+            queue.pop(&driver.ram).unwrap().unwrap();
+            queue.push(&driver.ram, 0, 0).unwrap();
+        };
+
+        // The driver wants to hear about the descriptor at index 1 of
+        // the first lap being used.
+        driver.set_event(RING_EVENT_FLAGS_DESC, 1, true);
+        cycle(&mut driver, &mut queue);
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+        cycle(&mut driver, &mut queue);
+        assert!(queue.needs_notification(&driver.ram).unwrap());
+
+        // Then about index 0 in the second lap, which is three more
+        // buffers away.
+        driver.set_event(RING_EVENT_FLAGS_DESC, 0, false);
+        cycle(&mut driver, &mut queue);
+        cycle(&mut driver, &mut queue);
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+        cycle(&mut driver, &mut queue);
+        assert!(queue.needs_notification(&driver.ram).unwrap());
+
+        // An event for the same index in the wrong lap is far behind.
+        driver.set_event(RING_EVENT_FLAGS_DESC, 1, true);
+        cycle(&mut driver, &mut queue);
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+    }
+
+    #[test]
+    fn it_publishes_device_events() {
+        let mut driver = Driver::new(4);
+        let mut queue = driver.queue(0);
+        queue.disable_notification(&driver.ram).unwrap();
+        assert_eq!(
+            driver.ram.read_u16(DEVICE + 2).unwrap(),
+            RING_EVENT_FLAGS_DISABLE
+        );
+        assert!(!queue.enable_notification(&driver.ram).unwrap());
+        assert_eq!(
+            driver.ram.read_u16(DEVICE + 2).unwrap(),
+            RING_EVENT_FLAGS_ENABLE
+        );
+
+        let mut queue = driver.queue(VIRTIO_F_EVENT_IDX);
+        for id in 0..4 {
+            driver.offer(&[(DATA, 1, 0)], id);
+        }
+        assert!(queue.enable_notification(&driver.ram).unwrap());
+        for id in 0..4 {
+            queue.pop(&driver.ram).unwrap().unwrap();
+            queue.push(&driver.ram, id, 0).unwrap();
+        }
+        assert!(!queue.enable_notification(&driver.ram).unwrap());
+        assert_eq!(
+            driver.ram.read_u16(DEVICE + 2).unwrap(),
+            RING_EVENT_FLAGS_DESC
+        );
+        assert_eq!(driver.ram.read_u16(DEVICE).unwrap(), 0);
+    }
+}
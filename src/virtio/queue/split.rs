@@ -0,0 +1,712 @@
+use super::{Chain, MAX_QUEUE_SIZE, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
+use byteorder::{ByteOrder, LittleEndian};
+use device::memory::GuestMemory;
+use error::*;
+use std::sync::atomic::{fence, Ordering};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const USED_ELEMENT_SIZE: u64 = 8;
+
+/// Offsets of the fields shared by the available and used rings.
+const RING_FLAGS: u64 = 0;
+const RING_INDEX: u64 = 2;
+const RING_ENTRIES: u64 = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl Descriptor {
+    fn read(memory: &GuestMemory, table: u64, index: u16) -> Result<Descriptor> {
+        let address = table + index as u64 * DESCRIPTOR_SIZE;
+        let mut data = [0u8; DESCRIPTOR_SIZE as usize];
+        memory.read(address, &mut data)?;
+
+        Ok(Descriptor {
+            address: LittleEndian::read_u64(&data[0..8]),
+            length: LittleEndian::read_u32(&data[8..12]),
+            flags: LittleEndian::read_u16(&data[12..14]),
+            next: LittleEndian::read_u16(&data[14..16]),
+        })
+    }
+
+    fn has(&self, flag: u16) -> bool {
+        (self.flags & flag) != 0
+    }
+}
+
+fn error<T>(reason: &'static str) -> Result<T> {
+    Err(ErrorKind::QueueError(reason).into())
+}
+
+/// The device's side of a split virtqueue: a descriptor table, an
+/// available ring the driver fills, and a used ring we fill.  Nothing
+/// the driver writes is trusted; a malformed chain is reported as a
+/// `QueueError`, after which the queue should be considered broken.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SplitQueue {
+    size: u16,
+    descriptors: u64,
+    available: u64,
+    used: u64,
+    indirect: bool,
+    event_idx: bool,
+    next_available: u16,
+    next_used: u16,
+    /// The used index as of the last time we checked whether to
+    /// interrupt the driver.
+    signalled: u16,
+}
+
+impl SplitQueue {
+    /// Creates a queue over the given rings, as configured by the
+    /// driver, with the given negotiated features.
+    pub fn new(
+        size: u16,
+        descriptors: u64,
+        available: u64,
+        used: u64,
+        features: u64,
+    ) -> Result<SplitQueue> {
+        if size == 0 || !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+            return error("queue size is not a power of two");
+        }
+
+        if descriptors % 16 != 0 || available % 2 != 0 || used % 4 != 0 {
+            return error("rings are misaligned");
+        }
+
+        Ok(SplitQueue {
+            size,
+            descriptors,
+            available,
+            used,
+            indirect: (features & VIRTIO_F_INDIRECT_DESC) != 0,
+            event_idx: (features & VIRTIO_F_EVENT_IDX) != 0,
+            next_available: 0,
+            next_used: 0,
+            signalled: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The descriptor table, available ring, and used ring.
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        (self.descriptors, self.available, self.used)
+    }
+
+    /// The `used_event` field, at the end of the available ring.
+    fn used_event(&self) -> u64 {
+        self.available + RING_ENTRIES + 2 * self.size as u64
+    }
+
+    /// The `avail_event` field, at the end of the used ring.
+    fn available_event(&self) -> u64 {
+        self.used + RING_ENTRIES + USED_ELEMENT_SIZE * self.size as u64
+    }
+
+    /// Takes the next chain the driver made available, if there is
+    /// one.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<Chain>> {
+        let index = memory.read_u16(self.available + RING_INDEX)?;
+        let pending = index.wrapping_sub(self.next_available);
+        if pending == 0 {
+            return Ok(None);
+        }
+
+        if pending > self.size {
+            return error("available index ran ahead of the ring");
+        }
+
+        // Don't read the ring entry before the index that covers it.
+        fence(Ordering::Acquire);
+        let slot = (self.next_available % self.size) as u64;
+        let head = memory.read_u16(self.available + RING_ENTRIES + 2 * slot)?;
+        self.next_available = self.next_available.wrapping_add(1);
+
+        self.walk(memory, head).map(Some)
+    }
+
+    /// Follows a descriptor chain through the descriptor table, and
+    /// into an indirect table if it ends in one.
+    fn walk(&self, memory: &GuestMemory, head: u16) -> Result<Chain> {
+        let mut chain = Chain::new(head);
+        let mut index = head;
+        let mut count = 0;
+
+        loop {
+            if index >= self.size {
+                return error("descriptor index out of range");
+            }
+
+            count += 1;
+            if count > self.size {
+                return error("descriptor chain loops");
+            }
+
+            let descriptor = Descriptor::read(memory, self.descriptors, index)?;
+            if descriptor.has(VIRTQ_DESC_F_INDIRECT) {
+                if !self.indirect {
+                    return error("indirect descriptors were not negotiated");
+                }
+
+                if descriptor.has(VIRTQ_DESC_F_NEXT) {
+                    return error("indirect descriptor has a next descriptor");
+                }
+
+                self.walk_indirect(memory, descriptor, &mut chain)?;
+                return Ok(chain);
+            }
+
+            chain.push(
+                descriptor.address,
+                descriptor.length,
+                descriptor.has(VIRTQ_DESC_F_WRITE),
+            )?;
+            if !descriptor.has(VIRTQ_DESC_F_NEXT) {
+                return Ok(chain);
+            }
+
+            index = descriptor.next;
+        }
+    }
+
+    fn walk_indirect(
+        &self,
+        memory: &GuestMemory,
+        table: Descriptor,
+        chain: &mut Chain,
+    ) -> Result<()> {
+        let entries = table.length as u64 / DESCRIPTOR_SIZE;
+        if table.length == 0
+            || table.length as u64 % DESCRIPTOR_SIZE != 0
+            || entries > MAX_QUEUE_SIZE as u64
+        {
+            return error("indirect table has an invalid length");
+        }
+
+        let mut index = 0u16;
+        let mut count = 0;
+
+        loop {
+            if index as u64 >= entries {
+                return error("indirect descriptor index out of range");
+            }
+
+            count += 1;
+            if count > entries {
+                return error("indirect descriptor chain loops");
+            }
+
+            let descriptor = Descriptor::read(memory, table.address, index)?;
+            if descriptor.has(VIRTQ_DESC_F_INDIRECT) {
+                return error("indirect table refers to another indirect table");
+            }
+
+            chain.push(
+                descriptor.address,
+                descriptor.length,
+                descriptor.has(VIRTQ_DESC_F_WRITE),
+            )?;
+            if !descriptor.has(VIRTQ_DESC_F_NEXT) {
+                return Ok(());
+            }
+
+            index = descriptor.next;
+        }
+    }
+
+    /// Hands a chain back to the driver through the used ring, noting
+    /// how many bytes were written into it.
+    pub fn push(&mut self, memory: &GuestMemory, head: u16, written: u32) -> Result<()> {
+        if head >= self.size {
+            return error("descriptor index out of range");
+        }
+
+        let slot = (self.next_used % self.size) as u64;
+        let element = self.used + RING_ENTRIES + USED_ELEMENT_SIZE * slot;
+        memory.write_u32(element, head as u32)?;
+        memory.write_u32(element + 4, written)?;
+        self.next_used = self.next_used.wrapping_add(1);
+
+        // The driver mustn't see the new index before the element.
+        fence(Ordering::Release);
+        memory.write_u16(self.used + RING_INDEX, self.next_used)
+    }
+
+    /// Whether the driver wants an interrupt for the chains used since
+    /// the last time this was asked.
+    pub fn needs_notification(&mut self, memory: &GuestMemory) -> Result<bool> {
+        // Our used index has to be visible before we look at what the
+        // driver asked for, or we could miss a request it made in the
+        // meantime.
+        fence(Ordering::SeqCst);
+
+        let new = self.next_used;
+        let old = self.signalled;
+        self.signalled = new;
+        if old == new {
+            return Ok(false);
+        }
+
+        if self.event_idx {
+            // Notify if the driver's event falls in [old, new).
+            let event = memory.read_u16(self.used_event())?;
+            Ok(new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old))
+        } else {
+            let flags = memory.read_u16(self.available + RING_FLAGS)?;
+            Ok((flags & VIRTQ_AVAIL_F_NO_INTERRUPT) == 0)
+        }
+    }
+
+    /// Asks the driver to notify us when it makes more chains
+    /// available.  Returns whether some already are, in which case
+    /// the caller should go and process them rather than wait.
+    pub fn enable_notification(&mut self, memory: &GuestMemory) -> Result<bool> {
+        if self.event_idx {
+            memory.write_u16(self.available_event(), self.next_available)?;
+        } else {
+            let flags = memory.read_u16(self.used + RING_FLAGS)?;
+            memory.write_u16(self.used + RING_FLAGS, flags & !VIRTQ_USED_F_NO_NOTIFY)?;
+        }
+
+        fence(Ordering::SeqCst);
+        let index = memory.read_u16(self.available + RING_INDEX)?;
+        Ok(index != self.next_available)
+    }
+
+    /// Tells the driver it needn't notify us about new chains, because
+    /// we're already processing the queue.  With event indices, this is
+    /// implied by not moving `avail_event` forward.
+    pub fn disable_notification(&mut self, memory: &GuestMemory) -> Result<()> {
+        if self.event_idx {
+            return Ok(());
+        }
+
+        let flags = memory.read_u16(self.used + RING_FLAGS)?;
+        memory.write_u16(self.used + RING_FLAGS, flags | VIRTQ_USED_F_NO_NOTIFY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::super::device::memory::GuestMemory;
+    use super::super::tests::Ram;
+    use super::super::{Buffer, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC};
+    use super::{
+        SplitQueue, VIRTQ_AVAIL_F_NO_INTERRUPT, VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_WRITE, VIRTQ_USED_F_NO_NOTIFY,
+    };
+
+    const SIZE: u16 = 8;
+    const DESCRIPTORS: u64 = 0x1000;
+    const AVAILABLE: u64 = 0x2000;
+    const USED: u64 = 0x3000;
+    const INDIRECT: u64 = 0x4000;
+    const DATA: u64 = 0x8000;
+
+    /// Plays the driver's part, filling in the descriptor table and
+    /// the available ring.
+    struct Driver {
+        ram: Ram,
+        available: u16,
+    }
+
+    impl Driver {
+        fn new() -> Driver {
+            Driver {
+                ram: Ram::new(0, 0x10000),
+                available: 0,
+            }
+        }
+
+        fn queue(&self, features: u64) -> SplitQueue {
+            SplitQueue::new(SIZE, DESCRIPTORS, AVAILABLE, USED, features).unwrap()
+        }
+
+        fn descriptor(
+            &self,
+            table: u64,
+            index: u16,
+            address: u64,
+            length: u32,
+            flags: u16,
+            next: u16,
+        ) {
+            let base = table + index as u64 * 16;
+            self.ram.write_u64(base, address).unwrap();
+            self.ram.write_u32(base + 8, length).unwrap();
+            self.ram.write_u16(base + 12, flags).unwrap();
+            self.ram.write_u16(base + 14, next).unwrap();
+        }
+
+        fn offer(&mut self, head: u16) {
+            let slot = (self.available % SIZE) as u64;
+            self.ram.write_u16(AVAILABLE + 4 + 2 * slot, head).unwrap();
+            self.available = self.available.wrapping_add(1);
+            self.ram.write_u16(AVAILABLE + 2, self.available).unwrap();
+        }
+
+        fn set_available(&mut self, index: u16) {
+            self.available = index;
+            self.ram.write_u16(AVAILABLE + 2, index).unwrap();
+        }
+
+        fn used_index(&self) -> u16 {
+            self.ram.read_u16(USED + 2).unwrap()
+        }
+
+        fn used(&self, slot: u16) -> (u32, u32) {
+            let base = USED + 4 + 8 * slot as u64;
+            (
+                self.ram.read_u32(base).unwrap(),
+                self.ram.read_u32(base + 4).unwrap(),
+            )
+        }
+
+        fn set_used_event(&self, index: u16) {
+            self.ram
+                .write_u16(AVAILABLE + 4 + 2 * SIZE as u64, index)
+                .unwrap();
+        }
+
+        fn available_event(&self) -> u16 {
+            self.ram.read_u16(USED + 4 + 8 * SIZE as u64).unwrap()
+        }
+    }
+
+    fn buffer(address: u64, length: u32) -> Buffer {
+        Buffer { address, length }
+    }
+
+    #[test]
+    fn it_rejects_invalid_layouts() {
+        assert!(SplitQueue::new(0, DESCRIPTORS, AVAILABLE, USED, 0).is_err());
+        assert!(SplitQueue::new(3, DESCRIPTORS, AVAILABLE, USED, 0).is_err());
+        assert!(SplitQueue::new(65535, DESCRIPTORS, AVAILABLE, USED, 0).is_err());
+        assert!(SplitQueue::new(32768, DESCRIPTORS, AVAILABLE, USED, 0).is_ok());
+        assert!(SplitQueue::new(SIZE, DESCRIPTORS + 8, AVAILABLE, USED, 0).is_err());
+        assert!(SplitQueue::new(SIZE, DESCRIPTORS, AVAILABLE + 1, USED, 0).is_err());
+        assert!(SplitQueue::new(SIZE, DESCRIPTORS, AVAILABLE, USED + 2, 0).is_err());
+    }
+
+    #[test]
+    fn it_pops_nothing_from_an_empty_ring() {
+        let driver = Driver::new();
+        let mut queue = driver.queue(0);
+        assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+    }
+
+    #[test]
+    fn it_pops_a_chain() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        driver.descriptor(DESCRIPTORS, 2, DATA, 0x10, VIRTQ_DESC_F_NEXT, 5);
+        driver.descriptor(DESCRIPTORS, 5, DATA + 0x10, 0x20, VIRTQ_DESC_F_NEXT, 1);
+        driver.descriptor(DESCRIPTORS, 1, DATA + 0x100, 0x40, VIRTQ_DESC_F_WRITE, 0);
+        driver.offer(2);
+
+        let chain = queue.pop(&driver.ram).unwrap().unwrap();
+        assert_eq!(chain.head(), 2);
+        assert_eq!(
+            chain.readable,
+            vec![buffer(DATA, 0x10), buffer(DATA + 0x10, 0x20)]
+        );
+        assert_eq!(chain.writable, vec![buffer(DATA + 0x100, 0x40)]);
+        assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+    }
+
+    #[test]
+    fn it_pops_chains_in_order_across_the_wrap() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        for i in 0..SIZE {
+            driver.descriptor(DESCRIPTORS, i, DATA + i as u64, 1, 0, 0);
+        }
+
+        // Start just short of the 16-bit index wrapping around.
+        queue.next_available = 0xfffd;
+        driver.set_available(0xfffd);
+        for round in 0..3 {
+            for i in 0..SIZE {
+                driver.offer((i + round) % SIZE);
+            }
+            for i in 0..SIZE {
+                let chain = queue.pop(&driver.ram).unwrap().unwrap();
+                assert_eq!(chain.head(), (i + round) % SIZE);
+            }
+            assert_eq!(queue.pop(&driver.ram).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn it_rejects_an_available_index_past_the_ring() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        driver.set_available(SIZE + 1);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_rejects_out_of_range_descriptors() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        driver.offer(SIZE);
+        assert!(queue.pop(&driver.ram).is_err());
+
+        driver.descriptor(DESCRIPTORS, 0, DATA, 1, VIRTQ_DESC_F_NEXT, SIZE);
+        driver.offer(0);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_detects_loops() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        driver.descriptor(DESCRIPTORS, 0, DATA, 1, VIRTQ_DESC_F_NEXT, 1);
+        driver.descriptor(DESCRIPTORS, 1, DATA, 1, VIRTQ_DESC_F_NEXT, 0);
+        driver.offer(0);
+        assert!(queue.pop(&driver.ram).is_err());
+
+        driver.descriptor(DESCRIPTORS, 3, DATA, 1, VIRTQ_DESC_F_NEXT, 3);
+        driver.offer(3);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_accepts_a_chain_as_long_as_the_ring() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        for i in 0..SIZE {
+            let flags = if i + 1 < SIZE { VIRTQ_DESC_F_NEXT } else { 0 };
+            driver.descriptor(DESCRIPTORS, i, DATA, 1, flags, i + 1);
+        }
+        driver.offer(0);
+        assert_eq!(
+            queue.pop(&driver.ram).unwrap().unwrap().readable.len(),
+            SIZE as usize
+        );
+    }
+
+    #[test]
+    fn it_rejects_readable_after_writable() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        driver.descriptor(
+            DESCRIPTORS,
+            0,
+            DATA,
+            1,
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            1,
+        );
+        driver.descriptor(DESCRIPTORS, 1, DATA, 1, 0, 0);
+        driver.offer(0);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_rejects_descriptors_outside_guest_memory() {
+        let mut driver = Driver::new();
+        let mut queue = SplitQueue::new(SIZE, 0x20000, AVAILABLE, USED, 0).unwrap();
+        driver.offer(0);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_follows_indirect_tables() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(VIRTIO_F_INDIRECT_DESC);
+        driver.descriptor(DESCRIPTORS, 0, DATA, 8, VIRTQ_DESC_F_NEXT, 1);
+        driver.descriptor(DESCRIPTORS, 1, INDIRECT, 3 * 16, VIRTQ_DESC_F_INDIRECT, 0);
+        driver.descriptor(INDIRECT, 0, DATA + 0x10, 0x10, VIRTQ_DESC_F_NEXT, 2);
+        driver.descriptor(
+            INDIRECT,
+            2,
+            DATA + 0x20,
+            0x20,
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            1,
+        );
+        driver.descriptor(INDIRECT, 1, DATA + 0x40, 0x40, VIRTQ_DESC_F_WRITE, 0);
+        driver.offer(0);
+
+        let chain = queue.pop(&driver.ram).unwrap().unwrap();
+        assert_eq!(chain.head(), 0);
+        assert_eq!(
+            chain.readable,
+            vec![buffer(DATA, 8), buffer(DATA + 0x10, 0x10)]
+        );
+        assert_eq!(
+            chain.writable,
+            vec![buffer(DATA + 0x20, 0x20), buffer(DATA + 0x40, 0x40)]
+        );
+    }
+
+    #[test]
+    fn it_rejects_indirect_tables_unless_negotiated() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        driver.descriptor(DESCRIPTORS, 0, INDIRECT, 16, VIRTQ_DESC_F_INDIRECT, 0);
+        driver.descriptor(INDIRECT, 0, DATA, 1, 0, 0);
+        driver.offer(0);
+        assert!(queue.pop(&driver.ram).is_err());
+    }
+
+    #[test]
+    fn it_rejects_malformed_indirect_tables() {
+        let cases: &[(u32, u16, &[(u16, u16, u16)])] = &[
+            // Empty table.
+            (0, VIRTQ_DESC_F_INDIRECT, &[]),
+            // Length not a multiple of the descriptor size.
+            (24, VIRTQ_DESC_F_INDIRECT, &[(0, 0, 0)]),
+            // Both indirect and next.
+            (16, VIRTQ_DESC_F_INDIRECT | VIRTQ_DESC_F_NEXT, &[(0, 0, 0)]),
+            // Nested indirect table.
+            (16, VIRTQ_DESC_F_INDIRECT, &[(0, VIRTQ_DESC_F_INDIRECT, 0)]),
+            // Next past the end of the table.
+            (16, VIRTQ_DESC_F_INDIRECT, &[(0, VIRTQ_DESC_F_NEXT, 1)]),
+            // A loop within the table.
+            (
+                32,
+                VIRTQ_DESC_F_INDIRECT,
+                &[(0, VIRTQ_DESC_F_NEXT, 1), (1, VIRTQ_DESC_F_NEXT, 0)],
+            ),
+        ];
+
+        for &(length, flags, entries) in cases {
+            let mut driver = Driver::new();
+            let mut queue = driver.queue(VIRTIO_F_INDIRECT_DESC);
+            driver.descriptor(DESCRIPTORS, 0, INDIRECT, length, flags, 1);
+            driver.descriptor(DESCRIPTORS, 1, DATA, 1, 0, 0);
+            for &(index, flags, next) in entries {
+                driver.descriptor(INDIRECT, index, DATA, 1, flags, next);
+            }
+            driver.offer(0);
+            assert!(
+                queue.pop(&driver.ram).is_err(),
+                "{:?}",
+                (length, flags, entries)
+            );
+        }
+    }
+
+    #[test]
+    fn it_pushes_used_chains() {
+        let driver = Driver::new();
+        let mut queue = driver.queue(0);
+        queue.next_used = 0xffff;
+        queue.push(&driver.ram, 3, 0x30).unwrap();
+        assert_eq!(driver.used_index(), 0);
+        assert_eq!(driver.used(7), (3, 0x30));
+
+        queue.push(&driver.ram, 5, 0x50).unwrap();
+        assert_eq!(driver.used_index(), 1);
+        assert_eq!(driver.used(0), (5, 0x50));
+
+        assert!(queue.push(&driver.ram, SIZE, 0).is_err());
+        assert_eq!(driver.used_index(), 1);
+    }
+
+    #[test]
+    fn it_honours_no_interrupt() {
+        let driver = Driver::new();
+        let mut queue = driver.queue(0);
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(queue.needs_notification(&driver.ram).unwrap());
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+
+        driver
+            .ram
+            .write_u16(AVAILABLE, VIRTQ_AVAIL_F_NO_INTERRUPT)
+            .unwrap();
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+    }
+
+    #[test]
+    fn it_honours_used_event() {
+        let driver = Driver::new();
+        let mut queue = driver.queue(VIRTIO_F_EVENT_IDX);
+
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(queue.needs_notification(&driver.ram).unwrap());
+
+        // The driver wants to hear about the used index passing 2, so
+        // the used index has to reach 3.
+        driver.set_used_event(2);
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(queue.needs_notification(&driver.ram).unwrap());
+
+        // Skipping past the event in one go still notifies.
+        driver.set_used_event(4);
+        for _ in 0..4 {
+            queue.push(&driver.ram, 0, 0).unwrap();
+        }
+        assert!(queue.needs_notification(&driver.ram).unwrap());
+
+        // An event that's already behind us doesn't.
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+
+        // Including across the wrap.
+        queue.next_used = 0xfffe;
+        queue.signalled = 0xfffe;
+        driver.set_used_event(0xffff);
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(!queue.needs_notification(&driver.ram).unwrap());
+        queue.push(&driver.ram, 0, 0).unwrap();
+        assert!(queue.needs_notification(&driver.ram).unwrap());
+    }
+
+    #[test]
+    fn it_toggles_notification_flags() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(0);
+        queue.disable_notification(&driver.ram).unwrap();
+        assert_eq!(driver.ram.read_u16(USED).unwrap(), VIRTQ_USED_F_NO_NOTIFY);
+        assert!(!queue.enable_notification(&driver.ram).unwrap());
+        assert_eq!(driver.ram.read_u16(USED).unwrap(), 0);
+
+        driver.descriptor(DESCRIPTORS, 0, DATA, 1, 0, 0);
+        driver.offer(0);
+        assert!(queue.enable_notification(&driver.ram).unwrap());
+    }
+
+    #[test]
+    fn it_publishes_available_event() {
+        let mut driver = Driver::new();
+        let mut queue = driver.queue(VIRTIO_F_EVENT_IDX);
+        driver.descriptor(DESCRIPTORS, 0, DATA, 1, 0, 0);
+        driver.offer(0);
+        driver.offer(0);
+
+        queue.disable_notification(&driver.ram).unwrap();
+        assert_eq!(driver.ram.read_u16(USED).unwrap(), 0);
+        assert!(queue.enable_notification(&driver.ram).unwrap());
+        assert_eq!(driver.available_event(), 0);
+
+        queue.pop(&driver.ram).unwrap().unwrap();
+        queue.pop(&driver.ram).unwrap().unwrap();
+        assert!(!queue.enable_notification(&driver.ram).unwrap());
+        assert_eq!(driver.available_event(), 2);
+    }
+}
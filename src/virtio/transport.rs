@@ -0,0 +1,225 @@
+use super::queue::{Queue, QUEUE_FEATURES};
+use super::{
+    Activation, Interrupt, Virtio, VIRTIO_F_VERSION_1, VIRTIO_STATUS_DRIVER_OK,
+    VIRTIO_STATUS_FEATURES_OK,
+};
+use device::interrupt::Interrupts;
+use device::memory::GuestMemory;
+use error::*;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+/// Everything a transport offers for a device: the device's own
+/// features, plus the ones the transport and the queues handle.
+pub fn offered(device: &Virtio) -> u64 {
+    device.features() | VIRTIO_F_VERSION_1 | QUEUE_FEATURES
+}
+
+/// What the driver has told us about a queue.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct QueueConfig {
+    pub max: u16,
+    pub size: u16,
+    pub enabled: bool,
+    pub descriptors: u64,
+    pub driver: u64,
+    pub device: u64,
+}
+
+impl QueueConfig {
+    /// Shrinks (or grows back) the queue, as long as the device can
+    /// handle the new size.
+    pub fn resize(&mut self, size: u16) {
+        if size != 0 && size <= self.max {
+            self.size = size;
+        }
+    }
+}
+
+/// The part of device setup that's the same whichever transport the
+/// driver goes through: feature negotiation, and the configuration of
+/// each queue.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct State {
+    pub device_feature_select: u32,
+    pub driver_feature_select: u32,
+    pub driver_features: u64,
+    pub queue_select: u16,
+    pub queues: Vec<QueueConfig>,
+}
+
+impl State {
+    /// Starts from scratch, with queues of the given maximum sizes.
+    pub fn new(sizes: &[u16]) -> State {
+        State {
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            queue_select: 0,
+            queues: sizes
+                .iter()
+                .map(|&max| QueueConfig {
+                    max,
+                    size: max,
+                    ..QueueConfig::default()
+                })
+                .collect(),
+        }
+    }
+
+    /// The 32 bits of the offered features the driver selected.
+    pub fn device_features(&self, offered: u64) -> u32 {
+        half(offered, self.device_feature_select)
+    }
+
+    /// The 32 bits of the accepted features the driver selected.
+    pub fn driver_features(&self) -> u32 {
+        half(self.driver_features, self.driver_feature_select)
+    }
+
+    pub fn set_driver_features(&mut self, value: u32) {
+        let value = value as u64;
+        self.driver_features = match self.driver_feature_select {
+            0 => (self.driver_features & !0xffff_ffff) | value,
+            1 => (self.driver_features & 0xffff_ffff) | (value << 32),
+            _ => self.driver_features,
+        };
+    }
+
+    /// The queue the driver selected, if it exists.
+    pub fn selected(&self) -> Option<&QueueConfig> {
+        self.queues.get(self.queue_select as usize)
+    }
+
+    pub fn selected_mut(&mut self) -> Option<&mut QueueConfig> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    /// Works out the new device status when the driver writes a
+    /// non-zero `value` over `old`.  The driver checks that
+    /// FEATURES_OK stuck, so it's left clear if the driver asked for
+    /// something we didn't offer, or for the legacy interface.
+    pub fn update_status(&self, offered: u64, old: u8, value: u8) -> u8 {
+        let mut status = old | value;
+        let negotiating = (value & !old & VIRTIO_STATUS_FEATURES_OK) != 0;
+        if negotiating
+            && ((self.driver_features & !offered) != 0
+                || (self.driver_features & VIRTIO_F_VERSION_1) == 0)
+        {
+            warn!(
+                "virtio driver accepted unsupported features {:#x}",
+                self.driver_features
+            );
+            status &= !VIRTIO_STATUS_FEATURES_OK;
+        }
+
+        status
+    }
+
+    /// Whether going from `old` to `status` means the device should be
+    /// started.
+    pub fn starting(old: u8, status: u8) -> bool {
+        (status & !old & VIRTIO_STATUS_DRIVER_OK) != 0 && (status & VIRTIO_STATUS_FEATURES_OK) != 0
+    }
+
+    /// Builds the queues the driver enabled, and hands them to the
+    /// device.
+    pub fn activate(
+        &self,
+        device: &Virtio,
+        memory: Arc<GuestMemory>,
+        interrupt: Arc<Interrupt>,
+    ) -> Result<()> {
+        let features = self.driver_features;
+        let queues = self
+            .queues
+            .iter()
+            .map(|q| {
+                if q.enabled {
+                    Queue::new(q.size, q.descriptors, q.driver, q.device, features).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        device.activate(Activation {
+            features,
+            memory,
+            queues,
+            interrupt,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Eventfd {
+    Irqfd {
+        gsi: u32,
+        fd: RawFd,
+    },
+    Ioeventfd {
+        address: u64,
+        length: u32,
+        data: Option<u64>,
+        fd: RawFd,
+    },
+}
+
+/// The eventfds a transport hooked up to KVM for its device, which
+/// have to be unhooked before the device closes them: KVM won't take
+/// another ioeventfd for the same register until the old one's gone.
+#[derive(Debug, Default)]
+pub struct Eventfds(Mutex<Vec<Eventfd>>);
+
+impl Eventfds {
+    pub fn irqfd(&self, interrupts: &Interrupts, gsi: u32, fd: RawFd) -> Result<()> {
+        interrupts.irqfd(gsi, fd, true)?;
+        self.0.lock().unwrap().push(Eventfd::Irqfd { gsi, fd });
+        Ok(())
+    }
+
+    pub fn ioeventfd(
+        &self,
+        interrupts: &Interrupts,
+        address: u64,
+        length: u32,
+        data: Option<u64>,
+        fd: RawFd,
+    ) -> Result<()> {
+        interrupts.ioeventfd(address, length, data, fd, true)?;
+        self.0.lock().unwrap().push(Eventfd::Ioeventfd {
+            address,
+            length,
+            data,
+            fd,
+        });
+        Ok(())
+    }
+
+    /// Unhooks everything, as the device is about to be reset.
+    pub fn detach(&self, interrupts: &Interrupts) {
+        for eventfd in self.0.lock().unwrap().drain(..) {
+            let result = match eventfd {
+                Eventfd::Irqfd { gsi, fd } => interrupts.irqfd(gsi, fd, false),
+                Eventfd::Ioeventfd {
+                    address,
+                    length,
+                    data,
+                    fd,
+                } => interrupts.ioeventfd(address, length, data, fd, false),
+            };
+            if let Err(e) = result {
+                warn!("could not unhook virtio eventfd: {}", e);
+            }
+        }
+    }
+}
+
+fn half(features: u64, select: u32) -> u32 {
+    match select {
+        0 => features as u32,
+        1 => (features >> 32) as u32,
+        _ => 0,
+    }
+}
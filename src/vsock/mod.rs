@@ -0,0 +1,155 @@
+use byteorder::{ByteOrder, LittleEndian};
+use error::*;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+mod muxer;
+
+pub use self::muxer::Muxer;
+
+/// The host's context ID, the one every guest reaches it at.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+/// The size of the header every packet starts with.
+pub const HEADER_SIZE: usize = 44;
+
+/// The most data a packet for the guest carries, which fits the
+/// receive buffers Linux hands out.
+pub const MAX_DATA: usize = 4096;
+
+pub const VSOCK_TYPE_STREAM: u16 = 1;
+
+pub const VSOCK_OP_REQUEST: u16 = 1;
+pub const VSOCK_OP_RESPONSE: u16 = 2;
+pub const VSOCK_OP_RST: u16 = 3;
+pub const VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VSOCK_OP_RW: u16 = 5;
+pub const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The sender of a shutdown won't take any more data.
+pub const VSOCK_SHUTDOWN_RCV: u32 = 1 << 0;
+/// The sender of a shutdown won't send any more data.
+pub const VSOCK_SHUTDOWN_SEND: u32 = 1 << 1;
+
+/// A packet going either way between the guest and the host.  Every
+/// one carries the sender's credit: how much it can buffer for the
+/// connection, and how much of that it's passed on so far.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Packet {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub kind: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    /// Reads a packet off the wire, unless it's cut short.
+    pub fn parse(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let length = LittleEndian::read_u32(&bytes[24..28]) as usize;
+        let data = bytes[HEADER_SIZE..].get(..length)?;
+
+        Some(Packet {
+            src_cid: LittleEndian::read_u64(&bytes[0..8]),
+            dst_cid: LittleEndian::read_u64(&bytes[8..16]),
+            src_port: LittleEndian::read_u32(&bytes[16..20]),
+            dst_port: LittleEndian::read_u32(&bytes[20..24]),
+            kind: LittleEndian::read_u16(&bytes[28..30]),
+            op: LittleEndian::read_u16(&bytes[30..32]),
+            flags: LittleEndian::read_u32(&bytes[32..36]),
+            buf_alloc: LittleEndian::read_u32(&bytes[36..40]),
+            fwd_cnt: LittleEndian::read_u32(&bytes[40..44]),
+            data: data.to_vec(),
+        })
+    }
+
+    /// The packet as it goes on the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        LittleEndian::write_u64(&mut bytes[0..8], self.src_cid);
+        LittleEndian::write_u64(&mut bytes[8..16], self.dst_cid);
+        LittleEndian::write_u32(&mut bytes[16..20], self.src_port);
+        LittleEndian::write_u32(&mut bytes[20..24], self.dst_port);
+        LittleEndian::write_u32(&mut bytes[24..28], self.data.len() as u32);
+        LittleEndian::write_u16(&mut bytes[28..30], self.kind);
+        LittleEndian::write_u16(&mut bytes[30..32], self.op);
+        LittleEndian::write_u32(&mut bytes[32..36], self.flags);
+        LittleEndian::write_u32(&mut bytes[36..40], self.buf_alloc);
+        LittleEndian::write_u32(&mut bytes[40..44], self.fwd_cnt);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// The reset that answers this packet.
+    pub fn reset(&self) -> Packet {
+        Packet {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            kind: VSOCK_TYPE_STREAM,
+            op: VSOCK_OP_RST,
+            ..Packet::default()
+        }
+    }
+}
+
+/// Where a vsock backend sends the packets meant for the guest.
+pub trait Sink: Debug + Send + Sync {
+    fn receive(&self, packet: Packet);
+}
+
+/// The host's end of a vsock device, which takes the guest's
+/// connections and makes its own.
+pub trait Backend: Debug + Send + Sync {
+    /// Takes a packet the guest sent to the host.
+    fn send(&self, packet: Packet) -> Result<()>;
+    /// Starts delivering the host's packets to the given sink.
+    /// Backends only have one device, so this is only called once.
+    fn attach(&self, sink: Arc<Sink>) -> Result<()>;
+    /// Drops every connection, since the guest's forgotten about
+    /// them.
+    fn reset(&self);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_what_it_writes() {
+        let packet = Packet {
+            src_cid: 3,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: 1024,
+            dst_port: 52,
+            kind: VSOCK_TYPE_STREAM,
+            op: VSOCK_OP_RW,
+            flags: 0,
+            buf_alloc: 0x40000,
+            fwd_cnt: 17,
+            data: b"hello".to_vec(),
+        };
+        let mut bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 5);
+        assert_eq!(Packet::parse(&bytes), Some(packet.clone()));
+
+        let reset = packet.reset();
+        assert_eq!((reset.src_cid, reset.dst_port), (VSOCK_HOST_CID, 1024));
+        assert_eq!(reset.op, VSOCK_OP_RST);
+
+        // The data can't be any shorter than the header says.
+        bytes.pop();
+        assert_eq!(Packet::parse(&bytes), None);
+        assert_eq!(Packet::parse(&bytes[..HEADER_SIZE - 1]), None);
+    }
+}
@@ -0,0 +1,893 @@
+use super::{
+    Backend, Packet, Sink, MAX_DATA, VSOCK_HOST_CID, VSOCK_OP_CREDIT_REQUEST,
+    VSOCK_OP_CREDIT_UPDATE, VSOCK_OP_REQUEST, VSOCK_OP_RESPONSE, VSOCK_OP_RST, VSOCK_OP_RW,
+    VSOCK_OP_SHUTDOWN, VSOCK_SHUTDOWN_RCV, VSOCK_SHUTDOWN_SEND, VSOCK_TYPE_STREAM,
+};
+use error::*;
+use mio::unix::{EventedFd, UnixReady};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How much of the guest's data each connection holds on to while the
+/// host isn't taking it.
+const BUF_ALLOC: u32 = 256 * 1024;
+/// How much of it has to be passed on before the guest's told there's
+/// room again, unless it asks.
+const CREDIT_THRESHOLD: u32 = BUF_ALLOC / 4;
+
+/// The host's ports for the connections it makes start here, well
+/// clear of anything a guest would listen on.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+/// How long the host has to say where it's connecting, and the guest
+/// has to answer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often connections being set up are looked after, while there
+/// are any.
+const TICK: Duration = Duration::from_millis(100);
+/// The longest `CONNECT <port>` line the host can send.
+const MAX_COMMAND: usize = 32;
+
+const WAKER: Token = Token(0);
+const LISTENER: Token = Token(1);
+
+/// What the device hands the muxer's thread.
+#[derive(Debug)]
+enum Inbound {
+    Packet(Packet),
+    Reset,
+}
+
+/// What's shared between the muxer and its thread.
+#[derive(Debug, Default)]
+struct Shared {
+    inbox: Mutex<VecDeque<Inbound>>,
+    stopping: AtomicBool,
+}
+
+/// What's set up before the muxer is attached.
+#[derive(Debug)]
+struct Setup {
+    poll: Poll,
+    registration: Registration,
+    listener: UnixListener,
+}
+
+/// The host's end of a vsock device, laid out the way Firecracker's
+/// is.  The host reaches the guest by connecting to the socket at
+/// `path`, and writing `CONNECT <port>\n`; it's told `OK <port>\n`,
+/// with the port it has on its end, once the guest accepts.  The
+/// guest's connections to port `p` on the host go to the socket at
+/// `<path>_<p>`.
+#[derive(Debug)]
+pub struct Muxer {
+    cid: u64,
+    path: String,
+    shared: Arc<Shared>,
+    waker: SetReadiness,
+    setup: Mutex<Option<Setup>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Muxer {
+    /// Listens for the host's connections at `path`, for the guest
+    /// with the given context ID.
+    pub fn bind(cid: u64, path: &str) -> Result<Muxer> {
+        let poll = Poll::new()?;
+        let (registration, waker) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())?;
+
+        // A socket left behind by an earlier run would be in the way.
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        poll.register(
+            &EventedFd(&listener.as_raw_fd()),
+            LISTENER,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        debug!("vsock for guest {} at {}", cid, path);
+
+        Ok(Muxer {
+            cid,
+            path: path.to_owned(),
+            shared: Arc::new(Shared::default()),
+            waker,
+            setup: Mutex::new(Some(Setup {
+                poll,
+                registration,
+                listener,
+            })),
+            thread: Mutex::new(None),
+        })
+    }
+
+    fn post(&self, inbound: Inbound) -> Result<()> {
+        self.shared.inbox.lock().unwrap().push_back(inbound);
+        self.waker.set_readiness(Ready::readable())?;
+        Ok(())
+    }
+}
+
+impl Backend for Muxer {
+    fn send(&self, packet: Packet) -> Result<()> {
+        self.post(Inbound::Packet(packet))
+    }
+
+    fn attach(&self, sink: Arc<Sink>) -> Result<()> {
+        let setup = self
+            .setup
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(ErrorKind::DeviceError("vsock backend attached twice"))?;
+        let mut bridge = Bridge {
+            cid: self.cid,
+            path: self.path.clone(),
+            poll: setup.poll,
+            _registration: setup.registration,
+            listener: setup.listener,
+            sink,
+            connections: HashMap::new(),
+            handshakes: HashMap::new(),
+            owners: HashMap::new(),
+            next_token: 2,
+            next_port: FIRST_HOST_PORT,
+        };
+        let shared = self.shared.clone();
+        let thread = thread::Builder::new()
+            .name("vsock".to_owned())
+            .spawn(move || bridge.run(&shared))?;
+        *self.thread.lock().unwrap() = Some(thread);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        if let Err(e) = self.post(Inbound::Reset) {
+            warn!("could not reset vsock connections: {}", e);
+        }
+    }
+}
+
+impl Drop for Muxer {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        let _ = self.waker.set_readiness(Ready::readable());
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A connection, as the host's port and the guest's.
+type Key = (u32, u32);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    /// The host asked for the connection, and the guest hasn't
+    /// answered yet.
+    Connecting(Instant),
+    Established,
+    /// The guest's done with the connection; whatever it sent is
+    /// passed on before it's reset.
+    Closing,
+}
+
+/// A connection between the guest and a Unix socket on the host.
+#[derive(Debug)]
+struct Connection {
+    stream: UnixStream,
+    token: Token,
+    phase: Phase,
+    /// What the guest sent that the host hasn't taken yet.
+    pending: VecDeque<u8>,
+    /// How much of what the guest sent has been passed on, and how
+    /// much of that it's been told about.
+    fwd_cnt: u32,
+    told: u32,
+    /// How much has been sent to the guest, and its credit as of its
+    /// last packet.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Whether the host and the guest are done sending.
+    host_done: bool,
+    guest_done: bool,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, token: Token, phase: Phase) -> Connection {
+        Connection {
+            stream,
+            token,
+            phase,
+            pending: VecDeque::new(),
+            fwd_cnt: 0,
+            told: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            host_done: false,
+            guest_done: false,
+        }
+    }
+
+    /// How much more the guest has room for.
+    fn peer_free(&self) -> u32 {
+        let unread = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(unread)
+    }
+}
+
+/// A host connection that hasn't said where it's going yet.
+#[derive(Debug)]
+struct Handshake {
+    stream: UnixStream,
+    line: Vec<u8>,
+    started: Instant,
+}
+
+/// The muxer itself, run on a thread of its own.
+#[derive(Debug)]
+struct Bridge {
+    cid: u64,
+    path: String,
+    poll: Poll,
+    _registration: Registration,
+    listener: UnixListener,
+    sink: Arc<Sink>,
+    connections: HashMap<Key, Connection>,
+    handshakes: HashMap<Token, Handshake>,
+    owners: HashMap<Token, Key>,
+    next_token: usize,
+    next_port: u32,
+}
+
+impl Bridge {
+    fn run(&mut self, shared: &Shared) {
+        let mut events = Events::with_capacity(64);
+        loop {
+            let busy = !self.handshakes.is_empty()
+                || self.connections.values().any(|connection| match connection.phase {
+                    Phase::Connecting(_) => true,
+                    _ => false,
+                });
+            if let Err(e) = self
+                .poll
+                .poll(&mut events, if busy { Some(TICK) } else { None })
+            {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                warn!("could not wait on vsock: {}", e);
+                return;
+            }
+            if shared.stopping.load(Ordering::SeqCst) {
+                return;
+            }
+
+            for event in &events {
+                match event.token() {
+                    WAKER => loop {
+                        let inbound = shared.inbox.lock().unwrap().pop_front();
+                        match inbound {
+                            Some(Inbound::Packet(packet)) => self.packet(packet),
+                            Some(Inbound::Reset) => self.clear(),
+                            None => break,
+                        }
+                    },
+                    LISTENER => self.accept(),
+                    token if self.handshakes.contains_key(&token) => self.handshake(token),
+                    token => {
+                        if let Some(&key) = self.owners.get(&token) {
+                            let readiness = event.readiness();
+                            if readiness.is_readable() || UnixReady::from(readiness).is_hup() {
+                                self.read(key);
+                            }
+                            if readiness.is_writable() {
+                                self.flush(key);
+                            }
+                        }
+                    }
+                }
+            }
+
+            self.expire();
+        }
+    }
+
+    /// Handles a packet the guest sent.
+    fn packet(&mut self, packet: Packet) {
+        if packet.src_cid != self.cid
+            || packet.dst_cid != VSOCK_HOST_CID
+            || packet.kind != VSOCK_TYPE_STREAM
+        {
+            debug!("dropping a vsock packet from {} to {}", packet.src_cid, packet.dst_cid);
+            if packet.op != VSOCK_OP_RST {
+                self.sink.receive(packet.reset());
+            }
+            return;
+        }
+
+        let key = (packet.dst_port, packet.src_port);
+        if packet.op == VSOCK_OP_REQUEST && !self.connections.contains_key(&key) {
+            self.connect(key, &packet);
+            return;
+        }
+        let phase = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                connection.peer_buf_alloc = packet.buf_alloc;
+                connection.peer_fwd_cnt = packet.fwd_cnt;
+                connection.phase
+            }
+            None => {
+                if packet.op != VSOCK_OP_RST {
+                    self.sink.receive(packet.reset());
+                }
+                return;
+            }
+        };
+
+        match (packet.op, phase) {
+            (VSOCK_OP_RESPONSE, Phase::Connecting(_)) => self.accepted(key),
+            (VSOCK_OP_RW, Phase::Established) => self.write(key, &packet.data),
+            (VSOCK_OP_SHUTDOWN, _) => self.shutdown(key, packet.flags),
+            (VSOCK_OP_RST, _) => self.remove(key),
+            (VSOCK_OP_CREDIT_REQUEST, _) => self.send(key, VSOCK_OP_CREDIT_UPDATE, 0, vec![]),
+            (VSOCK_OP_CREDIT_UPDATE, _) => (),
+            _ => {
+                debug!("resetting vsock connection {:?} on op {}", key, packet.op);
+                self.reset(key);
+                return;
+            }
+        }
+
+        // The guest may have made room for more.
+        self.read(key);
+    }
+
+    /// Connects the guest to the socket for the host port it asked
+    /// for.
+    fn connect(&mut self, key: Key, request: &Packet) {
+        let path = format!("{}_{}", self.path, key.0);
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("guest could not connect to {}: {}", path, e);
+                self.sink.receive(request.reset());
+                return;
+            }
+        };
+
+        let mut connection = Connection::new(stream, Token(0), Phase::Established);
+        connection.peer_buf_alloc = request.buf_alloc;
+        connection.peer_fwd_cnt = request.fwd_cnt;
+        match self.insert(key, connection) {
+            Ok(()) => self.send(key, VSOCK_OP_RESPONSE, 0, vec![]),
+            Err(e) => {
+                warn!("could not watch vsock connection to {}: {}", path, e);
+                self.sink.receive(request.reset());
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Key, mut connection: Connection) -> Result<()> {
+        if connection.token == Token(0) {
+            connection.token = Token(self.next_token);
+            self.next_token += 1;
+            connection.stream.set_nonblocking(true)?;
+            self.poll.register(
+                &EventedFd(&connection.stream.as_raw_fd()),
+                connection.token,
+                Ready::readable() | Ready::writable() | UnixReady::hup(),
+                PollOpt::edge(),
+            )?;
+        }
+        self.owners.insert(connection.token, key);
+        self.connections.insert(key, connection);
+        Ok(())
+    }
+
+    /// Takes the host's connections to the guest.
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("could not accept a vsock connection: {}", e);
+                    return;
+                }
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let registered = stream.set_nonblocking(true).and_then(|()| {
+                self.poll.register(
+                    &EventedFd(&stream.as_raw_fd()),
+                    token,
+                    Ready::readable() | Ready::writable() | UnixReady::hup(),
+                    PollOpt::edge(),
+                )
+            });
+            if let Err(e) = registered {
+                warn!("could not watch a vsock connection: {}", e);
+                continue;
+            }
+            self.handshakes.insert(
+                token,
+                Handshake {
+                    stream,
+                    line: vec![],
+                    started: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Reads where the host's connecting to, a byte at a time so
+    /// nothing after the line is taken.
+    fn handshake(&mut self, token: Token) {
+        let port = {
+            let handshake = match self.handshakes.get_mut(&token) {
+                Some(handshake) => handshake,
+                None => return,
+            };
+            let mut byte = [0u8; 1];
+            loop {
+                match handshake.stream.read(&mut byte) {
+                    Ok(1) if byte[0] == b'\n' => break,
+                    Ok(1) if handshake.line.len() < MAX_COMMAND => handshake.line.push(byte[0]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    _ => {
+                        handshake.line.clear();
+                        break;
+                    }
+                }
+            }
+            parse_connect(&handshake.line)
+        };
+
+        let handshake = match self.handshakes.remove(&token) {
+            Some(handshake) => handshake,
+            None => return,
+        };
+        let port = match port {
+            Some(port) => port,
+            None => {
+                debug!("dropping a vsock connection that didn't say where it goes");
+                let _ = self.poll.deregister(&EventedFd(&handshake.stream.as_raw_fd()));
+                return;
+            }
+        };
+
+        while self.connections.keys().any(|key| key.0 == self.next_port) {
+            self.next_port = self.next_port.wrapping_add(1).max(FIRST_HOST_PORT);
+        }
+        let key = (self.next_port, port);
+        self.next_port = self.next_port.wrapping_add(1).max(FIRST_HOST_PORT);
+
+        let connection = Connection::new(
+            handshake.stream,
+            token,
+            Phase::Connecting(handshake.started),
+        );
+        if let Err(e) = self.insert(key, connection) {
+            warn!("could not set up a vsock connection: {}", e);
+            return;
+        }
+        self.send(key, VSOCK_OP_REQUEST, 0, vec![]);
+    }
+
+    /// The guest took a connection from the host, which is told which
+    /// port it has.
+    fn accepted(&mut self, key: Key) {
+        let result = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                connection.phase = Phase::Established;
+                writeln!(connection.stream, "OK {}", key.0)
+            }
+            None => return,
+        };
+        if let Err(e) = result {
+            debug!("could not tell the host about vsock connection {:?}: {}", key, e);
+            self.reset(key);
+        }
+    }
+
+    /// Passes what the guest sent on to the host.
+    fn write(&mut self, key: Key, data: &[u8]) {
+        let overrun = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                if connection.guest_done {
+                    return;
+                }
+                let overrun = connection.pending.len() + data.len() > BUF_ALLOC as usize;
+                if !overrun {
+                    connection.pending.extend(data);
+                }
+                overrun
+            }
+            None => return,
+        };
+        if overrun {
+            debug!("vsock connection {:?} sent past its credit", key);
+            self.reset(key);
+            return;
+        }
+        self.flush(key);
+    }
+
+    /// Writes as much of what the guest sent as the host takes.
+    fn flush(&mut self, key: Key) {
+        enum Next {
+            Nothing,
+            Credit,
+            Reset,
+            Close,
+        }
+
+        let next = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                let mut failed = false;
+                while !connection.pending.is_empty() {
+                    let written = {
+                        let (data, _) = connection.pending.as_slices();
+                        connection.stream.write(data)
+                    };
+                    match written {
+                        Ok(count) => {
+                            connection.pending.drain(..count);
+                            connection.fwd_cnt = connection.fwd_cnt.wrapping_add(count as u32);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(_) => {
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+
+                let flushed = connection.pending.is_empty();
+                if failed {
+                    Next::Reset
+                } else if flushed
+                    && (connection.phase == Phase::Closing
+                        || (connection.guest_done && connection.host_done))
+                {
+                    Next::Close
+                } else {
+                    if flushed && connection.guest_done {
+                        let _ = connection.stream.shutdown(Shutdown::Write);
+                    }
+                    if connection.fwd_cnt.wrapping_sub(connection.told) >= CREDIT_THRESHOLD {
+                        Next::Credit
+                    } else {
+                        Next::Nothing
+                    }
+                }
+            }
+            None => return,
+        };
+
+        match next {
+            Next::Nothing => (),
+            Next::Credit => self.send(key, VSOCK_OP_CREDIT_UPDATE, 0, vec![]),
+            Next::Reset | Next::Close => self.reset(key),
+        }
+    }
+
+    /// Passes what the host sent on to the guest, as far as its credit
+    /// goes.
+    fn read(&mut self, key: Key) {
+        loop {
+            let read = match self.connections.get_mut(&key) {
+                Some(connection) => {
+                    if connection.phase != Phase::Established || connection.host_done {
+                        return;
+                    }
+                    let room = ::std::cmp::min(connection.peer_free() as usize, MAX_DATA);
+                    if room == 0 {
+                        return;
+                    }
+                    let mut data = vec![0u8; room];
+                    match connection.stream.read(&mut data) {
+                        Ok(0) => {
+                            connection.host_done = true;
+                            None
+                        }
+                        Ok(count) => {
+                            data.truncate(count);
+                            connection.tx_cnt = connection.tx_cnt.wrapping_add(count as u32);
+                            Some(Ok(data))
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => Some(Err(e)),
+                    }
+                }
+                None => return,
+            };
+
+            match read {
+                Some(Ok(data)) => self.send(key, VSOCK_OP_RW, 0, data),
+                Some(Err(e)) => {
+                    debug!("could not read vsock connection {:?}: {}", key, e);
+                    self.reset(key);
+                    return;
+                }
+                None => {
+                    self.send(key, VSOCK_OP_SHUTDOWN, VSOCK_SHUTDOWN_SEND, vec![]);
+                    // Once neither end has anything left to send, the
+                    // connection's over.
+                    self.flush(key);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The guest won't send any more, or won't take any more either.
+    fn shutdown(&mut self, key: Key, flags: u32) {
+        if let Some(connection) = self.connections.get_mut(&key) {
+            if flags & VSOCK_SHUTDOWN_SEND != 0 {
+                connection.guest_done = true;
+            }
+            if flags & VSOCK_SHUTDOWN_RCV != 0 && connection.guest_done {
+                connection.phase = Phase::Closing;
+            }
+        }
+        self.flush(key);
+    }
+
+    /// Sends the guest a packet for a connection, with its credit.
+    fn send(&mut self, key: Key, op: u16, flags: u32, data: Vec<u8>) {
+        let fwd_cnt = match self.connections.get_mut(&key) {
+            Some(connection) => {
+                connection.told = connection.fwd_cnt;
+                connection.fwd_cnt
+            }
+            None => 0,
+        };
+        self.sink.receive(Packet {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.cid,
+            src_port: key.0,
+            dst_port: key.1,
+            kind: VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt,
+            data,
+        });
+    }
+
+    /// Drops a connection, and tells the guest it's gone.
+    fn reset(&mut self, key: Key) {
+        self.remove(key);
+        self.send(key, VSOCK_OP_RST, 0, vec![]);
+    }
+
+    fn remove(&mut self, key: Key) {
+        if let Some(connection) = self.connections.remove(&key) {
+            self.owners.remove(&connection.token);
+            let _ = self
+                .poll
+                .deregister(&EventedFd(&connection.stream.as_raw_fd()));
+        }
+    }
+
+    /// Drops every connection, without a word to the guest.
+    fn clear(&mut self) {
+        let keys = self.connections.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    /// Gives up on the connections the host or the guest took too long
+    /// to set up.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let late = self
+            .handshakes
+            .iter()
+            .filter(|&(_, handshake)| now.duration_since(handshake.started) > CONNECT_TIMEOUT)
+            .map(|(&token, _)| token)
+            .collect::<Vec<_>>();
+        for token in late {
+            if let Some(handshake) = self.handshakes.remove(&token) {
+                let _ = self.poll.deregister(&EventedFd(&handshake.stream.as_raw_fd()));
+            }
+        }
+
+        let late = self
+            .connections
+            .iter()
+            .filter(|&(_, connection)| match connection.phase {
+                Phase::Connecting(started) => now.duration_since(started) > CONNECT_TIMEOUT,
+                _ => false,
+            })
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+        for key in late {
+            debug!("guest didn't answer vsock connection {:?}", key);
+            self.reset(key);
+        }
+    }
+}
+
+/// The port in a `CONNECT <port>` line.
+fn parse_connect(line: &[u8]) -> Option<u32> {
+    let line = str::from_utf8(line).ok()?;
+    let mut words = line.trim_end().split(' ');
+    match (words.next(), words.next(), words.next()) {
+        (Some("CONNECT"), Some(port), None) => port.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch;
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    const GUEST: u64 = 3;
+
+    /// Passes the packets meant for the guest on to the test.
+    #[derive(Debug)]
+    struct Guest(Mutex<Sender<Packet>>);
+
+    impl Sink for Guest {
+        fn receive(&self, packet: Packet) {
+            let _ = self.0.lock().unwrap().send(packet);
+        }
+    }
+
+    fn muxer(name: &str) -> (Muxer, String, Receiver<Packet>) {
+        let path = scratch::path(name).to_str().unwrap().to_owned();
+        let muxer = Muxer::bind(GUEST, &path).unwrap();
+        let (sender, packets) = mpsc::channel();
+        muxer.attach(Arc::new(Guest(Mutex::new(sender)))).unwrap();
+        (muxer, path, packets)
+    }
+
+    fn packet(op: u16, host: u32, guest: u32, data: &[u8]) -> Packet {
+        Packet {
+            src_cid: GUEST,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: guest,
+            dst_port: host,
+            kind: VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: 0x10000,
+            data: data.to_vec(),
+            ..Packet::default()
+        }
+    }
+
+    fn next(packets: &Receiver<Packet>) -> Packet {
+        packets.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn it_connects_the_guest_to_the_host() {
+        let (muxer, path, packets) = muxer("vsock-guest");
+        let service = format!("{}_52", path);
+        let _ = fs::remove_file(&service);
+        let listener = UnixListener::bind(&service).unwrap();
+
+        // Nothing listens on port 53.
+        muxer.send(packet(VSOCK_OP_REQUEST, 53, 1024, &[])).unwrap();
+        let reset = next(&packets);
+        assert_eq!((reset.op, reset.dst_port), (VSOCK_OP_RST, 1024));
+
+        muxer.send(packet(VSOCK_OP_REQUEST, 52, 1024, &[])).unwrap();
+        let response = next(&packets);
+        assert_eq!(response.op, VSOCK_OP_RESPONSE);
+        assert_eq!((response.src_port, response.dst_port), (52, 1024));
+        assert_eq!(response.buf_alloc, BUF_ALLOC);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        muxer.send(packet(VSOCK_OP_RW, 52, 1024, b"ping")).unwrap();
+        let mut data = [0u8; 4];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"ping");
+
+        stream.write_all(b"pong").unwrap();
+        let reply = next(&packets);
+        assert_eq!((reply.op, &reply.data[..]), (VSOCK_OP_RW, &b"pong"[..]));
+
+        // The host hanging up ends what it sends, and once the guest's
+        // done too, the connection's gone.
+        drop(stream);
+        let shutdown = next(&packets);
+        assert_eq!(
+            (shutdown.op, shutdown.flags),
+            (VSOCK_OP_SHUTDOWN, VSOCK_SHUTDOWN_SEND)
+        );
+        let flags = VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND;
+        let mut shutdown = packet(VSOCK_OP_SHUTDOWN, 52, 1024, &[]);
+        shutdown.flags = flags;
+        muxer.send(shutdown).unwrap();
+        assert_eq!(next(&packets).op, VSOCK_OP_RST);
+
+        let _ = fs::remove_file(&service);
+    }
+
+    #[test]
+    fn it_connects_the_host_to_the_guest() {
+        let (muxer, path, packets) = muxer("vsock-host");
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(b"CONNECT 1234\nhello").unwrap();
+
+        let request = next(&packets);
+        assert_eq!(request.op, VSOCK_OP_REQUEST);
+        assert_eq!((request.dst_cid, request.dst_port), (GUEST, 1234));
+        let port = request.src_port;
+        assert!(port >= FIRST_HOST_PORT);
+
+        // The guest only has room for eight bytes.
+        let mut response = packet(VSOCK_OP_RESPONSE, port, 1234, &[]);
+        response.buf_alloc = 8;
+        muxer.send(response).unwrap();
+        let mut line = vec![0u8; format!("OK {}\n", port).len()];
+        stream.read_exact(&mut line).unwrap();
+        assert_eq!(line, format!("OK {}\n", port).into_bytes());
+
+        // What the host sent after the line waited for the guest.
+        let data = next(&packets);
+        assert_eq!((data.op, &data.data[..]), (VSOCK_OP_RW, &b"hello"[..]));
+
+        // The guest is only ever sent what it has room for.
+        stream.write_all(b"world").unwrap();
+        let data = next(&packets);
+        assert_eq!(&data.data[..], b"wor");
+        let mut roomier = packet(VSOCK_OP_CREDIT_UPDATE, port, 1234, &[]);
+        roomier.buf_alloc = 8;
+        roomier.fwd_cnt = 8;
+        muxer.send(roomier).unwrap();
+        let data = next(&packets);
+        assert_eq!(&data.data[..], b"ld");
+
+        muxer.send(packet(VSOCK_OP_CREDIT_REQUEST, port, 1234, &[])).unwrap();
+        assert_eq!(next(&packets).op, VSOCK_OP_CREDIT_UPDATE);
+
+        // The guest resetting the connection hangs up on the host.
+        muxer.send(packet(VSOCK_OP_RST, port, 1234, &[])).unwrap();
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn it_reads_connect_lines() {
+        assert_eq!(parse_connect(b"CONNECT 52"), Some(52));
+        assert_eq!(parse_connect(b"CONNECT 52\r"), Some(52));
+        assert_eq!(parse_connect(b"CONNECT"), None);
+        assert_eq!(parse_connect(b"CONNECT 52 53"), None);
+        assert_eq!(parse_connect(b"LISTEN 52"), None);
+    }
+}